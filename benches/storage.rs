@@ -0,0 +1,68 @@
+//! Compares storing records of a high-rate source as database rows and in a segment log.
+//! Every record is stored on its own, as the `/add` endpoint does.
+//!
+//! Run with `cargo bench --bench storage [count]`, the database and the log are written to a temporary directory.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use broker::config::DatabaseCfg;
+use broker::common::defaults;
+use broker::data::db::init_db;
+use broker::data::rep;
+use broker::data::segment_log::{self, SegmentLog};
+use broker::models::{Record, Source};
+
+fn record(n: usize) -> Record {
+    Record {
+        src_id: "meter".to_string(),
+        data: format!(r#"{{"n":{},"voltage":230.{},"current":4.{}}}"#, n, n % 10, n % 7).into_bytes(),
+        seq: Some(n as i64),
+        ..Default::default()
+    }
+}
+
+fn report(name: &str, count: usize, elapsed: Duration) {
+    println!("{:<28} {:>8} records {:>10.1} ms {:>12.0} records/s",
+        name, count, elapsed.as_secs_f64() * 1000.0, count as f64 / elapsed.as_secs_f64());
+}
+
+#[tokio::main]
+async fn main() {
+    let count: usize = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(5000);
+
+    let dir = TempDir::new().unwrap();
+    let source = Source { src_id: "meter".to_string(), cfg: None, active: true };
+
+    // a row per record
+    let pool = init_db(&dir.path().join("rows.db").to_string_lossy(), &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &source).await.unwrap();
+
+    let started = Instant::now();
+    for n in 0..count {
+        rep::add_data(&pool, &[record(n)]).await.unwrap();
+    }
+    report("sqlite rows", count, started.elapsed());
+
+    // appended to the log and synced before it is acknowledged as the receiving endpoint does
+    let mut log = SegmentLog::open(dir.path().join("log"), "meter", defaults::log_segment_size()).unwrap();
+
+    let started = Instant::now();
+    for n in 0..count {
+        log.append(&[record(n)]).unwrap();
+        log.sync().unwrap();
+    }
+    report("segment log", count, started.elapsed());
+
+    // moved from the log to the delivery queue in batches
+    let pool = init_db(&dir.path().join("log.db").to_string_lossy(), &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &source).await.unwrap();
+    let log = Arc::new(Mutex::new(log));
+
+    let started = Instant::now();
+    let moved = segment_log::drain(&pool, &log, defaults::LOG_DRAIN_BATCH).await.unwrap();
+    report("segment log drain", moved, started.elapsed());
+}
@@ -0,0 +1,557 @@
+use std::path::{Component, Path, PathBuf};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use bytes::Bytes;
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use crate::config::{Config, Role};
+use crate::data::{backup, dead_letter, sequence, spool, stats, target, webhook};
+use crate::data::backup::BackupError;
+use crate::data::export::{ExportError, ExportFormat, Exporter};
+use crate::data::error::RepError;
+use crate::models::{EventKind, RecordFilter};
+
+/// Count of the latest events in the sequence report
+const SEQUENCE_EVENTS_COUNT: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SequenceQuery {
+    pub src_id: Option<String>,
+}
+
+/// Reports sequence tracking states of the sources and the latest anomalies
+#[get("/admin/sequences")]
+pub async fn sequence_report(
+    req: HttpRequest,
+    query: web::Query<SequenceQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let states = match sequence::get_sequence_states(&pool).await {
+        Ok(states) => states
+            .into_iter()
+            .filter(|state| query.src_id.as_ref().is_none_or(|src_id| *src_id == state.src_id))
+            .collect::<Vec<_>>(),
+        Err(e) => return RepError::from_boxed(e).error_response(),
+    };
+
+    let events = match sequence::get_sequence_events(&pool, query.src_id.as_deref(), &SEQUENCE_EVENTS_COUNT).await {
+        Ok(events) => events,
+        Err(e) => return RepError::from_boxed(e).error_response(),
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "sources": states,
+        "events": events,
+    }))
+}
+
+/// Default count of dead letters in the list
+const DEAD_LETTERS_COUNT: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct DeadLetterQuery {
+    pub src_id: Option<String>,
+    pub count: Option<u32>,
+}
+
+/// Lists dead letters without payloads, the oldest records first
+#[get("/admin/dead-letters")]
+pub async fn list_dead_letters(
+    req: HttpRequest,
+    query: web::Query<DeadLetterQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let count = query.count.unwrap_or(DEAD_LETTERS_COUNT);
+
+    match dead_letter::get_dead_letters(&pool, query.src_id.as_deref(), &count).await {
+        Ok(letters) => HttpResponse::Ok().json(letters),
+        Err(e) => RepError::from_boxed(e).error_response(),
+    }
+}
+
+/// Gets a dead letter with the Base64-encoded payload
+#[get("/admin/dead-letters/{id}")]
+pub async fn get_dead_letter(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let letter = match dead_letter::get_dead_letter(&pool, id.into_inner()).await {
+        Ok(Some(letter)) => letter,
+        Ok(None) => return HttpResponse::NotFound().body("Dead letter not found"),
+        Err(e) => return RepError::from_boxed(e).error_response(),
+    };
+
+    let data = match spool::load(&letter.to_record()).await {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to load the payload of the dead letter {}: {}", letter.id, e);
+            return HttpResponse::InternalServerError().body("Failed to load the payload");
+        }
+    };
+
+    let mut body = serde_json::to_value(&letter).unwrap_or_default();
+    body["data"] = STANDARD.encode(data).into();
+
+    HttpResponse::Ok().json(body)
+}
+
+/// Returns a dead letter to the delivery queue
+#[post("/admin/dead-letters/{id}/requeue")]
+pub async fn requeue_dead_letter(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match dead_letter::requeue(&pool, &[id.into_inner()]).await {
+        Ok(0) => HttpResponse::NotFound().body("Dead letter not found"),
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => RepError::from_boxed(e).error_response(),
+    }
+}
+
+/// Deletes a dead letter
+#[delete("/admin/dead-letters/{id}")]
+pub async fn delete_dead_letter(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match dead_letter::delete_dead_letters(&pool, &[id.into_inner()]).await {
+        Ok(0) => HttpResponse::NotFound().body("Dead letter not found"),
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => RepError::from_boxed(e).error_response(),
+    }
+}
+
+/// Deletes all dead letters, optionally of one source
+#[delete("/admin/dead-letters")]
+pub async fn purge_dead_letters(
+    req: HttpRequest,
+    query: web::Query<DeadLetterQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match dead_letter::purge_dead_letters(&pool, query.src_id.as_deref()).await {
+        Ok(purged) => HttpResponse::Ok().json(serde_json::json!({ "purged": purged })),
+        Err(e) => RepError::from_boxed(e).error_response(),
+    }
+}
+
+/// Reports backlog and the last upload results of the upstream targets
+#[get("/admin/targets")]
+pub async fn target_report(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match target::get_target_stats(&pool).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => RepError::from_boxed(e).error_response(),
+    }
+}
+
+/// Reports stored payload sizes and compression ratios of the sources storing compressed payloads
+#[get("/admin/compression")]
+pub async fn compression_report(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match stats::get_compression_stats(&pool).await {
+        Ok(stats) => HttpResponse::Ok().json(
+            stats.iter()
+                .map(|s| {
+                    let mut body = serde_json::to_value(s).unwrap_or_default();
+                    body["ratio"] = s.ratio().into();
+                    body
+                })
+                .collect::<Vec<_>>()
+        ),
+        Err(e) => RepError::from_boxed(e).error_response(),
+    }
+}
+
+/// Size of the chunks a streamed backup is sent in
+const BACKUP_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct BackupQuery {
+    #[serde(default)]
+    pub exclude_sent: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackupRequest {
+    /// Path of the backup file relative to the backup directory, it must not exist
+    pub path: PathBuf,
+    #[serde(default)]
+    pub exclude_sent: bool,
+}
+
+/// Writes a backup of the database to a path within the backup directory
+#[post("/admin/backup")]
+pub async fn backup_to_path(
+    req: HttpRequest,
+    request: web::Json<BackupRequest>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let path = match backup_path(cfg.backup_dir.as_deref(), &request.path) {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+
+    match backup::backup(&pool, &path, request.exclude_sent).await {
+        Ok(size) => HttpResponse::Ok().json(serde_json::json!({
+            "path": path,
+            "size": size,
+        })),
+        Err(e) => backup_error_response(e),
+    }
+}
+
+/// Streams a backup of the database
+#[get("/admin/backup")]
+pub async fn download_backup(
+    req: HttpRequest,
+    query: web::Query<BackupQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let path = std::env::temp_dir().join(format!("broker-backup-{}.db", nanos));
+
+    if let Err(e) = backup::backup(&pool, &path, query.exclude_sent).await {
+        let _ = tokio::fs::remove_file(&path).await;
+        return backup_error_response(e);
+    }
+
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => return backup_error_response(e.into()),
+    };
+
+    // the opened file is read till the end after its name is removed
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        log::warn!("Failed to remove the backup {}: {}", path.display(), e);
+    }
+
+    let chunks = futures::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut chunk = vec![0; BACKUP_CHUNK_SIZE];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(size) => {
+                chunk.truncate(size);
+                Some((Ok(Bytes::from(chunk)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/vnd.sqlite3")
+        .insert_header(("Content-Disposition", "attachment; filename=\"broker.db\""))
+        .streaming::<_, std::io::Error>(chunks)
+}
+
+/// Gets the path of the requested backup within the backup directory, paths leaving the directory are rejected
+fn backup_path(backup_dir: Option<&str>, path: &Path) -> Result<PathBuf, HttpResponse> {
+    let Some(backup_dir) = backup_dir else {
+        return Err(HttpResponse::Forbidden().body("Backups to a path are disabled, backup_dir is not configured"));
+    };
+
+    let relative = path.components().all(|component| matches!(component, Component::Normal(_)));
+    if !relative || path.as_os_str().is_empty() {
+        return Err(HttpResponse::BadRequest().body("Backup path must be a relative path within the backup directory"));
+    }
+
+    Ok(Path::new(backup_dir).join(path))
+}
+
+fn backup_error_response(e: BackupError) -> HttpResponse {
+    match e {
+        BackupError::Database(e) => e.error_response(),
+        BackupError::Exists(_) => HttpResponse::Conflict().body(e.to_string()),
+        e => {
+            log::error!("Failed to back up the database: {}", e);
+            HttpResponse::InternalServerError().body("Failed to back up the database")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    pub src_id: Option<String>,
+    /// Records stored at or after this time in Unix seconds
+    pub from: Option<i64>,
+    /// Records stored before this time in Unix seconds
+    pub to: Option<i64>,
+    pub sent: Option<bool>,
+    /// Exported records are marked as sent once the whole export is sent
+    #[serde(default)]
+    pub mark_sent: bool,
+}
+
+/// Streams the records matching the query as JSON Lines, CSV or Parquet
+#[get("/admin/export")]
+pub async fn export_records(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let query = query.into_inner();
+    let filter = RecordFilter { src_id: query.src_id, from: query.from, to: query.to, sent: query.sent };
+
+    let mut exporter = match Exporter::new(pool.get_ref().clone(), filter, query.format, query.mark_sent) {
+        Ok(exporter) => exporter,
+        Err(e) => return export_error_response(e),
+    };
+
+    // errors of the first page are still reported with the status
+    let first = match exporter.next_chunk().await {
+        Ok(chunk) => chunk,
+        Err(e) => return export_error_response(e),
+    };
+
+    let rest = futures::stream::unfold(Some(exporter), |exporter| async move {
+        let mut exporter = exporter?;
+        match exporter.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(Bytes::from(chunk)), Some(exporter))),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("Failed to export records: {}", e);
+                Some((Err(std::io::Error::other(e.to_string())), None))
+            }
+        }
+    });
+
+    let chunks = futures::stream::iter(first.map(|chunk| Ok(Bytes::from(chunk)))).chain(rest);
+
+    HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"records.{}\"", query.format.extension()),
+        ))
+        .streaming::<_, std::io::Error>(chunks)
+}
+
+fn export_error_response(e: ExportError) -> HttpResponse {
+    match e {
+        ExportError::Database(e) => e.error_response(),
+        e => {
+            log::error!("Failed to export records: {}", e);
+            HttpResponse::InternalServerError().body("Failed to export records")
+        }
+    }
+}
+
+/// Min length of the secret the webhook requests are signed with
+const WEBHOOK_SECRET_MIN_LENGTH: usize = 16;
+
+/// Default count of deliveries in the list
+const WEBHOOK_DELIVERIES_COUNT: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<EventKind>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+    pub count: Option<u32>,
+}
+
+/// Adds a webhook called with the events it is subscribed to
+#[post("/admin/webhooks")]
+pub async fn add_webhook(
+    req: HttpRequest,
+    body: web::Json<WebhookRequest>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let request = body.into_inner();
+    if !(request.url.starts_with("http://") || request.url.starts_with("https://")) {
+        return HttpResponse::BadRequest().body("Webhook URL must be an http:// or https:// URL");
+    }
+    if request.secret.len() < WEBHOOK_SECRET_MIN_LENGTH {
+        return HttpResponse::BadRequest()
+            .body(format!("Webhook secret must be at least {} characters long", WEBHOOK_SECRET_MIN_LENGTH));
+    }
+    if request.events.is_empty() {
+        return HttpResponse::BadRequest().body("Webhook must be subscribed to at least one event");
+    }
+
+    let id = match webhook::add_webhook(&pool, &request.url, &request.secret, &request.events).await {
+        Ok(id) => id,
+        Err(e) => return e.error_response(),
+    };
+
+    match webhook::get_webhook(&pool, id).await {
+        Ok(Some(webhook)) => HttpResponse::Created().json(webhook),
+        Ok(None) => HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Lists the webhooks without their secrets
+#[get("/admin/webhooks")]
+pub async fn list_webhooks(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match webhook::get_webhooks(&pool).await {
+        Ok(webhooks) => HttpResponse::Ok().json(webhooks),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Deletes a webhook with its deliveries
+#[delete("/admin/webhooks/{id}")]
+pub async fn delete_webhook(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    match webhook::delete_webhook(&pool, id.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(RepError::NotFound(_)) => HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Lists the latest deliveries of a webhook with their states and last errors
+#[get("/admin/webhooks/{id}/deliveries")]
+pub async fn list_webhook_deliveries(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    query: web::Query<DeliveryQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let id = id.into_inner();
+    match webhook::get_webhook(&pool, id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => return e.error_response(),
+    }
+
+    let count = query.count.unwrap_or(WEBHOOK_DELIVERIES_COUNT);
+    match webhook::get_deliveries(&pool, id, &count).await {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Queues a `webhook.test` delivery to the webhook, whatever events it is subscribed to
+#[post("/admin/webhooks/{id}/test")]
+pub async fn test_webhook(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Admin) {
+        return response;
+    }
+
+    let data = serde_json::json!({ "message": "Test event" });
+    match webhook::add_deliveries(&pool, EventKind::Test, &data, Some(id.into_inner())).await {
+        Ok(0) => HttpResponse::NotFound().body("Webhook not found"),
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(e) => e.error_response(),
+    }
+}
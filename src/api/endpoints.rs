@@ -1,37 +1,192 @@
-use actix_web::{HttpResponse, Responder, HttpRequest, web, post};
-use bytes::Bytes;
-use sqlx::SqlitePool;
-use crate::data::rep;
-use crate::models::Record;
+use actix_web::{HttpResponse, Responder, HttpRequest, ResponseError, web, post};
+use crate::config::Config;
+use crate::data::spool;
+use crate::data::segment_log::{self, Logs};
+use crate::data::dedupe::Stored;
+use crate::data::feed::Feed;
+use crate::data::spool::Payload;
+use crate::data::store::{RecordStore, SourceStore};
+use crate::ingest::encoding::decode;
+use crate::models::{Record, StorageEngine};
 
 #[post("/add")]
 pub async fn receive_data (
     req: HttpRequest,
-    body: Bytes,
-    pool: web::Data<SqlitePool>,
+    body: web::Payload,
+    sources: web::Data<dyn SourceStore>,
+    records: web::Data<dyn RecordStore>,
+    logs: Option<web::Data<Logs>>,
+    cfg: web::Data<Config>,
 ) -> impl Responder {
 
-    if body.len() == 0 {
+    let source = match super::filters::validate_source(&req, sources.get_ref()).await {
+        Ok(source) => source,
+        Err(response) => return response,
+    };
+
+    let source_cfg = match super::filters::source_config(&source) {
+        Ok(source_cfg) => source_cfg,
+        Err(response) => return response,
+    };
+
+    let seq = match super::filters::sequence_number(&req) {
+        Ok(seq) => seq,
+        Err(response) => return response,
+    };
+
+    let priority = match super::filters::record_priority(&req, &source, &source_cfg) {
+        Ok(priority) => priority,
+        Err(response) => return response,
+    };
+
+    let max_body_size = match super::filters::check_body_size(&req, &source_cfg, &cfg) {
+        Ok(size) => size,
+        Err(response) => return response,
+    };
+
+    let encoding = match super::filters::content_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(response) => return response,
+    };
+
+    // encoded bodies kept as received are opaque to the broker
+    let kept_encoding = encoding.filter(|_| source_cfg.keep_encoding);
+
+    let payload = match encoding {
+        Some(encoding) if kept_encoding.is_none() => {
+            let body = decode(body, encoding, cfg.max_expansion_ratio);
+            spool::receive(body, max_body_size, cfg.inline_payload_limit, &cfg.spool_dir).await
+        }
+        _ => spool::receive(body, max_body_size, cfg.inline_payload_limit, &cfg.spool_dir).await,
+    };
+
+    let payload = match payload {
+        Ok(payload) => payload,
+        Err(e) => return super::filters::spool_error_response(e),
+    };
+
+    if payload.is_empty() {
         return  HttpResponse::InternalServerError().body("Empty data is not allowed.");
     }
-    
-    let source_id = match super::filters::validate_source_id(&req, &pool).await {
-        Ok(source_id) => source_id,
-        Err(response) => return response,
+
+    let received = payload.len();
+
+    let dedupe_window = match source_cfg.storage {
+        StorageEngine::Sqlite => source_cfg.dedupe_window.unwrap_or(cfg.dedupe_window),
+        StorageEngine::Log => 0,
     };
-    
+    let dedupe_key = if dedupe_window > 0 {
+        match super::filters::dedupe_key(&req, &payload).await {
+            Ok(key) => Some(key),
+            Err(response) => {
+                if let Payload::Spooled { path, .. } = &payload {
+                    spool::discard(path).await;
+                }
+                return response;
+            }
+        }
+    } else {
+        None
+    };
+
+    let payload = match kept_encoding {
+        Some(_) => payload,
+        None => match super::filters::prepare_payload(records.get_ref(), &source, &source_cfg, payload, cfg.inline_payload_limit).await {
+            Ok(Some(payload)) => payload,
+            // filtered out by the transformation pipeline
+            Ok(None) => return HttpResponse::Ok().body(received.to_string()),
+            Err(response) => return response,
+        },
+    };
+
+    // payloads already compressed by the source are not compressed again
+    let (data, data_file, compression) = match payload {
+        Payload::Inline(data) => (data, None, source_cfg.compression.filter(|_| kept_encoding.is_none())),
+        Payload::Spooled { path, .. } => (Vec::new(), Some(path), None),
+    };
+
     let record = Record {
         id: 0_u32,
-        src_id: source_id,
-        data: body.to_vec(),
+        src_id: source.src_id,
+        data,
         sent: false,
+        data_file,
+        seq,
+        priority,
+        compression,
+        content_encoding: kept_encoding,
+        ..Default::default()
     };
     
-    let arr = vec![record];
-    let res = rep::add_data(&pool, &arr).await;
+    // spooled payloads of log sources are stored as rows as they are not kept in the log
+    if source_cfg.storage == StorageEngine::Log && record.data_file.is_none() {
+        return append_to_log(records.get_ref(), logs, record, received).await;
+    }
+
+    let res = match &dedupe_key {
+        Some(key) => records.add_data_once(&record, key, dedupe_window).await,
+        None => records.add_data(std::slice::from_ref(&record)).await.map(|ids| Stored::Added(ids[0])),
+    };
     
     match res {
-        Ok(_) => HttpResponse::Ok().body(body.len().to_string()),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(Stored::Added(id)) => {
+            records.source_seen(&record.src_id).await;
+            // live subscribers get the record once it is published in the order of ids
+            if let Some(feed) = req.app_data::<web::Data<Feed>>() {
+                feed.notify();
+            }
+            HttpResponse::Ok()
+                .insert_header(("X-Record-Id", id.to_string()))
+                .body(received.to_string())
+        }
+        Ok(Stored::Duplicate(id)) => {
+            if let Some(path) = &record.data_file {
+                spool::discard(path).await;
+            }
+            HttpResponse::Ok()
+                .insert_header(("X-Record-Id", id.to_string()))
+                .insert_header(("X-Duplicate", "true"))
+                .body(received.to_string())
+        }
+        Err(e) => {
+            if let Some(path) = &record.data_file {
+                spool::discard(path).await;
+            }
+            e.error_response()
+        }
+    }
+}
+
+/// Appends the record to the segment log of the source, it is acknowledged once synced to the disk
+async fn append_to_log(records: &dyn RecordStore, logs: Option<web::Data<Logs>>, record: Record, received: usize)
+    -> HttpResponse {
+
+    let Some(logs) = logs else {
+        return HttpResponse::InternalServerError().body("Log storage is not configured");
+    };
+
+    let src_id = record.src_id.clone();
+
+    // the log is not in the database, the sequence is tracked first so the client retries if it fails
+    if let Some(seq) = record.seq {
+        if let Err(e) = records.track_sequence(&src_id, seq).await {
+            log::error!("Failed to track the sequence of the source {}: {}", src_id, e);
+            return HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", "1"))
+                .body("Failed to track the sequence, retry later");
+        }
+    }
+
+    match segment_log::append_synced(logs.into_inner(), record).await {
+        Ok(offset) => {
+            records.source_seen(&src_id).await;
+            HttpResponse::Ok()
+                .insert_header(("X-Log-Offset", offset.to_string()))
+                .body(received.to_string())
+        }
+        Err(e) => {
+            log::error!("Failed to append to the log of the source {}: {}", src_id, e);
+            HttpResponse::InternalServerError().body("Internal storage error")
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,17 @@
-use actix_web::{dev::{ServiceRequest, ServiceResponse}, web, Error, HttpRequest, HttpResponse};
+use actix_web::{dev::{ServiceRequest, ServiceResponse}, Error, HttpRequest, HttpResponse, ResponseError};
 use actix_web::body::MessageBody;
 use actix_web::error::ErrorForbidden;
 use actix_web::middleware::Next;
-use sqlx::SqlitePool;
+use actix_web::http::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, WWW_AUTHENTICATE};
+use crate::common::compression::Compression;
+use crate::common::defaults::{MAX_PRIORITY, MIN_PRIORITY};
 use crate::common::helpers;
-use crate::data::rep;
+use crate::config::{Config, Role};
+use crate::data::spool;
+use crate::data::store::{RecordStore, SourceStore};
+use crate::data::spool::{Payload, SpoolError};
+use crate::ingest::payload::{self, IngestError};
+use crate::models::{Source, SourceCfg};
 
 /// Private IP address verification middleware (IPv4 и IPv6)
 pub async fn only_private_ip (
@@ -26,9 +33,55 @@ pub async fn only_private_ip (
     // post-processing
 }
 
+/// Checks the API key of the `Authorization: Bearer` header grants the role, returns the name of the key
+pub fn authorize(req: &HttpRequest, cfg: &Config, role: Role) -> Result<String, HttpResponse> {
+
+    let unauthorized = |message: &str| HttpResponse::Unauthorized()
+        .insert_header((WWW_AUTHENTICATE, "Bearer"))
+        .body(message.to_string());
+
+    let key = req
+        .headers()
+        .get(AUTHORIZATION)
+        .ok_or_else(|| unauthorized("Missing Authorization header"))?
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| unauthorized("Invalid Authorization header value, expected Bearer <key>"))?;
+
+    // every key is compared, so the time does not tell which key is close
+    let api_key = cfg.api_keys
+        .iter()
+        .fold(None, |found, api_key| match keys_equal(api_key.key.as_bytes(), key.as_bytes()) {
+            true => Some(api_key),
+            false => found,
+        })
+        .ok_or_else(|| unauthorized("Invalid API key"))?;
+
+    if !api_key.roles.contains(&role) {
+        return Err(HttpResponse::Forbidden().body(
+            format!("API key {} does not grant access. Access denied.", api_key.name))
+        );
+    }
+
+    Ok(api_key.name.clone())
+}
+
+/// Compares the keys in time independent of the position of the first difference
+fn keys_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 /// Checks X-Source-Id header
-pub async fn validate_source_id(req: &HttpRequest, pool: &web::Data<SqlitePool>)
+pub async fn validate_source_id(req: &HttpRequest, sources: &dyn SourceStore)
     -> Result<String, HttpResponse> {
+    validate_source(req, sources).await.map(|source| source.src_id)
+}
+
+/// Checks X-Source-Id header and returns the registered active data source
+pub async fn validate_source(req: &HttpRequest, sources: &dyn SourceStore)
+    -> Result<Source, HttpResponse> {
   
     let source_id = req
         .headers()
@@ -41,9 +94,9 @@ pub async fn validate_source_id(req: &HttpRequest, pool: &web::Data<SqlitePool>)
         return Err(HttpResponse::BadRequest().body("X-Source-Id header cannot be empty"));
     }
 
-    let source_entity = match  rep::get_source_by_id(&pool, &source_id).await {
+    let source_entity = match sources.get_source_by_id(source_id).await {
         Ok(source) => source,
-        Err(e) => return Err(HttpResponse::InternalServerError().body(e.to_string()))
+        Err(e) => return Err(e.error_response())
     };
     
     let source = match source_entity {
@@ -59,5 +112,144 @@ pub async fn validate_source_id(req: &HttpRequest, pool: &web::Data<SqlitePool>)
         );
     }
     
-    Ok(source_id.to_string())
+    Ok(source)
+}
+
+/// Parses the configuration of the source
+pub fn source_config(source: &Source) -> Result<SourceCfg, HttpResponse> {
+    source.validated_config().map_err(|e| HttpResponse::InternalServerError().body(
+        format!("Invalid configuration of the source {}: {}", source.src_id, e)
+    ))
+}
+
+/// Gets the max body size for the source and rejects requests declaring a larger Content-Length
+pub fn check_body_size(req: &HttpRequest, source_cfg: &SourceCfg, cfg: &Config)
+    -> Result<usize, HttpResponse> {
+
+    let max_body_size = source_cfg.max_body_size.unwrap_or(cfg.max_body_size);
+
+    let content_length = req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if content_length.is_some_and(|len| len > max_body_size) {
+        return Err(HttpResponse::PayloadTooLarge().body(SpoolError::TooLarge(max_body_size).to_string()));
+    }
+
+    Ok(max_body_size)
+}
+
+/// Gets the compression of the request body from the Content-Encoding header,
+/// rejects unsupported and stacked encodings
+pub fn content_encoding(req: &HttpRequest) -> Result<Option<Compression>, HttpResponse> {
+
+    let value = match req.headers().get(CONTENT_ENCODING) {
+        Some(value) => value.to_str().unwrap_or_default().trim(),
+        None => return Ok(None),
+    };
+
+    if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+        return Ok(None);
+    }
+
+    Compression::from_encoding(value)
+        .map(Some)
+        .ok_or_else(|| HttpResponse::UnsupportedMediaType().body(
+            format!("Unsupported Content-Encoding {}, expected gzip, deflate or zstd", value)
+        ))
+}
+
+/// Validates and transforms the received payload according to the source cfg.
+/// Returns `None` when the payload has been filtered out by the transformation pipeline.
+pub async fn prepare_payload(records: &dyn RecordStore, source: &Source, source_cfg: &SourceCfg,
+                             payload: Payload, inline_limit: usize)
+    -> Result<Option<Payload>, HttpResponse> {
+
+    payload::prepare_payload(records, source, source_cfg, payload, inline_limit)
+        .await
+        .map_err(|e| match e {
+            IngestError::Rejected(_) => HttpResponse::UnprocessableEntity().body(e.to_string()),
+            IngestError::Config { .. } | IngestError::Io(_) => {
+                log::error!("Failed to prepare the payload of the source {}: {}", source.src_id, e);
+                HttpResponse::InternalServerError().body("Failed to prepare the payload")
+            }
+        })
+}
+
+/// Gets the deduplication key of the submission: the Idempotency-Key header
+/// or the content hash when the header is absent
+pub async fn dedupe_key(req: &HttpRequest, payload: &Payload) -> Result<String, HttpResponse> {
+
+    if let Some(value) = req.headers().get("Idempotency-Key") {
+        let key = value
+            .to_str()
+            .map_err(|_| HttpResponse::BadRequest().body("Invalid Idempotency-Key header value"))?;
+
+        if key.is_empty() || key.len() > 255 {
+            return Err(HttpResponse::BadRequest().body("Idempotency-Key header must be 1 to 255 characters long"));
+        }
+
+        return Ok(format!("key:{}", key));
+    }
+
+    spool::digest(payload)
+        .await
+        .map(|digest| format!("sha256:{}", digest))
+        .map_err(|e| {
+            log::error!("Failed to digest the payload: {}", e);
+            HttpResponse::InternalServerError().body("Failed to digest the payload")
+        })
+}
+
+/// Gets the sequence number from the optional X-Sequence header
+pub fn sequence_number(req: &HttpRequest) -> Result<Option<i64>, HttpResponse> {
+    match req.headers().get("X-Sequence") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .filter(|seq| *seq >= 0)
+            .map(Some)
+            .ok_or_else(|| HttpResponse::BadRequest().body("Invalid X-Sequence header value")),
+        None => Ok(None),
+    }
+}
+
+/// Gets the record priority from the X-Priority header or the source cfg
+pub fn record_priority(req: &HttpRequest, source: &Source, source_cfg: &SourceCfg) -> Result<i64, HttpResponse> {
+
+    let valid = |priority: &i64| (MIN_PRIORITY..=MAX_PRIORITY).contains(priority);
+
+    if let Some(value) = req.headers().get("X-Priority") {
+        return value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .filter(valid)
+            .ok_or_else(|| HttpResponse::BadRequest().body(
+                format!("X-Priority header must be an integer from {} to {}", MIN_PRIORITY, MAX_PRIORITY)
+            ));
+    }
+
+    match source_cfg.priority {
+        Some(priority) if !valid(&priority) => Err(HttpResponse::InternalServerError().body(
+            format!("Invalid configuration of the source {}: priority must be from {} to {}",
+                    source.src_id, MIN_PRIORITY, MAX_PRIORITY)
+        )),
+        Some(priority) => Ok(priority),
+        None => Ok(MIN_PRIORITY),
+    }
+}
+
+/// Maps body receiving errors to responses
+pub fn spool_error_response(e: SpoolError) -> HttpResponse {
+    match e {
+        SpoolError::TooLarge(_) | SpoolError::ExpansionRatio(_) => HttpResponse::PayloadTooLarge().body(e.to_string()),
+        SpoolError::Payload(_) => HttpResponse::BadRequest().body(e.to_string()),
+        SpoolError::Io(_) | SpoolError::Crypto(_) => {
+            log::error!("Failed to spool the payload: {}", e);
+            HttpResponse::InternalServerError().body("Failed to spool the payload")
+        }
+    }
 }
\ No newline at end of file
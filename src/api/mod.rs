@@ -1,3 +1,7 @@
+pub mod admin;
 pub mod endpoints;
 pub mod filters;
+pub mod records;
+pub mod subscribe;
+pub mod video;
 mod api_macro;
\ No newline at end of file
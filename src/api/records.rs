@@ -0,0 +1,120 @@
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use crate::config::{Config, Role};
+use crate::data::{rep, spool};
+use crate::models::{RecordFilter, StoredRecord};
+
+/// Default count of records in a page
+const RECORDS_COUNT: u32 = 100;
+/// Max count of records in a page
+const MAX_RECORDS_COUNT: u32 = 1000;
+
+/// How payloads are put into the JSON response
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataEncoding {
+    #[default]
+    Base64,
+    /// JSON payloads are embedded as JSON, text payloads as strings and binary payloads are Base64-encoded
+    Raw,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecordQuery {
+    pub src_id: Option<String>,
+    /// Records stored at or after this time in Unix seconds
+    pub from: Option<i64>,
+    /// Records stored before this time in Unix seconds
+    pub to: Option<i64>,
+    pub sent: Option<bool>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<u32>,
+    pub count: Option<u32>,
+    #[serde(default)]
+    pub encoding: DataEncoding,
+}
+
+/// Gets the stored records matching the query with their payloads, the newest first.
+/// Records of the log-stored sources are returned once they are moved to the database.
+#[get("/records")]
+pub async fn query_records(
+    req: HttpRequest,
+    query: web::Query<RecordQuery>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    if let Err(response) = super::filters::authorize(&req, &cfg, Role::Read) {
+        return response;
+    }
+
+    let query = query.into_inner();
+    let filter = RecordFilter { src_id: query.src_id, from: query.from, to: query.to, sent: query.sent };
+    let count = query.count.unwrap_or(RECORDS_COUNT).clamp(1, MAX_RECORDS_COUNT);
+
+    let records = match rep::get_data_by_filter(&pool, &filter, query.cursor, &count).await {
+        Ok(records) => records,
+        Err(e) => return e.error_response(),
+    };
+
+    let next_cursor = match records.last() {
+        Some(last) if records.len() as u32 == count => Some(last.record.id),
+        _ => None,
+    };
+
+    let mut body = Vec::with_capacity(records.len());
+    for stored in &records {
+        match record_json(stored, query.encoding).await {
+            Ok(record) => body.push(record),
+            Err(e) => {
+                log::error!("Failed to read the payload of the record {}: {}", stored.record.id, e);
+                return HttpResponse::InternalServerError().body("Failed to read the records");
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "records": body,
+        "next_cursor": next_cursor,
+    }))
+}
+
+/// Gets the record metadata with the payload in the encoding
+pub(crate) async fn record_json(stored: &StoredRecord, encoding: DataEncoding) -> Result<Value, spool::SpoolError> {
+    let record = &stored.record;
+    let data = spool::load(record).await?;
+
+    let (encoding, data) = match encoding {
+        DataEncoding::Raw => raw_data(data),
+        DataEncoding::Base64 => ("base64", STANDARD.encode(data).into()),
+    };
+
+    Ok(serde_json::json!({
+        "id": record.id,
+        "src_id": record.src_id,
+        "sent": record.sent,
+        "seq": record.seq,
+        "kind": record.kind,
+        "priority": record.priority,
+        "created_at": stored.created_at,
+        "content_encoding": record.content_encoding,
+        "encoding": encoding,
+        "data": data,
+    }))
+}
+
+/// Gets the payload as JSON, a string or Base64 string with the name of the encoding
+fn raw_data(data: Vec<u8>) -> (&'static str, Value) {
+    if let Ok(value) = serde_json::from_slice::<Value>(&data) {
+        return ("json", value);
+    }
+
+    match String::from_utf8(data) {
+        Ok(text) => ("text", text.into()),
+        Err(e) => ("base64", STANDARD.encode(e.into_bytes()).into()),
+    }
+}
@@ -0,0 +1,180 @@
+use std::time::Duration;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_ws::{CloseCode, CloseReason, Message};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use crate::config::{Config, Role};
+use crate::data::feed::{Feed, FeedError, Subscription};
+use crate::models::StoredRecord;
+use super::records::{record_json, DataEncoding};
+
+/// Idle subscriptions are kept alive by SSE comments or WebSocket pings sent at this interval
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeQuery {
+    /// Records of all sources are sent if not set
+    pub src_id: Option<String>,
+    /// Id of the last record the subscriber has got, the records stored after it are sent first
+    pub after: Option<u32>,
+    #[serde(default)]
+    pub encoding: DataEncoding,
+}
+
+/// Streams the stored records as Server-Sent Events with the record id as the event id,
+/// so a reconnecting `EventSource` resumes from its `Last-Event-ID`.
+/// A subscriber disconnected for lagging behind gets a `lagged` event before the stream ends.
+#[get("/subscribe")]
+pub async fn subscribe(
+    req: HttpRequest,
+    query: web::Query<SubscribeQuery>,
+    pool: web::Data<SqlitePool>,
+    feed: web::Data<Feed>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    let subscription = match start_subscription(&req, &query, &pool, &feed, &cfg).await {
+        Ok(subscription) => subscription,
+        Err(response) => return response,
+    };
+
+    let encoding = query.encoding;
+    let events = futures::stream::unfold(Some(subscription), move |subscription| async move {
+        let mut subscription = subscription?;
+
+        // the subscription is not changed by a timed out read, nothing is lost
+        let record = match tokio::time::timeout(KEEP_ALIVE_INTERVAL, subscription.next()).await {
+            Err(_) => return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), Some(subscription))),
+            Ok(Ok(Some(record))) => record,
+            Ok(Ok(None)) => return None,
+            Ok(Err(FeedError::Lagged(missed))) => {
+                let event = format!("event: lagged\ndata: {}\n\n", serde_json::json!({ "missed": missed }));
+                return Some((Ok::<_, std::io::Error>(Bytes::from(event)), None));
+            }
+            Ok(Err(e)) => {
+                log::error!("Subscription failed: {}", e);
+                return None;
+            }
+        };
+
+        let event = match event_json(&record, encoding).await {
+            Some(json) => format!("id: {}\nevent: record\ndata: {}\n\n", record.record.id, json),
+            None => ": skipped\n\n".to_string(),
+        };
+        Some((Ok(Bytes::from(event)), Some(subscription)))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// Sends the stored records as WebSocket text messages.
+/// A subscriber lagging behind is disconnected with the 1013 (try again later) close code.
+#[get("/subscribe/ws")]
+pub async fn subscribe_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<SubscribeQuery>,
+    pool: web::Data<SqlitePool>,
+    feed: web::Data<Feed>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    let mut subscription = match start_subscription(&req, &query, &pool, &feed, &cfg).await {
+        Ok(subscription) => subscription,
+        Err(response) => return response,
+    };
+
+    let (response, mut session, mut messages) = match actix_ws::handle(&req, body) {
+        Ok(handshake) => handshake,
+        Err(e) => return e.error_response(),
+    };
+
+    let encoding = query.encoding;
+    actix_web::rt::spawn(async move {
+        let reason = loop {
+            // only the reads not losing anything when cancelled are raced
+            let next = tokio::select! {
+                next = subscription.next() => next,
+                message = messages.recv() => {
+                    match message {
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if session.pong(&bytes).await.is_err() {
+                                return;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                        Some(Ok(_)) => {}
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => {
+                    if session.ping(b"").await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            let record = match next {
+                Ok(Some(record)) => record,
+                Ok(None) => break Some(CloseCode::Away.into()),
+                Err(e @ FeedError::Lagged(_)) => break Some(CloseReason {
+                    code: CloseCode::Again,
+                    description: Some(e.to_string()),
+                }),
+                Err(e) => {
+                    log::error!("Subscription failed: {}", e);
+                    break Some(CloseCode::Error.into());
+                }
+            };
+
+            if let Some(json) = event_json(&record, encoding).await {
+                if session.text(json.to_string()).await.is_err() {
+                    return;
+                }
+            }
+        };
+
+        let _ = session.close(reason).await;
+    });
+
+    response
+}
+
+/// Authorizes the subscriber and subscribes from the `Last-Event-ID` header or the `after` parameter
+async fn start_subscription(req: &HttpRequest, query: &SubscribeQuery, pool: &SqlitePool, feed: &Feed, cfg: &Config)
+    -> Result<Subscription, HttpResponse> {
+
+    super::filters::authorize(req, cfg, Role::Read)?;
+
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+                .ok_or_else(|| HttpResponse::BadRequest().body("Invalid Last-Event-ID header value"))?
+        ),
+        None => None,
+    };
+
+    feed.subscribe(pool.clone(), query.src_id.clone(), last_event_id.or(query.after))
+        .await
+        .map_err(|e| e.error_response())
+}
+
+/// Gets the JSON of the record, None if its payload cannot be read
+async fn event_json(record: &StoredRecord, encoding: DataEncoding) -> Option<Value> {
+    match record_json(record, encoding).await {
+        Ok(json) => Some(json),
+        Err(e) => {
+            log::warn!("Failed to read the payload of the record {}: {}", record.record.id, e);
+            None
+        }
+    }
+}
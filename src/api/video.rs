@@ -0,0 +1,315 @@
+use std::fmt::Write;
+use std::path::Path;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web::http::header::CONTENT_TYPE;
+use sqlx::SqlitePool;
+use crate::common::defaults::DEFAULT_SEGMENT_DURATION;
+use crate::config::Config;
+use crate::data::{spool, video};
+use crate::data::video::INIT_SEGMENT_FILE;
+use crate::data::error::RepError;
+use crate::data::spool::{Payload, SpoolError};
+use crate::data::store::SourceStore;
+use crate::models::VideoSegment;
+use super::filters;
+
+/// Supported segment formats: content type and file extension
+const SEGMENT_TYPES: [(&str, &str); 3] = [
+    ("video/mp2t", "ts"),
+    ("video/iso.segment", "m4s"),
+    ("video/mp4", "m4s"),
+];
+
+/// Uploads a video segment of the stream (MPEG-TS or fMP4 chunk)
+#[post("/video/{stream_id}/{seq}")]
+pub async fn upload_segment(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    body: web::Payload,
+    pool: web::Data<SqlitePool>,
+    sources: web::Data<dyn SourceStore>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    let (stream_id, seq) = path.into_inner();
+
+    let source = match filters::validate_source(&req, sources.get_ref()).await {
+        Ok(source) => source,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = validate_stream_id(&stream_id) {
+        return response;
+    }
+
+    if let Err(response) = check_stream_owner(&pool, &stream_id, &source.src_id).await {
+        return response;
+    }
+
+    let content_type = req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("video/mp2t");
+
+    let extension = match SEGMENT_TYPES.iter().find(|(ct, _)| *ct == content_type) {
+        Some((_, extension)) => *extension,
+        None => return HttpResponse::UnsupportedMediaType().body(
+            format!("Unsupported video segment type {}", content_type)
+        ),
+    };
+
+    let duration = match req.headers().get("X-Segment-Duration") {
+        Some(value) => match value.to_str().ok().and_then(|value| value.parse::<f64>().ok()) {
+            Some(duration) if duration > 0.0 => duration,
+            _ => return HttpResponse::BadRequest().body("Invalid X-Segment-Duration header value"),
+        },
+        None => DEFAULT_SEGMENT_DURATION,
+    };
+
+    let source_cfg = match filters::source_config(&source) {
+        Ok(source_cfg) => source_cfg,
+        Err(response) => return response,
+    };
+
+    let max_body_size = match filters::check_body_size(&req, &source_cfg, &cfg) {
+        Ok(size) => size,
+        Err(response) => return response,
+    };
+
+    let stream_dir = Path::new(&cfg.video_dir).join(&stream_id);
+    let (spooled, size) = match receive_file(body, max_body_size, &stream_dir).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+
+    let segment_path = stream_dir.join(format!("{}.{}", seq, extension));
+    let segment = VideoSegment {
+        id: 0,
+        src_id: source.src_id,
+        stream_id,
+        seq,
+        path: segment_path.to_string_lossy().into_owned(),
+        content_type: content_type.to_string(),
+        duration,
+        size: size as i64,
+        created_at: chrono::Utc::now().timestamp(),
+        sent: false,
+    };
+
+    let id = match video::add_segment(&pool, &segment, &spooled).await {
+        Ok(id) => id,
+        Err(e) => {
+            spool::discard(&spooled).await;
+            return match RepError::from_boxed(e) {
+                RepError::Conflict(_) => HttpResponse::Conflict().body(
+                    format!("Segment {} of the stream {} already exists", seq, segment.stream_id)
+                ),
+                e => e.error_response(),
+            };
+        }
+    };
+
+    HttpResponse::Ok().body(id.to_string())
+}
+
+/// Uploads the fMP4 initialization segment of the stream
+#[post("/video/{stream_id}/init")]
+pub async fn upload_init_segment(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Payload,
+    pool: web::Data<SqlitePool>,
+    sources: web::Data<dyn SourceStore>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    let stream_id = path.into_inner();
+
+    let source = match filters::validate_source(&req, sources.get_ref()).await {
+        Ok(source) => source,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = validate_stream_id(&stream_id) {
+        return response;
+    }
+
+    if let Err(response) = check_stream_owner(&pool, &stream_id, &source.src_id).await {
+        return response;
+    }
+
+    let source_cfg = match filters::source_config(&source) {
+        Ok(source_cfg) => source_cfg,
+        Err(response) => return response,
+    };
+
+    let max_body_size = match filters::check_body_size(&req, &source_cfg, &cfg) {
+        Ok(size) => size,
+        Err(response) => return response,
+    };
+
+    let stream_dir = Path::new(&cfg.video_dir).join(&stream_id);
+    let (spooled, size) = match receive_file(body, max_body_size, &stream_dir).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+
+    match tokio::fs::rename(&spooled, stream_dir.join(INIT_SEGMENT_FILE)).await {
+        Ok(_) => HttpResponse::Ok().body(size.to_string()),
+        Err(e) => {
+            log::error!("Failed to store the init segment of the stream {}: {}", stream_id, e);
+            spool::discard(&spooled).await;
+            HttpResponse::InternalServerError().body("Failed to store the init segment")
+        }
+    }
+}
+
+/// Gets the fMP4 initialization segment of the stream
+#[get("/video/{stream_id}/init")]
+pub async fn get_init_segment(
+    path: web::Path<String>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    let stream_id = path.into_inner();
+
+    if let Err(response) = validate_stream_id(&stream_id) {
+        return response;
+    }
+
+    let init_path = Path::new(&cfg.video_dir).join(&stream_id).join(INIT_SEGMENT_FILE);
+    match spool::read_file(init_path).await {
+        Ok(data) => HttpResponse::Ok().content_type("video/mp4").body(data),
+        Err(SpoolError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => HttpResponse::NotFound().body(
+            format!("Stream {} has no initialization segment", stream_id)
+        ),
+        Err(e) => {
+            log::error!("Failed to read the initialization segment of the stream {}: {}", stream_id, e);
+            HttpResponse::InternalServerError().body("Failed to read the initialization segment")
+        }
+    }
+}
+
+/// Gets the HLS playlist of the stream
+#[get("/video/{stream_id}/index.m3u8")]
+pub async fn get_playlist(
+    path: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+
+    let stream_id = path.into_inner();
+
+    if let Err(response) = validate_stream_id(&stream_id) {
+        return response;
+    }
+
+    let segments = match video::get_stream_segments(&pool, &stream_id).await {
+        Ok(segments) => segments,
+        Err(e) => return RepError::from_boxed(e).error_response(),
+    };
+
+    if segments.is_empty() {
+        return HttpResponse::NotFound().body(format!("Stream {} has no segments", stream_id));
+    }
+
+    let has_init = Path::new(&cfg.video_dir).join(&stream_id).join(INIT_SEGMENT_FILE).exists();
+
+    HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .body(build_playlist(&segments, has_init))
+}
+
+/// Gets a video segment of the stream
+#[get("/video/{stream_id}/{seq}")]
+pub async fn get_segment(
+    path: web::Path<(String, i64)>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+
+    let (stream_id, seq) = path.into_inner();
+
+    let segment = match video::get_segment(&pool, &stream_id, seq).await {
+        Ok(Some(segment)) => segment,
+        Ok(None) => return HttpResponse::NotFound().body(
+            format!("Segment {} of the stream {} does not exist", seq, stream_id)
+        ),
+        Err(e) => return RepError::from_boxed(e).error_response(),
+    };
+
+    match spool::read_file(&segment.path).await {
+        Ok(data) => HttpResponse::Ok().content_type(segment.content_type).body(data),
+        Err(e) => {
+            log::error!("Failed to read the video segment {}: {}", segment.path, e);
+            HttpResponse::InternalServerError().body("Failed to read the video segment")
+        }
+    }
+}
+
+/// Builds a live HLS playlist, segment URIs are relative to the playlist
+pub fn build_playlist(segments: &[VideoSegment], has_init: bool) -> String {
+
+    let target_duration = segments.iter()
+        .map(|segment| segment.duration.ceil() as u64)
+        .max()
+        .unwrap_or(0);
+
+    let mut playlist = String::new();
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:{}", if has_init { 7 } else { 3 });
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+    let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", segments[0].seq);
+
+    if has_init {
+        let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"init\"");
+    }
+
+    let mut prev_seq: Option<i64> = None;
+    for segment in segments {
+        // lost segments break the timeline
+        if prev_seq.is_some_and(|prev| segment.seq != prev + 1) {
+            let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+        }
+        let _ = writeln!(playlist, "#EXTINF:{:.3},", segment.duration);
+        let _ = writeln!(playlist, "{}", segment.seq);
+        prev_seq = Some(segment.seq);
+    }
+
+    playlist
+}
+
+/// Stream id is used as a directory name so only safe characters are allowed
+fn validate_stream_id(stream_id: &str) -> Result<(), HttpResponse> {
+    let valid = !stream_id.is_empty()
+        && stream_id.len() <= 64
+        && stream_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(HttpResponse::BadRequest().body(format!("Invalid stream id {}", stream_id)));
+    }
+
+    Ok(())
+}
+
+/// Streams belong to the source which uploaded to them first, others cannot replace their segments
+async fn check_stream_owner(pool: &SqlitePool, stream_id: &str, src_id: &str) -> Result<(), HttpResponse> {
+    match video::claim_stream(pool, stream_id, src_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::Forbidden().body(
+            format!("Stream {} belongs to another source", stream_id)
+        )),
+        Err(e) => Err(RepError::from_boxed(e).error_response()),
+    }
+}
+
+/// Streams the request body to a temporary file in the directory
+async fn receive_file(body: web::Payload, max_body_size: usize, dir: &Path)
+    -> Result<(String, usize), HttpResponse> {
+
+    let dir = dir.to_string_lossy();
+    match spool::receive(body, max_body_size, 0, &dir).await {
+        Ok(Payload::Spooled { path, size }) => Ok((path, size)),
+        Ok(Payload::Inline(_)) => Err(HttpResponse::BadRequest().body("Empty video segment is not allowed.")),
+        Err(e) => Err(filters::spool_error_response(e)),
+    }
+}
@@ -1,10 +1,23 @@
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use actix_web::{web, App, HttpServer};
 use actix_web::middleware::from_fn;
-use crate::config;
-use crate::api::endpoints;
-use crate::data::db;
+use sqlx::SqlitePool;
+use crate::config::{self, Config};
+use crate::api::{admin, endpoints, records, subscribe, video};
+use crate::data::{backup, db, encryption, store, target};
+use crate::data::segment_log::{self, Logs};
+use crate::data::store::SqliteStore;
+use crate::data::export::{ExportFormat, Exporter};
+use crate::data::feed::Feed;
+use crate::models::RecordFilter;
+use crate::data::writer::Writer;
+use crate::common::crypto::{self, CryptoError, KeyRing};
 use crate::common::defaults::{CFG_FILE_PATH, DB_FILE_PATH};
 use crate::api::filters::only_private_ip;
+use crate::tasks;
+use crate::uplink::Uplink;
 
 pub async fn start_app() -> std::io::Result<()>  {
     // 1. initializes app configuration
@@ -17,18 +30,201 @@ pub async fn start_app() -> std::io::Result<()>  {
     }
 
     // 2. initializes SQLite file data base 
-    let pool = db::init_db(DB_FILE_PATH)
+    let pool = db::init_db(DB_FILE_PATH, &cfg.database)
         .await.unwrap();
 
-    // 3. starts receiving data from the data sources
+    if let Some(encryption) = &cfg.encryption {
+        install_keys(cfg).unwrap();
+        log::info!("Stored records are encrypted with key {}.", encryption.active_key);
+    }
+
+    let logs = Arc::new(Logs::new(&cfg.log_dir, cfg.log_segment_size));
+    let count = logs.open_existing().unwrap();
+    if count > 0 {
+        log::info!("{} segment logs have been opened.", count);
+    }
+
+    // 3. starts background tasks
+    tasks::video::start(pool.clone());
+    tasks::dedupe::start(pool.clone());
+    tasks::retention::start(pool.clone());
+    tasks::delivery::start(pool.clone(), cfg);
+    tasks::segment_log::start(pool.clone(), logs.clone(), cfg.max_log_size);
+    tasks::webhook::start(pool.clone(), cfg);
+    if let Some(import_dir) = &cfg.import_dir {
+        tasks::import::start(pool.clone(), cfg, logs.clone());
+        log::info!("Files dropped to {} are imported.", import_dir);
+    }
+
+    // 4. starts uploading records to the targets
+    target::sync_targets(&pool, &cfg.targets)
+        .await.unwrap();
+
+    let uplinks = Uplink::from_config(pool.clone(), cfg)
+        .unwrap();
+    if uplinks.is_empty() {
+        log::warn!("No targets nor upload endpoint are configured, records are not uploaded.");
+    }
+    for uplink in uplinks {
+        uplink.start();
+    }
+
+    // 5. starts receiving data from the data sources
+    let cfg_data = web::Data::new(cfg.clone());
+    let writer = Writer::start(pool.clone(), cfg.database.write_batch_size);
+    let store = Arc::new(SqliteStore::with_writer(pool.clone(), writer));
+    let feed = web::Data::new(Feed::new(&cfg.subscriptions));
+    tasks::feed::start(pool.clone(), feed.clone().into_inner());
+
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(cfg_data.clone())
+            .configure(store::configure(store.clone()))
+            .app_data(web::Data::from(logs.clone()))
+            .app_data(feed.clone())
             .wrap(from_fn(only_private_ip))
             .service(endpoints::receive_data)
+            .service(records::query_records)
+            .service(subscribe::subscribe)
+            .service(subscribe::subscribe_ws)
+            // fixed routes go before the {seq} ones
+            .service(video::upload_init_segment)
+            .service(video::get_init_segment)
+            .service(video::get_playlist)
+            .service(video::upload_segment)
+            .service(video::get_segment)
+            .service(admin::sequence_report)
+            .service(admin::list_dead_letters)
+            .service(admin::get_dead_letter)
+            .service(admin::requeue_dead_letter)
+            .service(admin::delete_dead_letter)
+            .service(admin::purge_dead_letters)
+            .service(admin::target_report)
+            .service(admin::compression_report)
+            .service(admin::backup_to_path)
+            .service(admin::download_backup)
+            .service(admin::export_records)
+            .service(admin::add_webhook)
+            .service(admin::list_webhooks)
+            .service(admin::delete_webhook)
+            .service(admin::list_webhook_deliveries)
+            .service(admin::test_webhook)
         //.route("/settings", web::get().to(get_settings))
     })
         .bind(("0.0.0.0", 5000))?
         .run()
         .await
-}
\ No newline at end of file
+}
+
+/// Re-encrypts the stored records and re-seals the payload and video files with the active key of the configuration,
+/// so retired keys can be removed. Fails while the segment logs keep records encrypted with other keys.
+pub async fn reencrypt() -> std::io::Result<()> {
+    let cfg = config::get_config(CFG_FILE_PATH);
+
+    let Some(encryption) = &cfg.encryption else {
+        return Err(std::io::Error::other("Encryption is not configured"));
+    };
+
+    let keyring = KeyRing::from_config(encryption)
+        .map_err(std::io::Error::other)?;
+
+    let pool = db::init_db(DB_FILE_PATH, &cfg.database)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let count = encryption::reencrypt(&pool, &keyring)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    log::info!("{} records have been re-encrypted with key {}.", count, encryption.active_key);
+
+    let count = encryption::reseal_files(&pool, &keyring, &cfg.video_dir)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    log::info!("{} payload and video files have been re-sealed with key {}.", count, encryption.active_key);
+
+    // log frames are moved to the database by the running broker and re-encrypted there
+    let count = segment_log::count_not_sealed_with(&cfg.log_dir, &encryption.active_key)
+        .map_err(std::io::Error::other)?;
+    if count > 0 {
+        return Err(std::io::Error::other(format!(
+            "{} records of the segment logs in {} are not encrypted with key {}, retired keys must be kept \
+            until the broker moves them to the database; run reencrypt again after that", count, cfg.log_dir, encryption.active_key
+        )));
+    }
+
+    Ok(())
+}
+
+/// Writes a backup of the database to the path, the broker may keep running meanwhile
+pub async fn backup(path: &Path, exclude_sent: bool) -> std::io::Result<()> {
+    let cfg = config::get_config(CFG_FILE_PATH);
+
+    let pool = db::init_db(DB_FILE_PATH, &cfg.database)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let size = backup::backup(&pool, path, exclude_sent)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    log::info!("Database has been backed up to {} ({} bytes).", path.display(), size);
+
+    Ok(())
+}
+
+/// Replaces the database with the backup, the broker must be stopped
+pub async fn restore(path: &Path) -> std::io::Result<()> {
+    let version = backup::restore(path, Path::new(DB_FILE_PATH))
+        .await
+        .map_err(std::io::Error::other)?;
+
+    log::info!("Database has been restored from {} (schema version {}).", path.display(), version);
+
+    Ok(())
+}
+
+/// Exports the records matching the filter to a new file
+pub async fn export(path: &Path, format: ExportFormat, filter: RecordFilter, mark_sent: bool) -> std::io::Result<()> {
+    let cfg = config::get_config(CFG_FILE_PATH);
+
+    let pool = db::init_db(DB_FILE_PATH, &cfg.database)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    export_records(cfg, pool, path, format, filter, mark_sent).await
+}
+
+/// Exports the records of the database to a new file, encrypted records are decrypted with the configured keys
+pub async fn export_records(cfg: &Config, pool: SqlitePool, path: &Path, format: ExportFormat, filter: RecordFilter,
+                            mark_sent: bool) -> std::io::Result<()> {
+
+    install_keys(cfg).map_err(std::io::Error::other)?;
+
+    let mut file = tokio::fs::File::create_new(path).await?;
+    let mut exporter = Exporter::new(pool, filter, format, mark_sent)
+        .map_err(std::io::Error::other)?;
+
+    while let Some(chunk) = exporter.next_chunk().await.map_err(std::io::Error::other)? {
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+
+    log::info!("{} records have been exported to {}.", exporter.exported(), path.display());
+
+    Ok(())
+}
+
+/// Installs the configured encryption keys, keys installed already are kept
+fn install_keys(cfg: &Config) -> Result<(), CryptoError> {
+    let Some(encryption) = &cfg.encryption else {
+        return Ok(());
+    };
+
+    match crypto::install(KeyRing::from_config(encryption)?) {
+        Ok(()) | Err(CryptoError::AlreadyInstalled) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1 @@
+pub mod token_manager;
@@ -23,10 +23,18 @@ pub struct TokenManager {
 struct Inner {
     current_token: Option<String>,
     expiry_time: Option<Instant>,
-    config: Config,
+    credentials: Credentials,
     client: reqwest::Client,
 }
 
+/// Credentials of the client at the token endpoint
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub endpoint: String,
+    pub client_id: String,
+    pub secret: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct TokenResponse {
     access_token: String,
@@ -35,11 +43,19 @@ struct TokenResponse {
 
 impl TokenManager {
     pub fn new(config: Config) -> Self {
+        Self::with_credentials(Credentials {
+            endpoint: config.hub_endpoint,
+            client_id: config.client_id,
+            secret: config.secret,
+        })
+    }
+
+    pub fn with_credentials(credentials: Credentials) -> Self {
         TokenManager {
             inner: Arc::new(Mutex::new(Inner {
                 current_token: None,
                 expiry_time: None,
-                config,
+                credentials,
                 client: reqwest::Client::new(),
             })),
         }
@@ -84,7 +100,7 @@ impl TokenManager {
 
     async fn refresh_token(&self) -> Result<(), AuthError> {
         let mut inner = self.inner.lock().await;
-        let token = Self::fetch_new_token(&inner.config, &inner.client).await?;
+        let token = Self::fetch_new_token(&inner.credentials, &inner.client).await?;
         
         inner.current_token = Some(token.access_token.clone());
         inner.expiry_time = Some(Instant::now() + Duration::from_secs(token.expires_in));
@@ -92,12 +108,12 @@ impl TokenManager {
         Ok(())
     }
 
-    async fn fetch_new_token(config: &Config, client: &reqwest::Client) -> Result<TokenResponse, AuthError> {
+    async fn fetch_new_token(credentials: &Credentials, client: &reqwest::Client) -> Result<TokenResponse, AuthError> {
         let response = client
-            .post(&config.hub_endpoint)
+            .post(&credentials.endpoint)
             .json(&serde_json::json!({
-                "client_id": config.client_id,
-                "secret": config.secret
+                "client_id": credentials.client_id,
+                "secret": credentials.secret
             }))
             .send()
             .await?;
@@ -0,0 +1,70 @@
+use std::io::{self, Read, Write};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use serde::{Deserialize, Serialize};
+
+/// Compression algorithm of payloads and upload batches
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    /// Zlib format, as `deflate` is used by HTTP
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    /// Gets the `Content-Encoding` token of the algorithm
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Gets the algorithm of the `Content-Encoding` token
+    pub fn from_encoding(encoding: &str) -> Option<Self> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Compression::Gzip),
+            "deflate" => Some(Compression::Deflate),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses the data with the default level of the algorithm
+pub fn compress(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Compression::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Compression::Zstd => zstd::encode_all(data, 0),
+    }
+}
+
+/// Decompresses the data compressed by the algorithm
+pub fn decompress(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::Gzip => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        Compression::Deflate => {
+            let mut decoded = Vec::new();
+            ZlibDecoder::new(data).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        Compression::Zstd => zstd::decode_all(data),
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use once_cell::sync::OnceCell;
+use sha2::Sha256;
+use thiserror::Error;
+use crate::config::{EncryptionCfg, KeyCfg};
+
+/// Length of the nonce stored in front of the ciphertext
+const NONCE_LEN: usize = 12;
+/// Min length of the secret the key is derived from
+const MIN_SECRET_LEN: usize = 16;
+/// Salt of the key derivation
+const KEY_SALT: &[u8] = b"broker-records";
+
+#[derive(Error, Debug)]
+pub enum CryptoError {
+    #[error("Encryption key {id} cannot be read: {message}")]
+    Key { id: String, message: String },
+    #[error("Unknown encryption key {0}")]
+    UnknownKey(String),
+    #[error("Data cannot be encrypted with key {0}")]
+    Encryption(String),
+    #[error("Data encrypted with key {0} cannot be decrypted")]
+    Decryption(String),
+    #[error("Encryption keys have already been installed")]
+    AlreadyInstalled,
+    #[error("File {0} is not encrypted while encryption is enabled")]
+    Unsealed(String),
+    #[error("Encrypted file {0} is truncated or altered")]
+    Tampered(String),
+}
+
+/// Keys of the stored records. Data is encrypted with the active key and
+/// decrypted with the key whose id is stored with it, so keys can be rotated.
+pub struct KeyRing {
+    active: String,
+    ciphers: HashMap<String, ChaCha20Poly1305>,
+}
+
+impl KeyRing {
+    /// Derives the keys from the secrets in the key files and environment variables
+    pub fn from_config(cfg: &EncryptionCfg) -> Result<Self, CryptoError> {
+        let mut ciphers = HashMap::new();
+        for key in &cfg.keys {
+            ciphers.insert(key.id.clone(), ChaCha20Poly1305::new(&derive_key(key)?));
+        }
+
+        if !ciphers.contains_key(&cfg.active_key) {
+            return Err(CryptoError::UnknownKey(cfg.active_key.clone()));
+        }
+
+        Ok(KeyRing { active: cfg.active_key.clone(), ciphers })
+    }
+
+    /// Gets the id of the key new data is encrypted with
+    pub fn active_key(&self) -> &str {
+        &self.active
+    }
+
+    /// Encrypts the data with the active key, the random nonce is prepended to the ciphertext
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.encrypt_with_aad(data, &[])
+    }
+
+    /// Encrypts the data with the active key and authenticates the associated data with it,
+    /// the same associated data is needed to decrypt it
+    pub fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.ciphers[&self.active]
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| CryptoError::Encryption(self.active.clone()))?;
+
+        let mut encrypted = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    /// Decrypts the data encrypted with the key
+    pub fn decrypt(&self, key_id: &str, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.decrypt_with_aad(key_id, data, &[])
+    }
+
+    /// Decrypts the data encrypted with the key and the associated data
+    pub fn decrypt_with_aad(&self, key_id: &str, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let cipher = self.ciphers
+            .get(key_id)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_string()))?;
+
+        if data.len() < NONCE_LEN {
+            return Err(CryptoError::Decryption(key_id.to_string()));
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| CryptoError::Decryption(key_id.to_string()))
+    }
+}
+
+/// Gets random bytes, e.g. an id binding encrypted parts to their file
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Derives the key from the secret with HKDF-SHA256
+fn derive_key(key: &KeyCfg) -> Result<Key, CryptoError> {
+    let key_error = |message: String| CryptoError::Key { id: key.id.clone(), message };
+
+    let secret = match (&key.file, &key.env) {
+        (Some(file), _) => std::fs::read(file).map_err(|e| key_error(format!("{}: {}", file, e)))?,
+        (None, Some(env)) => std::env::var(env).map_err(|e| key_error(format!("{}: {}", env, e)))?.into_bytes(),
+        (None, None) => return Err(key_error("neither a key file nor an environment variable is set".to_string())),
+    };
+    let secret = secret.trim_ascii();
+
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(key_error(format!("the secret must be at least {} bytes long", MIN_SECRET_LEN)));
+    }
+
+    let mut derived = Key::default();
+    Hkdf::<Sha256>::new(Some(KEY_SALT), secret)
+        .expand(b"records", &mut derived)
+        .map_err(|e| key_error(e.to_string()))?;
+
+    Ok(derived)
+}
+
+static KEYRING: OnceCell<KeyRing> = OnceCell::new();
+
+/// Installs the keys stored records are encrypted with, records are stored in plaintext without keys
+pub fn install(keyring: KeyRing) -> Result<(), CryptoError> {
+    KEYRING.set(keyring).map_err(|_| CryptoError::AlreadyInstalled)
+}
+
+/// Gets the installed keys
+pub fn keyring() -> Option<&'static KeyRing> {
+    KEYRING.get()
+}
+
+/// Decrypts the data with the installed key
+pub fn decrypt(key_id: &str, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    keyring()
+        .ok_or_else(|| CryptoError::UnknownKey(key_id.to_string()))?
+        .decrypt(key_id, data)
+}
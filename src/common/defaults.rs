@@ -1,6 +1,40 @@
 pub const CFG_FILE_PATH: &str = "config.json";
 pub const DB_FILE_PATH: &str = "broker.db";
 
+// video segment duration in seconds when a camera does not send X-Segment-Duration
+pub const DEFAULT_SEGMENT_DURATION: f64 = 2.0;
+
+// record priority range, higher values are delivered first
+pub const MIN_PRIORITY: i64 = 0;
+pub const MAX_PRIORITY: i64 = 9;
+
+// records of segment logs are moved to the delivery queue every this many seconds in batches of this size
+pub const LOG_DRAIN_INTERVAL: u64 = 1;
+pub const LOG_DRAIN_BATCH: usize = 1000;
+
+// stored records are published to the live subscribers at least every this many seconds
+pub const FEED_PUBLISH_INTERVAL: u64 = 1;
+
+// the import directory is scanned every this many seconds, files modified within the settle time are skipped
+// as they may be still being copied
+pub const IMPORT_SCAN_INTERVAL: u64 = 5;
+pub const IMPORT_SETTLE_TIME: u64 = 2;
+// files split to records are read at once, larger files are quarantined
+pub const MAX_IMPORT_FILE_SIZE: usize = 64 * 1024 * 1024;
+
+// due webhook deliveries are sent every this many seconds and the backlog is checked for the events
+// every this many seconds, failed deliveries are retried after the retry delay doubled by each attempt
+// up to the max delay, finished deliveries are kept for the retention time
+pub const WEBHOOK_DISPATCH_INTERVAL: u64 = 1;
+pub const WEBHOOK_CHECK_INTERVAL: u64 = 30;
+pub const WEBHOOK_RETRY_DELAY: u64 = 5;
+pub const WEBHOOK_MAX_RETRY_DELAY: u64 = 3600;
+pub const WEBHOOK_DELIVERY_RETENTION: u64 = 7 * 24 * 3600;
+
+// rejected payloads kept per source and max size of a kept sample in bytes
+pub const REJECTED_SAMPLES_PER_SOURCE: i64 = 10;
+pub const REJECTED_SAMPLE_SIZE: usize = 4096;
+
 // default setting key's section
 pub const DATA_FLOW_RECONNECT_DELAY_KEY: &str = "data_flow_reconnect_delay";
 pub const PACKET_SIZE_KEY: &str               = "packet_size";
@@ -21,4 +55,24 @@ pub const SETTING_VALUES: [(&str, &str); 9] = [
     (CLEAR_DATA_DELAY_KEY, "3600"),
     (PACKET_SIZE_KEY, "1000"),
     (VIDEO_SEGMENTS_EXPIRATION_KEY, "72"),
-];
\ No newline at end of file
+];
+
+// default config values section
+pub fn max_body_size() -> usize { 64 * 1024 * 1024 }
+pub fn inline_payload_limit() -> usize { 256 * 1024 }
+pub fn max_expansion_ratio() -> u32 { 100 }
+pub fn spool_dir() -> String { "spool".to_string() }
+pub fn video_dir() -> String { "video".to_string() }
+pub fn log_dir() -> String { "log".to_string() }
+pub fn log_segment_size() -> u64 { 8 * 1024 * 1024 }
+pub fn max_log_size() -> u64 { 512 * 1024 * 1024 }
+pub fn dedupe_window() -> u64 { 24 * 3600 }
+pub fn lease_duration() -> u64 { 5 * 60 }
+pub fn max_delivery_attempts() -> u32 { 10 }
+pub fn max_connections() -> u32 { 10 }
+pub fn busy_timeout() -> u64 { 5000 }
+pub fn cache_size() -> u32 { 8 * 1024 }
+pub fn write_batch_size() -> usize { 256 }
+pub fn subscription_buffer_size() -> usize { 1024 }
+pub fn webhook_max_attempts() -> u32 { 10 }
+pub fn webhook_timeout() -> u64 { 10 }
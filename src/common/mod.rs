@@ -1,2 +1,4 @@
 pub mod defaults;
-pub mod helpers;
\ No newline at end of file
+pub mod helpers;
+pub mod compression;
+pub mod crypto;
@@ -1,7 +1,10 @@
 use once_cell::sync::OnceCell;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use crate::common::compression::Compression;
 use std::panic;
 use thiserror::Error;
+use crate::common::defaults;
+use crate::models::DeliveryOrder;
 
 pub mod validation;
 
@@ -19,10 +22,310 @@ pub enum ConfigError {
 pub struct Config {
     pub enabled: bool,
     pub system_name: String,
+    /// Client ID at the access token URL of the legacy `upload_endpoint`
+    #[serde(default)]
     pub client_id: String,
+    /// Secret at the access token URL of the legacy `upload_endpoint`
+    #[serde(default)]
     pub secret: String,
+    /// Access token URL of the legacy `upload_endpoint`
+    #[serde(default)]
     pub hub_endpoint: String,
-    pub listen_port: u16
+    pub listen_port: u16,
+    /// URL the records were uploaded to before `targets`, it is uploaded to as the `hub` failover target
+    /// authenticated at `hub_endpoint` with `client_id` and `secret`
+    #[serde(default)]
+    pub upload_endpoint: Option<String>,
+    /// Max size of a request body in bytes (can be overridden by the source cfg)
+    #[serde(default = "defaults::max_body_size")]
+    pub max_body_size: usize,
+    /// Payloads larger than this size are stored as files instead of BLOBs
+    #[serde(default = "defaults::inline_payload_limit")]
+    pub inline_payload_limit: usize,
+    /// Max ratio of the decoded size to the received size of compressed request bodies
+    #[serde(default = "defaults::max_expansion_ratio")]
+    pub max_expansion_ratio: u32,
+    /// Directory for payloads stored as files
+    #[serde(default = "defaults::spool_dir")]
+    pub spool_dir: String,
+    /// Directory for video segments
+    #[serde(default = "defaults::video_dir")]
+    pub video_dir: String,
+    /// Directory watched for files carried in from isolated equipment, import is disabled if not set
+    #[serde(default)]
+    pub import_dir: Option<String>,
+    /// Directory the backups requested by the admin API are written to, such backups are disabled if not set
+    #[serde(default)]
+    pub backup_dir: Option<String>,
+    /// Directory for segment logs of the sources stored in logs
+    #[serde(default = "defaults::log_dir")]
+    pub log_dir: String,
+    /// Size of a log segment file in bytes, a record larger than this gets a segment of its own
+    #[serde(default = "defaults::log_segment_size")]
+    pub log_segment_size: u64,
+    /// Max size of a source log in bytes, the oldest segments are deleted even if their records are not moved yet
+    #[serde(default = "defaults::max_log_size")]
+    pub max_log_size: u64,
+    /// Duplicate submissions are detected within this window in seconds, 0 disables detection.
+    /// Records of the sources stored in logs are not deduplicated.
+    #[serde(default = "defaults::dedupe_window")]
+    pub dedupe_window: u64,
+    /// Default order of delivering unsent records (can be overridden by the source cfg)
+    #[serde(default)]
+    pub delivery_order: DeliveryOrder,
+    /// Claimed records are returned to the queue if not acknowledged within this time in seconds
+    #[serde(default = "defaults::lease_duration")]
+    pub lease_duration: u64,
+    /// Records failed to be delivered this many times are moved to the dead letters
+    #[serde(default = "defaults::max_delivery_attempts")]
+    pub max_delivery_attempts: u32,
+    /// Upstream hubs the stored records are uploaded to, the uplink is disabled if empty
+    #[serde(default)]
+    pub targets: Vec<TargetCfg>,
+    /// Encryption of the stored records, records are stored in plaintext if not set
+    #[serde(default)]
+    pub encryption: Option<EncryptionCfg>,
+    /// Connection pool and write settings of the SQLite database
+    #[serde(default)]
+    pub database: DatabaseCfg,
+    /// Keys of the local consumers, the record query API is closed if none has the read role
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyCfg>,
+    /// Live subscriptions of the local consumers to the incoming records
+    #[serde(default)]
+    pub subscriptions: SubscriptionCfg,
+    /// Delivery of the webhook events, webhooks are managed by the admin API
+    #[serde(default)]
+    pub webhooks: WebhookCfg,
+}
+
+/// Connection pool and write settings of the SQLite database
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DatabaseCfg {
+    #[serde(default = "defaults::max_connections")]
+    pub max_connections: u32,
+    /// Time in milliseconds a connection waits for the lock held by another writer before failing
+    #[serde(default = "defaults::busy_timeout")]
+    pub busy_timeout: u64,
+    #[serde(default)]
+    pub synchronous: Synchronous,
+    /// Page cache size of a connection in KiB
+    #[serde(default = "defaults::cache_size")]
+    pub cache_size: u32,
+    /// Max number of inserts committed together by the writer task
+    #[serde(default = "defaults::write_batch_size")]
+    pub write_batch_size: usize,
+}
+
+impl Default for DatabaseCfg {
+    fn default() -> Self {
+        DatabaseCfg {
+            max_connections: defaults::max_connections(),
+            busy_timeout: defaults::busy_timeout(),
+            synchronous: Synchronous::default(),
+            cache_size: defaults::cache_size(),
+            write_batch_size: defaults::write_batch_size(),
+        }
+    }
+}
+
+/// Live subscriptions of the local consumers to the incoming records
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SubscriptionCfg {
+    /// Count of the latest records kept for the subscribers, a subscriber further behind lags
+    #[serde(default = "defaults::subscription_buffer_size")]
+    pub buffer_size: usize,
+    #[serde(default)]
+    pub lag_policy: LagPolicy,
+}
+
+impl Default for SubscriptionCfg {
+    fn default() -> Self {
+        SubscriptionCfg {
+            buffer_size: defaults::subscription_buffer_size(),
+            lag_policy: LagPolicy::default(),
+        }
+    }
+}
+
+/// Delivery of the webhook events
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct WebhookCfg {
+    /// Count of unsent records above which the `backlog.above_threshold` event is raised, not raised if not set
+    #[serde(default)]
+    pub backlog_threshold: Option<u64>,
+    /// Failed calls are retried with a growing delay, the delivery fails after this many attempts
+    #[serde(default = "defaults::webhook_max_attempts")]
+    pub max_attempts: u32,
+    /// Time in seconds to wait for the response of the webhook
+    #[serde(default = "defaults::webhook_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for WebhookCfg {
+    fn default() -> Self {
+        WebhookCfg {
+            backlog_threshold: None,
+            max_attempts: defaults::webhook_max_attempts(),
+            timeout: defaults::webhook_timeout(),
+        }
+    }
+}
+
+/// What happens to a subscriber the records are published faster than it reads
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// The missed records are read from the database, so the subscriber gets every record
+    #[default]
+    Resume,
+    /// The subscriber is disconnected and resumes from its last record id when it reconnects
+    Disconnect,
+}
+
+/// How often SQLite syncs the database file to the disk
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Synchronous {
+    Off,
+    /// Safe with the WAL journal, the last commits may be lost on a power loss
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+/// Upstream hub the records are uploaded to
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TargetCfg {
+    /// Unique name of the target, delivery state of fan-out targets is tracked by it
+    pub name: String,
+    /// URL the record batches are posted to, or `mqtt://host:port` of the MQTT 5 broker
+    pub endpoint: String,
+    /// MQTT topic template with `{system_name}` and `{src_id}` placeholders, e.g. `site/{system_name}/{src_id}`
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// URL of the access token, requests are not authenticated if not set
+    #[serde(default)]
+    pub auth_endpoint: Option<String>,
+    /// Client ID for the access token or the MQTT client ID and username
+    #[serde(default)]
+    pub client_id: String,
+    /// Secret for the access token or the MQTT password
+    #[serde(default)]
+    pub secret: String,
+    #[serde(default)]
+    pub mode: TargetMode,
+    /// Compression of the batches posted to HTTP targets, sent uncompressed if the target answers 415
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+/// How records are distributed between the targets
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TargetMode {
+    /// Failover targets are tried in the listed order until one accepts the records,
+    /// they share the delivery state of the records
+    #[default]
+    Failover,
+    /// Every record is delivered to each fan-out target, the delivery state is tracked per target
+    FanOut,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enabled: true,
+            system_name: String::new(),
+            client_id: String::new(),
+            secret: String::new(),
+            hub_endpoint: String::new(),
+            listen_port: 5000,
+            upload_endpoint: None,
+            max_body_size: defaults::max_body_size(),
+            inline_payload_limit: defaults::inline_payload_limit(),
+            max_expansion_ratio: defaults::max_expansion_ratio(),
+            spool_dir: defaults::spool_dir(),
+            video_dir: defaults::video_dir(),
+            import_dir: None,
+            backup_dir: None,
+            log_dir: defaults::log_dir(),
+            log_segment_size: defaults::log_segment_size(),
+            max_log_size: defaults::max_log_size(),
+            dedupe_window: defaults::dedupe_window(),
+            delivery_order: DeliveryOrder::default(),
+            lease_duration: defaults::lease_duration(),
+            max_delivery_attempts: defaults::max_delivery_attempts(),
+            targets: Vec::new(),
+            encryption: None,
+            database: DatabaseCfg::default(),
+            api_keys: Vec::new(),
+            subscriptions: SubscriptionCfg::default(),
+            webhooks: WebhookCfg::default(),
+        }
+    }
+}
+
+/// Keys of the stored records
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct EncryptionCfg {
+    /// Id of the key new records are encrypted with
+    pub active_key: String,
+    /// Retired keys are kept until the records are re-encrypted with the active key
+    pub keys: Vec<KeyCfg>,
+}
+
+/// Secret an encryption key is derived from, read from a file or an environment variable
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct KeyCfg {
+    /// Key id stored with the encrypted records
+    pub id: String,
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub env: Option<String>,
+}
+
+/// Key a local consumer sends as `Authorization: Bearer <key>`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ApiKeyCfg {
+    /// Name of the consumer for the logs
+    pub name: String,
+    pub key: String,
+    pub roles: Vec<Role>,
+}
+
+/// Access granted by an API key, data sources send records by X-Source-Id and need no key
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reading the stored records
+    Read,
+    /// Administering the broker through the /admin routes
+    Admin,
+}
+
+/// Name of the target built from the legacy `upload_endpoint`
+pub const LEGACY_TARGET_NAME: &str = "hub";
+
+impl Config {
+    /// Gets the failover target of the legacy `upload_endpoint`, None if it is not set
+    pub fn legacy_target(&self) -> Option<TargetCfg> {
+        let endpoint = self.upload_endpoint.as_ref()?;
+
+        Some(TargetCfg {
+            name: LEGACY_TARGET_NAME.to_string(),
+            endpoint: endpoint.clone(),
+            topic: None,
+            auth_endpoint: Some(self.hub_endpoint.clone()),
+            client_id: self.client_id.clone(),
+            secret: self.secret.clone(),
+            mode: TargetMode::Failover,
+            compression: None,
+        })
+    }
 }
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
@@ -37,7 +340,11 @@ pub fn get_config(cfg_file_path:&str) -> &'static Config {
 
 fn load_config(cfg_file_path:&str) -> Result<Config, ConfigError> {
     let config_data = std::fs::read_to_string(cfg_file_path)?;
-    let config: Config = serde_json::from_str(&config_data)?;
+    let mut config: Config = serde_json::from_str(&config_data)?;
     validation::validate(&config)?;
+    // validation ensures the legacy endpoint is not combined with targets
+    if let Some(target) = config.legacy_target() {
+        config.targets.push(target);
+    }
     Ok(config)
-}
\ No newline at end of file
+}
@@ -1,10 +1,20 @@
-use super::{Config, ConfigError};
+use std::collections::HashSet;
+use super::{ApiKeyCfg, Config, ConfigError, DatabaseCfg, EncryptionCfg, SubscriptionCfg, TargetCfg, TargetMode, WebhookCfg};
 
 pub fn validate(config: &Config) -> Result<(), ConfigError> {
     validate_system_name(&config.system_name)?;
-    validate_client_id(&config.client_id)?;
-    validate_secret(&config.secret)?;
-    validate_endpoint(&config.hub_endpoint)?;
+    validate_hub(config)?;
+    validate_body_limits(config.max_body_size, config.inline_payload_limit, config.max_expansion_ratio)?;
+    validate_log(config.log_segment_size, config.max_log_size)?;
+    validate_delivery(config.lease_duration, config.max_delivery_attempts)?;
+    validate_targets(&config.targets)?;
+    validate_database(&config.database)?;
+    validate_api_keys(&config.api_keys)?;
+    validate_subscriptions(&config.subscriptions)?;
+    validate_webhooks(&config.webhooks)?;
+    if let Some(encryption) = &config.encryption {
+        validate_encryption(encryption)?;
+    }
     Ok(())
 }
 
@@ -15,6 +25,33 @@ fn validate_system_name(value: &str) -> Result<(), ConfigError> {
     Ok(())
 }
 
+/// Hub credentials are validated when they are set or the legacy `upload_endpoint` uses them
+fn validate_hub(config: &Config) -> Result<(), ConfigError> {
+    let legacy = config.upload_endpoint.is_some();
+    if legacy || !config.client_id.is_empty() {
+        validate_client_id(&config.client_id)?;
+    }
+    if legacy || !config.secret.is_empty() {
+        validate_secret(&config.secret)?;
+    }
+    if legacy || !config.hub_endpoint.is_empty() {
+        validate_endpoint(&config.hub_endpoint)?;
+    }
+    if let Some(upload_endpoint) = &config.upload_endpoint {
+        if !config.targets.is_empty() {
+            return Err(ConfigError::Validation(
+                "Upload endpoint cannot be combined with targets, configure the hub as a target instead".into(),
+            ));
+        }
+        if !upload_endpoint.starts_with("http://") && !upload_endpoint.starts_with("https://") {
+            return Err(ConfigError::Validation(
+                "Upload endpoint must use http:// or https:// protocol".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn validate_client_id(value: &str) -> Result<(), ConfigError> {
     if value.trim().is_empty() {
         return Err(ConfigError::Validation("Client ID cannot be empty".into()));
@@ -53,4 +90,144 @@ fn validate_endpoint(value: &str) -> Result<(), ConfigError> {
         ));
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn validate_log(log_segment_size: u64, max_log_size: u64) -> Result<(), ConfigError> {
+    if log_segment_size == 0 {
+        return Err(ConfigError::Validation("Log segment size must be greater than zero".into()));
+    }
+    if max_log_size < log_segment_size {
+        return Err(ConfigError::Validation("Max log size cannot be less than the log segment size".into()));
+    }
+    Ok(())
+}
+
+fn validate_database(database: &DatabaseCfg) -> Result<(), ConfigError> {
+    if database.max_connections == 0 {
+        return Err(ConfigError::Validation("Database max connections must be greater than zero".into()));
+    }
+    if database.write_batch_size == 0 {
+        return Err(ConfigError::Validation("Database write batch size must be greater than zero".into()));
+    }
+    Ok(())
+}
+
+fn validate_body_limits(max_body_size: usize, inline_payload_limit: usize, max_expansion_ratio: u32)
+    -> Result<(), ConfigError> {
+    if max_body_size == 0 {
+        return Err(ConfigError::Validation("Max body size must be greater than zero".into()));
+    }
+    if inline_payload_limit > max_body_size {
+        return Err(ConfigError::Validation(
+            "Inline payload limit cannot exceed max body size".into(),
+        ));
+    }
+    if max_expansion_ratio == 0 {
+        return Err(ConfigError::Validation("Max expansion ratio must be greater than zero".into()));
+    }
+    Ok(())
+}
+
+fn validate_delivery(lease_duration: u64, max_delivery_attempts: u32) -> Result<(), ConfigError> {
+    if lease_duration == 0 {
+        return Err(ConfigError::Validation("Lease duration must be greater than zero".into()));
+    }
+    if max_delivery_attempts == 0 {
+        return Err(ConfigError::Validation("Max delivery attempts must be greater than zero".into()));
+    }
+    Ok(())
+}
+
+fn validate_targets(targets: &[TargetCfg]) -> Result<(), ConfigError> {
+    let mut names = HashSet::new();
+    for target in targets {
+        if target.name.trim().is_empty() {
+            return Err(ConfigError::Validation("Target name cannot be empty".into()));
+        }
+        if !names.insert(target.name.as_str()) {
+            return Err(ConfigError::Validation(format!("Duplicate target name: {}", target.name)));
+        }
+        if target.endpoint.starts_with("mqtt://") {
+            if target.topic.as_deref().is_none_or(|topic| topic.trim().is_empty()) {
+                return Err(ConfigError::Validation(format!("MQTT target {} requires a topic", target.name)));
+            }
+            if target.compression.is_some() {
+                return Err(ConfigError::Validation(format!("MQTT target {} does not support compression", target.name)));
+            }
+        } else {
+            validate_endpoint(&target.endpoint)?;
+        }
+        if let Some(auth_endpoint) = &target.auth_endpoint {
+            validate_endpoint(auth_endpoint)?;
+        }
+    }
+    // records are marked as sent only after the primary delivery by failover targets
+    let has_fan_out = targets.iter().any(|target| target.mode == TargetMode::FanOut);
+    let has_failover = targets.iter().any(|target| target.mode == TargetMode::Failover);
+    if has_fan_out && !has_failover {
+        return Err(ConfigError::Validation(
+            "Fan-out targets require at least one failover target".into(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_encryption(encryption: &EncryptionCfg) -> Result<(), ConfigError> {
+    let mut ids = HashSet::new();
+    for key in &encryption.keys {
+        if key.id.trim().is_empty() {
+            return Err(ConfigError::Validation("Encryption key id cannot be empty".into()));
+        }
+        if !ids.insert(key.id.as_str()) {
+            return Err(ConfigError::Validation(format!("Duplicate encryption key id: {}", key.id)));
+        }
+        if key.file.is_some() == key.env.is_some() {
+            return Err(ConfigError::Validation(
+                format!("Encryption key {} requires either a key file or an environment variable", key.id),
+            ));
+        }
+    }
+    if !ids.contains(encryption.active_key.as_str()) {
+        return Err(ConfigError::Validation(format!("Unknown active encryption key: {}", encryption.active_key)));
+    }
+    Ok(())
+}
+
+fn validate_api_keys(api_keys: &[ApiKeyCfg]) -> Result<(), ConfigError> {
+    let mut names = HashSet::new();
+    let mut keys = HashSet::new();
+    for api_key in api_keys {
+        if api_key.name.trim().is_empty() {
+            return Err(ConfigError::Validation("API key name cannot be empty".into()));
+        }
+        if !names.insert(api_key.name.as_str()) {
+            return Err(ConfigError::Validation(format!("Duplicate API key name: {}", api_key.name)));
+        }
+        if api_key.key.len() < 16 {
+            return Err(ConfigError::Validation(
+                format!("API key {} must be at least 16 characters long", api_key.name),
+            ));
+        }
+        if !keys.insert(api_key.key.as_str()) {
+            return Err(ConfigError::Validation(format!("API key {} is not unique", api_key.name)));
+        }
+    }
+    Ok(())
+}
+
+fn validate_subscriptions(subscriptions: &SubscriptionCfg) -> Result<(), ConfigError> {
+    if subscriptions.buffer_size == 0 {
+        return Err(ConfigError::Validation("Subscription buffer size must be greater than zero".into()));
+    }
+    Ok(())
+}
+
+fn validate_webhooks(webhooks: &WebhookCfg) -> Result<(), ConfigError> {
+    if webhooks.max_attempts == 0 {
+        return Err(ConfigError::Validation("Webhook max attempts must be greater than zero".into()));
+    }
+    if webhooks.timeout == 0 {
+        return Err(ConfigError::Validation("Webhook timeout must be greater than zero".into()));
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Connection, Pool, Sqlite, SqliteConnection};
+use thiserror::Error;
+use crate::data::db::SCHEMA_VERSION;
+use crate::data::error::RepError;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Database(#[from] RepError),
+    #[error("Backup file {0} already exists")]
+    Exists(PathBuf),
+    #[error("Backup is not a broker database: {0}")]
+    Invalid(String),
+    #[error("Backup schema version {found} is newer than the supported version {supported}")]
+    SchemaVersion { found: i64, supported: i64 },
+}
+
+impl From<sqlx::Error> for BackupError {
+    fn from(e: sqlx::Error) -> Self {
+        BackupError::Database(e.into())
+    }
+}
+
+/// Writes a consistent copy of the live database to the path, other connections keep reading and writing meanwhile.
+/// Payloads stored as files are not included, the records keep their paths.
+/// Returns the size of the backup in bytes.
+pub async fn backup(pool: &Pool<Sqlite>, path: &Path, exclude_sent: bool) -> Result<u64, BackupError> {
+    if path.exists() {
+        return Err(BackupError::Exists(path.to_path_buf()));
+    }
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+
+    sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy())
+        .execute(pool)
+        .await?;
+
+    if exclude_sent {
+        // the copy is changed only, payload files of the sent records still belong to the live database
+        let mut conn = SqliteConnection::connect_with(&SqliteConnectOptions::new().filename(path)).await?;
+        sqlx::query("DELETE FROM records WHERE sent = 1").execute(&mut conn).await?;
+        sqlx::query("VACUUM").execute(&mut conn).await?;
+        conn.close().await?;
+    }
+
+    Ok(tokio::fs::metadata(path).await?.len())
+}
+
+/// Checks the backup is an intact broker database the broker can migrate to the current schema.
+/// Returns the schema version of the backup.
+pub async fn validate(path: &Path) -> Result<i64, BackupError> {
+    if !path.is_file() {
+        return Err(BackupError::Invalid(format!("{} is not a file", path.display())));
+    }
+
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&mut conn)
+        .await?;
+    if integrity != "ok" {
+        return Err(BackupError::Invalid(integrity));
+    }
+
+    let tables: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('settings', 'sources', 'records')"
+    )
+        .fetch_one(&mut conn)
+        .await?;
+    if tables != 3 {
+        return Err(BackupError::Invalid("tables of the broker are missing".to_string()));
+    }
+
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut conn)
+        .await?;
+    conn.close().await?;
+
+    // older backups are migrated when the broker starts
+    if version > SCHEMA_VERSION {
+        return Err(BackupError::SchemaVersion { found: version, supported: SCHEMA_VERSION });
+    }
+
+    Ok(version)
+}
+
+/// Replaces the database with the backup. The broker must be stopped,
+/// the journal of the replaced database is deleted with it.
+pub async fn restore(backup: &Path, db_path: &Path) -> Result<i64, BackupError> {
+    let version = validate(backup).await?;
+
+    // the copy is moved over the database in one step, so a failed copy leaves the database intact
+    let restored = with_suffix(db_path, ".restore");
+    tokio::fs::copy(backup, &restored).await?;
+    tokio::fs::File::open(&restored).await?.sync_all().await?;
+
+    for suffix in ["-wal", "-shm"] {
+        match tokio::fs::remove_file(with_suffix(db_path, suffix)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    tokio::fs::rename(&restored, db_path).await?;
+
+    Ok(version)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
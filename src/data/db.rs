@@ -1,6 +1,10 @@
 use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
 use sqlx::{Pool, Sqlite};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteSynchronous};
 use crate::common::defaults::SETTING_VALUES;
+use crate::config::{DatabaseCfg, Synchronous};
 
 const INIT_DB_SCRIPT: &str = r#"
     CREATE TABLE IF NOT EXISTS settings(
@@ -25,16 +29,247 @@ const INIT_DB_SCRIPT: &str = r#"
     );
 "#;
 
+/// Schema changes applied on top of INIT_DB_SCRIPT.
+/// The index of the last applied migration + 1 is stored in PRAGMA user_version.
+const MIGRATIONS: &[&str] = &[
+    // 1: payloads stored as files
+    r#"ALTER TABLE records ADD COLUMN data_file TEXT NULL;"#,
+    // 2: video segments
+    r#"
+        CREATE TABLE IF NOT EXISTS video_segments(
+            id INTEGER NOT NULL CONSTRAINT PK_video_segments PRIMARY KEY AUTOINCREMENT,
+            src_id TEXT NOT NULL,
+            stream_id TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            duration REAL NOT NULL,
+            size INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            sent BOOLEAN NOT NULL DEFAULT 0 CHECK(video_segments.sent IN (0,1)),
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE UNIQUE INDEX IF NOT EXISTS IX_video_segments_stream_seq ON video_segments (stream_id, seq);
+        CREATE INDEX IF NOT EXISTS IX_video_segments_created_at ON video_segments (created_at);
+    "#,
+    // 3: source statistics and rejected payload samples
+    r#"
+        CREATE TABLE IF NOT EXISTS source_stats(
+            src_id TEXT NOT NULL CONSTRAINT PK_source_stats PRIMARY KEY,
+            rejected INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS rejected_payloads(
+            id INTEGER NOT NULL CONSTRAINT PK_rejected_payloads PRIMARY KEY AUTOINCREMENT,
+            src_id TEXT NOT NULL,
+            error TEXT NOT NULL,
+            data BLOB NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS IX_rejected_payloads_src_id ON rejected_payloads (src_id);
+    "#,
+    // 4: idempotency keys of the stored records
+    r#"
+        CREATE TABLE IF NOT EXISTS dedupe_keys(
+            src_id TEXT NOT NULL,
+            key TEXT NOT NULL,
+            record_id INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            CONSTRAINT PK_dedupe_keys PRIMARY KEY (src_id, key),
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS IX_dedupe_keys_expires_at ON dedupe_keys (expires_at);
+    "#,
+    // 5: per-source sequence numbers
+    r#"
+        ALTER TABLE records ADD COLUMN seq INTEGER NULL;
+        ALTER TABLE records ADD COLUMN kind TEXT NOT NULL DEFAULT 'data';
+
+        CREATE TABLE IF NOT EXISTS source_sequences(
+            src_id TEXT NOT NULL CONSTRAINT PK_source_sequences PRIMARY KEY,
+            last_seq INTEGER NOT NULL,
+            gaps INTEGER NOT NULL DEFAULT 0,
+            missing INTEGER NOT NULL DEFAULT 0,
+            out_of_order INTEGER NOT NULL DEFAULT 0,
+            duplicates INTEGER NOT NULL DEFAULT 0,
+            resets INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS sequence_events(
+            id INTEGER NOT NULL CONSTRAINT PK_sequence_events PRIMARY KEY AUTOINCREMENT,
+            src_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            expected INTEGER NOT NULL,
+            received INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS IX_sequence_events_src_id ON sequence_events (src_id);
+    "#,
+    // 6: record priorities
+    r#"
+        ALTER TABLE records ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+
+        CREATE INDEX IF NOT EXISTS IX_records_sent_priority ON records (sent, priority, id);
+    "#,
+    // 7: delivery leases
+    r#"
+        ALTER TABLE records ADD COLUMN state TEXT NOT NULL DEFAULT 'pending';
+        ALTER TABLE records ADD COLUMN lease_owner TEXT NULL;
+        ALTER TABLE records ADD COLUMN lease_expires_at INTEGER NULL;
+        ALTER TABLE records ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+
+        UPDATE records SET state = 'delivered' WHERE sent = 1;
+
+        CREATE INDEX IF NOT EXISTS IX_records_state_priority ON records (state, priority, id);
+        CREATE INDEX IF NOT EXISTS IX_records_lease_expires_at ON records (lease_expires_at) WHERE state = 'leased';
+    "#,
+    // 8: dead letters
+    r#"
+        ALTER TABLE records ADD COLUMN last_error TEXT NULL;
+
+        CREATE TABLE IF NOT EXISTS dead_letters(
+            id INTEGER NOT NULL CONSTRAINT PK_dead_letters PRIMARY KEY,
+            src_id TEXT NOT NULL,
+            data BLOB NOT NULL,
+            data_file TEXT NULL,
+            seq INTEGER NULL,
+            kind TEXT NOT NULL,
+            priority INTEGER NOT NULL,
+            attempts INTEGER NOT NULL,
+            last_error TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY(src_id) REFERENCES sources(src_id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS IX_dead_letters_src_id ON dead_letters (src_id);
+    "#,
+    // 9: upstream targets and per-target delivery state of fan-out targets
+    r#"
+        CREATE TABLE IF NOT EXISTS targets(
+            name TEXT NOT NULL CONSTRAINT PK_targets PRIMARY KEY,
+            mode TEXT NOT NULL,
+            last_success_at INTEGER NULL,
+            last_error TEXT NULL,
+            last_error_at INTEGER NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS target_deliveries(
+            target TEXT NOT NULL,
+            record_id INTEGER NOT NULL,
+            state TEXT NOT NULL DEFAULT 'pending',
+            lease_owner TEXT NULL,
+            lease_expires_at INTEGER NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT NULL,
+            CONSTRAINT PK_target_deliveries PRIMARY KEY (target, record_id),
+            FOREIGN KEY(target) REFERENCES targets(name) ON DELETE CASCADE,
+            FOREIGN KEY(record_id) REFERENCES records(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS IX_target_deliveries_record_id ON target_deliveries (record_id);
+
+        CREATE TRIGGER IF NOT EXISTS TR_records_fan_out AFTER INSERT ON records WHEN NEW.sent = 0
+        BEGIN
+            INSERT INTO target_deliveries (target, record_id)
+            SELECT name, NEW.id FROM targets WHERE mode = 'fan_out';
+        END;
+    "#,
+    // 10: compression of stored payloads
+    r#"
+        ALTER TABLE records ADD COLUMN compression TEXT NULL;
+        ALTER TABLE dead_letters ADD COLUMN compression TEXT NULL;
+
+        ALTER TABLE source_stats ADD COLUMN raw_bytes INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE source_stats ADD COLUMN stored_bytes INTEGER NOT NULL DEFAULT 0;
+    "#,
+    // 11: encoded payloads stored as received
+    r#"
+        ALTER TABLE records ADD COLUMN content_encoding TEXT NULL;
+        ALTER TABLE dead_letters ADD COLUMN content_encoding TEXT NULL;
+    "#,
+    // 12: encryption of stored payloads
+    r#"
+        ALTER TABLE records ADD COLUMN key_id TEXT NULL;
+        ALTER TABLE dead_letters ADD COLUMN key_id TEXT NULL;
+    "#,
+    // 13: time the records were stored, unknown for the records stored before
+    r#"
+        ALTER TABLE records ADD COLUMN created_at INTEGER NULL;
+
+        CREATE INDEX IF NOT EXISTS IX_records_created_at ON records (created_at);
+    "#,
+    // 14: webhooks, sources storing records before are seen already
+    r#"
+        ALTER TABLE sources ADD COLUMN first_seen_at INTEGER NULL;
+
+        UPDATE sources SET first_seen_at = unixepoch() WHERE src_id IN (SELECT DISTINCT src_id FROM records);
+
+        CREATE TABLE IF NOT EXISTS webhooks(
+            id INTEGER NOT NULL CONSTRAINT PK_webhooks PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            events TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS webhook_deliveries(
+            id INTEGER NOT NULL CONSTRAINT PK_webhook_deliveries PRIMARY KEY AUTOINCREMENT,
+            webhook_id INTEGER NOT NULL,
+            event TEXT NOT NULL,
+            data TEXT NOT NULL,
+            state TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at INTEGER NOT NULL,
+            last_error TEXT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS IX_webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
+        CREATE INDEX IF NOT EXISTS IX_webhook_deliveries_state ON webhook_deliveries (state, next_attempt_at);
+    "#,
+    // 15: encryption of the rejected payload samples
+    r#"
+        ALTER TABLE rejected_payloads ADD COLUMN key_id TEXT NULL;
+    "#,
+    // 16: video streams owned by the source which uploaded to them first
+    r#"
+        CREATE TABLE IF NOT EXISTS video_streams(
+            stream_id TEXT NOT NULL CONSTRAINT PK_video_streams PRIMARY KEY,
+            src_id TEXT NOT NULL
+        );
+
+        INSERT OR IGNORE INTO video_streams (stream_id, src_id)
+        SELECT stream_id, src_id FROM video_segments ORDER BY id;
+    "#,
+];
+
+/// Current schema version of the database
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
 /// Initializes database in filesystem for persistent storing data from the data sources
-pub async fn init_db(db_file_path:&str)
+pub async fn init_db(db_file_path:&str, cfg: &DatabaseCfg)
     -> Result<Pool<Sqlite>, Box<dyn Error>> {
 
-    let connection_options = sqlx::sqlite::SqliteConnectOptions::new()
+    let connection_options = SqliteConnectOptions::new()
         .filename(db_file_path)
-        .create_if_missing(true);
+        .create_if_missing(true)
+        .busy_timeout(Duration::from_millis(cfg.busy_timeout))
+        .synchronous(synchronous(cfg.synchronous))
+        // negative values are in KiB instead of pages
+        .pragma("cache_size", format!("-{}", cfg.cache_size))
+        .foreign_keys(true);
     
-    let pool = sqlx::sqlite::SqlitePoolOptions::new()
-        .max_connections(10)
+    let pool = SqlitePoolOptions::new()
+        .max_connections(cfg.max_connections)
         .connect_with(connection_options)
         .await?;
     
@@ -43,9 +278,25 @@ pub async fn init_db(db_file_path:&str)
     Ok(pool)
 }
 
+fn synchronous(value: Synchronous) -> SqliteSynchronous {
+    match value {
+        Synchronous::Off => SqliteSynchronous::Off,
+        Synchronous::Normal => SqliteSynchronous::Normal,
+        Synchronous::Full => SqliteSynchronous::Full,
+        Synchronous::Extra => SqliteSynchronous::Extra,
+    }
+}
+
 /// Initializes database in memory. It uses for testing
 pub async fn init_db_in_memory() -> Result<Pool<Sqlite>, Box<dyn Error>> {
-    let pool = Pool::<Sqlite>::connect(":memory:").await?;
+    // every connection to ":memory:" opens its own empty database,
+    // so the pool keeps the only connection forever
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(SqliteConnectOptions::from_str(":memory:")?.foreign_keys(true))
+        .await?;
     init_query(&pool).await?;
     Ok(pool)
 }
@@ -87,6 +338,30 @@ async fn init_query(pool: &Pool<Sqlite>) -> Result<(), Box<dyn Error>> {
     sql.execute(&mut *tx).await?;
 
     tx.commit().await?;
+
+    migrate(pool).await?;
     
+    Ok(())
+}
+
+/// Applies the migrations which have not been applied yet
+async fn migrate(pool: &Pool<Sqlite>) -> Result<(), Box<dyn Error>> {
+
+    let mut tx = pool.begin().await?;
+
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut *tx)
+        .await?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        sqlx::query(migration).execute(&mut *tx).await?;
+        // PRAGMA does not support bound parameters
+        sqlx::query(&format!("PRAGMA user_version = {}", index + 1))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
     Ok(())
 }
\ No newline at end of file
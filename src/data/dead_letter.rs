@@ -0,0 +1,155 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use crate::data::{placeholders, rep};
+use crate::models::DeadLetter;
+
+/// Moves records to the dead letters within the transaction, returns the count of moved records
+pub(crate) async fn bury(conn: &mut SqliteConnection, ids: &[u32]) -> Result<u64, sqlx::Error> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let placeholders = placeholders(ids);
+
+    let insert_str = format!(
+        r#"
+            INSERT INTO dead_letters (id, src_id, data, data_file, seq, kind, priority, attempts, last_error, created_at, compression, content_encoding, key_id)
+            SELECT id, src_id, data, data_file, seq, kind, priority, attempts, COALESCE(last_error, ''), ?, compression, content_encoding, key_id
+            FROM records WHERE id IN ({})
+        "#,
+        placeholders
+    );
+
+    let mut insert = sqlx::query(&insert_str).bind(chrono::Utc::now().timestamp());
+    for id in ids {
+        insert = insert.bind(id);
+    }
+    let moved = insert.execute(&mut *conn).await?.rows_affected();
+
+    let delete_str = format!(r#"DELETE FROM records WHERE id IN ({})"#, placeholders);
+    let mut delete = sqlx::query(&delete_str);
+    for id in ids {
+        delete = delete.bind(id);
+    }
+    delete.execute(&mut *conn).await?;
+
+    Ok(moved)
+}
+
+/// Gets dead letters, optionally of one source, the oldest records first
+pub async fn get_dead_letters(pool: &Pool<Sqlite>, src_id: Option<&str>, count: &u32)
+    -> Result<Vec<DeadLetter>, Box<dyn Error>> {
+
+    let letters = sqlx::query_as::<_, DeadLetter>(
+        r#"
+            SELECT * FROM dead_letters
+            WHERE ? IS NULL OR src_id = ?
+            ORDER BY id
+            LIMIT ?
+        "#
+    )
+        .bind(src_id)
+        .bind(src_id)
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(letters)
+}
+
+/// Gets a dead letter by the identifier of the original record
+pub async fn get_dead_letter(pool: &Pool<Sqlite>, id: u32) -> Result<Option<DeadLetter>, Box<dyn Error>> {
+
+    let mut letter = sqlx::query_as::<_, DeadLetter>(r#"SELECT * FROM dead_letters WHERE id = ?"#)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+    if let Some(letter) = letter.as_mut() {
+        let data = std::mem::take(&mut letter.data);
+        letter.data = rep::decode_data(data, letter.key_id.take(), letter.compression.take())?;
+    }
+
+    Ok(letter)
+}
+
+/// Returns dead letters to the delivery queue with the attempt counter reset,
+/// records keep their identifiers and so their place in the delivery order.
+/// Returns the count of requeued records.
+pub async fn requeue(pool: &Pool<Sqlite>, ids: &[u32]) -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let placeholders = placeholders(ids);
+
+    let insert_str = format!(
+        r#"
+            INSERT INTO records (id, src_id, data, sent, data_file, seq, kind, priority, state, attempts, last_error, compression, content_encoding, key_id, created_at)
+            SELECT id, src_id, data, 0, data_file, seq, kind, priority, 'pending', 0, last_error, compression, content_encoding, key_id, unixepoch()
+            FROM dead_letters WHERE id IN ({})
+        "#,
+        placeholders
+    );
+
+    let mut insert = sqlx::query(&insert_str);
+    for id in ids {
+        insert = insert.bind(id);
+    }
+
+    let delete_str = format!(r#"DELETE FROM dead_letters WHERE id IN ({})"#, placeholders);
+    let mut delete = sqlx::query(&delete_str);
+    for id in ids {
+        delete = delete.bind(id);
+    }
+
+    let mut tx = pool.begin().await?;
+    let requeued = insert.execute(&mut *tx).await?.rows_affected();
+    delete.execute(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(requeued)
+}
+
+/// Deletes dead letters by identifiers, returns the count of deleted records
+pub async fn delete_dead_letters(pool: &Pool<Sqlite>, ids: &[u32]) -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let query_str = format!(
+        r#"DELETE FROM dead_letters WHERE id IN ({}) RETURNING data_file"#,
+        placeholders(ids)
+    );
+
+    let mut query = sqlx::query_scalar::<_, Option<String>>(&query_str);
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    let files = query.fetch_all(pool).await?;
+    let deleted = files.len() as u64;
+    rep::remove_data_files(files);
+
+    Ok(deleted)
+}
+
+/// Deletes all dead letters, optionally of one source, returns the count of deleted records
+pub async fn purge_dead_letters(pool: &Pool<Sqlite>, src_id: Option<&str>) -> Result<u64, Box<dyn Error>> {
+
+    let files = sqlx::query_scalar::<_, Option<String>>(
+        r#"DELETE FROM dead_letters WHERE ? IS NULL OR src_id = ? RETURNING data_file"#
+    )
+        .bind(src_id)
+        .bind(src_id)
+        .fetch_all(pool)
+        .await?;
+
+    let deleted = files.len() as u64;
+    rep::remove_data_files(files);
+
+    Ok(deleted)
+}
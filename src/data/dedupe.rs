@@ -0,0 +1,74 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use crate::data::error::RepError;
+use crate::data::rep;
+use crate::models::Record;
+
+/// Result of storing a record with an idempotency key
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stored {
+    /// The record has been added with the id
+    Added(u32),
+    /// The key has been already used within the window by the record with the id
+    Duplicate(u32),
+}
+
+/// Adds the record unless the source has used the key within the window (seconds).
+/// The check and the insert are done in one transaction.
+pub async fn add_data_once(pool: &Pool<Sqlite>, record: &Record, key: &str, window: u64)
+    -> Result<Stored, RepError> {
+
+    let mut tx = pool.begin().await?;
+    let stored = insert_once(&mut tx, record, key, window).await?;
+    tx.commit().await?;
+
+    Ok(stored)
+}
+
+/// Adds the record unless the source has used the key within the window, within the transaction of the caller
+pub(crate) async fn insert_once(conn: &mut SqliteConnection, record: &Record, key: &str, window: u64)
+    -> Result<Stored, RepError> {
+
+    let now = chrono::Utc::now().timestamp();
+
+    let existing = sqlx::query_scalar::<_, u32>(
+        r#"SELECT record_id FROM dedupe_keys WHERE src_id = ? AND key = ? AND expires_at > ?"#
+    )
+        .bind(&record.src_id)
+        .bind(key)
+        .bind(now)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    if let Some(id) = existing {
+        return Ok(Stored::Duplicate(id));
+    }
+
+    let id = rep::insert_record(conn, record).await?;
+
+    sqlx::query(
+        r#"
+            INSERT INTO dedupe_keys (src_id, key, record_id, expires_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT(src_id, key) DO UPDATE SET record_id = excluded.record_id, expires_at = excluded.expires_at
+        "#
+    )
+        .bind(&record.src_id)
+        .bind(key)
+        .bind(id)
+        .bind(now + window as i64)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(Stored::Added(id))
+}
+
+/// Deletes idempotency keys whose window has passed
+pub async fn delete_expired_keys(pool: &Pool<Sqlite>) -> Result<u64, Box<dyn Error>> {
+
+    let result = sqlx::query(r#"DELETE FROM dedupe_keys WHERE expires_at <= ?"#)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
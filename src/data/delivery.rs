@@ -0,0 +1,264 @@
+use std::cmp::Reverse;
+use std::error::Error;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use crate::data::{dead_letter, placeholders, rep};
+use crate::models::{DeliveryOrder, Record};
+
+/// Error of the records whose lease expired before they were acknowledged
+const LEASE_EXPIRED: &str = "Delivery lease expired";
+
+/// Atomically claims up to `count` pending records for the lease owner for `lease_duration` seconds.
+/// Records with expired leases are claimed again, see `reclaim_expired`. Records are returned in the delivery order,
+/// `order` applies to the sources without their own `delivery_order`.
+pub async fn claim(pool: &Pool<Sqlite>, owner: &str, order: DeliveryOrder, count: &u32, lease_duration: u64,
+                   max_attempts: u32)
+    -> Result<Vec<Record>, Box<dyn Error>> {
+
+    let lifo = lifo_sources(pool, order).await?;
+
+    let query_str = format!(
+        r#"
+            UPDATE records SET state = 'leased', lease_owner = ?, lease_expires_at = ?
+            WHERE id IN (
+                SELECT id FROM records
+                WHERE state = 'pending'
+                ORDER BY priority DESC, {}
+                LIMIT ?
+            )
+            RETURNING *
+        "#,
+        delivery_key("src_id", "id", &lifo)
+    );
+
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    expire_leases(&mut tx, now, max_attempts).await?;
+
+    let mut query = sqlx::query_as::<_, Record>(&query_str)
+        .bind(owner)
+        .bind(now + lease_duration as i64);
+    for src_id in &lifo {
+        query = query.bind(src_id);
+    }
+    let claimed = query
+        .bind(count)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    // a record which cannot be decoded, e.g. its key is missing, would fail every claim
+    let mut records = Vec::with_capacity(claimed.len());
+    for record in claimed {
+        let id = record.id;
+        match rep::decode_record(record) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                log::error!("Record {} cannot be decoded, it is moved to the dead letters: {}", id, e);
+                reject(pool, owner, &[id], &e.to_string()).await?;
+            }
+        }
+    }
+
+    // RETURNING does not keep the order of the sub-query
+    sort_for_delivery(&mut records, &lifo);
+
+    Ok(records)
+}
+
+/// Gets the sources whose records are delivered newest first
+pub(crate) async fn lifo_sources(pool: &Pool<Sqlite>, order: DeliveryOrder) -> Result<Vec<String>, Box<dyn Error>> {
+    let sources = rep::get_all_sources(pool).await?;
+
+    let lifo = sources
+        .into_iter()
+        .filter(|source| {
+            // a source with an invalid config keeps the default order, its records are still delivered
+            let source_order = source.config().ok().and_then(|cfg| cfg.delivery_order);
+            source_order.unwrap_or(order) == DeliveryOrder::Lifo
+        })
+        .map(|source| source.src_id)
+        .collect();
+
+    Ok(lifo)
+}
+
+/// Gets the SQL ordering records of a priority: records of LIFO sources come first, the newest first,
+/// so the latest values are not held up by a backlog, then the others, the oldest first.
+/// The ids of the LIFO sources are bound in place of the placeholders.
+pub(crate) fn delivery_key(src_id: &str, id: &str, lifo: &[String]) -> String {
+    if lifo.is_empty() {
+        return format!("{} ASC", id);
+    }
+
+    let placeholders = lifo.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+    format!("CASE WHEN {} IN ({}) THEN -{} ELSE {} END", src_id, placeholders, id, id)
+}
+
+/// Sorts the records in the order of `delivery_key`
+pub(crate) fn sort_for_delivery(records: &mut [Record], lifo: &[String]) {
+    records.sort_by_key(|r| {
+        let id = r.id as i64;
+        (Reverse(r.priority), if lifo.contains(&r.src_id) { -id } else { id })
+    });
+}
+
+/// Marks records leased by the owner as delivered, returns the count of acknowledged records.
+/// Records whose lease has been taken over by another owner are not affected.
+/// Records are marked as sent once fan-out targets have received them as well.
+pub async fn ack(pool: &Pool<Sqlite>, owner: &str, ids: &[u32]) -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let query_str = format!(
+        r#"
+            UPDATE records SET
+                state = 'delivered',
+                sent = NOT EXISTS (
+                    SELECT 1 FROM target_deliveries d
+                    WHERE d.record_id = records.id AND d.state IN ('pending', 'leased')
+                ),
+                lease_owner = NULL,
+                lease_expires_at = NULL
+            WHERE state = 'leased' AND lease_owner = ? AND id IN ({})
+        "#,
+        placeholders(ids)
+    );
+
+    let mut query = sqlx::query(&query_str).bind(owner);
+
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    let result = query.execute(pool).await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Returns records leased by the owner to the queue after a failed delivery attempt.
+/// Records reaching `max_attempts` are moved to the dead letters. Returns the count of released records.
+pub async fn nack(pool: &Pool<Sqlite>, owner: &str, ids: &[u32], error: &str, max_attempts: u32)
+    -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let released = release(&mut tx, owner, ids, error).await?;
+
+    let exhausted: Vec<u32> = released
+        .iter()
+        .filter(|(_, attempts)| *attempts >= max_attempts as i64)
+        .map(|(id, _)| *id)
+        .collect();
+
+    dead_letter::bury(&mut tx, &exhausted).await?;
+
+    tx.commit().await?;
+
+    Ok(released.len() as u64)
+}
+
+/// Moves records leased by the owner to the dead letters after the hub permanently rejected them.
+/// Returns the count of rejected records.
+pub async fn reject(pool: &Pool<Sqlite>, owner: &str, ids: &[u32], error: &str)
+    -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let released: Vec<u32> = release(&mut tx, owner, ids, error)
+        .await?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+
+    let rejected = dead_letter::bury(&mut tx, &released).await?;
+
+    tx.commit().await?;
+
+    Ok(rejected)
+}
+
+/// Counts a failed attempt of records leased by the owner and releases the lease,
+/// returns identifiers and attempt counters of the released records
+async fn release(conn: &mut SqliteConnection, owner: &str, ids: &[u32], error: &str)
+    -> Result<Vec<(u32, i64)>, sqlx::Error> {
+
+    let query_str = format!(
+        r#"
+            UPDATE records SET
+                attempts = attempts + 1,
+                last_error = ?,
+                state = 'pending',
+                lease_owner = NULL,
+                lease_expires_at = NULL
+            WHERE state = 'leased' AND lease_owner = ? AND id IN ({})
+            RETURNING id, attempts
+        "#,
+        placeholders(ids)
+    );
+
+    let mut query = sqlx::query_as::<_, (u32, i64)>(&query_str)
+        .bind(error)
+        .bind(owner);
+
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    query.fetch_all(conn).await
+}
+
+/// Returns records with expired leases to the queue, returns the count of reclaimed records.
+/// An expired lease counts as a failed attempt, records reaching `max_attempts` are moved to the dead letters
+/// the same way as by `nack`.
+pub async fn reclaim_expired(pool: &Pool<Sqlite>, max_attempts: u32) -> Result<u64, Box<dyn Error>> {
+
+    let mut tx = pool.begin().await?;
+    let reclaimed = expire_leases(&mut tx, chrono::Utc::now().timestamp(), max_attempts).await?;
+    tx.commit().await?;
+
+    Ok(reclaimed)
+}
+
+/// Counts a failed attempt of the records with leases expired at `now` and releases the leases,
+/// the records reaching `max_attempts` are buried. Returns the count of released records.
+async fn expire_leases(conn: &mut SqliteConnection, now: i64, max_attempts: u32) -> Result<u64, sqlx::Error> {
+
+    let released = sqlx::query_as::<_, (u32, i64)>(
+        r#"
+            UPDATE records SET
+                attempts = attempts + 1,
+                last_error = ?,
+                state = 'pending',
+                lease_owner = NULL,
+                lease_expires_at = NULL
+            WHERE state = 'leased' AND lease_expires_at <= ?
+            RETURNING id, attempts
+        "#
+    )
+        .bind(LEASE_EXPIRED)
+        .bind(now)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let exhausted: Vec<u32> = released
+        .iter()
+        .filter(|(_, attempts)| *attempts >= max_attempts as i64)
+        .map(|(id, _)| *id)
+        .collect();
+
+    dead_letter::bury(conn, &exhausted).await?;
+
+    Ok(released.len() as u64)
+}
@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use sqlx::{Pool, Sqlite};
+use crate::common::crypto::KeyRing;
+use crate::data::spool::{self, SpoolError};
+use crate::data::video::INIT_SEGMENT_FILE;
+
+/// Count of rows re-encrypted in one transaction
+const BATCH_SIZE: u32 = 500;
+
+/// Re-encrypts the data of the records, dead letters and rejected payload samples with the active key of the key ring,
+/// data stored in plaintext is encrypted too. Returns the count of re-encrypted rows.
+pub async fn reencrypt(pool: &Pool<Sqlite>, keyring: &KeyRing) -> Result<u64, Box<dyn Error>> {
+
+    let mut count = 0;
+    for table in ["records", "dead_letters", "rejected_payloads"] {
+        count += reencrypt_table(pool, keyring, table).await?;
+    }
+
+    Ok(count)
+}
+
+async fn reencrypt_table(pool: &Pool<Sqlite>, keyring: &KeyRing, table: &str) -> Result<u64, Box<dyn Error>> {
+
+    let select_str = format!(
+        r#"
+            SELECT id, data, key_id FROM {}
+            WHERE id > ? AND length(data) > 0 AND (key_id IS NULL OR key_id <> ?)
+            ORDER BY id
+            LIMIT ?
+        "#,
+        table
+    );
+    // rows changed in the meantime are left to the next run
+    let update_str = format!(r#"UPDATE {} SET data = ?, key_id = ? WHERE id = ? AND data = ? AND key_id IS ?"#, table);
+
+    let mut count = 0;
+    let mut after = 0_u32;
+
+    loop {
+        let rows = sqlx::query_as::<_, (u32, Vec<u8>, Option<String>)>(&select_str)
+            .bind(after)
+            .bind(keyring.active_key())
+            .bind(BATCH_SIZE)
+            .fetch_all(pool)
+            .await?;
+
+        let Some((last, ..)) = rows.last() else {
+            break;
+        };
+        after = *last;
+
+        let mut tx = pool.begin().await?;
+
+        for (id, data, key_id) in rows {
+            let encrypted = match &key_id {
+                Some(key_id) => keyring.encrypt(&keyring.decrypt(key_id, &data)?)?,
+                None => keyring.encrypt(&data)?,
+            };
+
+            count += sqlx::query(&update_str)
+                .bind(encrypted)
+                .bind(keyring.active_key())
+                .bind(id)
+                .bind(&data)
+                .bind(&key_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+
+        tx.commit().await?;
+    }
+
+    Ok(count)
+}
+
+/// Re-seals the spooled payload files of the records and dead letters, the video segments and the initialization
+/// segments in the video directory with the active key of the key ring, plaintext files are sealed too.
+/// Files removed in the meantime are skipped. Returns the count of re-sealed files.
+pub async fn reseal_files(pool: &Pool<Sqlite>, keyring: &KeyRing, video_dir: &str) -> Result<u64, Box<dyn Error>> {
+
+    let mut paths: Vec<PathBuf> = sqlx::query_scalar::<_, String>(
+        r#"
+            SELECT data_file FROM records WHERE data_file IS NOT NULL
+            UNION SELECT data_file FROM dead_letters WHERE data_file IS NOT NULL
+            UNION SELECT path FROM video_segments
+        "#
+    )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    find_init_segments(Path::new(video_dir), &mut paths).await?;
+
+    let mut count = 0;
+    for path in paths {
+        match spool::reseal(&path, keyring).await {
+            Ok(resealed) => count += resealed as u64,
+            Err(SpoolError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+        }
+    }
+
+    Ok(count)
+}
+
+/// Finds the initialization segments in the directories of the streams
+async fn find_init_segments(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            Box::pin(find_init_segments(&entry.path(), paths)).await?;
+        } else if entry.file_name() == INIT_SEGMENT_FILE {
+            paths.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
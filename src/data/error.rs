@@ -0,0 +1,123 @@
+use std::error::Error;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use sqlx::error::ErrorKind;
+use thiserror::Error;
+
+// primary SQLite result codes, extended codes keep them in the low byte
+const SQLITE_CORRUPT: i32 = 11;
+const SQLITE_FULL: i32 = 13;
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_NOTADB: i32 = 26;
+
+/// Seconds a client should wait before retrying when the database is busy
+const BUSY_RETRY_AFTER: u32 = 1;
+
+/// Error of the repository layer. The messages keep the database details for the log,
+/// responses to the clients only tell the kind of the error.
+#[derive(Error, Debug, Clone)]
+pub enum RepError {
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Constraint violation: {0}")]
+    Constraint(String),
+    /// The database is locked by another writer, the operation can be retried
+    #[error("Database is busy: {0}")]
+    Busy(String),
+    #[error("Storage is full: {0}")]
+    StorageFull(String),
+    #[error("Database is corrupt: {0}")]
+    Corrupt(String),
+    /// Stored data cannot be encoded or decoded, e.g. the encryption key is missing
+    #[error("Stored data error: {0}")]
+    Data(String),
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+impl RepError {
+    /// Whether the operation may succeed if retried
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RepError::Busy(_))
+    }
+
+    /// Gets the repository error of an error returned by the data modules
+    pub fn from_boxed(e: Box<dyn Error>) -> Self {
+        match e.downcast::<RepError>() {
+            Ok(e) => *e,
+            Err(e) => match e.downcast::<sqlx::Error>() {
+                Ok(e) => RepError::from(*e),
+                Err(e) => RepError::Database(e.to_string()),
+            },
+        }
+    }
+}
+
+impl From<sqlx::Error> for RepError {
+    fn from(e: sqlx::Error) -> Self {
+        let message = e.to_string();
+
+        match &e {
+            sqlx::Error::RowNotFound => RepError::NotFound(message),
+            // all connections are held by other tasks
+            sqlx::Error::PoolTimedOut => RepError::Busy(message),
+            sqlx::Error::Io(io) if io.kind() == std::io::ErrorKind::StorageFull => RepError::StorageFull(message),
+            sqlx::Error::Database(db) => match db.kind() {
+                ErrorKind::UniqueViolation => RepError::Conflict(message),
+                ErrorKind::ForeignKeyViolation | ErrorKind::NotNullViolation | ErrorKind::CheckViolation => {
+                    RepError::Constraint(message)
+                }
+                _ => {
+                    let code = db.code().and_then(|code| code.parse::<i32>().ok()).unwrap_or_default();
+                    match code & 0xff {
+                        SQLITE_BUSY | SQLITE_LOCKED => RepError::Busy(message),
+                        SQLITE_FULL => RepError::StorageFull(message),
+                        SQLITE_CORRUPT | SQLITE_NOTADB => RepError::Corrupt(message),
+                        _ => RepError::Database(message),
+                    }
+                }
+            },
+            _ => RepError::Database(message),
+        }
+    }
+}
+
+impl ResponseError for RepError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RepError::NotFound(_) => StatusCode::NOT_FOUND,
+            RepError::Conflict(_) => StatusCode::CONFLICT,
+            RepError::Constraint(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RepError::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RepError::StorageFull(_) => StatusCode::INSUFFICIENT_STORAGE,
+            RepError::Corrupt(_) | RepError::Data(_) | RepError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            log::error!("{}", self);
+        } else {
+            log::warn!("{}", self);
+        }
+
+        let message = match self {
+            RepError::NotFound(_) => "Not found",
+            RepError::Conflict(_) => "Already exists",
+            RepError::Constraint(_) => "Storage constraint violation",
+            RepError::Busy(_) => "Storage is busy, retry later",
+            RepError::StorageFull(_) => "Storage is full",
+            RepError::Corrupt(_) | RepError::Data(_) | RepError::Database(_) => "Internal storage error",
+        };
+
+        let mut response = HttpResponse::build(status);
+        if self.is_retryable() {
+            response.insert_header(("Retry-After", BUSY_RETRY_AFTER.to_string()));
+        }
+        response.body(message)
+    }
+}
@@ -0,0 +1,412 @@
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde::Deserialize;
+use sqlx::{FromRow, Pool, Sqlite};
+use thiserror::Error;
+use crate::common::compression::Compression;
+use crate::data::error::RepError;
+use crate::data::rep;
+use crate::data::spool::{self, SpoolError};
+use crate::models::RecordFilter;
+
+/// Count of records read from the database at once, an export keeps one page in memory
+const EXPORT_PAGE_SIZE: u32 = 1000;
+
+const CSV_HEADER: [&str; 8] = ["id", "src_id", "sent", "seq", "priority", "created_at", "content_encoding", "data"];
+
+const PARQUET_SCHEMA: &str = r#"
+    message record {
+        REQUIRED INT64 id;
+        REQUIRED BYTE_ARRAY src_id (UTF8);
+        REQUIRED BOOLEAN sent;
+        OPTIONAL INT64 seq;
+        REQUIRED INT64 priority;
+        OPTIONAL INT64 created_at;
+        OPTIONAL BYTE_ARRAY content_encoding (UTF8);
+        REQUIRED BYTE_ARRAY data;
+    }
+"#;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    Database(#[from] RepError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Payload file error: {0}")]
+    Spool(#[from] SpoolError),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] ParquetError),
+}
+
+impl From<sqlx::Error> for ExportError {
+    fn from(e: sqlx::Error) -> Self {
+        ExportError::Database(e.into())
+    }
+}
+
+/// File format of exported records
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A JSON object per line, the data is Base64-encoded
+    #[default]
+    Jsonl,
+    /// A header and a row per record, the data is Base64-encoded
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!("Unknown export format {}", value)),
+        }
+    }
+}
+
+/// Exported record with the decoded data
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportedRecord {
+    pub id: u32,
+    pub src_id: String,
+    pub sent: bool,
+    pub seq: Option<i64>,
+    pub priority: i64,
+    /// Time the record was stored in Unix seconds, unknown for the records stored by older versions
+    pub created_at: Option<i64>,
+    pub content_encoding: Option<Compression>,
+    pub data: Vec<u8>,
+}
+
+#[derive(FromRow)]
+struct StoredRecord {
+    id: u32,
+    src_id: String,
+    data: Vec<u8>,
+    sent: bool,
+    data_file: Option<String>,
+    seq: Option<i64>,
+    priority: i64,
+    created_at: Option<i64>,
+    compression: Option<Compression>,
+    content_encoding: Option<Compression>,
+    key_id: Option<String>,
+}
+
+/// Streams the records matching the filter in the format a page at a time.
+/// Exported records are marked as sent once the whole export is produced, if requested.
+pub struct Exporter {
+    pool: Pool<Sqlite>,
+    filter: RecordFilter,
+    mark_sent: bool,
+    /// None when the export is finished
+    encoder: Option<Encoder>,
+    /// Id of the last exported record
+    last_id: u32,
+    exported: u64,
+}
+
+impl Exporter {
+    pub fn new(pool: Pool<Sqlite>, filter: RecordFilter, format: ExportFormat, mark_sent: bool)
+        -> Result<Self, ExportError> {
+
+        Ok(Exporter { pool, filter, mark_sent, encoder: Some(Encoder::new(format)?), last_id: 0, exported: 0 })
+    }
+
+    /// Gets the next chunk of the export, None when all records are exported
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, ExportError> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Ok(None);
+        };
+
+        let records = get_page(&self.pool, &self.filter, self.last_id).await?;
+        if let Some(last) = records.last() {
+            self.last_id = last.id;
+            self.exported += records.len() as u64;
+            return encoder.encode(&records).map(Some);
+        }
+
+        let tail = match self.encoder.take() {
+            Some(encoder) => encoder.finish()?,
+            None => Vec::new(),
+        };
+
+        // records exported by the sent filter are already sent
+        if self.mark_sent && self.exported > 0 && self.filter.sent != Some(true) {
+            mark_sent(&self.pool, &self.filter, self.last_id).await?;
+        }
+
+        Ok(Some(tail).filter(|tail| !tail.is_empty()))
+    }
+
+    /// Gets the count of the records exported so far
+    pub fn exported(&self) -> u64 {
+        self.exported
+    }
+}
+
+/// Gets the records matching the filter after the id, the oldest first
+async fn get_page(pool: &Pool<Sqlite>, filter: &RecordFilter, after: u32) -> Result<Vec<ExportedRecord>, ExportError> {
+    let rows = sqlx::query_as::<_, StoredRecord>(
+        r#"
+            SELECT id, src_id, data, sent, data_file, seq, priority, created_at, compression, content_encoding, key_id
+            FROM records
+            WHERE id > ?
+              AND (? IS NULL OR src_id = ?)
+              AND (? IS NULL OR created_at >= ?)
+              AND (? IS NULL OR created_at < ?)
+              AND (? IS NULL OR sent = ?)
+            ORDER BY id
+            LIMIT ?
+        "#
+    )
+        .bind(after)
+        .bind(&filter.src_id)
+        .bind(&filter.src_id)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(filter.sent)
+        .bind(filter.sent)
+        .bind(EXPORT_PAGE_SIZE)
+        .fetch_all(pool)
+        .await?;
+
+    let mut records = Vec::with_capacity(rows.len());
+    for row in rows {
+        let data = match &row.data_file {
+            Some(path) => spool::read_file(path).await?,
+            None => rep::decode_data(row.data, row.key_id, row.compression)?,
+        };
+
+        records.push(ExportedRecord {
+            id: row.id,
+            src_id: row.src_id,
+            sent: row.sent,
+            seq: row.seq,
+            priority: row.priority,
+            created_at: row.created_at,
+            content_encoding: row.content_encoding,
+            data,
+        });
+    }
+
+    Ok(records)
+}
+
+/// Marks the records matching the filter up to the last exported one as delivered to every target
+async fn mark_sent(pool: &Pool<Sqlite>, filter: &RecordFilter, last_id: u32) -> Result<(), ExportError> {
+    const EXPORTED: &str = r#"
+        id <= ?
+        AND (? IS NULL OR src_id = ?)
+        AND (? IS NULL OR created_at >= ?)
+        AND (? IS NULL OR created_at < ?)
+        AND sent = 0
+    "#;
+
+    let deliveries = format!(
+        r#"
+            UPDATE target_deliveries SET state = 'delivered', lease_owner = NULL, lease_expires_at = NULL
+            WHERE record_id IN (SELECT id FROM records WHERE {})
+        "#,
+        EXPORTED
+    );
+    let records = format!(
+        r#"
+            UPDATE records SET sent = 1, state = 'delivered', lease_owner = NULL, lease_expires_at = NULL
+            WHERE {}
+        "#,
+        EXPORTED
+    );
+
+    // records already sent are not changed, so the sent filter is not needed
+    let mut tx = pool.begin().await?;
+    for query_str in [&deliveries, &records] {
+        sqlx::query(query_str)
+            .bind(last_id)
+            .bind(&filter.src_id)
+            .bind(&filter.src_id)
+            .bind(filter.from)
+            .bind(filter.from)
+            .bind(filter.to)
+            .bind(filter.to)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+enum Encoder {
+    Jsonl,
+    Csv { header: bool },
+    Parquet { writer: Box<SerializedFileWriter<SharedBuffer>>, buffer: SharedBuffer },
+}
+
+impl Encoder {
+    fn new(format: ExportFormat) -> Result<Self, ExportError> {
+        match format {
+            ExportFormat::Jsonl => Ok(Encoder::Jsonl),
+            ExportFormat::Csv => Ok(Encoder::Csv { header: true }),
+            ExportFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+                let buffer = SharedBuffer::default();
+                let writer = SerializedFileWriter::new(buffer.clone(), schema, Arc::new(WriterProperties::new()))?;
+                Ok(Encoder::Parquet { writer: Box::new(writer), buffer })
+            }
+        }
+    }
+
+    /// Encodes a page of records, a page is a row group of Parquet files
+    fn encode(&mut self, records: &[ExportedRecord]) -> Result<Vec<u8>, ExportError> {
+        match self {
+            Encoder::Jsonl => {
+                let mut chunk = Vec::new();
+                for record in records {
+                    serde_json::to_writer(&mut chunk, &serde_json::json!({
+                        "id": record.id,
+                        "src_id": record.src_id,
+                        "sent": record.sent,
+                        "seq": record.seq,
+                        "priority": record.priority,
+                        "created_at": record.created_at,
+                        "content_encoding": record.content_encoding,
+                        "data": STANDARD.encode(&record.data),
+                    }))?;
+                    chunk.push(b'\n');
+                }
+                Ok(chunk)
+            }
+            Encoder::Csv { header } => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                if std::mem::take(header) {
+                    writer.write_record(CSV_HEADER)?;
+                }
+                for record in records {
+                    writer.write_record([
+                        record.id.to_string(),
+                        record.src_id.clone(),
+                        record.sent.to_string(),
+                        record.seq.map(|seq| seq.to_string()).unwrap_or_default(),
+                        record.priority.to_string(),
+                        record.created_at.map(|time| time.to_string()).unwrap_or_default(),
+                        record.content_encoding.map(|encoding| encoding.encoding().to_string()).unwrap_or_default(),
+                        STANDARD.encode(&record.data),
+                    ])?;
+                }
+                writer.into_inner().map_err(|e| ExportError::Io(e.into_error()))
+            }
+            Encoder::Parquet { writer, buffer } => {
+                let mut row_group = writer.next_row_group()?;
+                write_column::<Int64Type>(&mut row_group, records.iter().map(|r| Some(r.id as i64)))?;
+                write_column::<ByteArrayType>(&mut row_group, records.iter().map(|r| Some(r.src_id.as_str().into())))?;
+                write_column::<BoolType>(&mut row_group, records.iter().map(|r| Some(r.sent)))?;
+                write_column::<Int64Type>(&mut row_group, records.iter().map(|r| r.seq))?;
+                write_column::<Int64Type>(&mut row_group, records.iter().map(|r| Some(r.priority)))?;
+                write_column::<Int64Type>(&mut row_group, records.iter().map(|r| r.created_at))?;
+                write_column::<ByteArrayType>(&mut row_group,
+                    records.iter().map(|r| r.content_encoding.map(|encoding| encoding.encoding().into())))?;
+                write_column::<ByteArrayType>(&mut row_group, records.iter().map(|r| Some(ByteArray::from(r.data.clone()))))?;
+                row_group.close()?;
+                Ok(buffer.take())
+            }
+        }
+    }
+
+    /// Gets the end of the file, the footer of Parquet files
+    fn finish(self) -> Result<Vec<u8>, ExportError> {
+        match self {
+            Encoder::Jsonl => Ok(Vec::new()),
+            // the header of an export without records
+            Encoder::Csv { header } => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                if header {
+                    writer.write_record(CSV_HEADER)?;
+                }
+                writer.into_inner().map_err(|e| ExportError::Io(e.into_error()))
+            }
+            Encoder::Parquet { writer, buffer } => {
+                writer.close()?;
+                Ok(buffer.take())
+            }
+        }
+    }
+}
+
+/// Writes the values of the next column, definition levels of the optional columns tell the missing values
+fn write_column<T: DataType>(row_group: &mut SerializedRowGroupWriter<'_, SharedBuffer>,
+                             values: impl Iterator<Item = Option<T::T>>) -> Result<(), ParquetError> {
+
+    let mut column = row_group
+        .next_column()?
+        .ok_or_else(|| ParquetError::General("Column is missing in the schema".to_string()))?;
+
+    let optional = column.typed::<T>().get_descriptor().max_def_level() > 0;
+    let mut levels = Vec::new();
+    let values: Vec<T::T> = values
+        .inspect(|value| levels.push(value.is_some() as i16))
+        .flatten()
+        .collect();
+
+    column.typed::<T>().write_batch(&values, optional.then_some(levels.as_slice()), None)?;
+    column.close()
+}
+
+/// Buffer of the Parquet writer, the written bytes are taken after every row group
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
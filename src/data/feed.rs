@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use sqlx::{Pool, Sqlite};
+use thiserror::Error;
+use tokio::sync::{broadcast, Mutex, Notify};
+use tokio::sync::broadcast::error::RecvError;
+use crate::config::{LagPolicy, SubscriptionCfg};
+use crate::data::error::RepError;
+use crate::data::rep;
+use crate::models::{RecordFilter, StoredRecord};
+
+/// Count of the missed or published records read from the database at once
+const RESUME_PAGE_SIZE: u32 = 100;
+
+#[derive(Error, Debug)]
+pub enum FeedError {
+    #[error("Subscriber lagged behind by {0} records")]
+    Lagged(u64),
+    #[error(transparent)]
+    Database(#[from] RepError),
+}
+
+/// In-process broadcast of the stored records to the live subscribers.
+///
+/// Records are published from the database in the order of their ids, whichever way they are stored
+/// (`/add`, the import or the segment log drain), so no subscriber skips a record committed
+/// before another one but published after it.
+#[derive(Debug)]
+pub struct Feed {
+    sender: broadcast::Sender<Arc<StoredRecord>>,
+    lag_policy: LagPolicy,
+    /// Id of the last published record, it serializes the publishing
+    published: Mutex<u32>,
+    /// Wakes up the publishing when records are stored
+    stored: Notify,
+}
+
+impl Feed {
+    pub fn new(cfg: &SubscriptionCfg) -> Self {
+        let (sender, _) = broadcast::channel(cfg.buffer_size);
+        Feed { sender, lag_policy: cfg.lag_policy, published: Mutex::new(0), stored: Notify::new() }
+    }
+
+    /// Signals that records have been stored, they are published by the feed task without waiting for its interval
+    pub fn notify(&self) {
+        self.stored.notify_one();
+    }
+
+    /// Waits for stored records to be signaled
+    pub async fn stored(&self) {
+        self.stored.notified().await
+    }
+
+    /// Publishes the records stored after the last published one in the order of their ids,
+    /// returns the count of published records. Nothing is published if nobody subscribes.
+    pub async fn publish_stored(&self, pool: &Pool<Sqlite>) -> Result<usize, RepError> {
+        let mut published = self.published.lock().await;
+        let mut count = 0;
+
+        while self.sender.receiver_count() > 0 {
+            let records = rep::get_data_since(pool, &RecordFilter::default(), *published, &RESUME_PAGE_SIZE).await?;
+            let Some(last) = records.last() else {
+                break;
+            };
+            *published = last.record.id;
+
+            let page = records.len();
+            for record in records {
+                let _ = self.sender.send(Arc::new(record));
+            }
+            count += page;
+
+            if page < RESUME_PAGE_SIZE as usize {
+                break;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Subscribes to the records of the source or all sources stored from now on. The records stored after
+    /// the `after` id are read from the database first, so a subscriber resumes from its last record.
+    pub async fn subscribe(&self, pool: Pool<Sqlite>, src_id: Option<String>, after: Option<u32>)
+        -> Result<Subscription, RepError> {
+
+        // the publishing waits, so the records after the last published one are received live
+        let mut published = self.published.lock().await;
+
+        // nothing is published without subscribers, the first one starts from the last stored record
+        if self.sender.receiver_count() == 0 {
+            *published = rep::get_last_id(&pool).await?;
+        }
+        let receiver = self.sender.subscribe();
+
+        Ok(Subscription {
+            pool,
+            receiver,
+            filter: RecordFilter { src_id, ..Default::default() },
+            lag_policy: self.lag_policy,
+            last_id: after.unwrap_or(*published),
+            resuming: after.is_some(),
+            missed: VecDeque::new(),
+        })
+    }
+}
+
+/// Records of a subscriber in the order of their ids
+pub struct Subscription {
+    pool: Pool<Sqlite>,
+    receiver: broadcast::Receiver<Arc<StoredRecord>>,
+    filter: RecordFilter,
+    lag_policy: LagPolicy,
+    /// Id of the last record returned to the subscriber
+    last_id: u32,
+    /// Set while the missed records are read from the database
+    resuming: bool,
+    missed: VecDeque<Arc<StoredRecord>>,
+}
+
+impl Subscription {
+    /// Gets the next record of the subscriber, None when the feed is closed.
+    /// Fails with `FeedError::Lagged` if the subscriber lags behind and the lag policy disconnects it.
+    pub async fn next(&mut self) -> Result<Option<Arc<StoredRecord>>, FeedError> {
+        loop {
+            if let Some(record) = self.missed.pop_front() {
+                self.last_id = record.record.id;
+                return Ok(Some(record));
+            }
+
+            if self.resuming {
+                let records = rep::get_data_since(&self.pool, &self.filter, self.last_id, &RESUME_PAGE_SIZE).await?;
+                self.resuming = records.len() as u32 == RESUME_PAGE_SIZE;
+                self.missed.extend(records.into_iter().map(Arc::new));
+                continue;
+            }
+
+            match self.receiver.recv().await {
+                Ok(record) => {
+                    if self.filter.src_id.as_ref().is_some_and(|src_id| *src_id != record.record.src_id) {
+                        continue;
+                    }
+                    // already returned from the database, records are published in the order of ids
+                    if record.record.id <= self.last_id {
+                        continue;
+                    }
+                    self.last_id = record.record.id;
+                    return Ok(Some(record));
+                }
+                Err(RecvError::Lagged(missed)) => match self.lag_policy {
+                    LagPolicy::Resume => self.resuming = true,
+                    LagPolicy::Disconnect => return Err(FeedError::Lagged(missed)),
+                },
+                Err(RecvError::Closed) => return Ok(None),
+            }
+        }
+    }
+}
@@ -1,2 +1,24 @@
 pub mod db;
 pub mod rep;
+pub mod spool;
+pub mod video;
+pub mod stats;
+pub mod dedupe;
+pub mod sequence;
+pub mod delivery;
+pub mod dead_letter;
+pub mod target;
+pub mod encryption;
+pub mod error;
+pub mod segment_log;
+pub mod store;
+pub mod writer;
+pub mod backup;
+pub mod export;
+pub mod feed;
+pub mod webhook;
+
+/// Gets a comma-separated list of bind placeholders for the identifiers
+pub(crate) fn placeholders(ids: &[u32]) -> String {
+    ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ")
+}
@@ -1,10 +1,14 @@
-use std::{collections::HashMap, error::Error};
-use crate::models::{Source, Record};
-use sqlx::{Pool, Row, Sqlite};
+use std::{borrow::Cow, collections::HashMap};
+use crate::common::compression::{self, Compression};
+use crate::common::crypto;
+use crate::data::error::RepError;
+use crate::data::{sequence, stats};
+use crate::models::{Cursor, DeliveryOrder, Page, Record, RecordFilter, RecordState, Source, StoredRecord};
+use sqlx::{Pool, Row, Sqlite, SqliteConnection};
 
  /// Gets a setting value by a key
 pub async fn get_setting_by_key(pool:&Pool<Sqlite>, key:&str)
-     -> Result<Option<String>, Box<dyn Error>> {
+     -> Result<Option<String>, RepError> {
     
      let query = sqlx::query(
          "SELECT value FROM settings WHERE key = ?"
@@ -12,19 +16,14 @@ pub async fn get_setting_by_key(pool:&Pool<Sqlite>, key:&str)
      
      let row = query.fetch_optional(pool).await?;
 
-     let val = match row {
-         Some(row) => Some(row.get("value")),
-         None => None,
-     };
+     let val = row.map(|row| row.get("value"));
 
-     // let val = row.map(|row| row.get("value"));
-     
      Ok(val)
 }
 
 /// Gets all setting dictionary
 pub async fn get_all_setting(pool:&Pool<Sqlite>)
-    -> Result<HashMap<String, String>, Box<dyn Error>> {
+    -> Result<HashMap<String, String>, RepError> {
    
     let query = sqlx::query(
         "SELECT key, value FROM settings"
@@ -46,9 +45,24 @@ pub async fn get_all_setting(pool:&Pool<Sqlite>)
     Ok(settings)
 }
 
+/// Adds or replaces the setting value
+pub async fn set_setting(pool:&Pool<Sqlite>, key:&str, value:&str)
+    -> Result<(), RepError> {
+
+    sqlx::query(
+        "INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value"
+    )
+        .bind(key)
+        .bind(value)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
 /// Gets all data sources
 pub async fn get_all_sources(pool:&Pool<Sqlite>)
-    ->Result<Vec<Source>, Box<dyn Error>> {
+    ->Result<Vec<Source>, RepError> {
 
     let query = sqlx::query(
         r#"SELECT src_id, cfg, active FROM sources"#
@@ -71,7 +85,7 @@ pub async fn get_all_sources(pool:&Pool<Sqlite>)
 }
 
 /// Gets a data source by its name (as src_id)
-pub async fn get_source_by_id(pool:&Pool<Sqlite>, name: &str) -> Result<Option<Source>, Box<dyn Error>>{
+pub async fn get_source_by_id(pool:&Pool<Sqlite>, name: &str) -> Result<Option<Source>, RepError>{
     
     let query = sqlx::query(
         r#"SELECT src_id, cfg, active FROM sources WHERE src_id = ?"#
@@ -79,21 +93,17 @@ pub async fn get_source_by_id(pool:&Pool<Sqlite>, name: &str) -> Result<Option<S
 
     let row = query.fetch_optional(pool).await?;
     
-    let source = match row 
-    {
-        Some(row)=> Some(Source {
-           src_id: row.get("src_id"),
-           cfg: row.get("cfg"),
-           active: row.get("active"),
-        }), 
-        None => None
-    };
+    let source = row.map(|row| Source {
+        src_id: row.get("src_id"),
+        cfg: row.get("cfg"),
+        active: row.get("active"),
+    });
     
     Ok(source)
 }
 
 /// Adds the data source to database
-pub async fn add_source(pool:&Pool<Sqlite>, source: &Source)->Result<(), Box<dyn Error>>{
+pub async fn add_source(pool:&Pool<Sqlite>, source: &Source)->Result<(), RepError>{
 
     let mut tx = pool.begin().await?;
     
@@ -110,8 +120,8 @@ pub async fn add_source(pool:&Pool<Sqlite>, source: &Source)->Result<(), Box<dyn
     Ok(())
 }
 
-/// Updates the data source
-pub async  fn update_source(pool:&Pool<Sqlite>, source: Source)->Result<(), Box<dyn Error>>{
+/// Updates the data source, fails with `RepError::NotFound` if it is not registered
+pub async  fn update_source(pool:&Pool<Sqlite>, source: Source)->Result<(), RepError>{
     
     let query = sqlx::query(
         r#"UPDATE sources SET cfg = ?, active = ? WHERE src_id = ?"#
@@ -120,63 +130,185 @@ pub async  fn update_source(pool:&Pool<Sqlite>, source: Source)->Result<(), Box<
         .bind(source.active)
         .bind(&source.src_id);
     
-    query.execute(pool).await?;
+    let result = query.execute(pool).await?;
+    if result.rows_affected() == 0 {
+        return Err(RepError::NotFound(format!("source {}", source.src_id)));
+    }
 
     Ok(())
 }
 
 /// Deletes a data source by the src_id
-pub async fn delete_source(pool: &Pool<Sqlite>, src_id: &str)-> Result<(), Box<dyn Error>>{
+pub async fn delete_source(pool: &Pool<Sqlite>, src_id: &str)-> Result<(), RepError>{
     
     let query = sqlx::query(
         r#"DELETE FROM sources WHERE src_id = ?"#
     ).bind(src_id);
     
-    query.execute(pool).await?;
+    let result = query.execute(pool).await?;
+    if result.rows_affected() == 0 {
+        return Err(RepError::NotFound(format!("source {}", src_id)));
+    }
 
     Ok(())
 }
 
-/// Gets last data records
+/// Gets the first unsent data records, high-priority records first, the oldest first within a priority
 pub async fn get_last_data(pool: &Pool<Sqlite>, count: &u32)
-    -> Result<Vec<Record>, Box<dyn Error>> {
+    -> Result<Vec<Record>, RepError> {
 
-    let query = sqlx::query_as::<_, Record>(
+    let page = get_data_page(pool, None, DeliveryOrder::Fifo, None, count).await?;
+
+    Ok(page.records)
+}
+
+/// Gets the first unsent data records of the data source, high-priority records first,
+/// the oldest first within a priority
+pub async fn get_data_by_src_id(pool: &Pool<Sqlite>, src_id: &str, count: &u32) 
+    -> Result<Vec<Record>, RepError> {
+
+    let page = get_data_page(pool, Some(src_id), DeliveryOrder::Fifo, None, count).await?;
+
+    Ok(page.records)
+}
+
+/// Gets a page of unsent data records, optionally of one data source, in the delivery order.
+/// Records are ordered by priority and then by id, so the order of a source is its insertion order.
+pub async fn get_data_page(pool: &Pool<Sqlite>, src_id: Option<&str>, order: DeliveryOrder,
+                           after: Option<Cursor>, count: &u32)
+    -> Result<Page, RepError> {
+
+    let (id_order, id_cmp) = match order {
+        DeliveryOrder::Fifo => ("ASC", ">"),
+        DeliveryOrder::Lifo => ("DESC", "<"),
+    };
+
+    let query_str = format!(
         r#"
-            SELECT * FROM records 
+            SELECT * FROM records
             WHERE sent = 0
-            ORDER BY id DESC
-            LIMIT ? OFFSET 0
-        "#
-    ).bind(count);
+              AND (? IS NULL OR src_id = ?)
+              AND (? IS NULL OR priority < ? OR (priority = ? AND id {} ?))
+            ORDER BY priority DESC, id {}
+            LIMIT ?
+        "#,
+        id_cmp, id_order
+    );
 
-    let records = query.fetch_all(pool).await?;
+    let priority = after.map(|cursor| cursor.priority);
+    let id = after.map(|cursor| cursor.id);
 
-    Ok(records)
+    let records = sqlx::query_as::<_, Record>(&query_str)
+        .bind(src_id)
+        .bind(src_id)
+        .bind(priority)
+        .bind(priority)
+        .bind(priority)
+        .bind(id)
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    let records = decode_records(records)?;
+
+    let next = match records.last() {
+        Some(last) if records.len() as u32 == *count => Some(Cursor { priority: last.priority, id: last.id }),
+        _ => None,
+    };
+
+    Ok(Page { records, next })
 }
 
-/// Gets last data records of the data source
-pub async fn get_data_by_src_id(pool: &Pool<Sqlite>, src_id: &str, count: &u32) 
-    -> Result<Vec<Record>, Box<dyn Error>> {
-    let query = sqlx::query_as::<_, Record>(
+/// Gets the data records matching the filter stored before the record with the `before` id, the newest first.
+/// Unlike `get_data_by_src_id` sent records are included unless filtered out.
+pub async fn get_data_by_filter(pool: &Pool<Sqlite>, filter: &RecordFilter, before: Option<u32>, count: &u32)
+    -> Result<Vec<StoredRecord>, RepError> {
+
+    let records = sqlx::query_as::<_, StoredRecord>(
         r#"
-            SELECT * FROM records 
-            WHERE src_id = ? AND sent = 0
+            SELECT * FROM records
+            WHERE (? IS NULL OR id < ?)
+              AND (? IS NULL OR src_id = ?)
+              AND (? IS NULL OR created_at >= ?)
+              AND (? IS NULL OR created_at < ?)
+              AND (? IS NULL OR sent = ?)
             ORDER BY id DESC
-            LIMIT ? OFFSET 0
+            LIMIT ?
         "#
     )
-        .bind(src_id)
-        .bind(count);
+        .bind(before)
+        .bind(before)
+        .bind(&filter.src_id)
+        .bind(&filter.src_id)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(filter.sent)
+        .bind(filter.sent)
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    decode_stored_records(records)
+}
+
+/// Gets the data records matching the filter stored after the record with the `after` id, the oldest first
+pub async fn get_data_since(pool: &Pool<Sqlite>, filter: &RecordFilter, after: u32, count: &u32)
+    -> Result<Vec<StoredRecord>, RepError> {
+
+    let records = sqlx::query_as::<_, StoredRecord>(
+        r#"
+            SELECT * FROM records
+            WHERE id > ?
+              AND (? IS NULL OR src_id = ?)
+              AND (? IS NULL OR created_at >= ?)
+              AND (? IS NULL OR created_at < ?)
+              AND (? IS NULL OR sent = ?)
+            ORDER BY id
+            LIMIT ?
+        "#
+    )
+        .bind(after)
+        .bind(&filter.src_id)
+        .bind(&filter.src_id)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(filter.sent)
+        .bind(filter.sent)
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    decode_stored_records(records)
+}
 
-    let records = query.fetch_all(pool).await?;
+/// Gets the id of the last stored data record, 0 if there are no records
+pub async fn get_last_id(pool: &Pool<Sqlite>) -> Result<u32, RepError> {
+    let id = sqlx::query_scalar::<_, Option<u32>>("SELECT MAX(id) FROM records")
+        .fetch_one(pool)
+        .await?;
 
-    Ok(records)
+    Ok(id.unwrap_or_default())
+}
+
+fn decode_stored_records(records: Vec<StoredRecord>) -> Result<Vec<StoredRecord>, RepError> {
+    records
+        .into_iter()
+        .map(|mut stored| {
+            let record = &mut stored.record;
+            let data = std::mem::take(&mut record.data);
+            record.data = decode_data(data, record.key_id.take(), record.compression.take())?;
+            Ok(stored)
+        })
+        .collect()
 }
 
 /// Adds data records to the database
-pub async fn add_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
-    -> Result<Vec<u32>, Box<dyn Error>> {
+pub async fn add_data(pool: &Pool<Sqlite>, records: &[Record])
+    -> Result<Vec<u32>, RepError> {
 
     if records.is_empty() {
         return Ok(Vec::new());
@@ -186,15 +318,7 @@ pub async fn add_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
     let mut ids = Vec::new();
 
     for record in records {
-        let id = sqlx::query_scalar::<_, u32>(
-            r#"INSERT INTO records (src_id, data, sent) VALUES (?, ?, ?) RETURNING id"#
-        )
-            .bind(&record.src_id)
-            .bind(&record.data)
-            .bind(record.sent)
-            .fetch_one(&mut *tx)
-            .await?;
-
+        let id = insert_record(&mut tx, record).await?;
         ids.push(id);
     }
 
@@ -203,9 +327,115 @@ pub async fn add_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
     Ok(ids)
 }
 
+/// Inserts a data record within the transaction, its sequence number is tracked in the same transaction
+/// so a stored record is never left untracked
+pub(crate) async fn insert_record(conn: &mut SqliteConnection, record: &Record) -> Result<u32, RepError> {
+    let id = insert_row(conn, record).await?;
+
+    if let Some(seq) = record.seq {
+        sequence::track_in(conn, &record.src_id, seq).await?;
+    }
+
+    Ok(id)
+}
+
+/// Inserts a data record within the transaction without tracking its sequence number
+pub(crate) async fn insert_row(conn: &mut SqliteConnection, record: &Record) -> Result<u32, RepError> {
+    let StoredData { data, key_id, compressed_len } = stored_data(record)?;
+
+    let id = sqlx::query_scalar::<_, u32>(
+        r#"INSERT INTO records (src_id, data, sent, data_file, seq, kind, priority, state, compression, content_encoding, key_id, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, unixepoch()) RETURNING id"#
+    )
+        .bind(&record.src_id)
+        .bind(data.as_ref())
+        .bind(record.sent)
+        .bind(&record.data_file)
+        .bind(record.seq)
+        .bind(record.kind)
+        .bind(record.priority)
+        .bind(initial_state(record))
+        .bind(record.compression)
+        .bind(record.content_encoding)
+        .bind(key_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    if record.compression.is_some() {
+        stats::add_compressed_size(conn, &record.src_id, record.data.len(), compressed_len).await?;
+    }
+
+    Ok(id)
+}
+
+/// Data of the record as it is stored
+struct StoredData<'a> {
+    data: Cow<'a, [u8]>,
+    /// Id of the key the data is encrypted with
+    key_id: Option<&'static str>,
+    /// Length of the compressed data before it is encrypted
+    compressed_len: usize,
+}
+
+/// Gets the data of the record to be stored: compressed if the record has a compression and then
+/// encrypted if encryption keys are installed.
+fn stored_data(record: &Record) -> Result<StoredData<'_>, RepError> {
+    let data = match record.compression {
+        Some(compression) => compression::compress(&record.data, compression)
+            .map(Cow::Owned)
+            .map_err(|e| RepError::Data(e.to_string()))?,
+        None => Cow::Borrowed(record.data.as_slice()),
+    };
+    let compressed_len = data.len();
+
+    match crypto::keyring() {
+        // payloads spooled to files have no inline data
+        Some(keyring) if !data.is_empty() => keyring
+            .encrypt(&data)
+            .map(|encrypted| StoredData {
+                data: Cow::Owned(encrypted),
+                key_id: Some(keyring.active_key()),
+                compressed_len,
+            })
+            .map_err(|e| RepError::Data(e.to_string())),
+        _ => Ok(StoredData { data, key_id: None, compressed_len }),
+    }
+}
+
+/// Decrypts and decompresses the stored data
+pub(crate) fn decode_data(data: Vec<u8>, key_id: Option<String>, compression: Option<Compression>)
+    -> Result<Vec<u8>, RepError> {
+
+    let data = match key_id {
+        Some(key_id) => crypto::decrypt(&key_id, &data).map_err(|e| RepError::Data(e.to_string()))?,
+        None => data,
+    };
+
+    match compression {
+        Some(compression) => compression::decompress(&data, compression).map_err(|e| RepError::Data(e.to_string())),
+        None => Ok(data),
+    }
+}
+
+/// Decrypts and decompresses the data of the stored records
+pub(crate) fn decode_records(records: Vec<Record>) -> Result<Vec<Record>, RepError> {
+    records.into_iter().map(decode_record).collect()
+}
+
+/// Decrypts and decompresses the data of the stored record
+pub(crate) fn decode_record(mut record: Record) -> Result<Record, RepError> {
+    let data = std::mem::take(&mut record.data);
+    record.data = decode_data(data, record.key_id.take(), record.compression.take())?;
+    Ok(record)
+}
+
+/// Gets the delivery state of a new record, records added as sent are delivered
+fn initial_state(record: &Record) -> RecordState {
+    if record.sent { RecordState::Delivered } else { record.state }
+}
+
 /// Adds data records to the database
-pub async fn bulk_add_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
-    -> Result<Vec<u32>, Box<dyn Error>> {
+pub async fn bulk_add_data(pool: &Pool<Sqlite>, records: &[Record])
+    -> Result<Vec<u32>, RepError> {
 
     if records.is_empty() {
         return Ok(Vec::new());
@@ -213,36 +443,56 @@ pub async fn bulk_add_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
     
     let placeholders: String = records
         .iter()
-        .map(|_| "(?, ?, ?)") 
+        .map(|_| "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, unixepoch())")
         .collect::<Vec<_>>()
         .join(", ");
     
     let query_str = format!(
-        r#"INSERT INTO records (src_id, data, sent) VALUES {} RETURNING id"#,
+        r#"INSERT INTO records (src_id, data, sent, data_file, seq, kind, priority, state, compression, content_encoding, key_id, created_at) VALUES {} RETURNING id"#,
         placeholders
     );
     
+    let data = records
+        .iter()
+        .map(stored_data)
+        .collect::<Result<Vec<_>, _>>()?;
+
     let mut query = sqlx::query_scalar::<_, u32>(&query_str);
     
-    for record in records {
+    for (record, stored) in records.iter().zip(&data) {
         query = query
             .bind(&record.src_id)
-            .bind(&record.data)
-            .bind(record.sent);
+            .bind(stored.data.as_ref())
+            .bind(record.sent)
+            .bind(&record.data_file)
+            .bind(record.seq)
+            .bind(record.kind)
+            .bind(record.priority)
+            .bind(initial_state(record))
+            .bind(record.compression)
+            .bind(record.content_encoding)
+            .bind(stored.key_id);
     }
     
     let mut tx = pool.begin().await?;
     
     let ids = query.fetch_all(&mut *tx).await?;
+
+    for (record, stored) in records.iter().zip(&data) {
+        if record.compression.is_some() {
+            stats::add_compressed_size(&mut tx, &record.src_id, record.data.len(), stored.compressed_len).await?;
+        }
+    }
     
     tx.commit().await?;
 
     Ok(ids)
 }
 
-/// Updates data records in the database
-pub async fn update_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
-    -> Result<(), Box<dyn Error>> {
+/// Updates data records in the database.
+/// Delivery should be tracked with `delivery::ack` and `delivery::nack` instead of flipping `sent`.
+pub async fn update_data(pool: &Pool<Sqlite>, records: &[Record])
+    -> Result<(), RepError> {
 
     if records.is_empty() {
         return Ok(());
@@ -251,11 +501,23 @@ pub async fn update_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
     let mut tx = pool.begin().await?;
     
     for record in records {
+        let StoredData { data, key_id, .. } = stored_data(record)?;
         let query = sqlx::query(
-            r#"UPDATE records SET src_id = ?, data = ?, sent = ? WHERE id = ?"#
+            r#"
+                UPDATE records SET src_id = ?, data = ?, compression = ?, key_id = ?, sent = ?,
+                    state = CASE
+                        WHEN ? THEN 'delivered'
+                        WHEN state = 'delivered' THEN 'pending'
+                        ELSE state
+                    END
+                WHERE id = ?
+            "#
         )
             .bind(&record.src_id)
-            .bind(&record.data)
+            .bind(data.as_ref())
+            .bind(record.compression)
+            .bind(key_id)
+            .bind(record.sent)
             .bind(record.sent)
             .bind(record.id);
 
@@ -269,7 +531,7 @@ pub async fn update_data(pool: &Pool<Sqlite>, records: &Vec<Record>)
 
 /// Deletes data by identifiers collection
 pub async fn delete_data(pool: &Pool<Sqlite>, ids: Vec<u32>)
-    -> Result<(), Box<dyn Error>>{
+    -> Result<(), RepError>{
 
     if ids.is_empty() {
         return Ok(());
@@ -277,36 +539,84 @@ pub async fn delete_data(pool: &Pool<Sqlite>, ids: Vec<u32>)
     
     let placeholders: String = ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
     let query_str = format!(
-        r#"DELETE FROM records WHERE id IN ({})"#,
+        r#"DELETE FROM records WHERE id IN ({}) RETURNING data_file"#,
         placeholders
     );
     
-    let mut query = sqlx::query(&query_str);
+    let mut query = sqlx::query_scalar::<_, Option<String>>(&query_str);
     
     for id in &ids {
         query = query.bind(id);
     }
     
     let mut tx = pool.begin().await?;
-    query.execute(&mut *tx).await?;
+    let files = query.fetch_all(&mut *tx).await?;
     tx.commit().await?;
 
+    remove_data_files(files);
+
     Ok(())
 }
 
 /// Deletes sent data records by identifiers collection
-pub async fn delete_sent_data(pool: &Pool<Sqlite>)-> Result<(), Box<dyn Error>> {
+pub async fn delete_sent_data(pool: &Pool<Sqlite>)-> Result<(), RepError> {
 
-    sqlx::query("DELETE FROM records WHERE sent = 1")
-        .execute(pool)
+    let files = sqlx::query_scalar::<_, Option<String>>(
+        "DELETE FROM records WHERE sent = 1 RETURNING data_file"
+    )
+        .fetch_all(pool)
         .await?;
 
+    remove_data_files(files);
+
     Ok(())
 }
 
+/// Deletes records exceeding `max_rows`: sent records first,
+/// then unsent ones from the lowest priority and the oldest
+pub async fn evict_data(pool: &Pool<Sqlite>, max_rows: u64) -> Result<u64, RepError> {
+
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM records")
+        .fetch_one(pool)
+        .await? as u64;
+
+    if count <= max_rows {
+        return Ok(0);
+    }
+
+    let files = sqlx::query_scalar::<_, Option<String>>(
+        r#"
+            DELETE FROM records WHERE id IN (
+                SELECT id FROM records
+                ORDER BY sent DESC, priority ASC, id ASC
+                LIMIT ?
+            )
+            RETURNING data_file
+        "#
+    )
+        .bind((count - max_rows) as i64)
+        .fetch_all(pool)
+        .await?;
+
+    let evicted = files.len() as u64;
+    remove_data_files(files);
+
+    Ok(evicted)
+}
+
+/// Removes payload files of the deleted records
+pub(crate) fn remove_data_files(files: Vec<Option<String>>) {
+    for file in files.into_iter().flatten() {
+        if let Err(e) = std::fs::remove_file(&file) {
+            log::warn!("Failed to remove payload file {}: {}", file, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::error::Error;
     use anyhow::Result;
     use crate::data::db::init_db_in_memory;
 
@@ -0,0 +1,725 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use sqlx::{Pool, Sqlite};
+use thiserror::Error;
+use crate::common::compression::Compression;
+use crate::common::crypto::{self, CryptoError, KeyRing};
+use crate::data::rep;
+use crate::models::Record;
+
+const SEGMENT_EXT: &str = "log";
+const INDEX_EXT: &str = "idx";
+/// File keeping the offset of the first record not yet moved to the delivery queue
+const DRAINED_FILE: &str = "drained";
+
+/// Frame header: length and CRC32 of the frame body
+const FRAME_HEADER: usize = 8;
+/// Record header in the frame body: offset, priority, seq, seq flag, compression, content encoding,
+/// length of the id of the key the data is encrypted with followed by the key id
+const RECORD_HEADER: usize = 28;
+/// Index entry: offset and position of the frame
+const INDEX_ENTRY: usize = 16;
+/// Positions of the frames are indexed every this many bytes
+const INDEX_INTERVAL: u64 = 4096;
+
+#[derive(Error, Debug)]
+pub enum LogError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Corrupted frame in the segment {segment} at position {position}")]
+    Corrupted { segment: String, position: u64 },
+    #[error("Source id {0} cannot be used as a log directory name")]
+    InvalidSource(String),
+    #[error("{0}")]
+    Crypto(#[from] CryptoError),
+}
+
+/// Segment file, records from `base` up to the base of the next segment
+#[derive(Debug)]
+struct Segment {
+    base: u64,
+    size: u64,
+}
+
+/// Frame read from a segment
+enum Frame {
+    Record { offset: u64, record: Record, len: u64 },
+    /// End of the segment
+    End,
+    /// Torn or corrupted frame
+    Invalid,
+}
+
+/// Append-only log of the records of one source split into fixed-size segment files.
+///
+/// Records are written as frames `[length u32][crc32 u32][body]` with increasing offsets,
+/// the data is encrypted with the active key if encryption keys are installed.
+/// A sparse index maps offsets to frame positions in every segment. Torn frames at the end of
+/// the last segment, left by a power loss, are truncated when the log is opened.
+#[derive(Debug)]
+pub struct SegmentLog {
+    dir: PathBuf,
+    src_id: String,
+    segment_size: u64,
+    /// Segments in the order of offsets, the last one is appended to
+    segments: Vec<Segment>,
+    active: File,
+    index: File,
+    /// Position of the last indexed frame of the active segment
+    indexed: Option<u64>,
+    next_offset: u64,
+    drained: u64,
+}
+
+impl SegmentLog {
+    /// Opens the log in the directory, creating it if it does not exist, and recovers the last segment
+    pub fn open(dir: impl AsRef<Path>, src_id: &str, segment_size: u64) -> Result<Self, LogError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let drained = read_drained(&dir)?;
+
+        let mut bases: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXT))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+            .collect();
+        bases.sort_unstable();
+
+        if bases.is_empty() {
+            bases.push(drained);
+        }
+
+        let mut segments = Vec::with_capacity(bases.len());
+        for base in &bases[..bases.len() - 1] {
+            let size = fs::metadata(segment_path(&dir, *base))?.len();
+            segments.push(Segment { base: *base, size });
+        }
+
+        let base = bases[bases.len() - 1];
+        let Recovered { size, next_offset, entries } = recover(&dir, base)?;
+        segments.push(Segment { base, size });
+
+        // the index of the last segment is rebuilt as it may miss entries or point past the end
+        let mut index = File::create(index_path(&dir, base))?;
+        for (offset, position) in &entries {
+            index.write_all(&index_entry(*offset, *position))?;
+        }
+
+        let active = OpenOptions::new().append(true).open(segment_path(&dir, base))?;
+
+        Ok(SegmentLog {
+            drained: drained.max(segments[0].base),
+            dir,
+            src_id: src_id.to_string(),
+            segment_size,
+            segments,
+            active,
+            index,
+            indexed: entries.last().map(|(_, position)| *position),
+            next_offset,
+        })
+    }
+
+    /// Appends records to the log, returns their offsets.
+    /// Records are written to the OS but not synced to the disk, see `sync`.
+    pub fn append(&mut self, records: &[Record]) -> Result<Vec<u64>, LogError> {
+        let mut offsets = Vec::with_capacity(records.len());
+        let mut frames = Vec::new();
+        let mut entries: Vec<(u64, u64)> = Vec::new();
+        // frames buffered but not written yet
+        let mut pending = 0;
+        let keyring = crypto::keyring();
+
+        for record in records {
+            let offset = self.next_offset + pending;
+            let frame = encode_frame(offset, record, keyring)?;
+
+            let mut position = self.active_size() + frames.len() as u64;
+            if position > 0 && position + frame.len() as u64 > self.segment_size {
+                self.write(&frames, &entries)?;
+                self.next_offset += pending;
+                pending = 0;
+                frames.clear();
+                entries.clear();
+                self.roll()?;
+                position = 0;
+            }
+
+            let indexed = entries.last().map(|(_, position)| *position).or(self.indexed);
+            if indexed.is_none_or(|indexed| position >= indexed + INDEX_INTERVAL) {
+                entries.push((offset, position));
+            }
+
+            frames.extend_from_slice(&frame);
+            offsets.push(offset);
+            pending += 1;
+        }
+
+        self.write(&frames, &entries)?;
+        self.next_offset += pending;
+
+        Ok(offsets)
+    }
+
+    fn active_size(&self) -> u64 {
+        self.segments.last().map_or(0, |segment| segment.size)
+    }
+
+    fn active_base(&self) -> u64 {
+        self.segments.last().map_or(0, |segment| segment.base)
+    }
+
+    /// Writes the frames and the index entries to the active segment
+    fn write(&mut self, frames: &[u8], entries: &[(u64, u64)]) -> Result<(), LogError> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+
+        let active = self.segments.last_mut().expect("log has an active segment");
+        if let Err(e) = self.active.write_all(frames) {
+            // a partial write is not left in the middle of the segment
+            let _ = self.active.set_len(active.size);
+            return Err(e.into());
+        }
+        active.size += frames.len() as u64;
+
+        let index: Vec<u8> = entries.iter().flat_map(|(offset, position)| index_entry(*offset, *position)).collect();
+        self.index.write_all(&index)?;
+        if let Some((_, position)) = entries.last() {
+            self.indexed = Some(*position);
+        }
+
+        Ok(())
+    }
+
+    /// Seals the active segment and starts a new one at the next offset
+    fn roll(&mut self) -> Result<(), LogError> {
+        self.sync()?;
+
+        let base = self.next_offset;
+        self.active = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, base))?;
+        self.index = File::create(index_path(&self.dir, base))?;
+        self.indexed = None;
+        self.segments.push(Segment { base, size: 0 });
+
+        Ok(())
+    }
+
+    /// Syncs the appended records to the disk
+    pub fn sync(&mut self) -> Result<(), LogError> {
+        self.active.sync_data()?;
+        self.index.sync_data()?;
+        Ok(())
+    }
+
+    /// Reads up to `count` records starting from the offset.
+    /// Records after a corrupted frame of a sealed segment are skipped.
+    pub fn read(&self, from: u64, count: usize) -> Result<Vec<(u64, Record)>, LogError> {
+        let mut records = Vec::new();
+        let from = from.max(self.segments[0].base);
+
+        let first = self.segments.partition_point(|segment| segment.base <= from).saturating_sub(1);
+        for segment in &self.segments[first..] {
+            if records.len() >= count {
+                break;
+            }
+
+            let path = segment_path(&self.dir, segment.base);
+            let position = self.indexed_position(segment.base, from)?;
+
+            let mut reader = BufReader::new(File::open(&path)?);
+            reader.seek(SeekFrom::Start(position))?;
+
+            let mut position = position;
+            while records.len() < count {
+                match read_frame(&mut reader, segment.size.saturating_sub(position), &self.src_id)? {
+                    Frame::Record { offset, record, len } => {
+                        position += len;
+                        if offset >= from {
+                            records.push((offset, decrypt(record)?));
+                        }
+                    }
+                    Frame::End => break,
+                    // the active segment is checked when the log is opened, a frame corrupted later is an error
+                    Frame::Invalid if segment.base == self.active_base() => return Err(LogError::Corrupted {
+                        segment: path.to_string_lossy().into_owned(),
+                        position,
+                    }),
+                    // the rest of a sealed segment cannot be framed, its records are skipped so the log is still drained
+                    Frame::Invalid => {
+                        log::error!("Log segment {} is corrupted at position {}, the rest of its records are skipped.",
+                            path.display(), position);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Gets the position of the last indexed frame at or before the offset in the segment
+    fn indexed_position(&self, base: u64, offset: u64) -> Result<u64, LogError> {
+        let index = match fs::read(index_path(&self.dir, base)) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let position = index
+            .chunks_exact(INDEX_ENTRY)
+            .map(|entry| (read_u64(&entry[..8]), read_u64(&entry[8..])))
+            .take_while(|(indexed, _)| *indexed <= offset)
+            .last()
+            .map_or(0, |(_, position)| position);
+
+        Ok(position)
+    }
+
+    /// Saves the offset of the first record not yet moved to the delivery queue
+    /// and deletes the segments whose records are all moved
+    pub fn set_drained(&mut self, offset: u64) -> Result<(), LogError> {
+        let tmp = self.dir.join(format!("{}.tmp", DRAINED_FILE));
+        fs::write(&tmp, offset.to_le_bytes())?;
+        fs::rename(&tmp, self.dir.join(DRAINED_FILE))?;
+        self.drained = offset;
+
+        while self.segments.len() > 1 && self.segments[1].base <= offset {
+            self.delete_first_segment()?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the oldest segments until the log fits into `max_size` bytes,
+    /// the active segment is kept. Returns the count of deleted records not moved to the delivery queue.
+    pub fn retain(&mut self, max_size: u64) -> Result<u64, LogError> {
+        let mut dropped = 0;
+
+        while self.segments.len() > 1 && self.size() > max_size {
+            let end = self.segments[1].base;
+            dropped += end.saturating_sub(self.drained.max(self.segments[0].base));
+            self.delete_first_segment()?;
+        }
+
+        if self.drained < self.segments[0].base {
+            self.set_drained(self.segments[0].base)?;
+        }
+
+        Ok(dropped)
+    }
+
+    fn delete_first_segment(&mut self) -> Result<(), LogError> {
+        let segment = self.segments.remove(0);
+        fs::remove_file(segment_path(&self.dir, segment.base))?;
+        if let Err(e) = fs::remove_file(index_path(&self.dir, segment.base)) {
+            log::warn!("Failed to remove the index of the log segment {}: {}", segment.base, e);
+        }
+        Ok(())
+    }
+
+    pub fn src_id(&self) -> &str {
+        &self.src_id
+    }
+
+    /// Offset of the next appended record
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Offset of the first record not yet moved to the delivery queue
+    pub fn drained(&self) -> u64 {
+        self.drained
+    }
+
+    /// Size of the segment files in bytes
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+/// Last segment checked when the log is opened
+struct Recovered {
+    size: u64,
+    next_offset: u64,
+    /// Index entries of the valid frames
+    entries: Vec<(u64, u64)>,
+}
+
+/// Scans the segment and truncates it after the last valid frame
+fn recover(dir: &Path, base: u64) -> Result<Recovered, LogError> {
+    let path = segment_path(dir, base);
+    let file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&path)?;
+    let len = file.metadata()?.len();
+
+    let mut reader = BufReader::new(&file);
+    let mut position = 0;
+    let mut next_offset = base;
+    let mut entries: Vec<(u64, u64)> = Vec::new();
+
+    loop {
+        match read_frame(&mut reader, len - position, "")? {
+            Frame::Record { offset, len, .. } => {
+                if entries.last().is_none_or(|(_, indexed)| position >= indexed + INDEX_INTERVAL) {
+                    entries.push((offset, position));
+                }
+                next_offset = offset + 1;
+                position += len;
+            }
+            Frame::End => break,
+            Frame::Invalid => {
+                log::warn!("Log segment {} is truncated from {} to {} bytes after a torn write.",
+                    path.display(), len, position);
+                file.set_len(position)?;
+                file.sync_all()?;
+                break;
+            }
+        }
+    }
+
+    Ok(Recovered { size: position, next_offset, entries })
+}
+
+/// Reads the next frame, `remaining` is the count of bytes up to the end of the segment
+fn read_frame(reader: &mut impl Read, remaining: u64, src_id: &str) -> io::Result<Frame> {
+    if remaining == 0 {
+        return Ok(Frame::End);
+    }
+    if remaining < FRAME_HEADER as u64 {
+        return Ok(Frame::Invalid);
+    }
+
+    let mut header = [0; FRAME_HEADER];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    if len < RECORD_HEADER as u64 || len > remaining - FRAME_HEADER as u64 {
+        return Ok(Frame::Invalid);
+    }
+
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body)?;
+    if crc32fast::hash(&body) != crc {
+        return Ok(Frame::Invalid);
+    }
+
+    Ok(match decode_record(body, src_id) {
+        Some((offset, record)) => Frame::Record { offset, record, len: FRAME_HEADER as u64 + len },
+        None => Frame::Invalid,
+    })
+}
+
+fn encode_frame(offset: u64, record: &Record, keyring: Option<&KeyRing>) -> Result<Vec<u8>, LogError> {
+    let (key_id, data) = match keyring {
+        Some(keyring) => (keyring.active_key(), Cow::Owned(keyring.encrypt(&record.data)?)),
+        None => ("", Cow::Borrowed(&record.data)),
+    };
+    let key_len = u8::try_from(key_id.len()).map_err(|_| CryptoError::Encryption(key_id.to_string()))?;
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER + RECORD_HEADER + key_id.len() + data.len());
+    frame.extend_from_slice(&[0; FRAME_HEADER]);
+    frame.extend_from_slice(&offset.to_le_bytes());
+    frame.extend_from_slice(&record.priority.to_le_bytes());
+    frame.extend_from_slice(&record.seq.unwrap_or_default().to_le_bytes());
+    frame.push(record.seq.is_some() as u8);
+    frame.push(compression_code(record.compression));
+    frame.push(compression_code(record.content_encoding));
+    frame.push(key_len);
+    frame.extend_from_slice(key_id.as_bytes());
+    frame.extend_from_slice(&data);
+
+    let body = &frame[FRAME_HEADER..];
+    let len = (body.len() as u32).to_le_bytes();
+    let crc = crc32fast::hash(body).to_le_bytes();
+    frame[..4].copy_from_slice(&len);
+    frame[4..FRAME_HEADER].copy_from_slice(&crc);
+
+    Ok(frame)
+}
+
+fn decode_record(mut body: Vec<u8>, src_id: &str) -> Option<(u64, Record)> {
+    let offset = read_u64(&body[..8]);
+    let priority = read_u64(&body[8..16]) as i64;
+    let seq = read_u64(&body[16..24]) as i64;
+    let seq = match body[24] {
+        0 => None,
+        1 => Some(seq),
+        _ => return None,
+    };
+    let compression = compression_from_code(body[25])?;
+    let content_encoding = compression_from_code(body[26])?;
+    let key_end = RECORD_HEADER + body[27] as usize;
+    let key_id = match String::from_utf8(body.get(RECORD_HEADER..key_end)?.to_vec()).ok()? {
+        key_id if key_id.is_empty() => None,
+        key_id => Some(key_id),
+    };
+    let data = body.split_off(key_end);
+
+    Some((offset, Record {
+        src_id: src_id.to_string(),
+        data,
+        seq,
+        priority,
+        compression,
+        content_encoding,
+        key_id,
+        ..Default::default()
+    }))
+}
+
+/// Decrypts the data of the record read from a frame
+fn decrypt(mut record: Record) -> Result<Record, LogError> {
+    if let Some(key_id) = record.key_id.take() {
+        record.data = crypto::decrypt(&key_id, &record.data)?;
+    }
+    Ok(record)
+}
+
+fn compression_code(compression: Option<Compression>) -> u8 {
+    match compression {
+        None => 0,
+        Some(Compression::Gzip) => 1,
+        Some(Compression::Deflate) => 2,
+        Some(Compression::Zstd) => 3,
+    }
+}
+
+fn compression_from_code(code: u8) -> Option<Option<Compression>> {
+    match code {
+        0 => Some(None),
+        1 => Some(Some(Compression::Gzip)),
+        2 => Some(Some(Compression::Deflate)),
+        3 => Some(Some(Compression::Zstd)),
+        _ => None,
+    }
+}
+
+fn index_entry(offset: u64, position: u64) -> [u8; INDEX_ENTRY] {
+    let mut entry = [0; INDEX_ENTRY];
+    entry[..8].copy_from_slice(&offset.to_le_bytes());
+    entry[8..].copy_from_slice(&position.to_le_bytes());
+    entry
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn read_drained(dir: &Path) -> Result<u64, LogError> {
+    match fs::read(dir.join(DRAINED_FILE)) {
+        Ok(bytes) if bytes.len() == 8 => Ok(read_u64(&bytes)),
+        Ok(_) => Ok(0),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn segment_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", base, SEGMENT_EXT))
+}
+
+fn index_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", base, INDEX_EXT))
+}
+
+/// Segment logs of the sources stored in the log directory, one subdirectory per source
+#[derive(Debug)]
+pub struct Logs {
+    dir: PathBuf,
+    segment_size: u64,
+    logs: Mutex<HashMap<String, Arc<Mutex<SegmentLog>>>>,
+}
+
+impl Logs {
+    pub fn new(dir: impl AsRef<Path>, segment_size: u64) -> Self {
+        Logs { dir: dir.as_ref().to_path_buf(), segment_size, logs: Mutex::new(HashMap::new()) }
+    }
+
+    /// Opens the logs left by the previous run, so that their records are moved to the delivery queue
+    pub fn open_existing(&self) -> Result<usize, LogError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut count = 0;
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(src_id) = entry.file_name().to_str() {
+                    self.get(src_id)?;
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Gets the log of the source, opening it on the first use
+    pub fn get(&self, src_id: &str) -> Result<Arc<Mutex<SegmentLog>>, LogError> {
+        let mut logs = lock(&self.logs);
+        if let Some(log) = logs.get(src_id) {
+            return Ok(log.clone());
+        }
+
+        validate_dir_name(src_id)?;
+        let log = SegmentLog::open(self.dir.join(src_id), src_id, self.segment_size)?;
+        let log = Arc::new(Mutex::new(log));
+        logs.insert(src_id.to_string(), log.clone());
+
+        Ok(log)
+    }
+
+    /// Gets the open logs
+    pub fn all(&self) -> Vec<Arc<Mutex<SegmentLog>>> {
+        lock(&self.logs).values().cloned().collect()
+    }
+
+    /// Deletes the log of the source with its records
+    pub fn remove(&self, src_id: &str) -> Result<(), LogError> {
+        if lock(&self.logs).remove(src_id).is_some() {
+            fs::remove_dir_all(self.dir.join(src_id))?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the records of the logs in the directory not moved to the delivery queue yet which are not encrypted
+/// with the active key. The logs are only read, so they can be checked while the broker appends to them.
+pub fn count_not_sealed_with(dir: impl AsRef<Path>, active_key: &str) -> Result<u64, LogError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let log_dir = entry.path();
+        let drained = read_drained(&log_dir)?;
+        for segment in fs::read_dir(&log_dir)? {
+            let path = segment?.path();
+            if path.extension().is_none_or(|ext| ext != SEGMENT_EXT) {
+                continue;
+            }
+
+            let file = File::open(&path)?;
+            let mut remaining = file.metadata()?.len();
+            let mut reader = BufReader::new(file);
+            // a torn frame at the end of the active segment is where the scan stops
+            while let Frame::Record { offset, record, len } = read_frame(&mut reader, remaining, "")? {
+                remaining -= len;
+                if offset >= drained && record.key_id.as_deref() != Some(active_key) {
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Locks the mutex, a log left by a panicked writer is still consistent on the disk
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn validate_dir_name(src_id: &str) -> Result<(), LogError> {
+    let valid = !src_id.is_empty()
+        && !src_id.starts_with('.')
+        && src_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    if valid { Ok(()) } else { Err(LogError::InvalidSource(src_id.to_string())) }
+}
+
+/// Appends the record to the log of the source and syncs it to the disk before it is acknowledged.
+/// The file operations run in the blocking thread pool. Returns the offset of the record.
+pub async fn append_synced(logs: Arc<Logs>, record: Record) -> Result<u64, LogError> {
+    let offsets = append_all_synced(logs, vec![record]).await?;
+    Ok(offsets[0])
+}
+
+/// Appends the records of a source to its log with a single sync, see `append_synced`.
+/// Returns the offsets of the records.
+pub async fn append_all_synced(logs: Arc<Logs>, records: Vec<Record>) -> Result<Vec<u64>, LogError> {
+    let Some(src_id) = records.first().map(|record| record.src_id.clone()) else {
+        return Ok(Vec::new());
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let source_log = logs.get(&src_id)?;
+        let mut source_log = lock(&source_log);
+        let offsets = source_log.append(&records)?;
+        source_log.sync()?;
+        Ok(offsets)
+    })
+        .await
+        .map_err(|e| LogError::Io(io::Error::other(e)))?
+}
+
+/// Runs the operation on the locked log in the blocking thread pool
+pub async fn blocking<T, F>(log: &Arc<Mutex<SegmentLog>>, operation: F) -> Result<T, LogError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut SegmentLog) -> Result<T, LogError> + Send + 'static,
+{
+    let log = log.clone();
+    tokio::task::spawn_blocking(move || operation(&mut lock(&log)))
+        .await
+        .map_err(|e| LogError::Io(io::Error::other(e)))?
+}
+
+/// Moves the records of the log to the delivery queue in batches, returns the count of moved records.
+/// Records are moved at least once: a batch is moved again if the broker stops before the drained offset is saved.
+pub async fn drain(pool: &Pool<Sqlite>, log: &Arc<Mutex<SegmentLog>>, batch: usize) -> Result<usize, Box<dyn Error>> {
+    let mut moved = 0;
+
+    loop {
+        let (records, drained, end) = blocking(log, move |log| {
+            Ok((log.read(log.drained(), batch)?, log.drained(), log.next_offset()))
+        }).await?;
+
+        let count = records.len();
+        // a batch short of the limit reaches the end of the log, records skipped in corrupted segments are passed too
+        let next = match records.last() {
+            Some((last, _)) if count == batch => last + 1,
+            _ => end,
+        };
+        if next == drained {
+            break;
+        }
+
+        let records: Vec<Record> = records.into_iter().map(|(_, record)| record).collect();
+        if !records.is_empty() {
+            rep::bulk_add_data(pool, &records).await?;
+        }
+
+        blocking(log, move |log| log.set_drained(next)).await?;
+        moved += count;
+
+        if count < batch {
+            break;
+        }
+    }
+
+    Ok(moved)
+}
@@ -0,0 +1,149 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use crate::data::error::RepError;
+use crate::data::rep;
+use crate::models::{Record, RecordKind, SequenceEvent, SequenceEventKind, SequenceState};
+
+/// Tracks the sequence number of a stored record of the source.
+/// Anomalies are stored as events, gaps are also stored as diagnostic records to be forwarded to the hub.
+pub async fn track(pool: &Pool<Sqlite>, src_id: &str, seq: i64)
+    -> Result<Option<SequenceEventKind>, Box<dyn Error>> {
+
+    let mut tx = pool.begin().await?;
+    let event = track_in(&mut tx, src_id, seq).await?;
+    tx.commit().await?;
+
+    Ok(event)
+}
+
+/// Tracks the sequence number within the transaction of the caller, see `track`
+pub(crate) async fn track_in(conn: &mut SqliteConnection, src_id: &str, seq: i64)
+    -> Result<Option<SequenceEventKind>, RepError> {
+
+    let last_seq = sqlx::query_scalar::<_, i64>(
+        r#"SELECT last_seq FROM source_sequences WHERE src_id = ?"#
+    )
+        .bind(src_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    let (event, expected) = classify(last_seq, seq);
+    let advances = advances(event);
+    let missing = if event == Some(SequenceEventKind::Gap) { seq - expected } else { 0 };
+    let count = |kind: SequenceEventKind| i64::from(event == Some(kind));
+
+    sqlx::query(
+        r#"
+            INSERT INTO source_sequences (src_id, last_seq, gaps, missing, out_of_order, duplicates, resets)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(src_id) DO UPDATE SET
+                last_seq = CASE WHEN ? THEN excluded.last_seq ELSE last_seq END,
+                gaps = gaps + excluded.gaps,
+                missing = missing + excluded.missing,
+                out_of_order = out_of_order + excluded.out_of_order,
+                duplicates = duplicates + excluded.duplicates,
+                resets = resets + excluded.resets
+        "#
+    )
+        .bind(src_id)
+        .bind(seq)
+        .bind(count(SequenceEventKind::Gap))
+        .bind(missing)
+        .bind(count(SequenceEventKind::OutOfOrder))
+        .bind(count(SequenceEventKind::Duplicate))
+        .bind(count(SequenceEventKind::Reset))
+        .bind(advances)
+        .execute(&mut *conn)
+        .await?;
+
+    if let Some(kind) = event {
+        let now = chrono::Utc::now().timestamp();
+
+        sqlx::query(
+            r#"INSERT INTO sequence_events (src_id, kind, expected, received, created_at) VALUES (?, ?, ?, ?, ?)"#
+        )
+            .bind(src_id)
+            .bind(kind)
+            .bind(expected)
+            .bind(seq)
+            .bind(now)
+            .execute(&mut *conn)
+            .await?;
+
+        if kind == SequenceEventKind::Gap {
+            rep::insert_row(conn, &gap_record(src_id, expected, seq, now)).await?;
+        }
+    }
+
+    Ok(event)
+}
+
+/// Gets the anomaly of the sequence number and the expected number
+pub(crate) fn classify(last_seq: Option<i64>, seq: i64) -> (Option<SequenceEventKind>, i64) {
+    match last_seq {
+        None => (None, seq),
+        Some(last) if seq == last => (Some(SequenceEventKind::Duplicate), last + 1),
+        Some(last) if seq == last + 1 => (None, seq),
+        Some(last) if seq > last + 1 => (Some(SequenceEventKind::Gap), last + 1),
+        Some(last) if seq == 0 => (Some(SequenceEventKind::Reset), last + 1),
+        Some(last) => (Some(SequenceEventKind::OutOfOrder), last + 1),
+    }
+}
+
+/// Whether the sequence moves to the number, out-of-order and duplicate numbers do not move it
+pub(crate) fn advances(event: Option<SequenceEventKind>) -> bool {
+    !matches!(event, Some(SequenceEventKind::OutOfOrder | SequenceEventKind::Duplicate))
+}
+
+/// Gets the diagnostic record of a gap forwarded to the hub
+pub(crate) fn gap_record(src_id: &str, expected: i64, received: i64, timestamp: i64) -> Record {
+    let data = serde_json::json!({
+        "event": "sequence_gap",
+        "src_id": src_id,
+        "expected": expected,
+        "received": received,
+        "missing": received - expected,
+        "timestamp": timestamp,
+    });
+
+    Record {
+        src_id: src_id.to_string(),
+        data: data.to_string().into_bytes(),
+        kind: RecordKind::Diagnostic,
+        ..Default::default()
+    }
+}
+
+/// Gets sequence tracking states of all sources
+pub async fn get_sequence_states(pool: &Pool<Sqlite>)
+    -> Result<Vec<SequenceState>, Box<dyn Error>> {
+
+    let states = sqlx::query_as::<_, SequenceState>(
+        r#"SELECT * FROM source_sequences ORDER BY src_id"#
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(states)
+}
+
+/// Gets the latest sequence events, optionally of one source
+pub async fn get_sequence_events(pool: &Pool<Sqlite>, src_id: Option<&str>, count: &u32)
+    -> Result<Vec<SequenceEvent>, Box<dyn Error>> {
+
+    let events = sqlx::query_as::<_, SequenceEvent>(
+        r#"
+            SELECT * FROM sequence_events
+            WHERE ? IS NULL OR src_id = ?
+            ORDER BY id DESC
+            LIMIT ?
+        "#
+    )
+        .bind(src_id)
+        .bind(src_id)
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(events)
+}
@@ -0,0 +1,401 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix_web::error::PayloadError;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use thiserror::Error;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::common::crypto::{self, CryptoError, KeyRing};
+use crate::models::Record;
+
+/// Magic of the spool files encrypted with a key of the ring.
+/// A sealed file is the magic, the length and the id of the key, a random file id, and the encrypted chunks
+/// each prefixed with its length whose top bit flags the final chunk. Files without the magic are plaintext.
+const SEALED_MAGIC: &[u8; 8] = b"\0BSEALED";
+/// Length of the random id binding the chunks to their file
+const FILE_ID_LEN: usize = 16;
+/// Flag of the final chunk in its length prefix
+const LAST_CHUNK: u32 = 1 << 31;
+/// Size of the plaintext chunks of the sealed files
+const SEALED_CHUNK: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum SpoolError {
+    #[error("Payload exceeds the limit of {0} bytes")]
+    TooLarge(usize),
+    #[error("Payload receiving error: {0}")]
+    Payload(String),
+    #[error("Decoded payload exceeds the max expansion ratio of {0}")]
+    ExpansionRatio(u32),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Crypto(#[from] CryptoError),
+}
+
+impl From<PayloadError> for SpoolError {
+    fn from(e: PayloadError) -> Self {
+        SpoolError::Payload(e.to_string())
+    }
+}
+
+/// Received request body
+#[derive(Debug)]
+pub enum Payload {
+    /// Small payload kept in memory and stored as a BLOB
+    Inline(Vec<u8>),
+    /// Large payload written to the spool directory
+    Spooled { path: String, size: usize },
+}
+
+impl Payload {
+    pub fn len(&self) -> usize {
+        match self {
+            Payload::Inline(data) => data.len(),
+            Payload::Spooled { size, .. } => *size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+static FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Spool file which is removed on drop unless it has been kept.
+/// It cleans up partial uploads when the client disconnects and the handler future is dropped.
+struct SpoolFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Receives a (possibly chunked) request body.
+/// The body is kept in memory up to `inline_limit` bytes and then streamed to a file in `dir`.
+pub async fn receive<S, E>(mut stream: S, max_size: usize, inline_limit: usize, dir: &str)
+    -> Result<Payload, SpoolError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<SpoolError>,
+{
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut file: Option<(SpoolFile, SpoolWriter)> = None;
+    let mut size = 0_usize;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(Into::into)?;
+
+        size += chunk.len();
+        if size > max_size {
+            return Err(SpoolError::TooLarge(max_size));
+        }
+
+        if file.is_none() && size > inline_limit {
+            let spool_file = SpoolFile { path: new_file_path(dir).await?, keep: false };
+            let mut handle = SpoolWriter::create(&spool_file.path, crypto::keyring()).await?;
+            handle.write(&buffer).await?;
+            buffer = Vec::new();
+            file = Some((spool_file, handle));
+        }
+
+        match file.as_mut() {
+            Some((_, handle)) => handle.write(&chunk).await?,
+            None => buffer.extend_from_slice(&chunk),
+        }
+    }
+
+    match file {
+        Some((mut spool_file, handle)) => {
+            handle.finish().await?;
+            spool_file.keep = true;
+            Ok(Payload::Spooled { path: spool_file.path.to_string_lossy().into_owned(), size })
+        }
+        None => Ok(Payload::Inline(buffer)),
+    }
+}
+
+/// Reads the payload of the record whether it is stored inline or as a file
+pub async fn load(record: &Record) -> Result<Vec<u8>, SpoolError> {
+    match &record.data_file {
+        Some(path) => read_file(path).await,
+        None => Ok(record.data.clone()),
+    }
+}
+
+/// Reads the plaintext content of the spool file
+pub async fn read_file(path: impl AsRef<Path>) -> Result<Vec<u8>, SpoolError> {
+    let mut reader = SpoolReader::open(path.as_ref(), crypto::keyring()).await?;
+    let mut data = Vec::new();
+    while let Some(chunk) = reader.next().await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Writes the content to the spool file, it is encrypted if encryption keys are installed
+pub async fn write_file(path: impl AsRef<Path>, data: &[u8]) -> Result<(), SpoolError> {
+    let mut writer = SpoolWriter::create(path.as_ref(), crypto::keyring()).await?;
+    writer.write(data).await?;
+    writer.finish().await
+}
+
+/// Seals the file with the active key of the key ring unless it is sealed with it already,
+/// plaintext files are sealed too. The file is replaced at once. Returns whether the file has been re-sealed.
+pub async fn reseal(path: impl AsRef<Path>, keyring: &KeyRing) -> Result<bool, SpoolError> {
+    let path = path.as_ref();
+    let mut reader = SpoolReader::open_any(path, keyring).await?;
+    if reader.key_id.as_deref() == Some(keyring.active_key()) {
+        return Ok(false);
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut writer = SpoolWriter::create(&tmp, Some(keyring)).await?;
+    let copied = async {
+        while let Some(chunk) = reader.next().await? {
+            writer.write(&chunk).await?;
+        }
+        writer.finish().await
+    }.await;
+
+    match copied {
+        Ok(()) => tokio::fs::rename(&tmp, path).await?,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Associated data of a sealed chunk: the file id, the chunk index and the final chunk flag.
+/// Chunks cannot be dropped, reordered, moved to another file or cut off after a chunk unnoticed.
+fn chunk_aad(file_id: &[u8; FILE_ID_LEN], index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(FILE_ID_LEN + 9);
+    aad.extend_from_slice(file_id);
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// Writer of the spool file, the content is sealed in chunks with the active key if a key ring is given
+struct SpoolWriter<'a> {
+    file: tokio::fs::File,
+    keyring: Option<&'a KeyRing>,
+    file_id: [u8; FILE_ID_LEN],
+    /// Index of the next sealed chunk
+    index: u64,
+    /// Plaintext not sealed yet
+    pending: Vec<u8>,
+}
+
+impl<'a> SpoolWriter<'a> {
+    async fn create(path: &Path, keyring: Option<&'a KeyRing>) -> Result<Self, SpoolError> {
+        let mut file = tokio::fs::File::create(path).await?;
+        let file_id = crypto::random_bytes();
+
+        if let Some(keyring) = keyring {
+            let key_id = keyring.active_key().as_bytes();
+            let key_len = u8::try_from(key_id.len())
+                .map_err(|_| CryptoError::Encryption(keyring.active_key().to_string()))?;
+
+            let mut header = SEALED_MAGIC.to_vec();
+            header.push(key_len);
+            header.extend_from_slice(key_id);
+            header.extend_from_slice(&file_id);
+            file.write_all(&header).await?;
+        }
+
+        Ok(SpoolWriter { file, keyring, file_id, index: 0, pending: Vec::new() })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), SpoolError> {
+        if self.keyring.is_none() {
+            return Ok(self.file.write_all(data).await?);
+        }
+
+        self.pending.extend_from_slice(data);
+        // a full chunk is kept back, so the final chunk is never empty unless the whole content is
+        while self.pending.len() > SEALED_CHUNK {
+            let rest = self.pending.split_off(SEALED_CHUNK);
+            let chunk = std::mem::replace(&mut self.pending, rest);
+            self.seal(&chunk, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn seal(&mut self, chunk: &[u8], last: bool) -> Result<(), SpoolError> {
+        if let Some(keyring) = self.keyring {
+            let encrypted = keyring.encrypt_with_aad(chunk, &chunk_aad(&self.file_id, self.index, last))?;
+            let len = encrypted.len() as u32 | if last { LAST_CHUNK } else { 0 };
+            self.file.write_all(&len.to_le_bytes()).await?;
+            self.file.write_all(&encrypted).await?;
+            self.index += 1;
+        }
+        Ok(())
+    }
+
+    /// Seals the rest of the content as the final chunk and syncs the file to the disk
+    async fn finish(mut self) -> Result<(), SpoolError> {
+        let chunk = std::mem::take(&mut self.pending);
+        self.seal(&chunk, true).await?;
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+}
+
+/// Reader of the plaintext chunks of the spool file, sealed chunks are decrypted one by one
+struct SpoolReader<'a> {
+    file: tokio::fs::File,
+    path: String,
+    keyring: Option<&'a KeyRing>,
+    /// Key of the sealed file
+    key_id: Option<String>,
+    file_id: [u8; FILE_ID_LEN],
+    /// Index of the next sealed chunk
+    index: u64,
+    /// Set once the final chunk has been read
+    finished: bool,
+    /// Start of the plaintext file read while looking for the magic
+    head: Vec<u8>,
+}
+
+impl<'a> SpoolReader<'a> {
+    /// Opens the spool file, plaintext files are rejected when a key ring is given
+    async fn open(path: &Path, keyring: Option<&'a KeyRing>) -> Result<Self, SpoolError> {
+        let reader = Self::open_file(path, keyring).await?;
+        if keyring.is_some() && reader.key_id.is_none() {
+            return Err(CryptoError::Unsealed(reader.path).into());
+        }
+        Ok(reader)
+    }
+
+    /// Opens the sealed or plaintext spool file
+    async fn open_any(path: &Path, keyring: &'a KeyRing) -> Result<Self, SpoolError> {
+        Self::open_file(path, Some(keyring)).await
+    }
+
+    async fn open_file(path: &Path, keyring: Option<&'a KeyRing>) -> Result<Self, SpoolError> {
+        let mut file = tokio::fs::File::open(path).await?;
+
+        let mut head = vec![0; SEALED_MAGIC.len()];
+        let read = read_full(&mut file, &mut head).await?;
+        head.truncate(read);
+
+        let mut key_id = None;
+        let mut file_id = [0; FILE_ID_LEN];
+        if head == SEALED_MAGIC {
+            let mut id = vec![0; file.read_u8().await? as usize];
+            file.read_exact(&mut id).await?;
+            file.read_exact(&mut file_id).await?;
+            key_id = Some(String::from_utf8_lossy(&id).into_owned());
+            head.clear();
+        }
+
+        Ok(SpoolReader {
+            file,
+            path: path.to_string_lossy().into_owned(),
+            keyring,
+            key_id,
+            file_id,
+            index: 0,
+            finished: false,
+            head,
+        })
+    }
+
+    /// Reads the next plaintext chunk, `None` at the end of the file
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, SpoolError> {
+        let Some(key_id) = &self.key_id else {
+            if !self.head.is_empty() {
+                return Ok(Some(std::mem::take(&mut self.head)));
+            }
+            let mut buffer = vec![0; SEALED_CHUNK];
+            let read = self.file.read(&mut buffer).await?;
+            buffer.truncate(read);
+            return Ok((read > 0).then_some(buffer));
+        };
+        let tampered = || SpoolError::from(CryptoError::Tampered(self.path.clone()));
+
+        let mut len = [0; 4];
+        match (read_full(&mut self.file, &mut len).await?, self.finished) {
+            (0, true) => return Ok(None),
+            // nothing may follow the final chunk and the final chunk may not be cut off
+            (4, false) => {}
+            _ => return Err(tampered()),
+        }
+        let len = u32::from_le_bytes(len);
+        let last = len & LAST_CHUNK != 0;
+
+        let mut encrypted = vec![0; (len & !LAST_CHUNK) as usize];
+        if read_full(&mut self.file, &mut encrypted).await? < encrypted.len() {
+            return Err(tampered());
+        }
+
+        let keyring = self.keyring.ok_or_else(|| CryptoError::UnknownKey(key_id.clone()))?;
+        let chunk = keyring
+            .decrypt_with_aad(key_id, &encrypted, &chunk_aad(&self.file_id, self.index, last))
+            .map_err(|e| match e {
+                CryptoError::Decryption(_) => tampered(),
+                e => e.into(),
+            })?;
+        self.index += 1;
+        self.finished = last;
+
+        Ok(Some(chunk))
+    }
+}
+
+/// Reads until the buffer is full or the end of the file, returns the count of read bytes
+async fn read_full(file: &mut tokio::fs::File, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Removes a spooled payload file, e.g. when the record could not be stored
+pub async fn discard(path: &str) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        log::warn!("Failed to remove payload file {}: {}", path, e);
+    }
+}
+
+async fn new_file_path(dir: &str) -> Result<PathBuf, std::io::Error> {
+    tokio::fs::create_dir_all(dir).await?;
+    let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let counter = FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    Ok(Path::new(dir).join(format!("{}-{}.bin", nanos, counter)))
+}
+
+/// Gets the SHA-256 hex digest of the payload content
+pub async fn digest(payload: &Payload) -> Result<String, SpoolError> {
+    let mut hasher = Sha256::new();
+    match payload {
+        Payload::Inline(data) => hasher.update(data),
+        Payload::Spooled { path, .. } => {
+            let mut reader = SpoolReader::open(Path::new(path), crypto::keyring()).await?;
+            while let Some(chunk) = reader.next().await? {
+                hasher.update(&chunk);
+            }
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
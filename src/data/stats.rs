@@ -0,0 +1,150 @@
+use std::borrow::Cow;
+use std::error::Error;
+use sqlx::{FromRow, Pool, Sqlite, SqliteConnection};
+use crate::common::crypto;
+use crate::common::defaults::{REJECTED_SAMPLES_PER_SOURCE, REJECTED_SAMPLE_SIZE};
+use crate::data::error::RepError;
+use crate::models::{CompressionStats, RejectedPayload};
+
+/// Counts a rejected payload of the source and keeps its truncated sample.
+/// Only the latest `REJECTED_SAMPLES_PER_SOURCE` samples are kept.
+pub async fn add_rejected_payload(pool: &Pool<Sqlite>, src_id: &str, error: &str, data: &[u8])
+    -> Result<(), Box<dyn Error>> {
+
+    let sample = &data[..data.len().min(REJECTED_SAMPLE_SIZE)];
+    let (sample, key_id) = match crypto::keyring() {
+        Some(keyring) => (Cow::Owned(keyring.encrypt(sample)?), Some(keyring.active_key())),
+        None => (Cow::Borrowed(sample), None),
+    };
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"
+            INSERT INTO source_stats (src_id, rejected) VALUES (?, 1)
+            ON CONFLICT(src_id) DO UPDATE SET rejected = rejected + 1
+        "#
+    )
+        .bind(src_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"INSERT INTO rejected_payloads (src_id, error, data, key_id, created_at) VALUES (?, ?, ?, ?, ?)"#
+    )
+        .bind(src_id)
+        .bind(error)
+        .bind(sample.as_ref())
+        .bind(key_id)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+            DELETE FROM rejected_payloads
+            WHERE src_id = ? AND id NOT IN (
+                SELECT id FROM rejected_payloads WHERE src_id = ? ORDER BY id DESC LIMIT ?
+            )
+        "#
+    )
+        .bind(src_id)
+        .bind(src_id)
+        .bind(REJECTED_SAMPLES_PER_SOURCE)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Gets the count of rejected payloads of the source
+pub async fn get_rejected_count(pool: &Pool<Sqlite>, src_id: &str)
+    -> Result<i64, Box<dyn Error>> {
+
+    let count = sqlx::query_scalar::<_, i64>(
+        r#"SELECT rejected FROM source_stats WHERE src_id = ?"#
+    )
+        .bind(src_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(count.unwrap_or(0))
+}
+
+/// Gets kept samples of rejected payloads of the source, the newest first
+pub async fn get_rejected_payloads(pool: &Pool<Sqlite>, src_id: &str)
+    -> Result<Vec<RejectedPayload>, Box<dyn Error>> {
+
+    let rows = sqlx::query_as::<_, RejectedRow>(
+        r#"SELECT * FROM rejected_payloads WHERE src_id = ? ORDER BY id DESC"#
+    )
+        .bind(src_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut samples = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut sample = row.sample;
+        if let Some(key_id) = row.key_id {
+            sample.data = crypto::decrypt(&key_id, &sample.data)?;
+        }
+        samples.push(sample);
+    }
+
+    Ok(samples)
+}
+
+/// Stored sample with the id of the key it is encrypted with
+#[derive(FromRow)]
+struct RejectedRow {
+    #[sqlx(flatten)]
+    sample: RejectedPayload,
+    key_id: Option<String>,
+}
+
+/// Adds the raw and the stored size of a compressed payload of the source within the transaction
+pub(crate) async fn add_compressed_size(conn: &mut SqliteConnection, src_id: &str, raw: usize, stored: usize)
+    -> Result<(), sqlx::Error> {
+
+    sqlx::query(
+        r#"
+            INSERT INTO source_stats (src_id, raw_bytes, stored_bytes) VALUES (?, ?, ?)
+            ON CONFLICT(src_id) DO UPDATE SET
+                raw_bytes = raw_bytes + excluded.raw_bytes,
+                stored_bytes = stored_bytes + excluded.stored_bytes
+        "#
+    )
+        .bind(src_id)
+        .bind(raw as i64)
+        .bind(stored as i64)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Gets compression statistics of the sources storing compressed payloads
+pub async fn get_compression_stats(pool: &Pool<Sqlite>) -> Result<Vec<CompressionStats>, Box<dyn Error>> {
+
+    let stats = sqlx::query_as::<_, CompressionStats>(
+        r#"SELECT src_id, raw_bytes, stored_bytes FROM source_stats WHERE stored_bytes > 0 ORDER BY src_id"#
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(stats)
+}
+
+/// Gets the count of unsent records of the sources having them
+pub async fn get_unsent_counts(pool: &Pool<Sqlite>) -> Result<Vec<(String, i64)>, RepError> {
+
+    let counts = sqlx::query_as::<_, (String, i64)>(
+        r#"SELECT src_id, COUNT(*) FROM records WHERE sent = 0 GROUP BY src_id ORDER BY src_id"#
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(counts)
+}
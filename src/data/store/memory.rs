@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use async_trait::async_trait;
+use crate::common::defaults::SETTING_VALUES;
+use crate::data::dedupe::Stored;
+use crate::data::error::RepError;
+use crate::data::{rep, sequence};
+use crate::models::{Cursor, DeliveryOrder, Page, Record, RecordState, SequenceEventKind, Source};
+use super::{RecordStore, SettingsStore, SourceStore};
+
+#[derive(Debug)]
+struct State {
+    settings: HashMap<String, String>,
+    sources: Vec<Source>,
+    records: BTreeMap<u32, Record>,
+    next_id: u32,
+    /// Record id and expiry of the idempotency keys by source and key
+    dedupe_keys: HashMap<(String, String), (u32, i64)>,
+    /// Last sequence number by source
+    sequences: HashMap<String, i64>,
+}
+
+impl State {
+    fn has_source(&self, src_id: &str) -> bool {
+        self.sources.iter().any(|s| s.src_id == src_id)
+    }
+
+    /// Fails the same way as the foreign key of the records table
+    fn check_sources(&self, records: &[Record]) -> Result<(), RepError> {
+        match records.iter().find(|r| !self.has_source(&r.src_id)) {
+            Some(record) => Err(RepError::Constraint(format!("unknown source {}", record.src_id))),
+            None => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, record: &Record) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let state = if record.sent { RecordState::Delivered } else { record.state };
+        self.records.insert(id, Record {
+            id,
+            state,
+            compression: None,
+            key_id: None,
+            ..record.clone()
+        });
+
+        if let Some(seq) = record.seq {
+            self.track(&record.src_id, seq);
+        }
+        id
+    }
+
+    fn track(&mut self, src_id: &str, seq: i64) -> Option<SequenceEventKind> {
+        let (event, expected) = sequence::classify(self.sequences.get(src_id).copied(), seq);
+
+        if sequence::advances(event) {
+            self.sequences.insert(src_id.to_string(), seq);
+        }
+        if event == Some(SequenceEventKind::Gap) {
+            let diagnostic = sequence::gap_record(src_id, expected, seq, chrono::Utc::now().timestamp());
+            self.insert(&diagnostic);
+        }
+
+        event
+    }
+}
+
+/// Stores keeping everything in memory, the same behavior as `SqliteStore` without the database.
+/// Intended for tests, records are neither compressed nor encrypted, rejected payloads are not sampled
+/// and no webhook events are raised.
+#[derive(Debug)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        let settings = SETTING_VALUES
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        MemoryStore {
+            state: Mutex::new(State {
+                settings,
+                sources: Vec::new(),
+                records: BTreeMap::new(),
+                next_id: 1,
+                dedupe_keys: HashMap::new(),
+                sequences: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl SettingsStore for MemoryStore {
+    async fn get_setting_by_key(&self, key: &str) -> Result<Option<String>, RepError> {
+        Ok(self.state().settings.get(key).cloned())
+    }
+
+    async fn get_all_setting(&self) -> Result<HashMap<String, String>, RepError> {
+        Ok(self.state().settings.clone())
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), RepError> {
+        self.state().settings.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SourceStore for MemoryStore {
+    async fn get_all_sources(&self) -> Result<Vec<Source>, RepError> {
+        Ok(self.state().sources.clone())
+    }
+
+    async fn get_source_by_id(&self, src_id: &str) -> Result<Option<Source>, RepError> {
+        Ok(self.state().sources.iter().find(|s| s.src_id == src_id).cloned())
+    }
+
+    async fn add_source(&self, source: &Source) -> Result<(), RepError> {
+        let mut state = self.state();
+        if state.has_source(&source.src_id) {
+            return Err(RepError::Conflict(format!("source {} already exists", source.src_id)));
+        }
+        state.sources.push(source.clone());
+        Ok(())
+    }
+
+    async fn update_source(&self, source: Source) -> Result<(), RepError> {
+        let mut state = self.state();
+        match state.sources.iter_mut().find(|s| s.src_id == source.src_id) {
+            Some(existing) => {
+                *existing = source;
+                Ok(())
+            }
+            None => Err(RepError::NotFound(format!("source {}", source.src_id))),
+        }
+    }
+
+    async fn delete_source(&self, src_id: &str) -> Result<(), RepError> {
+        let mut state = self.state();
+        if !state.has_source(src_id) {
+            return Err(RepError::NotFound(format!("source {}", src_id)));
+        }
+        state.sources.retain(|s| s.src_id != src_id);
+
+        // records of the source are deleted the same way as by the foreign key cascade
+        let mut files = Vec::new();
+        state.records.retain(|_, r| {
+            let keep = r.src_id != src_id;
+            if !keep {
+                files.push(r.data_file.clone());
+            }
+            keep
+        });
+        drop(state);
+
+        rep::remove_data_files(files);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RecordStore for MemoryStore {
+    async fn get_data_page(&self, src_id: Option<&str>, order: DeliveryOrder, after: Option<Cursor>, count: &u32)
+        -> Result<Page, RepError> {
+
+        let state = self.state();
+        let after_cursor = |r: &Record| match after {
+            None => true,
+            Some(cursor) => r.priority < cursor.priority || (r.priority == cursor.priority && match order {
+                DeliveryOrder::Fifo => r.id > cursor.id,
+                DeliveryOrder::Lifo => r.id < cursor.id,
+            }),
+        };
+
+        let mut records: Vec<Record> = state.records
+            .values()
+            .filter(|r| !r.sent && src_id.is_none_or(|src_id| r.src_id == src_id) && after_cursor(r))
+            .cloned()
+            .collect();
+
+        records.sort_by(|a, b| b.priority.cmp(&a.priority).then(match order {
+            DeliveryOrder::Fifo => a.id.cmp(&b.id),
+            DeliveryOrder::Lifo => b.id.cmp(&a.id),
+        }));
+        records.truncate(*count as usize);
+
+        let next = match records.last() {
+            Some(last) if records.len() as u32 == *count => Some(Cursor { priority: last.priority, id: last.id }),
+            _ => None,
+        };
+
+        Ok(Page { records, next })
+    }
+
+    async fn add_data(&self, records: &[Record]) -> Result<Vec<u32>, RepError> {
+        let mut state = self.state();
+        state.check_sources(records)?;
+
+        Ok(records.iter().map(|record| state.insert(record)).collect())
+    }
+
+    async fn add_data_once(&self, record: &Record, key: &str, window: u64) -> Result<Stored, RepError> {
+        let mut state = self.state();
+        state.check_sources(std::slice::from_ref(record))?;
+
+        let now = chrono::Utc::now().timestamp();
+        let dedupe_key = (record.src_id.clone(), key.to_string());
+        if let Some((id, expires_at)) = state.dedupe_keys.get(&dedupe_key) {
+            if *expires_at > now {
+                return Ok(Stored::Duplicate(*id));
+            }
+        }
+
+        let id = state.insert(record);
+        state.dedupe_keys.insert(dedupe_key, (id, now + window as i64));
+        Ok(Stored::Added(id))
+    }
+
+    async fn bulk_add_data(&self, records: &[Record]) -> Result<Vec<u32>, RepError> {
+        self.add_data(records).await
+    }
+
+    async fn update_data(&self, records: &[Record]) -> Result<(), RepError> {
+        let mut state = self.state();
+        state.check_sources(records)?;
+
+        for record in records {
+            if let Some(stored) = state.records.get_mut(&record.id) {
+                stored.src_id = record.src_id.clone();
+                stored.data = record.data.clone();
+                stored.sent = record.sent;
+                stored.state = match stored.state {
+                    _ if record.sent => RecordState::Delivered,
+                    RecordState::Delivered => RecordState::Pending,
+                    other => other,
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_data(&self, ids: Vec<u32>) -> Result<(), RepError> {
+        let mut state = self.state();
+        let files = ids
+            .iter()
+            .filter_map(|id| state.records.remove(id))
+            .map(|r| r.data_file)
+            .collect();
+        drop(state);
+
+        rep::remove_data_files(files);
+        Ok(())
+    }
+
+    async fn delete_sent_data(&self) -> Result<(), RepError> {
+        let mut state = self.state();
+        let mut files = Vec::new();
+        state.records.retain(|_, r| {
+            if r.sent {
+                files.push(r.data_file.clone());
+            }
+            !r.sent
+        });
+        drop(state);
+
+        rep::remove_data_files(files);
+        Ok(())
+    }
+
+    async fn evict_data(&self, max_rows: u64) -> Result<u64, RepError> {
+        let mut state = self.state();
+        let count = state.records.len() as u64;
+        if count <= max_rows {
+            return Ok(0);
+        }
+
+        let mut order: Vec<(bool, i64, u32)> = state.records
+            .values()
+            .map(|r| (r.sent, r.priority, r.id))
+            .collect();
+        order.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+        let files: Vec<Option<String>> = order
+            .into_iter()
+            .take((count - max_rows) as usize)
+            .filter_map(|(_, _, id)| state.records.remove(&id))
+            .map(|r| r.data_file)
+            .collect();
+        drop(state);
+
+        let evicted = files.len() as u64;
+        rep::remove_data_files(files);
+
+        Ok(evicted)
+    }
+
+    async fn track_sequence(&self, src_id: &str, seq: i64) -> Result<Option<SequenceEventKind>, RepError> {
+        Ok(self.state().track(src_id, seq))
+    }
+
+    async fn add_rejected_payload(&self, src_id: &str, _error: &str, _data: &[u8]) -> Result<(), RepError> {
+        self.state().check_sources(&[Record { src_id: src_id.to_string(), ..Default::default() }])
+    }
+
+    async fn source_seen(&self, _src_id: &str) {}
+}
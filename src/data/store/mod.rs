@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use actix_web::web;
+use async_trait::async_trait;
+use crate::data::dedupe::Stored;
+use crate::data::error::RepError;
+use crate::models::{Cursor, DeliveryOrder, Page, Record, SequenceEventKind, Source};
+
+pub mod memory;
+pub mod sqlite;
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+/// Storage of the broker settings
+#[async_trait]
+pub trait SettingsStore: Send + Sync {
+    /// Gets a setting value by a key
+    async fn get_setting_by_key(&self, key: &str) -> Result<Option<String>, RepError>;
+
+    /// Gets all setting dictionary
+    async fn get_all_setting(&self) -> Result<HashMap<String, String>, RepError>;
+
+    /// Adds or replaces the setting value
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), RepError>;
+}
+
+/// Storage of the registered data sources
+#[async_trait]
+pub trait SourceStore: Send + Sync {
+    /// Gets all data sources
+    async fn get_all_sources(&self) -> Result<Vec<Source>, RepError>;
+
+    /// Gets a data source by its src_id
+    async fn get_source_by_id(&self, src_id: &str) -> Result<Option<Source>, RepError>;
+
+    /// Adds the data source, src_id must be unique
+    async fn add_source(&self, source: &Source) -> Result<(), RepError>;
+
+    /// Updates the data source
+    async fn update_source(&self, source: Source) -> Result<(), RepError>;
+
+    /// Deletes the data source together with its records
+    async fn delete_source(&self, src_id: &str) -> Result<(), RepError>;
+}
+
+/// Storage of the data records
+#[async_trait]
+pub trait RecordStore: Send + Sync {
+    /// Gets a page of unsent data records, optionally of one data source, in the delivery order
+    async fn get_data_page(&self, src_id: Option<&str>, order: DeliveryOrder, after: Option<Cursor>, count: &u32)
+        -> Result<Page, RepError>;
+
+    /// Adds data records of registered sources, returns their identifiers
+    async fn add_data(&self, records: &[Record]) -> Result<Vec<u32>, RepError>;
+
+    /// Adds the data record unless its source has used the key within the window (seconds)
+    async fn add_data_once(&self, record: &Record, key: &str, window: u64) -> Result<Stored, RepError>;
+
+    /// Adds data records of registered sources in one statement, returns their identifiers
+    async fn bulk_add_data(&self, records: &[Record]) -> Result<Vec<u32>, RepError>;
+
+    /// Updates the source, data and sent flag of the records
+    async fn update_data(&self, records: &[Record]) -> Result<(), RepError>;
+
+    /// Deletes data records by identifiers
+    async fn delete_data(&self, ids: Vec<u32>) -> Result<(), RepError>;
+
+    /// Deletes sent data records
+    async fn delete_sent_data(&self) -> Result<(), RepError>;
+
+    /// Deletes records exceeding `max_rows`: sent records first,
+    /// then unsent ones from the lowest priority and the oldest
+    async fn evict_data(&self, max_rows: u64) -> Result<u64, RepError>;
+
+    /// Tracks the sequence number of a record of the source stored outside the records, returns the anomaly
+    /// of the number. Added records are tracked along with them. Gaps are also stored as diagnostic records.
+    async fn track_sequence(&self, src_id: &str, seq: i64) -> Result<Option<SequenceEventKind>, RepError>;
+
+    /// Counts a payload of the source rejected by the validation and keeps its sample
+    async fn add_rejected_payload(&self, src_id: &str, error: &str, data: &[u8]) -> Result<(), RepError>;
+
+    /// Marks the source as seen, the first record of a source raises the `source.first_seen` webhook event.
+    /// Failures are logged only as they must not fail the stored record.
+    async fn source_seen(&self, src_id: &str);
+
+    /// Gets the first unsent data records, high-priority records first, the oldest first within a priority
+    async fn get_last_data(&self, count: &u32) -> Result<Vec<Record>, RepError> {
+        Ok(self.get_data_page(None, DeliveryOrder::Fifo, None, count).await?.records)
+    }
+
+    /// Gets the first unsent data records of the data source
+    async fn get_data_by_src_id(&self, src_id: &str, count: &u32) -> Result<Vec<Record>, RepError> {
+        Ok(self.get_data_page(Some(src_id), DeliveryOrder::Fifo, None, count).await?.records)
+    }
+}
+
+/// Registers the store as `web::Data<dyn RecordStore>`, `web::Data<dyn SourceStore>`
+/// and `web::Data<dyn SettingsStore>` of the app
+pub fn configure<S>(store: Arc<S>) -> impl FnOnce(&mut web::ServiceConfig)
+where
+    S: RecordStore + SourceStore + SettingsStore + 'static,
+{
+    move |cfg| {
+        cfg.app_data(web::Data::<dyn RecordStore>::from(store.clone() as Arc<dyn RecordStore>))
+            .app_data(web::Data::<dyn SourceStore>::from(store.clone() as Arc<dyn SourceStore>))
+            .app_data(web::Data::<dyn SettingsStore>::from(store as Arc<dyn SettingsStore>));
+    }
+}
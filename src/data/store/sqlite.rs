@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use sqlx::{Pool, Sqlite};
+use crate::data::{dedupe, rep, sequence, stats};
+use crate::data::dedupe::Stored;
+use crate::data::error::RepError;
+use crate::data::writer::Writer;
+use crate::models::{Cursor, DeliveryOrder, Page, Record, SequenceEventKind, Source};
+use crate::webhook;
+use super::{RecordStore, SettingsStore, SourceStore};
+
+/// Stores backed by the SQLite database, payloads are compressed and encrypted at rest
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+    writer: Option<Writer>,
+}
+
+impl SqliteStore {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        SqliteStore { pool, writer: None }
+    }
+
+    /// Gets the store adding the records through the writer, so concurrent inserts are committed together
+    pub fn with_writer(pool: Pool<Sqlite>, writer: Writer) -> Self {
+        SqliteStore { pool, writer: Some(writer) }
+    }
+
+    pub fn pool(&self) -> &Pool<Sqlite> {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl SettingsStore for SqliteStore {
+    async fn get_setting_by_key(&self, key: &str) -> Result<Option<String>, RepError> {
+        rep::get_setting_by_key(&self.pool, key).await
+    }
+
+    async fn get_all_setting(&self) -> Result<HashMap<String, String>, RepError> {
+        rep::get_all_setting(&self.pool).await
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), RepError> {
+        rep::set_setting(&self.pool, key, value).await
+    }
+}
+
+#[async_trait]
+impl SourceStore for SqliteStore {
+    async fn get_all_sources(&self) -> Result<Vec<Source>, RepError> {
+        rep::get_all_sources(&self.pool).await
+    }
+
+    async fn get_source_by_id(&self, src_id: &str) -> Result<Option<Source>, RepError> {
+        rep::get_source_by_id(&self.pool, src_id).await
+    }
+
+    async fn add_source(&self, source: &Source) -> Result<(), RepError> {
+        rep::add_source(&self.pool, source).await
+    }
+
+    async fn update_source(&self, source: Source) -> Result<(), RepError> {
+        rep::update_source(&self.pool, source).await
+    }
+
+    async fn delete_source(&self, src_id: &str) -> Result<(), RepError> {
+        rep::delete_source(&self.pool, src_id).await
+    }
+}
+
+#[async_trait]
+impl RecordStore for SqliteStore {
+    async fn get_data_page(&self, src_id: Option<&str>, order: DeliveryOrder, after: Option<Cursor>, count: &u32)
+        -> Result<Page, RepError> {
+        rep::get_data_page(&self.pool, src_id, order, after, count).await
+    }
+
+    async fn add_data(&self, records: &[Record]) -> Result<Vec<u32>, RepError> {
+        match &self.writer {
+            Some(writer) if !records.is_empty() => writer.add_data(records.to_vec()).await,
+            _ => rep::add_data(&self.pool, records).await,
+        }
+    }
+
+    async fn add_data_once(&self, record: &Record, key: &str, window: u64) -> Result<Stored, RepError> {
+        match &self.writer {
+            Some(writer) => writer.add_data_once(record.clone(), key.to_string(), window).await,
+            None => dedupe::add_data_once(&self.pool, record, key, window).await,
+        }
+    }
+
+    async fn bulk_add_data(&self, records: &[Record]) -> Result<Vec<u32>, RepError> {
+        rep::bulk_add_data(&self.pool, records).await
+    }
+
+    async fn update_data(&self, records: &[Record]) -> Result<(), RepError> {
+        rep::update_data(&self.pool, records).await
+    }
+
+    async fn delete_data(&self, ids: Vec<u32>) -> Result<(), RepError> {
+        rep::delete_data(&self.pool, ids).await
+    }
+
+    async fn delete_sent_data(&self) -> Result<(), RepError> {
+        rep::delete_sent_data(&self.pool).await
+    }
+
+    async fn evict_data(&self, max_rows: u64) -> Result<u64, RepError> {
+        rep::evict_data(&self.pool, max_rows).await
+    }
+
+    async fn track_sequence(&self, src_id: &str, seq: i64) -> Result<Option<SequenceEventKind>, RepError> {
+        sequence::track(&self.pool, src_id, seq).await.map_err(RepError::from_boxed)
+    }
+
+    async fn add_rejected_payload(&self, src_id: &str, error: &str, data: &[u8]) -> Result<(), RepError> {
+        stats::add_rejected_payload(&self.pool, src_id, error, data).await.map_err(RepError::from_boxed)
+    }
+
+    async fn source_seen(&self, src_id: &str) {
+        webhook::source_seen(&self.pool, src_id).await
+    }
+}
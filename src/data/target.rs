@@ -0,0 +1,335 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use crate::config::{TargetCfg, TargetMode};
+use crate::data::{dead_letter, delivery, placeholders, rep};
+use crate::models::{DeliveryOrder, Record, TargetStats};
+
+/// Synchronizes the stored targets with the configuration.
+/// New fan-out targets receive all unsent records, removed ones no longer hold records back.
+pub async fn sync_targets(pool: &Pool<Sqlite>, targets: &[TargetCfg]) -> Result<(), Box<dyn Error>> {
+
+    let mut tx = pool.begin().await?;
+
+    let names: Vec<&str> = targets.iter().map(|target| target.name.as_str()).collect();
+    let existing = sqlx::query_as::<_, (String, TargetMode)>(r#"SELECT name, mode FROM targets"#)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    for (name, _) in existing.iter().filter(|(name, _)| !names.contains(&name.as_str())) {
+        sqlx::query(r#"DELETE FROM targets WHERE name = ?"#)
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    for target in targets {
+        sqlx::query(
+            r#"INSERT INTO targets (name, mode) VALUES (?, ?) ON CONFLICT(name) DO UPDATE SET mode = excluded.mode"#
+        )
+            .bind(&target.name)
+            .bind(target.mode)
+            .execute(&mut *tx)
+            .await?;
+
+        let was_fan_out = existing.iter().any(|(name, mode)| *name == target.name && *mode == TargetMode::FanOut);
+
+        match target.mode {
+            TargetMode::FanOut if !was_fan_out => {
+                sqlx::query(
+                    r#"INSERT OR IGNORE INTO target_deliveries (target, record_id) SELECT ?, id FROM records WHERE sent = 0"#
+                )
+                    .bind(&target.name)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            TargetMode::Failover if was_fan_out => {
+                sqlx::query(r#"DELETE FROM target_deliveries WHERE target = ?"#)
+                    .bind(&target.name)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+
+    update_sent(&mut tx, None).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Atomically claims up to `count` records pending for the fan-out target for `lease_duration` seconds.
+/// Records with expired leases are claimed again. Records are returned in the delivery order.
+pub async fn claim(pool: &Pool<Sqlite>, target: &str, owner: &str, order: DeliveryOrder, count: &u32,
+                   lease_duration: u64)
+    -> Result<Vec<Record>, Box<dyn Error>> {
+
+    let lifo = delivery::lifo_sources(pool, order).await?;
+
+    let query_str = format!(
+        r#"
+            UPDATE target_deliveries SET state = 'leased', lease_owner = ?, lease_expires_at = ?
+            WHERE target = ? AND record_id IN (
+                SELECT d.record_id FROM target_deliveries d
+                JOIN records r ON r.id = d.record_id
+                WHERE d.target = ?
+                  AND (d.state = 'pending' OR (d.state = 'leased' AND d.lease_expires_at <= ?))
+                ORDER BY r.priority DESC, {}
+                LIMIT ?
+            )
+            RETURNING record_id
+        "#,
+        delivery::delivery_key("r.src_id", "r.id", &lifo)
+    );
+
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    let mut query = sqlx::query_scalar::<_, u32>(&query_str)
+        .bind(owner)
+        .bind(now + lease_duration as i64)
+        .bind(target)
+        .bind(target)
+        .bind(now);
+    for src_id in &lifo {
+        query = query.bind(src_id);
+    }
+    let ids = query
+        .bind(count)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let records_str = format!(r#"SELECT * FROM records WHERE id IN ({})"#, placeholders(&ids));
+    let mut query = sqlx::query_as::<_, Record>(&records_str);
+    for id in &ids {
+        query = query.bind(id);
+    }
+    let claimed = query.fetch_all(&mut *tx).await?;
+
+    tx.commit().await?;
+
+    // a record which cannot be decoded, e.g. its key is missing, would fail every claim,
+    // it cannot be delivered to any target either
+    let mut records = Vec::with_capacity(claimed.len());
+    for record in claimed {
+        let id = record.id;
+        match rep::decode_record(record) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                log::error!("Record {} cannot be decoded, it is moved to the dead letters: {}", id, e);
+                bury(pool, id, &e.to_string()).await?;
+            }
+        }
+    }
+
+    delivery::sort_for_delivery(&mut records, &lifo);
+
+    Ok(records)
+}
+
+/// Moves the record to the dead letters with the error
+async fn bury(pool: &Pool<Sqlite>, id: u32, error: &str) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(r#"UPDATE records SET last_error = ? WHERE id = ?"#)
+        .bind(error)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    dead_letter::bury(&mut tx, &[id]).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Marks records leased by the owner as delivered to the fan-out target,
+/// returns the count of acknowledged records
+pub async fn ack(pool: &Pool<Sqlite>, target: &str, owner: &str, ids: &[u32]) -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let query_str = format!(
+        r#"
+            UPDATE target_deliveries SET state = 'delivered', lease_owner = NULL, lease_expires_at = NULL
+            WHERE target = ? AND state = 'leased' AND lease_owner = ? AND record_id IN ({})
+        "#,
+        placeholders(ids)
+    );
+
+    let mut query = sqlx::query(&query_str)
+        .bind(target)
+        .bind(owner);
+
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    let mut tx = pool.begin().await?;
+    let acknowledged = query.execute(&mut *tx).await?.rows_affected();
+    update_sent(&mut tx, Some(ids)).await?;
+    tx.commit().await?;
+
+    Ok(acknowledged)
+}
+
+/// Returns records leased by the owner to the fan-out target queue after a failed delivery attempt.
+/// Records reaching `max_attempts` are marked as failed for the target. Returns the count of released records.
+pub async fn nack(pool: &Pool<Sqlite>, target: &str, owner: &str, ids: &[u32], error: &str, max_attempts: u32)
+    -> Result<u64, Box<dyn Error>> {
+
+    release(pool, target, owner, ids, error, Some(max_attempts)).await
+}
+
+/// Marks records leased by the owner as failed for the fan-out target after the target permanently
+/// rejected them. Returns the count of rejected records.
+pub async fn reject(pool: &Pool<Sqlite>, target: &str, owner: &str, ids: &[u32], error: &str)
+    -> Result<u64, Box<dyn Error>> {
+
+    release(pool, target, owner, ids, error, None).await
+}
+
+/// Counts a failed attempt and releases the lease, records are failed without `max_attempts`
+async fn release(pool: &Pool<Sqlite>, target: &str, owner: &str, ids: &[u32], error: &str,
+                 max_attempts: Option<u32>)
+    -> Result<u64, Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let query_str = format!(
+        r#"
+            UPDATE target_deliveries SET
+                attempts = attempts + 1,
+                last_error = ?,
+                state = CASE WHEN ? IS NULL OR attempts + 1 >= ? THEN 'failed' ELSE 'pending' END,
+                lease_owner = NULL,
+                lease_expires_at = NULL
+            WHERE target = ? AND state = 'leased' AND lease_owner = ? AND record_id IN ({})
+        "#,
+        placeholders(ids)
+    );
+
+    let mut query = sqlx::query(&query_str)
+        .bind(error)
+        .bind(max_attempts)
+        .bind(max_attempts)
+        .bind(target)
+        .bind(owner);
+
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    let mut tx = pool.begin().await?;
+    let released = query.execute(&mut *tx).await?.rows_affected();
+    // failed records no longer hold the records back
+    update_sent(&mut tx, Some(ids)).await?;
+    tx.commit().await?;
+
+    Ok(released)
+}
+
+/// Returns records with expired fan-out leases to the queues, returns the count of reclaimed records
+pub async fn reclaim_expired(pool: &Pool<Sqlite>) -> Result<u64, Box<dyn Error>> {
+
+    let result = sqlx::query(
+        r#"
+            UPDATE target_deliveries SET state = 'pending', lease_owner = NULL, lease_expires_at = NULL
+            WHERE state = 'leased' AND lease_expires_at <= ?
+        "#
+    )
+        .bind(chrono::Utc::now().timestamp())
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Stores the time of the last successful upload to the target
+pub async fn record_success(pool: &Pool<Sqlite>, target: &str) -> Result<(), Box<dyn Error>> {
+
+    sqlx::query(r#"UPDATE targets SET last_success_at = ? WHERE name = ?"#)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(target)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Stores the last upload error of the target
+pub async fn record_failure(pool: &Pool<Sqlite>, target: &str, error: &str) -> Result<(), Box<dyn Error>> {
+
+    sqlx::query(r#"UPDATE targets SET last_error = ?, last_error_at = ? WHERE name = ?"#)
+        .bind(error)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(target)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Gets delivery statistics of the targets. Failover targets share the backlog of the records
+/// and count dead letters as failed.
+pub async fn get_target_stats(pool: &Pool<Sqlite>) -> Result<Vec<TargetStats>, Box<dyn Error>> {
+
+    let stats = sqlx::query_as::<_, TargetStats>(
+        r#"
+            SELECT t.name, t.mode, t.last_success_at, t.last_error, t.last_error_at,
+                CASE WHEN t.mode = 'fan_out'
+                    THEN (SELECT COUNT(*) FROM target_deliveries d WHERE d.target = t.name AND d.state IN ('pending', 'leased'))
+                    ELSE (SELECT COUNT(*) FROM records WHERE state IN ('pending', 'leased'))
+                END AS backlog,
+                CASE WHEN t.mode = 'fan_out'
+                    THEN (SELECT COUNT(*) FROM target_deliveries d WHERE d.target = t.name AND d.state = 'failed')
+                    ELSE (SELECT COUNT(*) FROM dead_letters)
+                END AS failed
+            FROM targets t
+            ORDER BY t.rowid
+        "#
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(stats)
+}
+
+/// Marks records delivered to the failover targets as sent once no fan-out target is waiting for them
+async fn update_sent(conn: &mut SqliteConnection, ids: Option<&[u32]>) -> Result<(), sqlx::Error> {
+
+    let filter = match ids {
+        Some(ids) => format!("AND id IN ({})", placeholders(ids)),
+        None => String::new(),
+    };
+
+    let query_str = format!(
+        r#"
+            UPDATE records SET sent = 1
+            WHERE sent = 0 AND state = 'delivered' {}
+              AND NOT EXISTS (
+                  SELECT 1 FROM target_deliveries d
+                  WHERE d.record_id = records.id AND d.state IN ('pending', 'leased')
+              )
+        "#,
+        filter
+    );
+
+    let mut query = sqlx::query(&query_str);
+    for id in ids.unwrap_or_default() {
+        query = query.bind(id);
+    }
+
+    query.execute(conn).await?;
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite};
+use crate::models::VideoSegment;
+
+/// File of the fMP4 initialization segment in the stream directory
+pub const INIT_SEGMENT_FILE: &str = "init.mp4";
+
+/// Claims the stream for the source if no source has uploaded to it yet,
+/// returns whether the stream belongs to the source
+pub async fn claim_stream(pool: &Pool<Sqlite>, stream_id: &str, src_id: &str)
+    -> Result<bool, Box<dyn Error>> {
+
+    sqlx::query(r#"INSERT INTO video_streams (stream_id, src_id) VALUES (?, ?) ON CONFLICT(stream_id) DO NOTHING"#)
+        .bind(stream_id)
+        .bind(src_id)
+        .execute(pool)
+        .await?;
+
+    let owner = sqlx::query_scalar::<_, String>(r#"SELECT src_id FROM video_streams WHERE stream_id = ?"#)
+        .bind(stream_id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(owner == src_id)
+}
+
+/// Adds a video segment to the index and moves its received file to the segment path.
+/// The row is inserted first, so a concurrent upload of the same segment fails on it before
+/// its file replaces the file of the stored segment, and it is rolled back if the file is not moved.
+pub async fn add_segment(pool: &Pool<Sqlite>, segment: &VideoSegment, file: &str)
+    -> Result<u32, Box<dyn Error>> {
+
+    let mut tx = pool.begin().await?;
+
+    let id = sqlx::query_scalar::<_, u32>(
+        r#"
+            INSERT INTO video_segments (src_id, stream_id, seq, path, content_type, duration, size, created_at, sent)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id
+        "#
+    )
+        .bind(&segment.src_id)
+        .bind(&segment.stream_id)
+        .bind(segment.seq)
+        .bind(&segment.path)
+        .bind(&segment.content_type)
+        .bind(segment.duration)
+        .bind(segment.size)
+        .bind(segment.created_at)
+        .bind(segment.sent)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tokio::fs::rename(file, &segment.path).await?;
+
+    if let Err(e) = tx.commit().await {
+        let _ = tokio::fs::remove_file(&segment.path).await;
+        return Err(e.into());
+    }
+
+    Ok(id)
+}
+
+/// Gets a segment of the stream by its sequence number
+pub async fn get_segment(pool: &Pool<Sqlite>, stream_id: &str, seq: i64)
+    -> Result<Option<VideoSegment>, Box<dyn Error>> {
+
+    let segment = sqlx::query_as::<_, VideoSegment>(
+        r#"SELECT * FROM video_segments WHERE stream_id = ? AND seq = ?"#
+    )
+        .bind(stream_id)
+        .bind(seq)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(segment)
+}
+
+/// Gets all segments of the stream ordered by sequence number
+pub async fn get_stream_segments(pool: &Pool<Sqlite>, stream_id: &str)
+    -> Result<Vec<VideoSegment>, Box<dyn Error>> {
+
+    let segments = sqlx::query_as::<_, VideoSegment>(
+        r#"SELECT * FROM video_segments WHERE stream_id = ? ORDER BY seq"#
+    )
+        .bind(stream_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(segments)
+}
+
+/// Gets segments which have not been uploaded to the hub yet
+pub async fn get_unsent_segments(pool: &Pool<Sqlite>, count: &u32)
+    -> Result<Vec<VideoSegment>, Box<dyn Error>> {
+
+    let segments = sqlx::query_as::<_, VideoSegment>(
+        r#"
+            SELECT * FROM video_segments
+            WHERE sent = 0
+            ORDER BY id
+            LIMIT ?
+        "#
+    )
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(segments)
+}
+
+/// Marks segments as uploaded to the hub
+pub async fn mark_segments_sent(pool: &Pool<Sqlite>, ids: &[u32])
+    -> Result<(), Box<dyn Error>> {
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let placeholders = ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+    let query_str = format!(
+        r#"UPDATE video_segments SET sent = 1 WHERE id IN ({})"#,
+        placeholders
+    );
+
+    let mut query = sqlx::query(&query_str);
+    for id in ids {
+        query = query.bind(id);
+    }
+
+    query.execute(pool).await?;
+
+    Ok(())
+}
+
+/// Deletes segments created before the timestamp and returns paths of their files
+pub async fn delete_segments_before(pool: &Pool<Sqlite>, created_at: i64)
+    -> Result<Vec<String>, Box<dyn Error>> {
+
+    let paths = sqlx::query_scalar::<_, String>(
+        r#"DELETE FROM video_segments WHERE created_at < ? RETURNING path"#
+    )
+        .bind(created_at)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(paths)
+}
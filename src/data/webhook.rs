@@ -0,0 +1,201 @@
+use serde_json::Value;
+use sqlx::types::Json;
+use sqlx::{FromRow, Pool, Sqlite};
+use crate::data::error::RepError;
+use crate::models::{EventKind, Webhook, WebhookDelivery};
+
+/// Pending delivery with the endpoint of its webhook
+#[derive(Debug, Clone, FromRow)]
+pub struct DueDelivery {
+    pub id: u32,
+    pub event: EventKind,
+    #[sqlx(json)]
+    pub data: Value,
+    pub attempts: i64,
+    pub created_at: i64,
+    pub url: String,
+    pub secret: String,
+}
+
+/// Adds the webhook, returns its id
+pub async fn add_webhook(pool: &Pool<Sqlite>, url: &str, secret: &str, events: &[EventKind]) -> Result<u32, RepError> {
+
+    let id = sqlx::query_scalar::<_, u32>(
+        r#"INSERT INTO webhooks (url, secret, events, created_at) VALUES (?, ?, ?, unixepoch()) RETURNING id"#
+    )
+        .bind(url)
+        .bind(secret)
+        .bind(Json(events))
+        .fetch_one(pool)
+        .await?;
+
+    Ok(id)
+}
+
+/// Gets all webhooks
+pub async fn get_webhooks(pool: &Pool<Sqlite>) -> Result<Vec<Webhook>, RepError> {
+
+    let webhooks = sqlx::query_as::<_, Webhook>(r#"SELECT * FROM webhooks ORDER BY id"#)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(webhooks)
+}
+
+/// Gets a webhook by the id
+pub async fn get_webhook(pool: &Pool<Sqlite>, id: u32) -> Result<Option<Webhook>, RepError> {
+
+    let webhook = sqlx::query_as::<_, Webhook>(r#"SELECT * FROM webhooks WHERE id = ?"#)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(webhook)
+}
+
+/// Deletes the webhook with its deliveries, fails with `RepError::NotFound` if it does not exist
+pub async fn delete_webhook(pool: &Pool<Sqlite>, id: u32) -> Result<(), RepError> {
+
+    let result = sqlx::query(r#"DELETE FROM webhooks WHERE id = ?"#)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(RepError::NotFound(format!("webhook {}", id)));
+    }
+
+    Ok(())
+}
+
+/// Queues a delivery of the event to every webhook subscribed to it, or to the webhook only if set.
+/// Returns the count of the queued deliveries.
+pub async fn add_deliveries(pool: &Pool<Sqlite>, event: EventKind, data: &Value, webhook_id: Option<u32>)
+    -> Result<u64, RepError> {
+
+    let result = sqlx::query(
+        r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, data, next_attempt_at, created_at)
+            SELECT id, ?, ?, unixepoch(), unixepoch() FROM webhooks
+            WHERE CASE WHEN ? IS NULL
+                THEN EXISTS (SELECT 1 FROM json_each(webhooks.events) WHERE value = ?)
+                ELSE id = ?
+            END
+        "#
+    )
+        .bind(event)
+        .bind(Json(data))
+        .bind(webhook_id)
+        .bind(event)
+        .bind(webhook_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Gets the latest deliveries of the webhook, the newest first
+pub async fn get_deliveries(pool: &Pool<Sqlite>, webhook_id: u32, count: &u32) -> Result<Vec<WebhookDelivery>, RepError> {
+
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        r#"SELECT * FROM webhook_deliveries WHERE webhook_id = ? ORDER BY id DESC LIMIT ?"#
+    )
+        .bind(webhook_id)
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(deliveries)
+}
+
+/// Gets the pending deliveries whose attempt is due, the oldest first
+pub async fn get_due_deliveries(pool: &Pool<Sqlite>, count: &u32) -> Result<Vec<DueDelivery>, RepError> {
+
+    let deliveries = sqlx::query_as::<_, DueDelivery>(
+        r#"
+            SELECT d.id, d.event, d.data, d.attempts, d.created_at, w.url, w.secret
+            FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+            WHERE d.state = 'pending' AND d.next_attempt_at <= unixepoch()
+            ORDER BY d.id
+            LIMIT ?
+        "#
+    )
+        .bind(count)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(deliveries)
+}
+
+/// Marks the delivery as delivered
+pub async fn ack_delivery(pool: &Pool<Sqlite>, id: u32) -> Result<(), RepError> {
+
+    sqlx::query(
+        r#"UPDATE webhook_deliveries SET state = 'delivered', attempts = attempts + 1, last_error = NULL WHERE id = ?"#
+    )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Stores the failed attempt of the delivery, it is retried after the delay until it reaches the max attempts
+pub async fn nack_delivery(pool: &Pool<Sqlite>, id: u32, error: &str, retry_delay: u64, max_attempts: u32)
+    -> Result<(), RepError> {
+
+    sqlx::query(
+        r#"
+            UPDATE webhook_deliveries
+            SET attempts = attempts + 1,
+                last_error = ?,
+                next_attempt_at = unixepoch() + ?,
+                state = CASE WHEN attempts + 1 >= ? THEN 'failed' ELSE 'pending' END
+            WHERE id = ?
+        "#
+    )
+        .bind(error)
+        .bind(retry_delay as i64)
+        .bind(max_attempts)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Deletes the delivered and failed deliveries created before the time, returns the count of the deleted ones
+pub async fn delete_finished_deliveries(pool: &Pool<Sqlite>, before: i64) -> Result<u64, RepError> {
+
+    let result = sqlx::query(
+        r#"DELETE FROM webhook_deliveries WHERE state IN ('delivered', 'failed') AND created_at < ?"#
+    )
+        .bind(before)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Marks the source as seen, returns true the first time
+pub async fn mark_source_seen(pool: &Pool<Sqlite>, src_id: &str) -> Result<bool, RepError> {
+
+    // the source is read first, so the write lock is not taken for every record
+    let seen = sqlx::query_scalar::<_, bool>(r#"SELECT first_seen_at IS NOT NULL FROM sources WHERE src_id = ?"#)
+        .bind(src_id)
+        .fetch_optional(pool)
+        .await?;
+
+    if seen != Some(false) {
+        return Ok(false);
+    }
+
+    let result = sqlx::query(
+        r#"UPDATE sources SET first_seen_at = unixepoch() WHERE src_id = ? AND first_seen_at IS NULL"#
+    )
+        .bind(src_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
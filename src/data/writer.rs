@@ -0,0 +1,154 @@
+use std::time::Duration;
+use sqlx::{Acquire, Pool, Sqlite, SqliteConnection};
+use tokio::sync::{mpsc, oneshot};
+use crate::data::dedupe::{self, Stored};
+use crate::data::error::RepError;
+use crate::data::rep;
+use crate::models::Record;
+
+// inserts waiting for the writer, callers wait for a free slot when the queue is full
+const QUEUE_SIZE: usize = 1024;
+// commits retried while other connections hold the write lock, e.g. the tasks, before the inserts fail
+const BUSY_RETRIES: u32 = 5;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+type Reply = oneshot::Sender<Result<Vec<Stored>, RepError>>;
+
+/// Rows of an insert
+enum Rows {
+    Records(Vec<Record>),
+    /// Record stored unless the source has used the key within the window (seconds)
+    Once { record: Record, key: String, window: u64 },
+}
+
+struct Insert {
+    rows: Rows,
+    reply: Reply,
+}
+
+/// Single writer of the records. Concurrent inserts would wait for the write lock of SQLite one by one,
+/// the writer commits the inserts queued in the meantime in a single transaction instead.
+#[derive(Debug, Clone)]
+pub struct Writer {
+    sender: mpsc::Sender<Insert>,
+}
+
+impl Writer {
+    /// Starts the writer task, at most `batch_size` inserts are committed together
+    pub fn start(pool: Pool<Sqlite>, batch_size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(pool, receiver, batch_size));
+        Writer { sender }
+    }
+
+    /// Inserts the records in the next group commit, returns the ids of the records
+    pub async fn add_data(&self, records: Vec<Record>) -> Result<Vec<u32>, RepError> {
+        let stored = self.insert(Rows::Records(records)).await?;
+        Ok(stored.into_iter().map(|stored| match stored {
+            Stored::Added(id) | Stored::Duplicate(id) => id,
+        }).collect())
+    }
+
+    /// Inserts the record in the next group commit unless the source has used the key within the window,
+    /// see `dedupe::add_data_once`
+    pub async fn add_data_once(&self, record: Record, key: String, window: u64) -> Result<Stored, RepError> {
+        let stored = self.insert(Rows::Once { record, key, window }).await?;
+        stored.into_iter().next().ok_or_else(stopped)
+    }
+
+    async fn insert(&self, rows: Rows) -> Result<Vec<Stored>, RepError> {
+        let (reply, result) = oneshot::channel();
+
+        self.sender
+            .send(Insert { rows, reply })
+            .await
+            .map_err(|_| stopped())?;
+
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> RepError {
+    RepError::Database("Writer task is stopped".to_string())
+}
+
+async fn run(pool: Pool<Sqlite>, mut receiver: mpsc::Receiver<Insert>, batch_size: usize) {
+    let mut batch = Vec::with_capacity(batch_size);
+
+    // waits for the first insert and takes the ones queued meanwhile
+    while receiver.recv_many(&mut batch, batch_size).await > 0 {
+        let (rows, replies): (Vec<Rows>, Vec<Reply>) = batch
+            .drain(..)
+            .map(|insert| (insert.rows, insert.reply))
+            .unzip();
+
+        match commit_retrying(&pool, &rows).await {
+            Ok(results) => {
+                for (reply, result) in replies.into_iter().zip(results) {
+                    let _ = reply.send(result);
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to commit {} inserts: {}", replies.len(), e);
+                for reply in replies {
+                    let _ = reply.send(Err(e.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Commits the inserts, a transaction failed as the database is busy is rolled back and retried
+async fn commit_retrying(pool: &Pool<Sqlite>, inserts: &[Rows]) -> Result<Vec<Result<Vec<Stored>, RepError>>, RepError> {
+    let mut retries = 0;
+
+    loop {
+        match commit(pool, inserts).await {
+            Err(e) if e.is_retryable() && retries < BUSY_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(BUSY_RETRY_DELAY * retries).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Inserts the records of every insert within a savepoint, so a rejected insert does not roll back the others.
+/// Fails as a whole if the transaction cannot be committed or the database is busy.
+async fn commit(pool: &Pool<Sqlite>, inserts: &[Rows]) -> Result<Vec<Result<Vec<Stored>, RepError>>, RepError> {
+    let mut tx = pool.begin().await?;
+    let mut results = Vec::with_capacity(inserts.len());
+
+    for rows in inserts {
+        let mut savepoint = tx.begin().await?;
+
+        match insert_rows(&mut savepoint, rows).await {
+            Ok(ids) => {
+                savepoint.commit().await?;
+                results.push(Ok(ids));
+            }
+            Err(e) if e.is_retryable() => return Err(e),
+            Err(e) => {
+                savepoint.rollback().await?;
+                results.push(Err(e));
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(results)
+}
+
+async fn insert_rows(conn: &mut SqliteConnection, rows: &Rows) -> Result<Vec<Stored>, RepError> {
+    match rows {
+        Rows::Records(records) => {
+            let mut stored = Vec::with_capacity(records.len());
+            for record in records {
+                stored.push(Stored::Added(rep::insert_record(conn, record).await?));
+            }
+            Ok(stored)
+        }
+        Rows::Once { record, key, window } => Ok(vec![dedupe::insert_once(conn, record, key, *window).await?]),
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Write};
+use actix_web::error::PayloadError;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use crate::common::compression::Compression;
+use crate::data::spool::SpoolError;
+
+/// Decodes the compressed request body while it is received.
+/// The decoded output may not exceed `max_ratio` times the size received so far, the decoder fails
+/// as soon as it writes past that budget, so decompression bombs are never expanded in memory.
+pub fn decode<S>(body: S, encoding: Compression, max_ratio: u32)
+    -> impl Stream<Item = Result<Bytes, SpoolError>> + Unpin
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let decoder = Decoder::new(encoding, max_ratio);
+
+    Box::pin(futures::stream::unfold((body, Some(decoder)), |(mut body, decoder)| async move {
+        let mut decoder = match decoder? {
+            Ok(decoder) => decoder,
+            Err(e) => return Some((Err(e), (body, None))),
+        };
+        match body.next().await {
+            Some(Ok(chunk)) => match decoder.write(&chunk) {
+                Ok(decoded) => Some((Ok(decoded), (body, Some(Ok(decoder))))),
+                Err(e) => Some((Err(e), (body, None))),
+            },
+            Some(Err(e)) => Some((Err(e.into()), (body, None))),
+            None => Some((decoder.finish(), (body, None))),
+        }
+    }))
+}
+
+/// Output of the decoder limited to `max_ratio` times the received size
+struct Budget {
+    output: Vec<u8>,
+    decoded: usize,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl Write for Budget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.decoded + buf.len() > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::other("expansion ratio exceeded"));
+        }
+        self.decoded += buf.len();
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Codec {
+    Gzip(flate2::write::GzDecoder<Budget>),
+    Deflate(flate2::write::ZlibDecoder<Budget>),
+    Zstd(zstd::stream::write::Decoder<'static, Budget>),
+}
+
+struct Decoder {
+    codec: Codec,
+    received: usize,
+    max_ratio: u32,
+}
+
+impl Decoder {
+    fn new(encoding: Compression, max_ratio: u32) -> Result<Self, SpoolError> {
+        let budget = Budget { output: Vec::new(), decoded: 0, limit: 0, exceeded: false };
+        let codec = match encoding {
+            Compression::Gzip => Codec::Gzip(flate2::write::GzDecoder::new(budget)),
+            Compression::Deflate => Codec::Deflate(flate2::write::ZlibDecoder::new(budget)),
+            Compression::Zstd => Codec::Zstd(zstd::stream::write::Decoder::new(budget)?),
+        };
+        Ok(Decoder { codec, received: 0, max_ratio })
+    }
+
+    fn budget(&mut self) -> &mut Budget {
+        match &mut self.codec {
+            Codec::Gzip(decoder) => decoder.get_mut(),
+            Codec::Deflate(decoder) => decoder.get_mut(),
+            Codec::Zstd(decoder) => decoder.get_mut(),
+        }
+    }
+
+    /// Decodes the received chunk, returns the decoded output
+    fn write(&mut self, chunk: &[u8]) -> Result<Bytes, SpoolError> {
+        self.received += chunk.len();
+        self.budget().limit = self.received.saturating_mul(self.max_ratio as usize);
+
+        let written = match &mut self.codec {
+            Codec::Gzip(decoder) => decoder.write_all(chunk),
+            Codec::Deflate(decoder) => decoder.write_all(chunk),
+            Codec::Zstd(decoder) => decoder.write_all(chunk),
+        };
+        self.check(written)?;
+
+        Ok(Bytes::from(std::mem::take(&mut self.budget().output)))
+    }
+
+    /// Decodes the rest of the body, returns the decoded output
+    fn finish(mut self) -> Result<Bytes, SpoolError> {
+        let finished = match &mut self.codec {
+            Codec::Gzip(decoder) => decoder.try_finish(),
+            Codec::Deflate(decoder) => decoder.try_finish(),
+            Codec::Zstd(decoder) => decoder.flush(),
+        };
+        self.check(finished)?;
+
+        Ok(Bytes::from(std::mem::take(&mut self.budget().output)))
+    }
+
+    fn check(&mut self, result: io::Result<()>) -> Result<(), SpoolError> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(_) if self.budget().exceeded => Err(SpoolError::ExpansionRatio(self.max_ratio)),
+            Err(e) => Err(SpoolError::Payload(format!("Failed to decode the body: {}", e))),
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use bytes::Bytes;
+use sqlx::SqlitePool;
+use thiserror::Error;
+use crate::common::defaults::{IMPORT_SETTLE_TIME, MAX_IMPORT_FILE_SIZE, MAX_PRIORITY, MIN_PRIORITY};
+use crate::config::Config;
+use crate::data::{dedupe, rep, segment_log, spool};
+use crate::data::dedupe::Stored;
+use crate::data::error::RepError;
+use crate::data::segment_log::{LogError, Logs};
+use crate::data::store::SqliteStore;
+use crate::data::spool::{Payload, SpoolError};
+use crate::ingest::payload::{self, IngestError};
+use crate::models::{ImportCfg, Record, Source, SourceCfg, StorageEngine};
+use crate::webhook;
+
+/// Subdirectories of the import directory the files are moved to
+pub const PROCESSED_DIR: &str = "processed";
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("No source pattern matches the file")]
+    NoSource,
+    #[error("{0}")]
+    Source(String),
+    #[error("{0}")]
+    Payload(#[from] SpoolError),
+    #[error("{0}")]
+    Ingest(#[from] IngestError),
+    #[error("{0}")]
+    Database(#[from] RepError),
+    #[error("{0}")]
+    Log(#[from] LogError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Files handled by a scan of the import directory
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub processed: usize,
+    pub quarantined: usize,
+    /// Records stored from the processed files, duplicates are not counted
+    pub records: usize,
+}
+
+/// Imports the settled files of the import directory. Imported files are moved to the processed folder,
+/// files failed to be imported are moved to the quarantine folder with the error next to them.
+/// Records of the sources with the log storage are appended to their logs.
+pub async fn scan(pool: &SqlitePool, cfg: &Config, logs: &Arc<Logs>) -> Result<ImportReport, ImportError> {
+    let mut report = ImportReport::default();
+    let Some(dir) = cfg.import_dir.as_deref().map(Path::new) else {
+        return Ok(report);
+    };
+
+    let files = settled_files(dir).await?;
+    if files.is_empty() {
+        return Ok(report);
+    }
+
+    let sources = import_sources(pool).await?;
+
+    for path in files {
+        match import_file(pool, cfg, logs, &sources, &path).await {
+            Ok(records) => {
+                move_file(&path, &dir.join(PROCESSED_DIR)).await?;
+                report.processed += 1;
+                report.records += records;
+            }
+            Err(e) => {
+                log::warn!("Failed to import {}: {}", path.display(), e);
+                let quarantined = move_file(&path, &dir.join(QUARANTINE_DIR)).await?;
+                tokio::fs::write(append_extension(&quarantined, "error"), e.to_string()).await?;
+                report.quarantined += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Gets the sources with import patterns, the other settings are validated when their files are imported
+pub async fn import_sources(pool: &SqlitePool) -> Result<Vec<(Source, SourceCfg, ImportCfg)>, RepError> {
+    let sources = rep::get_all_sources(pool)
+        .await?
+        .into_iter()
+        .filter_map(|source| match source.config() {
+            Ok(source_cfg) => source_cfg.import.clone().map(|import| (source, source_cfg, import)),
+            Err(e) => {
+                log::warn!("Invalid configuration of the source {}: {}", source.src_id, e);
+                None
+            }
+        })
+        .collect();
+
+    Ok(sources)
+}
+
+/// Stores the file as records of the source whose pattern matches the file name.
+/// Payloads are validated the same way as the ones posted to `/add`, nothing is stored if one is rejected.
+/// Returns the count of the stored records.
+pub async fn import_file(pool: &SqlitePool, cfg: &Config, logs: &Arc<Logs>,
+                         sources: &[(Source, SourceCfg, ImportCfg)], path: &Path)
+    -> Result<usize, ImportError> {
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let (source, source_cfg, import) = sources
+        .iter()
+        .find(|(_, _, import)| matches(&import.pattern, &name))
+        .ok_or(ImportError::NoSource)?;
+
+    if !source.active {
+        return Err(ImportError::Source(format!("Source with ID {} is disabled", source.src_id)));
+    }
+
+    if let Err(e) = source_cfg.validate() {
+        return Err(ImportError::Source(format!("Invalid configuration of the source {}: {}", source.src_id, e)));
+    }
+
+    let priority = match source_cfg.priority {
+        Some(priority) if !(MIN_PRIORITY..=MAX_PRIORITY).contains(&priority) => return Err(ImportError::Source(
+            format!("Invalid configuration of the source {}: priority must be from {} to {}",
+                    source.src_id, MIN_PRIORITY, MAX_PRIORITY)
+        )),
+        priority => priority.unwrap_or(MIN_PRIORITY),
+    };
+
+    let max_body_size = source_cfg.max_body_size.unwrap_or(cfg.max_body_size);
+
+    let parts = match &import.delimiter {
+        Some(delimiter) => {
+            // the file is read at once to be split, its size is limited the same way
+            let max_file_size = import.max_file_size.unwrap_or(MAX_IMPORT_FILE_SIZE);
+            if tokio::fs::metadata(path).await?.len() > max_file_size as u64 {
+                return Err(SpoolError::TooLarge(max_file_size).into());
+            }
+            split(tokio::fs::read(path).await?, delimiter.as_bytes())
+        }
+        None => {
+            // a file larger than a request body is not read
+            if tokio::fs::metadata(path).await?.len() > max_body_size as u64 {
+                return Err(SpoolError::TooLarge(max_body_size).into());
+            }
+            vec![tokio::fs::read(path).await?]
+        }
+    };
+
+    let mut records = Vec::with_capacity(parts.len());
+    for part in parts {
+        match prepare_record(pool, cfg, source, source_cfg, priority, max_body_size, part).await {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => {}
+            Err(e) => {
+                discard(&records);
+                return Err(e);
+            }
+        }
+    }
+
+    let stored = match source_cfg.storage {
+        StorageEngine::Sqlite => store(pool, source_cfg.dedupe_window.unwrap_or(cfg.dedupe_window), records).await?,
+        StorageEngine::Log => append_to_log(pool, logs, records).await?,
+    };
+    if stored > 0 {
+        webhook::source_seen(pool, &source.src_id).await;
+    }
+
+    Ok(stored)
+}
+
+/// Gets the record of the payload with its deduplication key, None if the payload is filtered out
+async fn prepare_record(pool: &SqlitePool, cfg: &Config, source: &Source, source_cfg: &SourceCfg,
+                        priority: i64, max_body_size: usize, data: Vec<u8>)
+    -> Result<Option<(Record, String)>, ImportError> {
+
+    let body = futures::stream::iter([Ok::<_, SpoolError>(Bytes::from(data))]);
+    let payload = spool::receive(body, max_body_size, cfg.inline_payload_limit, &cfg.spool_dir).await?;
+
+    if payload.is_empty() {
+        return Err(SpoolError::Payload("Empty data is not allowed.".to_string()).into());
+    }
+
+    let key = match spool::digest(&payload).await {
+        Ok(digest) => format!("sha256:{}", digest),
+        Err(e) => {
+            discard_payload(&payload).await;
+            return Err(e.into());
+        }
+    };
+
+    let store = SqliteStore::new(pool.clone());
+    let payload = match payload::prepare_payload(&store, source, source_cfg, payload, cfg.inline_payload_limit).await? {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+
+    let (data, data_file, compression) = match payload {
+        Payload::Inline(data) => (data, None, source_cfg.compression),
+        Payload::Spooled { path, .. } => (Vec::new(), Some(path), None),
+    };
+
+    let record = Record {
+        src_id: source.src_id.clone(),
+        data,
+        data_file,
+        priority,
+        compression,
+        ..Default::default()
+    };
+
+    Ok(Some((record, key)))
+}
+
+/// Stores the records, the ones already stored within the deduplication window are skipped,
+/// so a file carried in again is not stored twice
+async fn store(pool: &SqlitePool, dedupe_window: u64, records: Vec<(Record, String)>) -> Result<usize, ImportError> {
+    if dedupe_window == 0 {
+        let data: Vec<Record> = records.iter().map(|(record, _)| record.clone()).collect();
+        return match rep::add_data(pool, &data).await {
+            Ok(ids) => Ok(ids.len()),
+            Err(e) => {
+                discard(&records);
+                Err(e.into())
+            }
+        };
+    }
+
+    let mut stored = 0;
+    for (index, (record, key)) in records.iter().enumerate() {
+        match dedupe::add_data_once(pool, record, key, dedupe_window).await {
+            Ok(Stored::Added(_)) => stored += 1,
+            Ok(Stored::Duplicate(_)) => {
+                if let Some(path) = &record.data_file {
+                    spool::discard(path).await;
+                }
+            }
+            Err(e) => {
+                discard(&records[index..]);
+                return Err(e.into());
+            }
+        }
+    }
+
+    Ok(stored)
+}
+
+/// Appends the records to the log of the source, spooled payloads are stored as rows as they are not kept
+/// in the log. Records of a log are not deduplicated.
+async fn append_to_log(pool: &SqlitePool, logs: &Arc<Logs>, records: Vec<(Record, String)>)
+    -> Result<usize, ImportError> {
+
+    let (spooled, inline): (Vec<_>, Vec<_>) = records.into_iter().partition(|(record, _)| record.data_file.is_some());
+
+    let inline: Vec<Record> = inline.into_iter().map(|(record, _)| record).collect();
+    let appended = inline.len();
+    if let Err(e) = segment_log::append_all_synced(logs.clone(), inline).await {
+        discard(&spooled);
+        return Err(e.into());
+    }
+
+    Ok(appended + store(pool, 0, spooled).await?)
+}
+
+/// Removes the payload files of the records which are not stored
+fn discard(records: &[(Record, String)]) {
+    rep::remove_data_files(records.iter().map(|(record, _)| record.data_file.clone()).collect());
+}
+
+async fn discard_payload(payload: &Payload) {
+    if let Payload::Spooled { path, .. } = payload {
+        spool::discard(path).await;
+    }
+}
+
+/// Gets the files of the directory not modified within the settle time, the oldest first
+async fn settled_files(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let settled_before = SystemTime::now() - Duration::from_secs(IMPORT_SETTLE_TIME);
+
+    let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        // hidden files are temporary files of the copying tools
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() && metadata.modified()? <= settled_before {
+            files.push((metadata.modified()?, entry.path()));
+        }
+    }
+
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Moves the file to the directory, a file of the same name is not overwritten. Returns the new path.
+async fn move_file(path: &Path, dir: &Path) -> Result<PathBuf, std::io::Error> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut target = dir.join(name.as_ref());
+    if tokio::fs::try_exists(&target).await? {
+        let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        target = dir.join(format!("{}-{}", nanos, name));
+    }
+
+    tokio::fs::rename(path, &target).await?;
+    Ok(target)
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Splits the data by the delimiter, empty parts are skipped
+fn split(data: Vec<u8>, delimiter: &[u8]) -> Vec<Vec<u8>> {
+    if delimiter.is_empty() {
+        return vec![data];
+    }
+
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    while index + delimiter.len() <= data.len() {
+        if data[index..].starts_with(delimiter) {
+            parts.push(data[start..index].to_vec());
+            index += delimiter.len();
+            start = index;
+        } else {
+            index += 1;
+        }
+    }
+    parts.push(data[start..].to_vec());
+
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+/// Matches the file name against the pattern, `*` matches any characters and `?` matches one character
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // position after the last `*` and the name position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // the last `*` matches one more character
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,5 @@
+pub mod encoding;
+pub mod import;
+pub mod payload;
+pub mod schema;
+pub mod transform;
@@ -0,0 +1,93 @@
+use thiserror::Error;
+use crate::data::spool::{self, Payload, SpoolError};
+use crate::data::store::RecordStore;
+use crate::ingest::schema;
+use crate::ingest::transform::Pipeline;
+use crate::models::{Source, SourceCfg};
+
+#[derive(Error, Debug)]
+pub enum IngestError {
+    /// The schema or the transformations of the source are invalid
+    #[error("Invalid configuration of the source {src_id}: {message}")]
+    Config { src_id: String, message: String },
+    /// The payload does not match the schema or cannot be transformed
+    #[error("{0}")]
+    Rejected(String),
+    #[error("{0}")]
+    Io(#[from] SpoolError),
+}
+
+/// Validates and transforms the received payload according to the source cfg.
+/// Returns `None` when the payload has been filtered out by the transformation pipeline.
+pub async fn prepare_payload(records: &dyn RecordStore, source: &Source, source_cfg: &SourceCfg,
+                             payload: Payload, inline_limit: usize)
+    -> Result<Option<Payload>, IngestError> {
+
+    if source_cfg.schema.is_none() && source_cfg.transforms.is_empty() {
+        return Ok(Some(payload));
+    }
+
+    let data = match &payload {
+        Payload::Inline(data) => Ok(data.clone()),
+        Payload::Spooled { path, .. } => spool::read_file(path).await.map_err(IngestError::from),
+    };
+
+    let processed = match data {
+        Ok(data) => process_payload(records, source, source_cfg, data).await,
+        Err(e) => Err(e),
+    };
+
+    if let Payload::Spooled { path, .. } = &payload {
+        match &processed {
+            Ok(Some(data)) if data.len() > inline_limit => {
+                return match spool::write_file(path, data).await {
+                    Ok(_) => Ok(Some(Payload::Spooled { path: path.clone(), size: data.len() })),
+                    Err(e) => {
+                        spool::discard(path).await;
+                        Err(e.into())
+                    }
+                };
+            }
+            _ => spool::discard(path).await,
+        }
+    }
+
+    processed.map(|data| data.map(Payload::Inline))
+}
+
+/// Validates the payload against the schema of the source and applies its transformations.
+/// Rejected payloads are counted and sampled for debugging.
+async fn process_payload(records: &dyn RecordStore, source: &Source, source_cfg: &SourceCfg, data: Vec<u8>)
+    -> Result<Option<Vec<u8>>, IngestError> {
+
+    let config_error = |e: &dyn std::fmt::Display| IngestError::Config {
+        src_id: source.src_id.clone(),
+        message: e.to_string(),
+    };
+
+    let pipeline = Pipeline::build(&source_cfg.transforms).map_err(|e| config_error(&e))?;
+
+    let result = match &source_cfg.schema {
+        Some(schema) => match schema::validate(schema, &data) {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_config_error() => return Err(config_error(&e)),
+            Err(e) => Err(e.to_string()),
+        },
+        None => Ok(()),
+    };
+
+    let result = match result {
+        Ok(_) => pipeline.apply(data.clone()).map_err(|e| e.to_string()),
+        Err(error) => Err(error),
+    };
+
+    match result {
+        Ok(data) => Ok(data),
+        Err(error) => {
+            if let Err(e) = records.add_rejected_payload(&source.src_id, &error, &data).await {
+                log::error!("Failed to keep the rejected payload of the source {}: {}", source.src_id, e);
+            }
+            Err(IngestError::Rejected(error))
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use jsonschema::Validator;
+use once_cell::sync::Lazy;
+use thiserror::Error;
+use crate::models::{BinaryField, CsvColumn, PayloadSchema, ValueKind};
+
+/// Count of compiled JSON schemas kept, the cache is cleared when it is full
+const MAX_CACHED_VALIDATORS: usize = 256;
+
+/// Compiled JSON schemas by their text, so a schema is compiled once rather than for every payload
+static VALIDATORS: Lazy<RwLock<HashMap<String, Arc<Validator>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SchemaError {
+    #[error("Invalid payload schema: {0}")]
+    InvalidSchema(String),
+    #[error("Payload is not valid JSON: {0}")]
+    Json(String),
+    #[error("Payload does not match the JSON schema at '{path}': {message}")]
+    JsonSchema { path: String, message: String },
+    #[error("CSV line {line}: {message}")]
+    Csv { line: u64, message: String },
+    #[error("Binary payload: {0}")]
+    Binary(String),
+}
+
+impl SchemaError {
+    /// The error is caused by the source configuration rather than by the payload
+    pub fn is_config_error(&self) -> bool {
+        matches!(self, SchemaError::InvalidSchema(_))
+    }
+}
+
+/// Checks the schema itself, so an invalid schema is reported when the source configuration is parsed
+/// rather than when a payload is validated
+pub fn check(schema: &PayloadSchema) -> Result<(), SchemaError> {
+    match schema {
+        PayloadSchema::Json { schema } => json_validator(schema).map(|_| ()),
+        PayloadSchema::Csv { delimiter, .. } => check_delimiter(*delimiter),
+        PayloadSchema::Binary { fields, .. } => layout_size(fields).map(|_| ()),
+    }
+}
+
+/// Validates the payload against the schema declared by the source
+pub fn validate(schema: &PayloadSchema, data: &[u8]) -> Result<(), SchemaError> {
+    match schema {
+        PayloadSchema::Json { schema } => validate_json(schema, data),
+        PayloadSchema::Csv { columns, delimiter, header } => validate_csv(columns, *delimiter, *header, data),
+        PayloadSchema::Binary { fields, repeated } => validate_binary(fields, *repeated, data),
+    }
+}
+
+/// Gets the compiled JSON schema from the cache, it is compiled if not cached yet
+fn json_validator(schema: &serde_json::Value) -> Result<Arc<Validator>, SchemaError> {
+    let key = schema.to_string();
+
+    if let Some(validator) = VALIDATORS.read().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        return Ok(validator.clone());
+    }
+
+    let validator = Arc::new(
+        jsonschema::validator_for(schema).map_err(|e| SchemaError::InvalidSchema(e.to_string()))?
+    );
+
+    let mut validators = VALIDATORS.write().unwrap_or_else(|e| e.into_inner());
+    if validators.len() >= MAX_CACHED_VALIDATORS {
+        validators.clear();
+    }
+    validators.insert(key, validator.clone());
+
+    Ok(validator)
+}
+
+fn validate_json(schema: &serde_json::Value, data: &[u8]) -> Result<(), SchemaError> {
+
+    let validator = json_validator(schema)?;
+
+    let instance: serde_json::Value = serde_json::from_slice(data)
+        .map_err(|e| SchemaError::Json(e.to_string()))?;
+
+    let error = validator.iter_errors(&instance)
+        .next()
+        .map(|e| SchemaError::JsonSchema {
+            path: e.instance_path().to_string(),
+            message: e.to_string(),
+        });
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn validate_csv(columns: &[CsvColumn], delimiter: char, header: bool, data: &[u8])
+    -> Result<(), SchemaError> {
+
+    check_delimiter(delimiter)?;
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter as u8)
+        .has_headers(header)
+        .flexible(true)
+        .from_reader(data);
+
+    for row in reader.records() {
+        let row = row.map_err(|e| SchemaError::Csv {
+            line: e.position().map(|p| p.line()).unwrap_or(0),
+            message: e.to_string(),
+        })?;
+
+        let line = row.position().map(|p| p.line()).unwrap_or(0);
+
+        if row.len() != columns.len() {
+            return Err(SchemaError::Csv {
+                line,
+                message: format!("expected {} columns, got {}", columns.len(), row.len()),
+            });
+        }
+
+        for (value, column) in row.iter().zip(columns) {
+            if !matches_kind(value, column.kind) {
+                return Err(SchemaError::Csv {
+                    line,
+                    message: format!("column '{}' expected {:?}, got '{}'", column.name, column.kind, value),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_delimiter(delimiter: char) -> Result<(), SchemaError> {
+    if !delimiter.is_ascii() {
+        return Err(SchemaError::InvalidSchema("CSV delimiter must be an ASCII character".into()));
+    }
+
+    Ok(())
+}
+
+fn matches_kind(value: &str, kind: ValueKind) -> bool {
+    let value = value.trim();
+    match kind {
+        ValueKind::String => true,
+        ValueKind::Integer => value.parse::<i64>().is_ok(),
+        ValueKind::Number => value.parse::<f64>().is_ok(),
+        ValueKind::Boolean => value.parse::<bool>().is_ok(),
+    }
+}
+
+fn validate_binary(fields: &[BinaryField], repeated: bool, data: &[u8]) -> Result<(), SchemaError> {
+
+    let size = layout_size(fields)?;
+
+    let valid = if repeated {
+        data.len().is_multiple_of(size)
+    } else {
+        data.len() == size
+    };
+
+    if !valid {
+        let expected = if repeated { format!("a multiple of {}", size) } else { size.to_string() };
+        return Err(SchemaError::Binary(
+            format!("length {} bytes does not match the layout size, expected {}", data.len(), expected)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Gets the size of the binary layout in bytes
+fn layout_size(fields: &[BinaryField]) -> Result<usize, SchemaError> {
+    let size: usize = fields.iter().map(|field| field.kind.size()).sum();
+
+    if size == 0 {
+        return Err(SchemaError::InvalidSchema("Binary layout has no fields".into()));
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Transform stage '{stage}' failed: {message}")]
+pub struct TransformError {
+    pub stage: String,
+    pub message: String,
+}
+
+/// A stage of the payload transformation pipeline.
+/// It returns `None` when the payload must not be stored.
+pub trait Transform: Send + Sync {
+    fn name(&self) -> &str;
+    fn apply(&self, data: Vec<u8>) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// Creates custom stages from their `params`
+pub type TransformFactory = dyn Fn(&Value) -> Result<Box<dyn Transform>, String> + Send + Sync;
+
+static CUSTOM_TRANSFORMS: Lazy<RwLock<HashMap<String, Arc<TransformFactory>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Registers a custom stage which sources can refer to as `{"type": "custom", "name": ...}`
+pub fn register<F>(name: &str, factory: F)
+where
+    F: Fn(&Value) -> Result<Box<dyn Transform>, String> + Send + Sync + 'static,
+{
+    CUSTOM_TRANSFORMS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_string(), Arc::new(factory));
+}
+
+/// Configuration of a pipeline stage in the source cfg
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformCfg {
+    /// Replaces the document with the value at the JSON pointer
+    Extract { path: String },
+    /// Renames the field at the JSON pointer
+    Rename { path: String, to: String },
+    /// Removes the fields at the JSON pointers
+    Drop { paths: Vec<String> },
+    /// Converts CSV rows to a JSON array of objects
+    CsvToJson {
+        #[serde(default = "default_delimiter")]
+        delimiter: char,
+        /// Column names, the first row is used as a header when they are absent
+        #[serde(default)]
+        columns: Option<Vec<String>>,
+    },
+    /// Applies `value * factor + offset` to the numbers at the JSON pointer
+    Scale {
+        path: String,
+        #[serde(default = "default_factor")]
+        factor: f64,
+        #[serde(default)]
+        offset: f64,
+    },
+    /// Keeps array items (or the whole document) matching the predicate
+    Filter { path: String, op: FilterOp, value: Value },
+    /// Stage registered in Rust with `register`
+    Custom {
+        name: String,
+        #[serde(default)]
+        params: Value,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOp {
+    Eq, Ne, Gt, Ge, Lt, Le, Exists,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+/// Ordered set of stages applied to payloads of a source
+pub struct Pipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    /// Builds the pipeline from the source cfg
+    pub fn build(cfg: &[TransformCfg]) -> Result<Self, TransformError> {
+        let stages = cfg.iter()
+            .map(build_stage)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Pipeline { stages })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Applies the stages in order, `None` means that a stage filtered the payload out
+    pub fn apply(&self, data: Vec<u8>) -> Result<Option<Vec<u8>>, TransformError> {
+        let mut data = data;
+        for stage in &self.stages {
+            match stage.apply(data) {
+                Ok(Some(result)) => data = result,
+                Ok(None) => return Ok(None),
+                Err(message) => return Err(TransformError { stage: stage.name().to_string(), message }),
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+fn build_stage(cfg: &TransformCfg) -> Result<Box<dyn Transform>, TransformError> {
+    let stage: Box<dyn Transform> = match cfg {
+        TransformCfg::Custom { name, params } => {
+            let factory = CUSTOM_TRANSFORMS
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(name)
+                .cloned()
+                .ok_or_else(|| TransformError { stage: name.clone(), message: "stage is not registered".into() })?;
+            factory(params).map_err(|message| TransformError { stage: name.clone(), message })?
+        }
+        _ => Box::new(BuiltinStage(cfg.clone())),
+    };
+    Ok(stage)
+}
+
+/// Built-in JSON and CSV stages
+struct BuiltinStage(TransformCfg);
+
+impl Transform for BuiltinStage {
+    fn name(&self) -> &str {
+        match &self.0 {
+            TransformCfg::Extract { .. } => "extract",
+            TransformCfg::Rename { .. } => "rename",
+            TransformCfg::Drop { .. } => "drop",
+            TransformCfg::CsvToJson { .. } => "csv_to_json",
+            TransformCfg::Scale { .. } => "scale",
+            TransformCfg::Filter { .. } => "filter",
+            TransformCfg::Custom { name, .. } => name,
+        }
+    }
+
+    fn apply(&self, data: Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+        if let TransformCfg::CsvToJson { delimiter, columns } = &self.0 {
+            return csv_to_json(&data, *delimiter, columns.as_deref()).map(Some);
+        }
+
+        let mut doc: Value = serde_json::from_slice(&data)
+            .map_err(|e| format!("payload is not valid JSON: {}", e))?;
+
+        match &self.0 {
+            TransformCfg::Extract { path } => {
+                doc = doc.pointer(path)
+                    .cloned()
+                    .ok_or_else(|| format!("path {} does not exist", path))?;
+            }
+            TransformCfg::Rename { path, to } => {
+                let (parent, key) = split_pointer(path)?;
+                for_each_at(&mut doc, &parent, &mut |value| {
+                    if let Some(object) = value.as_object_mut() {
+                        if let Some(field) = object.remove(&key) {
+                            object.insert(to.clone(), field);
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+            TransformCfg::Drop { paths } => {
+                for path in paths {
+                    let (parent, key) = split_pointer(path)?;
+                    for_each_at(&mut doc, &parent, &mut |value| {
+                        if let Some(object) = value.as_object_mut() {
+                            object.remove(&key);
+                        }
+                        Ok(())
+                    })?;
+                }
+            }
+            TransformCfg::Scale { path, factor, offset } => {
+                for_each_at(&mut doc, path, &mut |value| {
+                    let number = value.as_f64()
+                        .ok_or_else(|| format!("value at {} is not a number", path))?;
+                    *value = Number::from_f64(number * factor + offset)
+                        .map(Value::Number)
+                        .ok_or_else(|| format!("value at {} is out of range", path))?;
+                    Ok(())
+                })?;
+            }
+            TransformCfg::Filter { path, op, value } => {
+                match &mut doc {
+                    Value::Array(items) => items.retain(|item| matches(item.pointer(path), *op, value)),
+                    _ => if !matches(doc.pointer(path), *op, value) {
+                        return Ok(None);
+                    },
+                }
+            }
+            TransformCfg::CsvToJson { .. } | TransformCfg::Custom { .. } => unreachable!(),
+        }
+
+        serde_json::to_vec(&doc).map(Some).map_err(|e| e.to_string())
+    }
+}
+
+/// Calls `f` for every value at the JSON pointer, `*` matches all items of an array
+fn for_each_at(value: &mut Value, pointer: &str, f: &mut dyn FnMut(&mut Value) -> Result<(), String>)
+    -> Result<(), String> {
+
+    if pointer.is_empty() {
+        return f(value);
+    }
+
+    let pointer = pointer.strip_prefix('/')
+        .ok_or_else(|| format!("path {} must start with '/'", pointer))?;
+    let (token, rest) = match pointer.find('/') {
+        Some(index) => (&pointer[..index], &pointer[index..]),
+        None => (pointer, ""),
+    };
+    let token = token.replace("~1", "/").replace("~0", "~");
+
+    match value {
+        Value::Array(items) if token == "*" => {
+            for item in items {
+                for_each_at(item, rest, f)?;
+            }
+            Ok(())
+        }
+        Value::Array(items) => match token.parse::<usize>().ok().and_then(|index| items.get_mut(index)) {
+            Some(item) => for_each_at(item, rest, f),
+            None => Ok(()),
+        },
+        Value::Object(object) => match object.get_mut(&token) {
+            Some(field) => for_each_at(field, rest, f),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Splits the JSON pointer into the parent pointer and the last key
+fn split_pointer(pointer: &str) -> Result<(String, String), String> {
+    match pointer.rfind('/') {
+        Some(index) => Ok((
+            pointer[..index].to_string(),
+            pointer[index + 1..].replace("~1", "/").replace("~0", "~"),
+        )),
+        None => Err(format!("path {} must start with '/'", pointer)),
+    }
+}
+
+fn matches(actual: Option<&Value>, op: FilterOp, expected: &Value) -> bool {
+    let Some(actual) = actual else {
+        return false;
+    };
+
+    match op {
+        FilterOp::Exists => true,
+        FilterOp::Eq => actual == expected,
+        FilterOp::Ne => actual != expected,
+        _ => match (actual.as_f64(), expected.as_f64()) {
+            (Some(a), Some(b)) => match op {
+                FilterOp::Gt => a > b,
+                FilterOp::Ge => a >= b,
+                FilterOp::Lt => a < b,
+                FilterOp::Le => a <= b,
+                _ => false,
+            },
+            _ => false,
+        },
+    }
+}
+
+fn csv_to_json(data: &[u8], delimiter: char, columns: Option<&[String]>) -> Result<Vec<u8>, String> {
+
+    if !delimiter.is_ascii() {
+        return Err("delimiter must be an ASCII character".into());
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter as u8)
+        .has_headers(columns.is_none())
+        .from_reader(data);
+
+    let names: Vec<String> = match columns {
+        Some(columns) => columns.to_vec(),
+        None => reader.headers()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+    };
+
+    let mut rows = Vec::new();
+    for row in reader.records() {
+        let row = row.map_err(|e| e.to_string())?;
+        let object: Map<String, Value> = names.iter()
+            .zip(row.iter())
+            .map(|(name, value)| (name.clone(), parse_csv_value(value)))
+            .collect();
+        rows.push(Value::Object(object));
+    }
+
+    serde_json::to_vec(&Value::Array(rows)).map_err(|e| e.to_string())
+}
+
+fn parse_csv_value(value: &str) -> Value {
+    let trimmed = value.trim();
+    if let Ok(number) = trimmed.parse::<i64>() {
+        return Value::from(number);
+    }
+    if let Some(number) = trimmed.parse::<f64>().ok().and_then(Number::from_f64) {
+        return Value::Number(number);
+    }
+    if let Ok(flag) = trimmed.parse::<bool>() {
+        return Value::Bool(flag);
+    }
+    Value::String(value.to_string())
+}
@@ -6,6 +6,10 @@ pub mod api;
 pub mod auth;
 pub mod macros;
 pub mod common;
+pub mod tasks;
+pub mod ingest;
+pub mod uplink;
+pub mod webhook;
 
 //#[macro_use]
 extern crate actix_web;
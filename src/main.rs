@@ -1,4 +1,6 @@
 use broker::*;
+use broker::data::export::ExportFormat;
+use broker::models::RecordFilter;
 
 #[actix_web::main]
 async fn main()->std::io::Result<()>  {
@@ -7,9 +9,67 @@ async fn main()->std::io::Result<()>  {
         .filter_level(log::LevelFilter::Info)
         .init();
     
+    // `broker reencrypt` re-encrypts the stored records with the active key and exits
+    if std::env::args().nth(1).as_deref() == Some("reencrypt") {
+        return app::reencrypt().await;
+    }
+
+    // `broker backup <path> [--exclude-sent]` writes a backup of the database and exits
+    if std::env::args().nth(1).as_deref() == Some("backup") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        let Some(path) = args.iter().find(|arg| !arg.starts_with("--")) else {
+            return Err(std::io::Error::other("Usage: broker backup <path> [--exclude-sent]"));
+        };
+        let exclude_sent = args.iter().any(|arg| arg == "--exclude-sent");
+        return app::backup(std::path::Path::new(path), exclude_sent).await;
+    }
+
+    // `broker restore <path>` replaces the database with the backup and exits
+    if std::env::args().nth(1).as_deref() == Some("restore") {
+        let Some(path) = std::env::args().nth(2) else {
+            return Err(std::io::Error::other("Usage: broker restore <path>"));
+        };
+        return app::restore(std::path::Path::new(&path)).await;
+    }
+
+    // `broker export <path> [--format jsonl|csv|parquet] [--src-id <id>] [--from <time>] [--to <time>]
+    // [--sent true|false] [--mark-sent]` exports the records to a new file and exits
+    if std::env::args().nth(1).as_deref() == Some("export") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        let (path, format, filter, mark_sent) = export_args(&args)
+            .map_err(|e| std::io::Error::other(format!("{}. Usage: broker export <path> [--format jsonl|csv|parquet] \
+                [--src-id <id>] [--from <time>] [--to <time>] [--sent true|false] [--mark-sent]", e)))?;
+        return app::export(std::path::Path::new(path), format, filter, mark_sent).await;
+    }
+
     log::info!("Starting broker application.");
 
     let app = app::start_app().await;
     
     app
-}
\ No newline at end of file
+}
+
+fn export_args(args: &[String]) -> Result<(&str, ExportFormat, RecordFilter, bool), String> {
+    let path = args.first().filter(|path| !path.starts_with("--")).ok_or("Path is missing")?;
+
+    let value = |name: &str| args.iter()
+        .position(|arg| arg == name)
+        .map(|index| args.get(index + 1).map(String::as_str).ok_or(format!("Value of {} is missing", name)))
+        .transpose();
+
+    let time = |name: &str| value(name)?
+        .map(|time| time.parse::<i64>().map_err(|_| format!("Invalid time {}", time)))
+        .transpose();
+
+    let format = value("--format")?.map(str::parse).transpose()?.unwrap_or_default();
+    let filter = RecordFilter {
+        src_id: value("--src-id")?.map(str::to_string),
+        from: time("--from")?,
+        to: time("--to")?,
+        sent: value("--sent")?
+            .map(|sent| sent.parse::<bool>().map_err(|_| format!("Invalid sent state {}", sent)))
+            .transpose()?,
+    };
+
+    Ok((path, format, filter, args.iter().any(|arg| arg == "--mark-sent")))
+}
@@ -1,11 +1,84 @@
+use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use thiserror::Error;
+use crate::common::compression::Compression;
+use crate::config::TargetMode;
+use crate::ingest::schema::{self, SchemaError};
+use crate::ingest::transform::TransformCfg;
 
-#[derive(Debug, PartialEq, Clone, FromRow)]
+#[derive(Debug, PartialEq, Clone, Default, FromRow)]
 pub struct Record {
     pub id: u32,
     pub src_id: String,
     pub data: Vec<u8>,
-    pub sent: bool
+    pub sent: bool,
+    /// Path of the payload file when the payload is too large to be stored inline
+    #[sqlx(default)]
+    pub data_file: Option<String>,
+    /// Sequence number sent by the source in the X-Sequence header
+    #[sqlx(default)]
+    pub seq: Option<i64>,
+    #[sqlx(default)]
+    pub kind: RecordKind,
+    /// Delivery priority, high-priority records are sent first and evicted last
+    #[sqlx(default)]
+    pub priority: i64,
+    #[sqlx(default)]
+    pub state: RecordState,
+    /// Count of failed delivery attempts
+    #[sqlx(default)]
+    pub attempts: i64,
+    /// Error of the last failed delivery attempt
+    #[sqlx(default)]
+    pub last_error: Option<String>,
+    /// Compression of the stored data, the data is compressed when stored and decompressed when read
+    #[sqlx(default)]
+    pub compression: Option<Compression>,
+    /// `Content-Encoding` of the payload stored as received, the payload is not decoded by the broker
+    #[sqlx(default)]
+    pub content_encoding: Option<Compression>,
+    /// Id of the key the stored data is encrypted with, records are read decrypted
+    #[sqlx(default)]
+    pub key_id: Option<String>,
+}
+
+/// Delivery state of the data record
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RecordState {
+    /// Waiting for delivery
+    #[default]
+    Pending,
+    /// Claimed by a sender until the lease expires
+    Leased,
+    /// Delivered to the hub (`sent` is set)
+    Delivered,
+}
+
+/// Kind of the data record
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    /// Payload received from the data source
+    #[default]
+    Data,
+    /// Event generated by the broker about the data source, e.g. a sequence gap
+    Diagnostic,
+    /// Video segment uploaded by a camera, sent by the uplink from the segment index
+    Video,
+}
+
+impl RecordKind {
+    /// Gets the name of the kind as it is serialized
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordKind::Data => "data",
+            RecordKind::Diagnostic => "diagnostic",
+            RecordKind::Video => "video",
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -14,3 +87,462 @@ pub struct Source {
     pub cfg: Option<String>,
     pub active: bool,
 }
+
+/// Structured content of the `Source::cfg` JSON document
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SourceCfg {
+    /// Overrides `Config::max_body_size` for the source
+    pub max_body_size: Option<usize>,
+    /// Expected payload format, payloads are not validated when it is absent
+    pub schema: Option<PayloadSchema>,
+    /// Transformations applied to valid payloads before storing
+    pub transforms: Vec<TransformCfg>,
+    /// Overrides `Config::dedupe_window` for the source
+    pub dedupe_window: Option<u64>,
+    /// Priority of the source records, it can be overridden by the X-Priority header
+    pub priority: Option<i64>,
+    /// Overrides `Config::delivery_order` for the source
+    pub delivery_order: Option<DeliveryOrder>,
+    /// Compression of stored payloads, payloads spooled to files are stored as is
+    pub compression: Option<Compression>,
+    /// Stores compressed request bodies as received instead of decoding them.
+    /// Encoded payloads are delivered with their encoding, it cannot be combined with a schema or transforms.
+    pub keep_encoding: bool,
+    /// Storage engine of the source records
+    pub storage: StorageEngine,
+    /// Files of the import directory ingested as records of the source
+    pub import: Option<ImportCfg>,
+    /// Count of unsent records of the source above which the `source.quota_exceeded` webhook event is raised,
+    /// records are still accepted
+    pub quota: Option<u64>,
+}
+
+/// Files dropped to the import directory for the source
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct ImportCfg {
+    /// File name pattern, `*` matches any characters and `?` matches one character
+    pub pattern: String,
+    /// Files are split to records by the delimiter, e.g. `\n`, a file is a record if not set
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    /// Size limit of the files split by the delimiter, `MAX_IMPORT_FILE_SIZE` if not set
+    #[serde(default)]
+    pub max_file_size: Option<usize>,
+}
+
+/// Where the records of a source are stored when received
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageEngine {
+    /// A database row per record
+    #[default]
+    Sqlite,
+    /// Appended to the segment log of the source and moved to the database in batches,
+    /// for high-rate sources. Records are not deduplicated: `Config::dedupe_window` does not apply and
+    /// a `dedupe_window` of the source is rejected. Payloads spooled to files are stored as rows.
+    Log,
+}
+
+/// Order of delivering unsent records within the same priority
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOrder {
+    /// The oldest records first, keeps time series in order
+    #[default]
+    Fifo,
+    /// The newest records first, for sources where the latest value matters
+    Lifo,
+}
+
+/// Position after the last returned record for keyset pagination, formatted as `priority.id`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Cursor {
+    pub priority: i64,
+    pub id: u32,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.priority, self.id)
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (priority, id) = s.split_once('.').ok_or_else(|| format!("Invalid cursor {}", s))?;
+        Ok(Cursor {
+            priority: priority.parse().map_err(|_| format!("Invalid cursor {}", s))?,
+            id: id.parse().map_err(|_| format!("Invalid cursor {}", s))?,
+        })
+    }
+}
+
+/// Page of records with the cursor of the next page
+#[derive(Debug, PartialEq, Clone)]
+pub struct Page {
+    pub records: Vec<Record>,
+    /// `None` when there are no more records
+    pub next: Option<Cursor>,
+}
+
+/// Records selected by the source, the storing time and the sent state, all records if nothing is set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordFilter {
+    pub src_id: Option<String>,
+    /// Records stored at or after this time in Unix seconds
+    pub from: Option<i64>,
+    /// Records stored before this time in Unix seconds
+    pub to: Option<i64>,
+    pub sent: Option<bool>,
+}
+
+/// Data record with the time it was stored
+#[derive(Debug, PartialEq, Clone, FromRow)]
+pub struct StoredRecord {
+    #[sqlx(flatten)]
+    pub record: Record,
+    /// Time the record was stored in Unix seconds, unknown for the records stored by older versions
+    pub created_at: Option<i64>,
+}
+
+/// Expected format of the source payloads
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum PayloadSchema {
+    /// JSON document validated by a JSON Schema
+    Json { schema: serde_json::Value },
+    /// CSV rows with the declared columns
+    Csv {
+        columns: Vec<CsvColumn>,
+        #[serde(default = "default_csv_delimiter")]
+        delimiter: char,
+        /// The first row contains column names
+        #[serde(default)]
+        header: bool,
+    },
+    /// Fixed-layout binary structure, `repeated` allows several structures in one payload
+    Binary {
+        fields: Vec<BinaryField>,
+        #[serde(default)]
+        repeated: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct CsvColumn {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ValueKind,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueKind {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct BinaryField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: BinaryKind,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryKind {
+    U8, I8, U16, I16, U32, I32, U64, I64, F32, F64,
+}
+
+impl BinaryKind {
+    /// Size of the field in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            BinaryKind::U8 | BinaryKind::I8 => 1,
+            BinaryKind::U16 | BinaryKind::I16 => 2,
+            BinaryKind::U32 | BinaryKind::I32 | BinaryKind::F32 => 4,
+            BinaryKind::U64 | BinaryKind::I64 | BinaryKind::F64 => 8,
+        }
+    }
+}
+
+fn default_csv_delimiter() -> char {
+    ','
+}
+
+impl Source {
+    /// Parses the source configuration, an absent cfg means defaults
+    pub fn config(&self) -> Result<SourceCfg, serde_json::Error> {
+        match &self.cfg {
+            Some(cfg) if !cfg.trim().is_empty() => serde_json::from_str(cfg),
+            _ => Ok(SourceCfg::default()),
+        }
+    }
+
+    /// Parses the source configuration and rejects the settings which cannot be combined
+    pub fn validated_config(&self) -> Result<SourceCfg, SourceCfgError> {
+        let source_cfg = self.config()?;
+        source_cfg.validate()?;
+        Ok(source_cfg)
+    }
+}
+
+/// Invalid configuration of a source
+#[derive(Error, Debug)]
+pub enum SourceCfgError {
+    #[error("{0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("keep_encoding cannot be combined with a schema or transforms")]
+    KeepEncoding,
+    #[error("a dedupe_window cannot be set for the log storage")]
+    LogDedupe,
+    #[error("{0}")]
+    Schema(#[from] SchemaError),
+}
+
+impl SourceCfg {
+    /// Rejects the settings which cannot be combined
+    pub fn validate(&self) -> Result<(), SourceCfgError> {
+        // payloads kept encoded cannot be validated nor transformed
+        if self.keep_encoding && (self.schema.is_some() || !self.transforms.is_empty()) {
+            return Err(SourceCfgError::KeepEncoding);
+        }
+
+        // records appended to a log have no ids to be deduplicated against
+        if self.storage == StorageEngine::Log && self.dedupe_window.is_some_and(|window| window > 0) {
+            return Err(SourceCfgError::LogDedupe);
+        }
+
+        if let Some(schema) = &self.schema {
+            schema::check(schema)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Video segment uploaded by a camera
+#[derive(Debug, PartialEq, Clone, FromRow)]
+pub struct VideoSegment {
+    pub id: u32,
+    pub src_id: String,
+    pub stream_id: String,
+    pub seq: i64,
+    pub path: String,
+    pub content_type: String,
+    /// Duration in seconds
+    pub duration: f64,
+    pub size: i64,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    pub sent: bool,
+}
+
+/// Sample of a payload rejected by the validation
+#[derive(Debug, PartialEq, Clone, FromRow)]
+pub struct RejectedPayload {
+    pub id: u32,
+    pub src_id: String,
+    pub error: String,
+    /// Payload truncated to `REJECTED_SAMPLE_SIZE` bytes
+    pub data: Vec<u8>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+/// Record set aside after the hub rejected it or delivery attempts were exhausted
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct DeadLetter {
+    /// Identifier of the original record
+    pub id: u32,
+    pub src_id: String,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    #[serde(skip)]
+    pub data_file: Option<String>,
+    pub seq: Option<i64>,
+    pub kind: RecordKind,
+    pub priority: i64,
+    pub attempts: i64,
+    pub last_error: String,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    #[serde(skip)]
+    pub compression: Option<Compression>,
+    pub content_encoding: Option<Compression>,
+    #[serde(skip)]
+    pub key_id: Option<String>,
+}
+
+impl DeadLetter {
+    /// Gets the record as it was before being moved to the dead letters
+    pub fn to_record(&self) -> Record {
+        Record {
+            id: self.id,
+            src_id: self.src_id.clone(),
+            data: self.data.clone(),
+            data_file: self.data_file.clone(),
+            seq: self.seq,
+            kind: self.kind,
+            priority: self.priority,
+            attempts: self.attempts,
+            last_error: Some(self.last_error.clone()),
+            compression: self.compression,
+            content_encoding: self.content_encoding,
+            key_id: self.key_id.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Delivery statistics of an upstream target
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct TargetStats {
+    pub name: String,
+    pub mode: TargetMode,
+    /// Count of records waiting for delivery to the target
+    pub backlog: i64,
+    /// Count of records the target failed to receive
+    pub failed: i64,
+    /// Unix timestamps in seconds
+    pub last_success_at: Option<i64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+}
+
+/// Compression statistics of a data source
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct CompressionStats {
+    pub src_id: String,
+    /// Size of compressed payloads before compression
+    pub raw_bytes: i64,
+    /// Stored size of compressed payloads
+    pub stored_bytes: i64,
+}
+
+impl CompressionStats {
+    /// Gets the ratio of the raw size to the stored size
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 { 1.0 } else { self.raw_bytes as f64 / self.stored_bytes as f64 }
+    }
+}
+
+/// Sequence anomaly of a data source
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct SequenceEvent {
+    pub id: u32,
+    pub src_id: String,
+    pub kind: SequenceEventKind,
+    /// Expected sequence number
+    pub expected: i64,
+    /// Received sequence number
+    pub received: i64,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceEventKind {
+    /// Sequence numbers have been skipped, i.e. readings were lost
+    Gap,
+    /// The sequence number is lower than the last one
+    OutOfOrder,
+    /// The sequence number equals the last one
+    Duplicate,
+    /// The source has restarted its sequence from 0
+    Reset,
+}
+
+/// Sequence tracking state and anomaly counters of a data source
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct SequenceState {
+    pub src_id: String,
+    pub last_seq: i64,
+    pub gaps: i64,
+    /// Total count of missing sequence numbers
+    pub missing: i64,
+    pub out_of_order: i64,
+    pub duplicates: i64,
+    pub resets: i64,
+}
+
+/// Local HTTP endpoint called when the subscribed events happen
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct Webhook {
+    pub id: u32,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of the requests
+    #[serde(skip)]
+    pub secret: String,
+    #[sqlx(json)]
+    pub events: Vec<EventKind>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+/// Kind of the events webhooks subscribe to
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT")]
+pub enum EventKind {
+    /// The first record of a registered source has been received
+    #[serde(rename = "source.first_seen")]
+    #[sqlx(rename = "source.first_seen")]
+    SourceFirstSeen,
+    /// Unsent records of a source exceed its quota
+    #[serde(rename = "source.quota_exceeded")]
+    #[sqlx(rename = "source.quota_exceeded")]
+    SourceQuotaExceeded,
+    /// A target is unavailable after being available
+    #[serde(rename = "hub.disconnected")]
+    #[sqlx(rename = "hub.disconnected")]
+    HubDisconnected,
+    /// A target is available again
+    #[serde(rename = "hub.reconnected")]
+    #[sqlx(rename = "hub.reconnected")]
+    HubReconnected,
+    /// Unsent records exceed the backlog threshold
+    #[serde(rename = "backlog.above_threshold")]
+    #[sqlx(rename = "backlog.above_threshold")]
+    BacklogAboveThreshold,
+    /// Sent to a webhook on request to check it
+    #[serde(rename = "webhook.test")]
+    #[sqlx(rename = "webhook.test")]
+    Test,
+}
+
+/// Call of a webhook for an event
+#[derive(Debug, PartialEq, Clone, FromRow, Serialize)]
+pub struct WebhookDelivery {
+    pub id: u32,
+    pub webhook_id: u32,
+    pub event: EventKind,
+    #[sqlx(json)]
+    pub data: serde_json::Value,
+    pub state: WebhookDeliveryState,
+    pub attempts: i64,
+    /// Unix timestamp in seconds of the next attempt of a pending delivery
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookDeliveryState {
+    /// Waiting for the first or the next attempt
+    Pending,
+    Delivered,
+    /// Not delivered within the max attempts
+    Failed,
+}
@@ -0,0 +1,17 @@
+use sqlx::{Pool, Sqlite};
+use crate::data::dedupe;
+
+/// Starts periodic deletion of expired idempotency keys every `clear_data_delay` seconds
+pub fn start(pool: Pool<Sqlite>) {
+    tokio::spawn(async move {
+        loop {
+            match dedupe::delete_expired_keys(&pool).await {
+                Ok(0) => {}
+                Ok(count) => log::info!("{} expired idempotency keys have been deleted.", count),
+                Err(e) => log::error!("Failed to delete expired idempotency keys: {}", e),
+            }
+
+            tokio::time::sleep(super::clear_data_delay(&pool).await).await;
+        }
+    });
+}
@@ -0,0 +1,24 @@
+use sqlx::{Pool, Sqlite};
+use crate::config::Config;
+use crate::data::{delivery, target};
+
+/// Starts periodic reclaiming of records with expired delivery leases every `clear_data_delay` seconds
+pub fn start(pool: Pool<Sqlite>, cfg: &'static Config) {
+    tokio::spawn(async move {
+        loop {
+            match delivery::reclaim_expired(&pool, cfg.max_delivery_attempts).await {
+                Ok(0) => {}
+                Ok(count) => log::warn!("{} records with expired delivery leases have been reclaimed.", count),
+                Err(e) => log::error!("Failed to reclaim expired delivery leases: {}", e),
+            }
+
+            match target::reclaim_expired(&pool).await {
+                Ok(0) => {}
+                Ok(count) => log::warn!("{} records with expired fan-out leases have been reclaimed.", count),
+                Err(e) => log::error!("Failed to reclaim expired fan-out leases: {}", e),
+            }
+
+            tokio::time::sleep(super::clear_data_delay(&pool).await).await;
+        }
+    });
+}
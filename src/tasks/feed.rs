@@ -0,0 +1,19 @@
+use std::sync::Arc;
+use std::time::Duration;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::FEED_PUBLISH_INTERVAL;
+use crate::data::feed::Feed;
+
+/// Starts publishing the stored records to the live subscribers when `/add` signals them
+/// or every `FEED_PUBLISH_INTERVAL` seconds for the records stored by the import and the log drain
+pub fn start(pool: Pool<Sqlite>, feed: Arc<Feed>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = feed.publish_stored(&pool).await {
+                log::error!("Failed to publish the stored records: {}", e);
+            }
+
+            let _ = tokio::time::timeout(Duration::from_secs(FEED_PUBLISH_INTERVAL), feed.stored()).await;
+        }
+    });
+}
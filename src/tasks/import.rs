@@ -0,0 +1,25 @@
+use std::sync::Arc;
+use std::time::Duration;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::IMPORT_SCAN_INTERVAL;
+use crate::config::Config;
+use crate::data::segment_log::Logs;
+use crate::ingest::import;
+
+/// Starts importing the files dropped to the import directory every `IMPORT_SCAN_INTERVAL` seconds
+pub fn start(pool: Pool<Sqlite>, cfg: &'static Config, logs: Arc<Logs>) {
+    tokio::spawn(async move {
+        loop {
+            match import::scan(&pool, cfg, &logs).await {
+                Ok(report) if report.processed + report.quarantined == 0 => {}
+                Ok(report) => log::info!(
+                    "{} files have been imported ({} records), {} files have been quarantined.",
+                    report.processed, report.records, report.quarantined
+                ),
+                Err(e) => log::error!("Failed to scan the import directory: {}", e),
+            }
+
+            tokio::time::sleep(Duration::from_secs(IMPORT_SCAN_INTERVAL)).await;
+        }
+    });
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::CLEAR_DATA_DELAY_KEY;
+use crate::data::rep;
+
+pub mod dedupe;
+pub mod delivery;
+pub mod feed;
+pub mod import;
+pub mod retention;
+pub mod segment_log;
+pub mod video;
+pub mod webhook;
+
+/// Gets the delay between clean-up runs from the `clear_data_delay` setting (seconds)
+pub(crate) async fn clear_data_delay(pool: &Pool<Sqlite>) -> Duration {
+    let delay = rep::get_setting_by_key(pool, CLEAR_DATA_DELAY_KEY)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(3600);
+
+    Duration::from_secs(delay)
+}
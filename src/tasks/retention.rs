@@ -0,0 +1,31 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::MAX_COUNT_DATA_ROWS_KEY;
+use crate::data::rep;
+
+/// Keeps the count of stored records within the `max_count_data_rows` setting,
+/// sent and low-priority records are evicted first
+pub async fn enforce_max_rows(pool: &Pool<Sqlite>) -> Result<u64, Box<dyn Error>> {
+
+    let max_rows: u64 = rep::get_setting_by_key(pool, MAX_COUNT_DATA_ROWS_KEY)
+        .await?
+        .ok_or("Max count of data rows setting is missing")?
+        .parse()?;
+
+    Ok(rep::evict_data(pool, max_rows).await?)
+}
+
+/// Starts periodic retention every `clear_data_delay` seconds
+pub fn start(pool: Pool<Sqlite>) {
+    tokio::spawn(async move {
+        loop {
+            match enforce_max_rows(&pool).await {
+                Ok(0) => {}
+                Ok(count) => log::warn!("{} data records have been evicted by the retention policy.", count),
+                Err(e) => log::error!("Failed to apply the retention policy: {}", e),
+            }
+
+            tokio::time::sleep(super::clear_data_delay(&pool).await).await;
+        }
+    });
+}
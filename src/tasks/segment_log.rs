@@ -0,0 +1,46 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::{LOG_DRAIN_BATCH, LOG_DRAIN_INTERVAL};
+use crate::data::rep;
+use crate::data::segment_log::{self, Logs, SegmentLog};
+
+/// Syncs the log to the disk, moves its records to the delivery queue and deletes the oldest segments
+/// exceeding `max_log_size`. The log of a deleted source is deleted. Returns the count of moved records.
+pub async fn flush(pool: &Pool<Sqlite>, logs: &Logs, source_log: &Arc<Mutex<SegmentLog>>, max_log_size: u64)
+    -> Result<usize, Box<dyn Error>> {
+
+    let src_id = segment_log::blocking(source_log, |log| Ok(log.src_id().to_string())).await?;
+    if rep::get_source_by_id(pool, &src_id).await?.is_none() {
+        log::warn!("The log of the deleted source {} is deleted.", src_id);
+        logs.remove(&src_id)?;
+        return Ok(0);
+    }
+
+    segment_log::blocking(source_log, |log| log.sync()).await?;
+    // the error is kept as a string to be sent across the await, the log is trimmed anyway
+    let moved = segment_log::drain(pool, source_log, LOG_DRAIN_BATCH).await.map_err(|e| e.to_string());
+
+    let dropped = segment_log::blocking(source_log, move |log| log.retain(max_log_size)).await?;
+    if dropped > 0 {
+        log::warn!("{} records of the source {} have been dropped by the log size limit.", dropped, src_id);
+    }
+
+    Ok(moved?)
+}
+
+/// Starts moving records of the segment logs to the delivery queue every `LOG_DRAIN_INTERVAL` seconds
+pub fn start(pool: Pool<Sqlite>, logs: Arc<Logs>, max_log_size: u64) {
+    tokio::spawn(async move {
+        loop {
+            for source_log in logs.all() {
+                if let Err(e) = flush(&pool, &logs, &source_log, max_log_size).await {
+                    log::error!("Failed to move the records of the segment log: {}", e);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(LOG_DRAIN_INTERVAL)).await;
+        }
+    });
+}
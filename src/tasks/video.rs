@@ -0,0 +1,39 @@
+use std::error::Error;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::VIDEO_SEGMENTS_EXPIRATION_KEY;
+use crate::data::{rep, video};
+
+/// Deletes video segments older than `video_segments_expiration` hours with their files
+pub async fn purge_expired_segments(pool: &Pool<Sqlite>) -> Result<usize, Box<dyn Error>> {
+
+    let hours: i64 = rep::get_setting_by_key(pool, VIDEO_SEGMENTS_EXPIRATION_KEY)
+        .await?
+        .ok_or("Video segments expiration setting is missing")?
+        .parse()?;
+
+    let expired_before = chrono::Utc::now().timestamp() - hours * 3600;
+    let paths = video::delete_segments_before(pool, expired_before).await?;
+
+    for path in &paths {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            log::warn!("Failed to remove video segment {}: {}", path, e);
+        }
+    }
+
+    Ok(paths.len())
+}
+
+/// Starts periodic purging of expired video segments every `clear_data_delay` seconds
+pub fn start(pool: Pool<Sqlite>) {
+    tokio::spawn(async move {
+        loop {
+            match purge_expired_segments(&pool).await {
+                Ok(0) => {}
+                Ok(count) => log::info!("{} expired video segments have been purged.", count),
+                Err(e) => log::error!("Failed to purge video segments: {}", e),
+            }
+
+            tokio::time::sleep(super::clear_data_delay(&pool).await).await;
+        }
+    });
+}
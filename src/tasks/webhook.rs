@@ -0,0 +1,38 @@
+use std::time::Duration;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::{WEBHOOK_CHECK_INTERVAL, WEBHOOK_DISPATCH_INTERVAL};
+use crate::config::Config;
+use crate::webhook::{Dispatcher, Monitor};
+
+/// Starts calling the webhooks every `WEBHOOK_DISPATCH_INTERVAL` seconds
+/// and checking the backlog for the events every `WEBHOOK_CHECK_INTERVAL` seconds
+pub fn start(pool: Pool<Sqlite>, cfg: &Config) {
+    let dispatcher = Dispatcher::new(pool.clone(), cfg);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = dispatcher.dispatch().await {
+                log::error!("Failed to dispatch webhook events: {}", e);
+            }
+
+            tokio::time::sleep(Duration::from_secs(WEBHOOK_DISPATCH_INTERVAL)).await;
+        }
+    });
+
+    let dispatcher = Dispatcher::new(pool.clone(), cfg);
+    let mut monitor = Monitor::new(pool, cfg);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = monitor.check().await {
+                log::error!("Failed to check the backlog for webhook events: {}", e);
+            }
+
+            match dispatcher.purge().await {
+                Ok(0) => {}
+                Ok(purged) => log::info!("{} finished webhook deliveries have been deleted.", purged),
+                Err(e) => log::error!("Failed to delete finished webhook deliveries: {}", e),
+            }
+
+            tokio::time::sleep(Duration::from_secs(WEBHOOK_CHECK_INTERVAL)).await;
+        }
+    });
+}
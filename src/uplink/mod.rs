@@ -0,0 +1,547 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use thiserror::Error;
+use crate::auth::token_manager::{Credentials, TokenManager};
+use crate::common::compression::{compress, Compression};
+use crate::common::defaults::{DATA_SENDING_DELAY_KEY, MIN_PRIORITY, PACKET_SIZE_KEY};
+use crate::config::{Config, TargetCfg, TargetMode};
+use crate::data::spool::{self, SpoolError};
+use crate::data::{delivery, rep, target, video};
+use crate::models::{DeliveryOrder, Record, RecordKind, VideoSegment};
+use crate::webhook::{self, Event};
+use mqtt::MqttPublisher;
+
+pub mod mqtt;
+
+#[derive(Error, Debug)]
+pub enum UplinkError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Hub rejected the records with status {status}: {message}")]
+    Rejected { status: u16, message: String },
+    #[error("Hub is unavailable with status {status}: {message}")]
+    Unavailable { status: u16, message: String },
+    #[error("MQTT error: {0}")]
+    Mqtt(String),
+    #[error("Spool error: {0}")]
+    Spool(#[from] SpoolError),
+    #[error("Encoding error: {0}")]
+    Encoding(#[from] std::io::Error),
+}
+
+impl UplinkError {
+    /// Checks if retrying the same records cannot succeed
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, UplinkError::Rejected { .. } | UplinkError::Spool(_) | UplinkError::Encoding(_))
+    }
+}
+
+/// Batch of records uploaded to the hub
+#[derive(Debug, Serialize)]
+struct Batch<'a> {
+    system_name: &'a str,
+    records: &'a [UplinkRecord],
+}
+
+#[derive(Debug, Serialize)]
+struct UplinkRecord {
+    id: u32,
+    src_id: String,
+    seq: Option<i64>,
+    kind: RecordKind,
+    priority: i64,
+    /// Payload, Base64-encoded in HTTP batches
+    #[serde(serialize_with = "serialize_base64")]
+    data: Vec<u8>,
+    /// `Content-Encoding` of the payload stored as received from the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<Compression>,
+    /// Stream position of a video segment, the id is the segment id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segment: Option<SegmentInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct SegmentInfo {
+    stream_id: String,
+    seq: i64,
+    content_type: String,
+    duration: f64,
+}
+
+fn serialize_base64<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+/// Client of an upstream target
+#[derive(Debug, Clone)]
+pub struct HubClient {
+    name: String,
+    transport: Transport,
+    /// Whether the last batch reached the target, the hub webhook events are raised when it changes
+    connected: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Clone)]
+enum Transport {
+    /// Batches are posted to the HTTP endpoint
+    Http {
+        endpoint: String,
+        client: reqwest::Client,
+        tokens: Option<TokenManager>,
+        /// Compression of the batches, disabled once the target does not accept it
+        compression: Option<Compression>,
+        compression_enabled: Arc<AtomicBool>,
+    },
+    /// Records are published to the MQTT broker
+    Mqtt(Arc<MqttPublisher>),
+}
+
+impl HubClient {
+    /// Creates a client of the target, `mqtt://` endpoints are served by an MQTT broker
+    pub fn new(target: &TargetCfg) -> Result<Self, UplinkError> {
+        let transport = if target.endpoint.starts_with("mqtt://") {
+            Transport::Mqtt(Arc::new(MqttPublisher::new(target)?))
+        } else {
+            let tokens = target.auth_endpoint.as_ref().map(|endpoint| {
+                TokenManager::with_credentials(Credentials {
+                    endpoint: endpoint.clone(),
+                    client_id: target.client_id.clone(),
+                    secret: target.secret.clone(),
+                })
+            });
+
+            Transport::Http {
+                endpoint: target.endpoint.clone(),
+                client: reqwest::Client::new(),
+                tokens,
+                compression: target.compression,
+                compression_enabled: Arc::new(AtomicBool::new(true)),
+            }
+        };
+
+        Ok(HubClient { name: target.name.clone(), transport, connected: Arc::new(AtomicBool::new(true)) })
+    }
+
+    /// Starts refreshing the access token of the target
+    async fn authenticate(&self) {
+        if let Transport::Http { tokens: Some(tokens), .. } = &self.transport {
+            if let Err(e) = tokens.clone().start().await {
+                log::error!("Failed to get the access token of target {}: {}", self.name, e);
+            }
+        }
+    }
+
+    async fn send(&self, system_name: &str, records: &[UplinkRecord]) -> Result<(), UplinkError> {
+        let (compression, compression_enabled) = match &self.transport {
+            Transport::Http { compression, compression_enabled, .. } => (*compression, compression_enabled),
+            Transport::Mqtt(publisher) => return publisher.publish(system_name, records).await,
+        };
+
+        let body = serde_json::to_vec(&Batch { system_name, records }).map_err(std::io::Error::from)?;
+
+        match compression.filter(|_| compression_enabled.load(Ordering::Relaxed)) {
+            Some(compression) => {
+                match self.post(compress(&body, compression)?, Some(compression)).await {
+                    Err(UplinkError::Rejected { status: 415, .. }) => {
+                        log::warn!("Target {} does not accept {} batches, sending uncompressed",
+                            self.name, compression.encoding());
+                        compression_enabled.store(false, Ordering::Relaxed);
+                        self.post(body, None).await
+                    }
+                    result => result,
+                }
+            }
+            None => self.post(body, None).await,
+        }
+    }
+
+    /// Posts the JSON batch to the HTTP endpoint with the `Content-Encoding` of the compression
+    async fn post(&self, body: Vec<u8>, compression: Option<Compression>) -> Result<(), UplinkError> {
+        let Transport::Http { endpoint, client, tokens, .. } = &self.transport else {
+            unreachable!("batches are posted only to HTTP targets");
+        };
+
+        let mut request = client
+            .post(endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+
+        if let Some(compression) = compression {
+            request = request.header(CONTENT_ENCODING, compression.encoding());
+        }
+
+        if let Some(tokens) = tokens {
+            if let Some(token) = tokens.get_token().await {
+                request = request.bearer_auth(token);
+            }
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let message = response.text().await.unwrap_or_default();
+        let status_code = status.as_u16();
+
+        // timeouts and throttling are not a verdict on the records
+        if status.is_client_error() && status_code != 408 && status_code != 429 {
+            Err(UplinkError::Rejected { status: status_code, message })
+        } else {
+            Err(UplinkError::Unavailable { status: status_code, message })
+        }
+    }
+}
+
+/// Where the uplink delivers records and whose delivery state it tracks
+#[derive(Debug, Clone)]
+enum Channel {
+    /// Failover targets tried in order, sharing the delivery state of the records
+    Failover(Vec<HubClient>),
+    /// A fan-out target with its own delivery state
+    FanOut(HubClient),
+}
+
+/// Uploads stored records to the hub, records are claimed with a lease and
+/// acknowledged, retried or moved to the dead letters depending on the hub response
+#[derive(Debug, Clone)]
+pub struct Uplink {
+    pool: Pool<Sqlite>,
+    channel: Channel,
+    system_name: String,
+    owner: String,
+    order: DeliveryOrder,
+    lease_duration: u64,
+    max_attempts: u32,
+}
+
+impl Uplink {
+    /// Creates an uplink delivering records to the first available of the failover targets
+    pub fn failover(pool: Pool<Sqlite>, config: &Config, clients: Vec<HubClient>) -> Self {
+        Self::new(pool, config, Channel::Failover(clients))
+    }
+
+    /// Creates an uplink delivering every record to the fan-out target
+    pub fn fan_out(pool: Pool<Sqlite>, config: &Config, client: HubClient) -> Self {
+        Self::new(pool, config, Channel::FanOut(client))
+    }
+
+    /// Creates uplinks of the configured targets: one for all failover targets and one per fan-out target
+    pub fn from_config(pool: Pool<Sqlite>, config: &Config) -> Result<Vec<Self>, UplinkError> {
+        let failover = config.targets
+            .iter()
+            .filter(|target| target.mode == TargetMode::Failover)
+            .map(HubClient::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut uplinks = Vec::new();
+        if !failover.is_empty() {
+            uplinks.push(Self::failover(pool.clone(), config, failover));
+        }
+
+        for target in config.targets.iter().filter(|target| target.mode == TargetMode::FanOut) {
+            uplinks.push(Self::fan_out(pool.clone(), config, HubClient::new(target)?));
+        }
+
+        Ok(uplinks)
+    }
+
+    fn new(pool: Pool<Sqlite>, config: &Config, channel: Channel) -> Self {
+        Uplink {
+            pool,
+            channel,
+            system_name: config.system_name.clone(),
+            owner: format!("{}-{}", config.system_name, std::process::id()),
+            order: config.delivery_order,
+            lease_duration: config.lease_duration,
+            max_attempts: config.max_delivery_attempts,
+        }
+    }
+
+    /// Claims and uploads one batch of records, returns the count of delivered records
+    pub async fn deliver_batch(&self) -> Result<u64, Box<dyn Error>> {
+
+        let count: u32 = rep::get_setting_by_key(&self.pool, PACKET_SIZE_KEY)
+            .await?
+            .ok_or("Packet size setting is missing")?
+            .parse()?;
+
+        let records = self.claim(&count).await?;
+
+        if records.is_empty() {
+            return Ok(0);
+        }
+
+        let mut batch = Vec::with_capacity(records.len());
+        for record in &records {
+            match encode(record).await {
+                Ok(encoded) => batch.push(encoded),
+                Err(e) => {
+                    log::error!("Record {} cannot be read: {}", record.id, e);
+                    self.reject(&[record.id], &e.to_string()).await?;
+                }
+            }
+        }
+
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        match self.send(&batch).await {
+            Ok(()) => self.ack(&ids(&batch)).await,
+            // a single malformed record rejects the whole batch, so records are retried one by one
+            Err(e) if e.is_permanent() && batch.len() > 1 => self.deliver_one_by_one(&batch).await,
+            Err(e) => {
+                self.fail(&batch, &e).await?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Uploads the oldest unsent video segment, returns the count of uploaded segments.
+    /// Segments are sent one per batch as they are large, only by the failover uplink as they
+    /// have a single sent flag. A rejected or unreadable segment is marked as sent so it does not
+    /// hold the others up, its file is kept for local viewing until it expires.
+    pub async fn deliver_segment(&self) -> Result<u64, Box<dyn Error>> {
+        if !matches!(self.channel, Channel::Failover(_)) {
+            return Ok(0);
+        }
+
+        let Some(segment) = video::get_unsent_segments(&self.pool, &1).await?.pop() else {
+            return Ok(0);
+        };
+
+        let result = match encode_segment(&segment).await {
+            Ok(encoded) => self.send(std::slice::from_ref(&encoded)).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                video::mark_segments_sent(&self.pool, &[segment.id]).await?;
+                Ok(1)
+            }
+            Err(e) if e.is_permanent() => {
+                log::error!("Video segment {} of the stream {} is not uploaded: {}", segment.seq, segment.stream_id, e);
+                video::mark_segments_sent(&self.pool, &[segment.id]).await?;
+                Ok(0)
+            }
+            Err(e) => {
+                log::warn!("Failed to upload the video segment {} of the stream {}: {}", segment.seq, segment.stream_id, e);
+                Ok(0)
+            }
+        }
+    }
+
+    async fn deliver_one_by_one(&self, batch: &[UplinkRecord]) -> Result<u64, Box<dyn Error>> {
+        let mut delivered = 0;
+
+        for (n, record) in batch.iter().enumerate() {
+            match self.send(std::slice::from_ref(record)).await {
+                Ok(()) => delivered += self.ack(&[record.id]).await?,
+                Err(e) if e.is_permanent() => self.fail(std::slice::from_ref(record), &e).await?,
+                Err(e) => {
+                    // the hub is unavailable, the rest of the batch is retried later
+                    self.fail(&batch[n..], &e).await?;
+                    break;
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    async fn fail(&self, records: &[UplinkRecord], error: &UplinkError) -> Result<(), Box<dyn Error>> {
+        let ids = ids(records);
+        let message = error.to_string();
+
+        if error.is_permanent() {
+            log::error!("{} records have been rejected: {}", ids.len(), message);
+            self.reject(&ids, &message).await?;
+        } else {
+            log::warn!("Failed to upload {} records: {}", ids.len(), message);
+            self.nack(&ids, &message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the records to the targets of the channel, failover targets are tried in order
+    /// until one accepts or rejects the records
+    async fn send(&self, records: &[UplinkRecord]) -> Result<(), UplinkError> {
+        let clients = match &self.channel {
+            Channel::Failover(clients) => clients.as_slice(),
+            Channel::FanOut(client) => std::slice::from_ref(client),
+        };
+
+        let mut last_error = None;
+
+        for client in clients {
+            let result = client.send(&self.system_name, records).await;
+
+            let stored = match &result {
+                Ok(()) => target::record_success(&self.pool, &client.name).await,
+                Err(e) => target::record_failure(&self.pool, &client.name, &e.to_string()).await,
+            };
+            // the boxed error is not sent across the await of the webhook event
+            if let Err(e) = stored.map_err(|e| e.to_string()) {
+                log::error!("Failed to store the state of target {}: {}", client.name, e);
+            }
+            self.track_connection(client, &result).await;
+            match result {
+                Err(e) if !e.is_permanent() => last_error = Some(e),
+                result => return result,
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Raises the hub webhook events when the target becomes unavailable or available again,
+    /// a rejected batch still proves the target is reachable
+    async fn track_connection(&self, client: &HubClient, result: &Result<(), UplinkError>) {
+        let connected = !matches!(result, Err(e) if !e.is_permanent());
+        if client.connected.swap(connected, Ordering::Relaxed) == connected {
+            return;
+        }
+
+        let event = match result {
+            Err(e) if !connected => Event::HubDisconnected { target: client.name.clone(), error: e.to_string() },
+            _ => Event::HubReconnected { target: client.name.clone() },
+        };
+        webhook::emit(&self.pool, event).await;
+    }
+
+    async fn claim(&self, count: &u32) -> Result<Vec<Record>, Box<dyn Error>> {
+        match &self.channel {
+            Channel::Failover(_) =>
+                delivery::claim(&self.pool, &self.owner, self.order, count, self.lease_duration, self.max_attempts).await,
+            Channel::FanOut(client) =>
+                target::claim(&self.pool, &client.name, &self.owner, self.order, count, self.lease_duration).await,
+        }
+    }
+
+    async fn ack(&self, ids: &[u32]) -> Result<u64, Box<dyn Error>> {
+        match &self.channel {
+            Channel::Failover(_) => delivery::ack(&self.pool, &self.owner, ids).await,
+            Channel::FanOut(client) => target::ack(&self.pool, &client.name, &self.owner, ids).await,
+        }
+    }
+
+    async fn nack(&self, ids: &[u32], error: &str) -> Result<u64, Box<dyn Error>> {
+        match &self.channel {
+            Channel::Failover(_) =>
+                delivery::nack(&self.pool, &self.owner, ids, error, self.max_attempts).await,
+            Channel::FanOut(client) =>
+                target::nack(&self.pool, &client.name, &self.owner, ids, error, self.max_attempts).await,
+        }
+    }
+
+    /// Failover records are moved to the dead letters, fan-out ones are marked as failed for the target
+    async fn reject(&self, ids: &[u32], error: &str) -> Result<u64, Box<dyn Error>> {
+        match &self.channel {
+            Channel::Failover(_) => delivery::reject(&self.pool, &self.owner, ids, error).await,
+            Channel::FanOut(client) => target::reject(&self.pool, &client.name, &self.owner, ids, error).await,
+        }
+    }
+
+    /// Starts uploading records, the uplink waits `data_sending_delay` milliseconds
+    /// when there is nothing to send or the hub is unavailable
+    pub fn start(self) {
+        tokio::spawn(async move {
+            match &self.channel {
+                Channel::Failover(clients) => {
+                    for client in clients {
+                        client.authenticate().await;
+                    }
+                }
+                Channel::FanOut(client) => client.authenticate().await,
+            }
+
+            loop {
+                let mut delivered = match self.deliver_batch().await {
+                    Ok(delivered) => delivered,
+                    Err(e) => {
+                        log::error!("Failed to deliver records: {}", e);
+                        0
+                    }
+                };
+
+                delivered += match self.deliver_segment().await {
+                    Ok(delivered) => delivered,
+                    Err(e) => {
+                        log::error!("Failed to deliver video segments: {}", e);
+                        0
+                    }
+                };
+
+                if delivered == 0 {
+                    tokio::time::sleep(self.sending_delay().await).await;
+                }
+            }
+        });
+    }
+
+    async fn sending_delay(&self) -> Duration {
+        let delay = rep::get_setting_by_key(&self.pool, DATA_SENDING_DELAY_KEY)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(1000);
+
+        Duration::from_millis(delay)
+    }
+}
+
+async fn encode(record: &Record) -> Result<UplinkRecord, UplinkError> {
+    let data = spool::load(record).await?;
+
+    Ok(UplinkRecord {
+        id: record.id,
+        src_id: record.src_id.clone(),
+        seq: record.seq,
+        kind: record.kind,
+        priority: record.priority,
+        data,
+        content_encoding: record.content_encoding,
+        segment: None,
+    })
+}
+
+async fn encode_segment(segment: &VideoSegment) -> Result<UplinkRecord, UplinkError> {
+    let data = spool::read_file(&segment.path).await?;
+
+    Ok(UplinkRecord {
+        id: segment.id,
+        src_id: segment.src_id.clone(),
+        seq: None,
+        kind: RecordKind::Video,
+        priority: MIN_PRIORITY,
+        data,
+        content_encoding: None,
+        segment: Some(SegmentInfo {
+            stream_id: segment.stream_id.clone(),
+            seq: segment.seq,
+            content_type: segment.content_type.clone(),
+            duration: segment.duration,
+        }),
+    })
+}
+
+fn ids(records: &[UplinkRecord]) -> Vec<u32> {
+    records.iter().map(|record| record.id).collect()
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::time::Duration;
+use rumqttc::Outgoing;
+use rumqttc::v5::{AsyncClient, Event, EventLoop, Incoming, MqttOptions};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use tokio::sync::Mutex;
+use crate::config::TargetCfg;
+use super::{UplinkError, UplinkRecord};
+
+/// Default port of MQTT brokers
+const MQTT_PORT: u16 = 1883;
+/// Time to wait for the broker to acknowledge a publication
+const PUBACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Publishes records to an MQTT 5 broker with QoS 1, one message per record.
+/// The payload is published as is, the `kind`, `seq` and `content_encoding` of the record are user properties.
+pub struct MqttPublisher {
+    options: MqttOptions,
+    topic: String,
+    connection: Mutex<Option<(AsyncClient, EventLoop)>>,
+}
+
+impl MqttPublisher {
+    /// Creates a publisher of the target with the `mqtt://host:port` endpoint
+    pub fn new(target: &TargetCfg) -> Result<Self, UplinkError> {
+        let url = reqwest::Url::parse(&target.endpoint)
+            .map_err(|e| UplinkError::Mqtt(format!("Invalid endpoint {}: {}", target.endpoint, e)))?;
+        let host = url.host_str()
+            .ok_or_else(|| UplinkError::Mqtt(format!("Endpoint {} has no host", target.endpoint)))?;
+
+        let client_id = if target.client_id.is_empty() {
+            format!("broker-{}", target.name)
+        } else {
+            target.client_id.clone()
+        };
+
+        let mut options = MqttOptions::new(client_id.clone(), host, url.port().unwrap_or(MQTT_PORT));
+        options.set_keep_alive(Duration::from_secs(60));
+        if !target.secret.is_empty() {
+            options.set_credentials(client_id, target.secret.clone());
+        }
+
+        Ok(MqttPublisher {
+            options,
+            topic: target.topic.clone().unwrap_or_default(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Publishes the records one by one, every record is acknowledged by PUBACK before the next one is sent
+    pub(super) async fn publish(&self, system_name: &str, records: &[UplinkRecord]) -> Result<(), UplinkError> {
+        let mut connection = self.connection.lock().await;
+        let (client, eventloop) = connection.get_or_insert_with(|| AsyncClient::new(self.options.clone(), 10));
+
+        let result = self.publish_records(client, eventloop, system_name, records).await;
+
+        if result.is_err() {
+            // drops requests queued by the failed connection, so they are not replayed
+            *connection = None;
+        }
+
+        result
+    }
+
+    async fn publish_records(&self, client: &AsyncClient, eventloop: &mut EventLoop, system_name: &str,
+                             records: &[UplinkRecord]) -> Result<(), UplinkError> {
+
+        for record in records {
+            let topic = topic(&self.topic, system_name, &record.src_id);
+            client.publish_with_properties(topic, QoS::AtLeastOnce, false, record.data.clone(), properties(record))
+                .await
+                .map_err(|e| UplinkError::Mqtt(e.to_string()))?;
+
+            tokio::time::timeout(PUBACK_TIMEOUT, wait_puback(eventloop))
+                .await
+                .map_err(|_| UplinkError::Mqtt("PUBACK timeout".to_string()))??;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MqttPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttPublisher")
+            .field("options", &self.options)
+            .field("topic", &self.topic)
+            .finish()
+    }
+}
+
+/// Polls the event loop until the broker acknowledges the queued publication
+async fn wait_puback(eventloop: &mut EventLoop) -> Result<(), UplinkError> {
+    let mut pkid = None;
+
+    loop {
+        let event = eventloop.poll().await.map_err(|e| UplinkError::Mqtt(e.to_string()))?;
+
+        match event {
+            Event::Outgoing(Outgoing::Publish(id)) => pkid = Some(id),
+            Event::Incoming(Incoming::PubAck(ack)) if Some(ack.pkid) == pkid => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Gets the metadata of the record published along with its payload
+fn properties(record: &UplinkRecord) -> PublishProperties {
+    let mut user_properties = vec![("kind".to_string(), record.kind.as_str().to_string())];
+    if let Some(seq) = record.seq {
+        user_properties.push(("seq".to_string(), seq.to_string()));
+    }
+    if let Some(encoding) = record.content_encoding {
+        user_properties.push(("content_encoding".to_string(), encoding.encoding().to_string()));
+    }
+
+    PublishProperties { user_properties, ..Default::default() }
+}
+
+/// Gets the topic of the record from the template with `{system_name}` and `{src_id}` placeholders
+fn topic(template: &str, system_name: &str, src_id: &str) -> String {
+    template
+        .replace("{system_name}", system_name)
+        .replace("{src_id}", src_id)
+}
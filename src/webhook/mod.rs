@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite};
+use crate::common::defaults::{WEBHOOK_DELIVERY_RETENTION, WEBHOOK_MAX_RETRY_DELAY, WEBHOOK_RETRY_DELAY};
+use crate::config::Config;
+use crate::data::error::RepError;
+use crate::data::webhook::{self, DueDelivery};
+use crate::data::{rep, stats};
+use crate::models::EventKind;
+
+/// Count of the due deliveries sent at once
+const DISPATCH_BATCH_SIZE: u32 = 100;
+
+/// Local event the webhooks are called for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SourceFirstSeen { src_id: String },
+    SourceQuotaExceeded { src_id: String, unsent: i64, quota: u64 },
+    HubDisconnected { target: String, error: String },
+    HubReconnected { target: String },
+    BacklogAboveThreshold { unsent: i64, threshold: u64 },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::SourceFirstSeen { .. } => EventKind::SourceFirstSeen,
+            Event::SourceQuotaExceeded { .. } => EventKind::SourceQuotaExceeded,
+            Event::HubDisconnected { .. } => EventKind::HubDisconnected,
+            Event::HubReconnected { .. } => EventKind::HubReconnected,
+            Event::BacklogAboveThreshold { .. } => EventKind::BacklogAboveThreshold,
+        }
+    }
+
+    /// Gets the details of the event sent as the `data` of the webhook request
+    pub fn data(&self) -> Value {
+        match self {
+            Event::SourceFirstSeen { src_id } => serde_json::json!({ "src_id": src_id }),
+            Event::SourceQuotaExceeded { src_id, unsent, quota } =>
+                serde_json::json!({ "src_id": src_id, "unsent": unsent, "quota": quota }),
+            Event::HubDisconnected { target, error } => serde_json::json!({ "target": target, "error": error }),
+            Event::HubReconnected { target } => serde_json::json!({ "target": target }),
+            Event::BacklogAboveThreshold { unsent, threshold } =>
+                serde_json::json!({ "unsent": unsent, "threshold": threshold }),
+        }
+    }
+}
+
+/// Queues the event for the webhooks subscribed to it, failures are logged only
+/// as the event must not fail the operation raising it
+pub async fn emit(pool: &Pool<Sqlite>, event: Event) {
+    if let Err(e) = webhook::add_deliveries(pool, event.kind(), &event.data(), None).await {
+        log::error!("Failed to queue the webhook event {:?}: {}", event, e);
+    }
+}
+
+/// Raises `source.first_seen` for the first record of the source
+pub async fn source_seen(pool: &Pool<Sqlite>, src_id: &str) {
+    match webhook::mark_source_seen(pool, src_id).await {
+        Ok(true) => emit(pool, Event::SourceFirstSeen { src_id: src_id.to_string() }).await,
+        Ok(false) => {}
+        Err(e) => log::error!("Failed to mark the source {} as seen: {}", src_id, e),
+    }
+}
+
+/// Gets the `X-Webhook-Signature` of the request: the HMAC-SHA256 of `<timestamp>.<body>` in hex
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Gets the delay before the next attempt, doubled by each failed attempt
+pub fn retry_delay(attempts: i64) -> u64 {
+    let exponent = attempts.clamp(0, 20) as u32;
+    WEBHOOK_RETRY_DELAY.saturating_mul(2_u64.pow(exponent)).min(WEBHOOK_MAX_RETRY_DELAY)
+}
+
+/// Calls the webhooks of the queued events, failed calls are retried with a growing delay
+#[derive(Debug, Clone)]
+pub struct Dispatcher {
+    pool: Pool<Sqlite>,
+    client: reqwest::Client,
+    system_name: String,
+    max_attempts: u32,
+}
+
+impl Dispatcher {
+    pub fn new(pool: Pool<Sqlite>, config: &Config) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.webhooks.timeout))
+            .build()
+            .unwrap_or_default();
+
+        Dispatcher {
+            pool,
+            client,
+            system_name: config.system_name.clone(),
+            max_attempts: config.webhooks.max_attempts,
+        }
+    }
+
+    /// Sends the due deliveries, returns the count of the delivered ones
+    pub async fn dispatch(&self) -> Result<u64, RepError> {
+        let deliveries = webhook::get_due_deliveries(&self.pool, &DISPATCH_BATCH_SIZE).await?;
+
+        // a slow webhook does not hold the others up
+        let results = futures::future::join_all(deliveries.iter().map(|delivery| self.send(delivery))).await;
+
+        let mut delivered = 0;
+        for (delivery, result) in deliveries.iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    webhook::ack_delivery(&self.pool, delivery.id).await?;
+                    delivered += 1;
+                }
+                Err(error) => {
+                    log::warn!("Failed to call the webhook {} for the delivery {}: {}", delivery.url, delivery.id, error);
+                    let delay = retry_delay(delivery.attempts);
+                    webhook::nack_delivery(&self.pool, delivery.id, &error, delay, self.max_attempts).await?;
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// Posts the event to the webhook, any status but 2xx fails the attempt
+    async fn send(&self, delivery: &DueDelivery) -> Result<(), String> {
+        let body = serde_json::json!({
+            "id": delivery.id,
+            "event": delivery.event,
+            "system_name": self.system_name,
+            "created_at": delivery.created_at,
+            "data": delivery.data,
+        });
+        let body = serde_json::to_vec(&body).map_err(|e| e.to_string())?;
+
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = sign(&delivery.secret, timestamp, &body);
+        let event = serde_json::to_value(delivery.event).ok().and_then(|event| event.as_str().map(str::to_string));
+
+        let response = self.client
+            .post(&delivery.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", delivery.id.to_string())
+            .header("X-Webhook-Event", event.unwrap_or_default())
+            .header("X-Webhook-Timestamp", timestamp.to_string())
+            .header("X-Webhook-Signature", signature)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let message = response.text().await.unwrap_or_default();
+        Err(format!("Webhook answered with status {}: {}", status.as_u16(), message))
+    }
+
+    /// Deletes the finished deliveries older than `WEBHOOK_DELIVERY_RETENTION`
+    pub async fn purge(&self) -> Result<u64, RepError> {
+        let before = chrono::Utc::now().timestamp() - WEBHOOK_DELIVERY_RETENTION as i64;
+        webhook::delete_finished_deliveries(&self.pool, before).await
+    }
+}
+
+/// Raises the events of the source quotas and the backlog threshold when they are exceeded.
+/// An event is raised again once the backlog has gone back under the limit and exceeds it again.
+#[derive(Debug)]
+pub struct Monitor {
+    pool: Pool<Sqlite>,
+    backlog_threshold: Option<u64>,
+    over_quota: HashSet<String>,
+    over_threshold: bool,
+}
+
+impl Monitor {
+    pub fn new(pool: Pool<Sqlite>, config: &Config) -> Self {
+        Monitor {
+            pool,
+            backlog_threshold: config.webhooks.backlog_threshold,
+            over_quota: HashSet::new(),
+            over_threshold: false,
+        }
+    }
+
+    /// Checks the unsent records against the limits
+    pub async fn check(&mut self) -> Result<(), RepError> {
+        let counts = stats::get_unsent_counts(&self.pool).await?;
+
+        let mut over_quota = HashSet::new();
+        for source in rep::get_all_sources(&self.pool).await? {
+            let quota = match source.config() {
+                Ok(source_cfg) => source_cfg.quota,
+                Err(_) => None,
+            };
+            let Some(quota) = quota else {
+                continue;
+            };
+
+            let unsent = counts
+                .iter()
+                .find(|(src_id, _)| *src_id == source.src_id)
+                .map(|(_, count)| *count)
+                .unwrap_or_default();
+
+            if unsent as u64 > quota {
+                if !self.over_quota.contains(&source.src_id) {
+                    emit(&self.pool, Event::SourceQuotaExceeded { src_id: source.src_id.clone(), unsent, quota }).await;
+                }
+                over_quota.insert(source.src_id);
+            }
+        }
+        self.over_quota = over_quota;
+
+        if let Some(threshold) = self.backlog_threshold {
+            let unsent: i64 = counts.iter().map(|(_, count)| count).sum();
+            let over_threshold = unsent as u64 > threshold;
+            if over_threshold && !self.over_threshold {
+                emit(&self.pool, Event::BacklogAboveThreshold { unsent, threshold }).await;
+            }
+            self.over_threshold = over_threshold;
+        }
+
+        Ok(())
+    }
+}
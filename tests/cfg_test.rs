@@ -1,5 +1,5 @@
 use std::fs;
-use broker::config::{get_config, validation::validate, Config, ConfigError};
+use broker::config::{get_config, validation::validate, Config, ConfigError, DatabaseCfg, EncryptionCfg, KeyCfg, TargetMode};
 use std::io::Write;
 use std::panic::catch_unwind;
 use tempfile::NamedTempFile;
@@ -33,7 +33,7 @@ fn test_get_config_valid() {
     let temp_file = create_temp_config(json);
     let config = get_config(temp_file.path().to_str().unwrap());
 
-    assert_eq!(config.enabled, false);
+    assert!(!config.enabled);
     assert_eq!(config.system_name, "MySystem");
     assert_eq!(config.client_id, "my_client");
     assert_eq!(config.secret, "MySecret123!");
@@ -68,6 +68,7 @@ fn test_validate_valid_config() {
         secret: "Valid123!".to_string(),
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(result.is_ok());
@@ -82,6 +83,7 @@ fn test_validate_empty_system_name() {
         secret: "Valid123!".to_string(),
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(matches!(result, Err(ConfigError::Validation(ref s)) if s == "System name cannot be empty"));
@@ -96,6 +98,7 @@ fn test_validate_empty_client_id() {
         secret: "Valid123!".to_string(),
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(matches!(result, Err(ConfigError::Validation(ref s)) if s == "Client ID cannot be empty"));
@@ -110,6 +113,7 @@ fn test_validate_short_secret() {
         secret: "Short".to_string(), // Меньше 8 символов
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(matches!(result, Err(ConfigError::Validation(ref s)) if s == "Secret must be at least 8 characters long"));
@@ -124,6 +128,7 @@ fn test_validate_secret_no_uppercase() {
         secret: "secret123!".to_string(), // Нет заглавных букв
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(matches!(result, Err(ConfigError::Validation(ref s)) if s == "Secret must contain at least one uppercase letter"));
@@ -138,6 +143,7 @@ fn test_validate_secret_no_digit() {
         secret: "Secret!!!".to_string(), // Нет цифр
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(matches!(result, Err(ConfigError::Validation(ref s)) if s == "Secret must contain at least one digit"));
@@ -152,6 +158,7 @@ fn test_validate_secret_no_special() {
         secret: "Secret123".to_string(), // Нет спецсимволов
         hub_endpoint: "https://test.com".to_string(),
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(
@@ -172,6 +179,7 @@ fn test_validate_invalid_endpoint() {
         secret: "Valid123!".to_string(),
         hub_endpoint: "ftp://test.com".to_string(), // Неправильный протокол
         listen_port: 8080,
+        ..Default::default()
     };
     let result = validate(&config);
     assert!(
@@ -181,4 +189,219 @@ fn test_validate_invalid_endpoint() {
             if s == "Hub endpoint must use http:// or https:// protocol"
         )
     );
-}
\ No newline at end of file
+}
+#[test]
+fn test_validate_inline_limit_exceeds_max_body_size() {
+    let config = Config {
+        enabled: true,
+        system_name: "ValidSystem".to_string(),
+        client_id: "valid123".to_string(),
+        secret: "Valid123!".to_string(),
+        hub_endpoint: "https://test.com".to_string(),
+        listen_port: 8080,
+        max_body_size: 1024,
+        inline_payload_limit: 2048,
+        ..Default::default()
+    };
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Inline payload limit cannot exceed max body size"
+        )
+    );
+}
+#[test]
+fn test_validate_zero_expansion_ratio() {
+    let config = Config {
+        enabled: true,
+        system_name: "ValidSystem".to_string(),
+        client_id: "valid123".to_string(),
+        secret: "Valid123!".to_string(),
+        hub_endpoint: "https://test.com".to_string(),
+        listen_port: 8080,
+        max_expansion_ratio: 0,
+        ..Default::default()
+    };
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Max expansion ratio must be greater than zero"
+        )
+    );
+}
+#[test]
+fn test_validate_log_size() {
+    let config = Config {
+        enabled: true,
+        system_name: "ValidSystem".to_string(),
+        client_id: "valid123".to_string(),
+        secret: "Valid123!".to_string(),
+        hub_endpoint: "https://test.com".to_string(),
+        listen_port: 8080,
+        log_segment_size: 1024,
+        max_log_size: 512,
+        ..Default::default()
+    };
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Max log size cannot be less than the log segment size"
+        )
+    );
+}
+#[test]
+fn test_validate_database_write_batch_size() {
+    let config = Config {
+        enabled: true,
+        system_name: "ValidSystem".to_string(),
+        client_id: "valid123".to_string(),
+        secret: "Valid123!".to_string(),
+        hub_endpoint: "https://test.com".to_string(),
+        listen_port: 8080,
+        database: DatabaseCfg { write_batch_size: 0, ..Default::default() },
+        ..Default::default()
+    };
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Database write batch size must be greater than zero"
+        )
+    );
+}
+#[test]
+fn test_validate_unknown_active_encryption_key() {
+    let config = Config {
+        enabled: true,
+        system_name: "ValidSystem".to_string(),
+        client_id: "valid123".to_string(),
+        secret: "Valid123!".to_string(),
+        hub_endpoint: "https://test.com".to_string(),
+        listen_port: 8080,
+        encryption: Some(EncryptionCfg {
+            active_key: "k2".to_string(),
+            keys: vec![KeyCfg { id: "k1".to_string(), file: Some("k1.key".to_string()), env: None }],
+        }),
+        ..Default::default()
+    };
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Unknown active encryption key: k2"
+        )
+    );
+}
+#[test]
+fn test_validate_fan_out_without_failover_target() {
+    let config: Config = serde_json::from_str(r#"{
+        "enabled": true,
+        "system_name": "ValidSystem",
+        "client_id": "valid123",
+        "secret": "Valid123!",
+        "hub_endpoint": "https://test.com",
+        "listen_port": 8080,
+        "targets": [{ "name": "mirror", "endpoint": "https://mirror.test.com/records", "mode": "fan_out" }]
+    }"#).unwrap();
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Fan-out targets require at least one failover target"
+        )
+    );
+}
+#[test]
+fn test_validate_short_api_key() {
+    let config: Config = serde_json::from_str(r#"{
+        "enabled": true,
+        "system_name": "ValidSystem",
+        "client_id": "valid123",
+        "secret": "Valid123!",
+        "hub_endpoint": "https://test.com",
+        "listen_port": 8080,
+        "api_keys": [{ "name": "hmi", "key": "short", "roles": ["read"] }]
+    }"#).unwrap();
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "API key hmi must be at least 16 characters long"
+        )
+    );
+}
+#[test]
+fn test_validate_webhook_timeout() {
+    let config: Config = serde_json::from_str(r#"{
+        "enabled": true,
+        "system_name": "ValidSystem",
+        "client_id": "valid123",
+        "secret": "Valid123!",
+        "hub_endpoint": "https://test.com",
+        "listen_port": 8080,
+        "webhooks": { "backlog_threshold": 1000, "timeout": 0 }
+    }"#).unwrap();
+    let result = validate(&config);
+    assert!(
+        matches!(
+            result,
+            Err(ConfigError::Validation(ref s))
+            if s == "Webhook timeout must be greater than zero"
+        )
+    );
+}
+#[test]
+fn test_legacy_upload_endpoint() {
+    let config: Config = serde_json::from_str(r#"{
+        "enabled": true,
+        "system_name": "ValidSystem",
+        "client_id": "valid123",
+        "secret": "Valid123!",
+        "hub_endpoint": "https://test.com/token",
+        "upload_endpoint": "https://test.com/records",
+        "listen_port": 8080
+    }"#).unwrap();
+    assert!(validate(&config).is_ok());
+
+    let target = config.legacy_target().unwrap();
+    assert_eq!((target.name.as_str(), target.endpoint.as_str()), ("hub", "https://test.com/records"));
+    assert_eq!(target.auth_endpoint.as_deref(), Some("https://test.com/token"));
+    assert_eq!((target.client_id.as_str(), target.secret.as_str()), ("valid123", "Valid123!"));
+    assert_eq!(target.mode, TargetMode::Failover);
+
+    let config: Config = serde_json::from_str(r#"{
+        "enabled": true,
+        "system_name": "ValidSystem",
+        "client_id": "valid123",
+        "secret": "Valid123!",
+        "hub_endpoint": "https://test.com/token",
+        "upload_endpoint": "https://test.com/records",
+        "listen_port": 8080,
+        "targets": [{ "name": "primary", "endpoint": "https://primary.test.com/records" }]
+    }"#).unwrap();
+    assert!(matches!(
+        validate(&config),
+        Err(ConfigError::Validation(ref s))
+        if s == "Upload endpoint cannot be combined with targets, configure the hub as a target instead"
+    ));
+
+    // hub credentials are not needed by the targets
+    let config: Config = serde_json::from_str(r#"{
+        "enabled": true,
+        "system_name": "ValidSystem",
+        "listen_port": 8080,
+        "targets": [{ "name": "primary", "endpoint": "https://primary.test.com/records" }]
+    }"#).unwrap();
+    assert!(validate(&config).is_ok());
+    assert!(config.legacy_target().is_none());
+}
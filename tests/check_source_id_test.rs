@@ -2,25 +2,23 @@ use std::error::Error;
 use actix_web::body::to_bytes;
 use actix_web::http::header;
 use actix_web::test::TestRequest;
-use actix_web::web::Data;
 use sqlx::SqlitePool;
 use broker::api::filters::validate_source_id;
 use broker::data::db::init_db_in_memory;
-use broker::data::rep;
+use broker::data::store::{SourceStore, SqliteStore};
 use broker::models::Source;
 
-async fn setup_pool() -> Result<Data<SqlitePool>, Box<dyn Error>> {
+async fn setup_store() -> Result<SqliteStore, Box<dyn Error>> {
     let pool = init_db_in_memory().await?;
-    let res = Data::new(pool);
-    Ok(res)
+    Ok(SqliteStore::new(pool))
 }
 
 #[tokio::test]
 async fn test_validate_source_id_missing_header() -> Result<(), Box<dyn Error>> {
     let req = TestRequest::default().to_http_request();
-    let pool = Data::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+    let store = SqliteStore::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
 
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_err());
     if let Err(response) = result {
@@ -40,9 +38,9 @@ async fn test_validate_source_id_invalid_header()-> Result<(), Box<dyn Error>> {
         .insert_header((header::HeaderName::from_static("x-source-id"), vec![0xFF]))
         .to_http_request();
     
-    let pool = Data::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+    let store = SqliteStore::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
 
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_err());
     if let Err(response) = result {
@@ -62,9 +60,9 @@ async fn test_validate_source_id_empty_header() -> Result<(), Box<dyn Error>> {
     let req = TestRequest::default()
         .insert_header(("X-Source-Id", ""))
         .to_http_request();
-    let pool = Data::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+    let store = SqliteStore::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
 
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_err());
     if let Err(response) = result {
@@ -86,9 +84,9 @@ async fn test_validate_source_id_source_not_found() -> Result<(), Box<dyn Error>
         .insert_header(("X-Source-Id", "src1"))
         .to_http_request();
    
-    let pool = setup_pool().await?;
+    let store = setup_store().await?;
 
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_err());
     if let Err(response) = result {
@@ -106,7 +104,7 @@ async fn test_validate_source_id_source_not_found() -> Result<(), Box<dyn Error>
 #[tokio::test]
 async fn test_validate_source_id_source_disabled() -> Result<(), Box<dyn Error>> {
     
-    let pool = setup_pool().await?;
+    let store = setup_store().await?;
   
     let source = Source{
         src_id: "src1".to_string(),
@@ -114,13 +112,13 @@ async fn test_validate_source_id_source_disabled() -> Result<(), Box<dyn Error>>
         active: false,
     };
     
-    rep::add_source(&pool, &source).await?;
+    store.add_source(&source).await?;
     
     let req = TestRequest::default()
         .insert_header(("X-Source-Id", "src1"))
         .to_http_request();
     
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_err());
     
@@ -139,7 +137,7 @@ async fn test_validate_source_id_source_disabled() -> Result<(), Box<dyn Error>>
 #[tokio::test]
 async fn test_validate_source_id_success() -> Result<(), Box<dyn Error>>{
   
-    let pool = setup_pool().await?;
+    let store = setup_store().await?;
 
     let source = Source {
         src_id: "src1".to_string(),
@@ -147,13 +145,13 @@ async fn test_validate_source_id_success() -> Result<(), Box<dyn Error>>{
         active: true,
     };
 
-    rep::add_source(&pool, &source).await?;
+    store.add_source(&source).await?;
   
     let req = TestRequest::default()
         .insert_header(("X-Source-Id", "src1"))
         .to_http_request();
     
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_ok());
     assert_eq!(result.unwrap(), "src1");
@@ -168,18 +166,16 @@ async fn test_validate_source_id_database_error() -> Result<(), Box<dyn Error>>
         .insert_header(("X-Source-Id", "src1"))
         .to_http_request();
    
-    let pool = Data::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+    let store = SqliteStore::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
     
-    let result = validate_source_id(&req, &pool).await;
+    let result = validate_source_id(&req, &store).await;
 
     assert!(result.is_err());
     if let Err(response) = result {
         assert_eq!(response.status(), 500);
         let body_bytes = to_bytes(response.into_body()).await?;
-        assert_eq!(
-            body_bytes,
-            "error returned from database: (code: 1) no such table: sources"
-        );
+        // database details are logged, not returned
+        assert_eq!(body_bytes, "Internal storage error");
     }
 
     Ok(())
@@ -0,0 +1,181 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+use serde_json::Value;
+use broker::api::{admin, endpoints};
+use broker::common::compression::Compression;
+use broker::config::{ApiKeyCfg, Config, Role, TargetCfg, TargetMode};
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, stats};
+use broker::data::store::{self, SqliteStore};
+use broker::models::{Record, Source};
+use broker::uplink::{HubClient, Uplink};
+
+const ADMIN_KEY: &str = "admin-key-0123456789abcdef";
+
+/// Gets the config with an API key granting the admin role
+fn admin_config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "admin".to_string(), key: ADMIN_KEY.to_string(), roles: vec![Role::Admin] }],
+        ..Default::default()
+    }
+}
+
+fn admin_auth() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", ADMIN_KEY))
+}
+
+#[tokio::test]
+async fn test_compressed_records_are_read_decompressed() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let payload = "temperature=21.5;".repeat(100).into_bytes();
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: payload.clone(), compression: Some(Compression::Gzip), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: payload.clone(), compression: Some(Compression::Zstd), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: b"plain".to_vec(), ..Default::default() },
+    ]).await.unwrap();
+
+    let stored: Vec<usize> = sqlx::query_scalar::<_, Vec<u8>>("SELECT data FROM records ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|data| data.len())
+        .collect();
+    assert!(stored[0] < payload.len() && stored[1] < payload.len());
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|r| r.compression.is_none()));
+    assert_eq!(records[0].data, payload);
+    assert_eq!(records[1].data, payload);
+    assert_eq!(records[2].data, b"plain");
+
+    let stats = stats::get_compression_stats(&pool).await.unwrap();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].raw_bytes, 2 * payload.len() as i64);
+    assert_eq!(stats[0].stored_bytes, (stored[0] + stored[1]) as i64);
+    assert!(stats[0].ratio() > 1.0);
+}
+
+#[actix_web::test]
+async fn test_source_compression_and_report() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source {
+        src_id: "src1".to_string(),
+        cfg: Some(r#"{"compression": "zstd"}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(admin_config()))
+            .service(endpoints::receive_data)
+            .service(admin::compression_report),
+    ).await;
+
+    let payload = "a".repeat(1000);
+    let req = test::TestRequest::post()
+        .uri("/add")
+        .peer_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345))
+        .insert_header(("X-Source-Id", "src1"))
+        .set_payload(payload.clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records[0].data, payload.as_bytes());
+
+    let req = test::TestRequest::get().uri("/admin/compression").insert_header(admin_auth()).to_request();
+    let report: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report[0]["src_id"], "src1");
+    assert_eq!(report[0]["raw_bytes"], 1000);
+    assert!(report[0]["ratio"].as_f64().unwrap() > 1.0);
+}
+
+/// Batches received by the hub with their `Content-Encoding`
+type Received = Arc<Mutex<Vec<(Option<String>, Value)>>>;
+
+/// Starts a hub recording the `Content-Encoding` of the received batches,
+/// compressed batches are answered with 415 unless `accept_gzip`
+fn start_hub(accept_gzip: bool, received: Received) -> String {
+    let server = HttpServer::new(move || {
+        let received = received.clone();
+        App::new().route("/records", web::post().to(move |req: HttpRequest, body: web::Bytes| {
+            let encoding = req.headers()
+                .get("Content-Encoding")
+                .map(|value| value.to_str().unwrap().to_string());
+
+            let response = match encoding.as_deref() {
+                Some(_) if !accept_gzip => HttpResponse::UnsupportedMediaType().finish(),
+                // the body is decoded by the server according to the encoding
+                _ => {
+                    received.lock().unwrap().push((encoding, serde_json::from_slice(&body).unwrap()));
+                    HttpResponse::Ok().finish()
+                }
+            };
+            async move { response }
+        }))
+    })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{}/records", addr)
+}
+
+async fn deliver_gzip(accept_gzip: bool) -> Vec<(Option<String>, Value)> {
+    let received: Received = Arc::new(Mutex::new(Vec::new()));
+    let endpoint = start_hub(accept_gzip, received.clone());
+
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let config = Config { system_name: "test".to_string(), ..Default::default() };
+    let target = TargetCfg {
+        name: "hub".to_string(),
+        endpoint,
+        topic: None,
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode: TargetMode::Failover,
+        compression: Some(Compression::Gzip),
+    };
+    let uplink = Uplink::failover(pool.clone(), &config, vec![HubClient::new(&target).unwrap()]);
+
+    for data in ["a", "b"] {
+        rep::add_data(&pool, &[Record { src_id: "src1".to_string(), data: data.into(), ..Default::default() }])
+            .await
+            .unwrap();
+        assert_eq!(uplink.deliver_batch().await.unwrap(), 1);
+    }
+
+    let received = received.lock().unwrap().clone();
+    received
+}
+
+#[actix_web::test]
+async fn test_upload_batches_are_compressed() {
+    let received = deliver_gzip(true).await;
+
+    assert_eq!(received.len(), 2);
+    assert!(received.iter().all(|(encoding, _)| encoding.as_deref() == Some("gzip")));
+    assert_eq!(received[0].1["system_name"], "test");
+    assert_eq!(received[0].1["records"][0]["data"], "YQ==");
+}
+
+#[actix_web::test]
+async fn test_upload_falls_back_to_uncompressed_batches() {
+    let received = deliver_gzip(false).await;
+
+    // the rejected compressed batch is resent uncompressed and compression stays disabled
+    assert_eq!(received.len(), 2);
+    assert!(received.iter().all(|(encoding, _)| encoding.is_none()));
+}
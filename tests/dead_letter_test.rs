@@ -0,0 +1,193 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use actix_web::{test, web, App, HttpResponse, HttpServer};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use broker::api::admin;
+use broker::config::{ApiKeyCfg, Config, Role, TargetCfg, TargetMode};
+use broker::data::db::init_db_in_memory;
+use broker::data::{dead_letter, delivery, rep, target};
+use broker::models::{DeliveryOrder, Record, Source};
+use broker::uplink::{HubClient, Uplink};
+
+const ADMIN_KEY: &str = "admin-key-0123456789abcdef";
+
+/// Gets the config with an API key granting the admin role
+fn admin_config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "admin".to_string(), key: ADMIN_KEY.to_string(), roles: vec![Role::Admin] }],
+        ..Default::default()
+    }
+}
+
+fn admin_auth() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", ADMIN_KEY))
+}
+
+async fn init_pool(records: &[&str]) -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    let records: Vec<Record> = records
+        .iter()
+        .map(|data| Record { src_id: "src1".to_string(), data: data.as_bytes().to_vec(), ..Default::default() })
+        .collect();
+    rep::add_data(&pool, &records).await.unwrap();
+    pool
+}
+
+/// Starts a hub rejecting records with the "bad" payload with 400 or failing with `status` if set
+fn start_hub(status: Arc<AtomicU16>) -> String {
+    let server = HttpServer::new(move || {
+        let status = status.clone();
+        App::new().route("/records", web::post().to(move |batch: web::Json<Value>| {
+            let status = status.load(Ordering::SeqCst);
+            async move {
+                let bad = STANDARD.encode("bad");
+                let records = batch["records"].as_array().unwrap();
+                if status != 0 {
+                    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish()
+                } else if records.iter().any(|r| r["data"] == bad.as_str()) {
+                    HttpResponse::BadRequest().body("malformed record")
+                } else {
+                    HttpResponse::Ok().finish()
+                }
+            }
+        }))
+    })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{}/records", addr)
+}
+
+fn uplink(pool: &Pool<Sqlite>, endpoint: &str) -> Uplink {
+    let config = Config { system_name: "test".to_string(), max_delivery_attempts: 2, ..Default::default() };
+    let target = TargetCfg {
+        name: "hub".to_string(),
+        endpoint: endpoint.to_string(),
+        topic: None,
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode: TargetMode::Failover,
+        compression: None,
+    };
+    Uplink::failover(pool.clone(), &config, vec![HubClient::new(&target).unwrap()])
+}
+
+#[actix_web::test]
+async fn test_rejected_record_is_dead_lettered() {
+    let pool = init_pool(&["a", "bad", "c"]).await;
+    let endpoint = start_hub(Arc::new(AtomicU16::new(0)));
+
+    assert_eq!(uplink(&pool, &endpoint).deliver_batch().await.unwrap(), 2);
+
+    let letters = dead_letter::get_dead_letters(&pool, None, &10).await.unwrap();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].id, 2);
+    assert_eq!(letters[0].data, b"bad");
+    assert!(letters[0].last_error.contains("malformed record"));
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_unavailable_hub_exhausts_attempts() {
+    let pool = init_pool(&["a"]).await;
+    let status = Arc::new(AtomicU16::new(503));
+    let endpoint = start_hub(status.clone());
+    let uplink = uplink(&pool, &endpoint);
+
+    assert_eq!(uplink.deliver_batch().await.unwrap(), 0);
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records[0].attempts, 1);
+
+    // throttling is retried as well
+    status.store(429, Ordering::SeqCst);
+    assert_eq!(uplink.deliver_batch().await.unwrap(), 0);
+
+    let letters = dead_letter::get_dead_letters(&pool, None, &10).await.unwrap();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].attempts, 2);
+}
+
+#[actix_web::test]
+async fn test_requeue_and_purge() {
+    let pool = init_pool(&["a", "b", "c"]).await;
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &3, 60, 10).await.unwrap();
+    let ids: Vec<u32> = claimed.iter().map(|r| r.id).collect();
+    assert_eq!(delivery::reject(&pool, "a", &ids, "invalid").await.unwrap(), 3);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(admin_config()))
+            .service(admin::list_dead_letters)
+            .service(admin::get_dead_letter)
+            .service(admin::requeue_dead_letter)
+            .service(admin::delete_dead_letter)
+            .service(admin::purge_dead_letters),
+    ).await;
+
+    let req = test::TestRequest::get().uri("/admin/dead-letters?src_id=src1").insert_header(admin_auth()).to_request();
+    let letters: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(letters.as_array().unwrap().len(), 3);
+    assert_eq!(letters[0]["last_error"], "invalid");
+    assert!(letters[0].get("data").is_none());
+
+    let req = test::TestRequest::get().uri("/admin/dead-letters/2").insert_header(admin_auth()).to_request();
+    let letter: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(letter["data"], STANDARD.encode("b"));
+
+    let req = test::TestRequest::post().uri("/admin/dead-letters/2/requeue").insert_header(admin_auth()).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    let req = test::TestRequest::post().uri("/admin/dead-letters/2/requeue").insert_header(admin_auth()).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    // the requeued record keeps its identifier and starts over
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!((records[0].id, records[0].attempts), (2, 0));
+
+    let req = test::TestRequest::delete().uri("/admin/dead-letters/1").insert_header(admin_auth()).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let req = test::TestRequest::delete().uri("/admin/dead-letters").insert_header(admin_auth()).to_request();
+    let purged: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(purged["purged"], 1);
+}
+
+#[actix_web::test]
+async fn test_undecodable_records_are_buried_on_claim() {
+    let pool = init_pool(&["a", "b", "c", "d"]).await;
+    target::sync_targets(&pool, &[TargetCfg {
+        name: "mirror".to_string(),
+        endpoint: "http://127.0.0.1:1/records".to_string(),
+        topic: None,
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode: TargetMode::FanOut,
+        compression: None,
+    }]).await.unwrap();
+
+    // the key of the records is not configured
+    sqlx::query("UPDATE records SET key_id = 'retired' WHERE id IN (2, 4)").execute(&pool).await.unwrap();
+
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &2, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.id).collect::<Vec<u32>>(), vec![1]);
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &10, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.id).collect::<Vec<u32>>(), vec![3]);
+
+    let letters = dead_letter::get_dead_letters(&pool, None, &10).await.unwrap();
+    assert_eq!(letters.iter().map(|l| l.id).collect::<Vec<u32>>(), vec![2, 4]);
+    assert!(!letters[0].last_error.is_empty());
+
+    sqlx::query("UPDATE records SET key_id = 'retired' WHERE id = 3").execute(&pool).await.unwrap();
+    let claimed = target::claim(&pool, "mirror", "a", DeliveryOrder::Fifo, &10, 60).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.id).collect::<Vec<u32>>(), vec![1]);
+    assert_eq!(dead_letter::get_dead_letters(&pool, None, &10).await.unwrap().len(), 3);
+}
@@ -0,0 +1,125 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_http::body::MessageBody;
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, web, App, Error};
+use sqlx::{Pool, Sqlite};
+use tempfile::NamedTempFile;
+use broker::config::DatabaseCfg;
+use broker::api::endpoints;
+use broker::config::Config;
+use broker::data::db::{init_db, init_db_in_memory};
+use broker::data::dedupe::{self, Stored};
+use broker::data::rep;
+use broker::data::store::{self, SqliteStore};
+use broker::models::{Record, Source};
+
+async fn init_app(pool: Pool<Sqlite>) -> impl Service<
+    Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = Error,
+> {
+    rep::add_source(&pool, &Source {
+        src_id: "src1".to_string(),
+        cfg: None,
+        active: true,
+    }).await.unwrap();
+
+    rep::add_source(&pool, &Source {
+        src_id: "no_dedupe".to_string(),
+        cfg: Some(r#"{"dedupe_window": 0}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+
+    test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await
+}
+
+fn add_request(src_id: &str, key: Option<&str>, payload: &'static str) -> Request {
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    let mut req = test::TestRequest::post()
+        .uri("/add")
+        .peer_addr(socket_addr)
+        .insert_header(("X-Source-Id", src_id))
+        .set_payload(payload);
+    if let Some(key) = key {
+        req = req.insert_header(("Idempotency-Key", key));
+    }
+    req.to_request()
+}
+
+async fn send(app: &impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>,
+              req: Request) -> (u32, bool) {
+    let resp = test::call_service(app, req).await;
+    assert_eq!(resp.status(), 200);
+    let id = resp.headers().get("X-Record-Id").unwrap().to_str().unwrap().parse().unwrap();
+    let duplicate = resp.headers().contains_key("X-Duplicate");
+    (id, duplicate)
+}
+
+#[actix_web::test]
+async fn test_idempotency_key() {
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone()).await;
+
+    let first = send(&app, add_request("src1", Some("k1"), "a")).await;
+    // the retry has the same key even if the payload differs
+    let retry = send(&app, add_request("src1", Some("k1"), "b")).await;
+    let other = send(&app, add_request("src1", Some("k2"), "a")).await;
+
+    assert_eq!(first, (1, false));
+    assert_eq!(retry, (1, true));
+    assert_eq!(other, (2, false));
+    assert_eq!(rep::get_last_data(&pool, &10).await.unwrap().len(), 2);
+}
+
+#[actix_web::test]
+async fn test_content_hash_without_key() {
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone()).await;
+
+    assert_eq!(send(&app, add_request("src1", None, "a")).await, (1, false));
+    assert_eq!(send(&app, add_request("src1", None, "a")).await, (1, true));
+    assert_eq!(send(&app, add_request("src1", None, "b")).await, (2, false));
+
+    // deduplication is disabled for the source
+    assert_eq!(send(&app, add_request("no_dedupe", None, "a")).await, (3, false));
+    assert_eq!(send(&app, add_request("no_dedupe", None, "a")).await, (4, false));
+}
+
+#[tokio::test]
+async fn test_window_expiration() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let record = Record { src_id: "src1".to_string(), data: vec![1], ..Default::default() };
+
+    assert_eq!(dedupe::add_data_once(&pool, &record, "k", 0).await.unwrap(), Stored::Added(1));
+    // zero window has already passed
+    assert_eq!(dedupe::add_data_once(&pool, &record, "k", 60).await.unwrap(), Stored::Added(2));
+    assert_eq!(dedupe::add_data_once(&pool, &record, "k", 60).await.unwrap(), Stored::Duplicate(2));
+
+    sqlx::query("UPDATE dedupe_keys SET expires_at = 0").execute(&pool).await.unwrap();
+    assert_eq!(dedupe::delete_expired_keys(&pool).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_keys_survive_restart() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    let record = Record { src_id: "src1".to_string(), data: vec![1], ..Default::default() };
+
+    let pool = init_db(path, &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    assert_eq!(dedupe::add_data_once(&pool, &record, "k", 60).await.unwrap(), Stored::Added(1));
+    pool.close().await;
+
+    let pool = init_db(path, &DatabaseCfg::default()).await.unwrap();
+    assert_eq!(dedupe::add_data_once(&pool, &record, "k", 60).await.unwrap(), Stored::Duplicate(1));
+}
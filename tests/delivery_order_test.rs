@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use tempfile::NamedTempFile;
+use broker::config::{DatabaseCfg, TargetCfg, TargetMode};
+use broker::data::db::{init_db, init_db_in_memory};
+use broker::data::{delivery, rep, target};
+use broker::models::{Cursor, DeliveryOrder, Record, Source};
+
+fn record(src_id: &str, data: u8, priority: i64) -> Record {
+    Record { src_id: src_id.to_string(), data: vec![data], priority, ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_fifo_and_lifo_pages() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    rep::add_data(&pool, &[
+        record("src1", 1, 0),
+        record("src1", 2, 0),
+        record("src1", 3, 5),
+        record("src1", 4, 0),
+        record("src1", 5, 5),
+    ]).await.unwrap();
+
+    for (order, expected) in [
+        (DeliveryOrder::Fifo, vec![vec![3, 5], vec![1, 2], vec![4]]),
+        (DeliveryOrder::Lifo, vec![vec![5, 3], vec![4, 2], vec![1]]),
+    ] {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = rep::get_data_page(&pool, Some("src1"), order, cursor, &2).await.unwrap();
+            pages.push(page.records.iter().map(|r| r.data[0]).collect::<Vec<u8>>());
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, expected);
+    }
+}
+
+#[test]
+fn test_cursor_format() {
+    let cursor = Cursor { priority: 5, id: 42 };
+    assert_eq!(cursor.to_string(), "5.42");
+    assert_eq!("5.42".parse::<Cursor>(), Ok(cursor));
+    assert!("42".parse::<Cursor>().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_strict_per_source_order_under_concurrent_inserts() {
+    let file = NamedTempFile::new().unwrap();
+    let pool = init_db(file.path().to_str().unwrap(), &DatabaseCfg::default()).await.unwrap();
+
+    let sources = ["src1", "src2", "src3", "src4"];
+    for src_id in sources {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: None, active: true }).await.unwrap();
+    }
+
+    let count = 50_u8;
+    let tasks: Vec<_> = sources.iter().map(|src_id| {
+        let pool = pool.clone();
+        let src_id = src_id.to_string();
+        tokio::spawn(async move {
+            for n in 0..count {
+                rep::add_data(&pool, &[record(&src_id, n, 0)]).await.unwrap();
+            }
+        })
+    }).collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // reads all pages across sources and checks the order of every source
+    let mut received: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut cursor = None;
+    loop {
+        let page = rep::get_data_page(&pool, None, DeliveryOrder::Fifo, cursor, &17).await.unwrap();
+        for record in &page.records {
+            received.entry(record.src_id.clone()).or_default().push(record.data[0]);
+        }
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    let expected: Vec<u8> = (0..count).collect();
+    for src_id in sources {
+        assert_eq!(received[src_id], expected, "order of {}", src_id);
+    }
+}
+
+#[tokio::test]
+async fn test_per_source_delivery_order() {
+    let pool = init_db_in_memory().await.unwrap();
+    for (src_id, cfg) in [("fifo", None), ("lifo", Some(r#"{"delivery_order": "lifo"}"#))] {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: cfg.map(str::to_string), active: true })
+            .await
+            .unwrap();
+    }
+    target::sync_targets(&pool, &[TargetCfg {
+        name: "mirror".to_string(),
+        endpoint: "http://127.0.0.1:1/records".to_string(),
+        topic: None,
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode: TargetMode::FanOut,
+        compression: None,
+    }]).await.unwrap();
+
+    rep::add_data(&pool, &[
+        record("fifo", 1, 0),
+        record("lifo", 2, 0),
+        record("fifo", 3, 0),
+        record("lifo", 4, 0),
+        record("lifo", 5, 9),
+    ]).await.unwrap();
+
+    // the latest values of LIFO sources are not held up by the backlog of FIFO ones
+    let expected = vec![5, 4, 2, 1, 3];
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &2, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.data[0]).collect::<Vec<u8>>(), expected[..2]);
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &10, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.data[0]).collect::<Vec<u8>>(), expected[2..]);
+
+    let claimed = target::claim(&pool, "mirror", "a", DeliveryOrder::Fifo, &10, 60).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.data[0]).collect::<Vec<u8>>(), expected);
+
+    // the source order overrides the default one
+    let pool = init_db_in_memory().await.unwrap();
+    for (src_id, cfg) in [("fifo", Some(r#"{"delivery_order": "fifo"}"#)), ("lifo", None)] {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: cfg.map(str::to_string), active: true })
+            .await
+            .unwrap();
+    }
+    rep::add_data(&pool, &[record("fifo", 1, 0), record("lifo", 2, 0), record("fifo", 3, 0), record("lifo", 4, 0)])
+        .await
+        .unwrap();
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Lifo, &10, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.data[0]).collect::<Vec<u8>>(), vec![4, 2, 1, 3]);
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+use tempfile::NamedTempFile;
+use broker::config::DatabaseCfg;
+use broker::data::db::{init_db, init_db_in_memory};
+use broker::data::{dead_letter, delivery, rep};
+use broker::models::{DeliveryOrder, Record, RecordState, Source};
+
+fn record(data: u8) -> Record {
+    Record { src_id: "src1".to_string(), data: vec![data], ..Default::default() }
+}
+
+async fn states(pool: &sqlx::Pool<sqlx::Sqlite>) -> Vec<(RecordState, i64)> {
+    sqlx::query_as::<_, Record>("SELECT * FROM records ORDER BY id")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| (r.state, r.attempts))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_claim_ack_nack() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    rep::add_data(&pool, &[record(1), record(2), record(3)]).await.unwrap();
+
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &2, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.id).collect::<Vec<u32>>(), vec![1, 2]);
+    assert!(claimed.iter().all(|r| r.state == RecordState::Leased));
+
+    // leased records are not claimed twice
+    let claimed = delivery::claim(&pool, "b", DeliveryOrder::Fifo, &10, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.id).collect::<Vec<u32>>(), vec![3]);
+
+    // only the lease owner can acknowledge
+    assert_eq!(delivery::ack(&pool, "b", &[1]).await.unwrap(), 0);
+    assert_eq!(delivery::ack(&pool, "a", &[1]).await.unwrap(), 1);
+    assert_eq!(delivery::nack(&pool, "a", &[2], "timeout", 2).await.unwrap(), 1);
+    assert_eq!(delivery::nack(&pool, "b", &[3], "timeout", 1).await.unwrap(), 1);
+
+    // the record with exhausted attempts is moved to the dead letters
+    assert_eq!(states(&pool).await, vec![
+        (RecordState::Delivered, 0),
+        (RecordState::Pending, 1),
+    ]);
+
+    let sent = sqlx::query_scalar::<_, bool>("SELECT sent FROM records WHERE id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(sent);
+
+    // the nacked record is retried and fails on the last attempt
+    let claimed = delivery::claim(&pool, "a", DeliveryOrder::Fifo, &10, 60, 10).await.unwrap();
+    assert_eq!(claimed.iter().map(|r| r.id).collect::<Vec<u32>>(), vec![2]);
+    assert_eq!(claimed[0].last_error.as_deref(), Some("timeout"));
+    delivery::nack(&pool, "a", &[2], "timeout", 2).await.unwrap();
+    assert_eq!(states(&pool).await.len(), 1);
+    assert!(delivery::claim(&pool, "a", DeliveryOrder::Fifo, &10, 60, 10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_expired_leases_are_reclaimed() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    rep::add_data(&pool, &[record(1), record(2)]).await.unwrap();
+
+    // the sender crashes while holding the lease
+    delivery::claim(&pool, "a", DeliveryOrder::Fifo, &1, 60, 10).await.unwrap();
+    sqlx::query("UPDATE records SET lease_expires_at = 0 WHERE id = 1").execute(&pool).await.unwrap();
+
+    // another sender takes over the expired lease
+    let claimed = delivery::claim(&pool, "b", DeliveryOrder::Fifo, &1, 60, 10).await.unwrap();
+    assert_eq!(claimed[0].id, 1);
+    assert_eq!(delivery::ack(&pool, "a", &[1]).await.unwrap(), 0);
+    assert_eq!(delivery::ack(&pool, "b", &[1]).await.unwrap(), 1);
+
+    delivery::claim(&pool, "a", DeliveryOrder::Fifo, &1, 60, 10).await.unwrap();
+    assert_eq!(delivery::reclaim_expired(&pool, 10).await.unwrap(), 0);
+    sqlx::query("UPDATE records SET lease_expires_at = 0 WHERE id = 2").execute(&pool).await.unwrap();
+    assert_eq!(delivery::reclaim_expired(&pool, 10).await.unwrap(), 1);
+    // an expired lease counts as a failed attempt
+    assert_eq!(states(&pool).await[1], (RecordState::Pending, 1));
+}
+
+#[tokio::test]
+async fn test_records_with_expired_leases_are_dead_lettered() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    rep::add_data(&pool, &[record(1), record(2)]).await.unwrap();
+
+    // a record crashing every sender holding it is not claimed forever
+    delivery::claim(&pool, "a", DeliveryOrder::Fifo, &2, 60, 2).await.unwrap();
+    sqlx::query("UPDATE records SET lease_expires_at = 0").execute(&pool).await.unwrap();
+    let claimed = delivery::claim(&pool, "b", DeliveryOrder::Fifo, &1, 60, 2).await.unwrap();
+    assert_eq!(claimed[0].id, 1);
+    assert_eq!(states(&pool).await, vec![(RecordState::Leased, 1), (RecordState::Pending, 1)]);
+
+    sqlx::query("UPDATE records SET lease_expires_at = 0").execute(&pool).await.unwrap();
+    assert!(delivery::claim(&pool, "c", DeliveryOrder::Fifo, &10, 60, 2).await.unwrap().iter().all(|r| r.id == 2));
+    sqlx::query("UPDATE records SET lease_expires_at = 0").execute(&pool).await.unwrap();
+    assert_eq!(delivery::reclaim_expired(&pool, 2).await.unwrap(), 1);
+
+    assert!(states(&pool).await.is_empty());
+    let letters = dead_letter::get_dead_letters(&pool, None, &10).await.unwrap();
+    assert_eq!(letters.len(), 2);
+    assert!(letters.iter().all(|letter| letter.attempts == 2 && letter.last_error == "Delivery lease expired"));
+}
+
+#[tokio::test]
+async fn test_records_added_as_sent_are_delivered() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    rep::bulk_add_data(&pool, &[Record { sent: true, ..record(1) }, record(2)]).await.unwrap();
+
+    assert_eq!(states(&pool).await, vec![(RecordState::Delivered, 0), (RecordState::Pending, 0)]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_senders_do_not_share_records() {
+    let file = NamedTempFile::new().unwrap();
+    let pool = init_db(file.path().to_str().unwrap(), &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let records: Vec<Record> = (0..200).map(|n| record(n as u8)).collect();
+    rep::add_data(&pool, &records).await.unwrap();
+
+    let senders: Vec<_> = (0..4).map(|n| {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let owner = format!("sender{}", n);
+            let mut ids = Vec::new();
+            loop {
+                let claimed = delivery::claim(&pool, &owner, DeliveryOrder::Fifo, &7, 60, 10).await.unwrap();
+                if claimed.is_empty() {
+                    break;
+                }
+                let batch: Vec<u32> = claimed.iter().map(|r| r.id).collect();
+                assert_eq!(delivery::ack(&pool, &owner, &batch).await.unwrap(), batch.len() as u64);
+                ids.extend(batch);
+            }
+            ids
+        })
+    }).collect();
+
+    let mut delivered = Vec::new();
+    for sender in senders {
+        delivered.extend(sender.await.unwrap());
+    }
+
+    assert_eq!(delivered.len(), 200);
+    assert_eq!(delivered.iter().collect::<HashSet<_>>().len(), 200);
+}
@@ -0,0 +1,150 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_http::body::MessageBody;
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, web, App, Error};
+use sqlx::{Pool, Sqlite};
+use tempfile::TempDir;
+use broker::api::endpoints;
+use broker::common::compression::{compress, Compression};
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, spool};
+use broker::data::store::{self, SqliteStore};
+use broker::models::Source;
+
+async fn init_app(pool: Pool<Sqlite>, cfg: Config) -> impl Service<
+    Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = Error,
+> {
+    let sources = [
+        ("src1", None),
+        ("raw", Some(r#"{"keep_encoding": true}"#)),
+        ("raw-json", Some(r#"{"keep_encoding": true, "schema": {"format": "json", "schema": {}}}"#)),
+    ];
+    for (src_id, cfg) in sources {
+        rep::add_source(&pool, &Source {
+            src_id: src_id.to_string(),
+            cfg: cfg.map(str::to_string),
+            active: true,
+        }).await.unwrap();
+    }
+
+    test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(cfg))
+            .service(endpoints::receive_data),
+    ).await
+}
+
+fn test_config(dir: &TempDir) -> Config {
+    Config {
+        max_body_size: 64 * 1024,
+        inline_payload_limit: 16 * 1024,
+        max_expansion_ratio: 20,
+        dedupe_window: 0,
+        spool_dir: dir.path().to_string_lossy().into_owned(),
+        ..Default::default()
+    }
+}
+
+fn add_request(src_id: &str, encoding: &str, payload: Vec<u8>) -> Request {
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    test::TestRequest::post()
+        .uri("/add")
+        .peer_addr(socket_addr)
+        .insert_header(("X-Source-Id", src_id))
+        .insert_header(("Content-Encoding", encoding))
+        .set_payload(payload)
+        .to_request()
+}
+
+/// Readings compressing a few times
+fn sample_payload(count: u32) -> Vec<u8> {
+    (0..count).map(|n| format!("{},", n * n % 9973)).collect::<String>().into_bytes()
+}
+
+#[actix_web::test]
+async fn test_compressed_bodies_are_decoded() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    let payload = sample_payload(1000);
+    for compression in [Compression::Gzip, Compression::Deflate, Compression::Zstd] {
+        let body = compress(&payload, compression).unwrap();
+        let resp = test::call_service(&app, add_request("src1", compression.encoding(), body)).await;
+        assert_eq!(resp.status(), 200);
+        // the decoded size is reported
+        assert_eq!(test::read_body(resp).await, payload.len().to_string());
+    }
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records.len(), 3);
+    for record in records {
+        assert!(record.content_encoding.is_none());
+        assert_eq!(spool::load(&record).await.unwrap(), payload);
+    }
+}
+
+#[actix_web::test]
+async fn test_encoded_bodies_are_kept_as_received() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    let body = compress(&sample_payload(1000), Compression::Zstd).unwrap();
+    let resp = test::call_service(&app, add_request("raw", "zstd", body.clone())).await;
+    assert_eq!(resp.status(), 200);
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records[0].data, body);
+    assert_eq!(records[0].content_encoding, Some(Compression::Zstd));
+
+    // encoded payloads cannot be validated
+    let resp = test::call_service(&app, add_request("raw-json", "zstd", body)).await;
+    assert_eq!(resp.status(), 500);
+    let message = test::read_body(resp).await;
+    assert!(String::from_utf8_lossy(&message).contains("keep_encoding cannot be combined with a schema"));
+    assert_eq!(rep::get_last_data(&pool, &10).await.unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn test_decompression_bomb_is_rejected() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    // 256 MB of zeros compressed to a few hundred KB, sent in one chunk
+    let bomb = compress(&vec![0; 256 * 1024 * 1024], Compression::Zstd).unwrap();
+    assert!(bomb.len() < 64 * 1024);
+    let resp = test::call_service(&app, add_request("src1", "zstd", bomb)).await;
+    assert_eq!(resp.status(), 413);
+    let body = test::read_body(resp).await;
+    assert_eq!(body, "Decoded payload exceeds the max expansion ratio of 20");
+
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn test_invalid_encodings_are_rejected() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    let resp = test::call_service(&app, add_request("src1", "br", b"data".to_vec())).await;
+    assert_eq!(resp.status(), 415);
+
+    let resp = test::call_service(&app, add_request("src1", "gzip, zstd", b"data".to_vec())).await;
+    assert_eq!(resp.status(), 415);
+
+    let resp = test::call_service(&app, add_request("src1", "gzip", b"not gzip".to_vec())).await;
+    assert_eq!(resp.status(), 400);
+
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+}
@@ -0,0 +1,301 @@
+use std::io::Write;
+use sqlx::{Pool, Sqlite};
+use tempfile::NamedTempFile;
+use broker::common::compression::Compression;
+use broker::common::crypto::{self, CryptoError, KeyRing};
+use broker::config::{EncryptionCfg, KeyCfg};
+use broker::data::db::init_db_in_memory;
+use broker::data::{dead_letter, delivery, encryption, rep, spool, stats};
+use broker::data::segment_log::{self, SegmentLog};
+use broker::models::{DeliveryOrder, Record, Source};
+
+const KEY_ENV: &str = "BROKER_TEST_KEY_1";
+
+fn env_key(id: &str) -> KeyCfg {
+    std::env::set_var(KEY_ENV, "first-secret-of-the-broker");
+    KeyCfg { id: id.to_string(), file: None, env: Some(KEY_ENV.to_string()) }
+}
+
+fn file_key(id: &str, file: &NamedTempFile) -> KeyCfg {
+    KeyCfg { id: id.to_string(), file: Some(file.path().to_string_lossy().into_owned()), env: None }
+}
+
+fn key_file(secret: &str) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    writeln!(file, "{}", secret).unwrap();
+    file
+}
+
+/// Installs the key ring used by the data layer, records are encrypted with the "k1" key
+fn install_keys() {
+    let keyring = KeyRing::from_config(&EncryptionCfg { active_key: "k1".to_string(), keys: vec![env_key("k1")] });
+    let _ = crypto::install(keyring.unwrap());
+}
+
+async fn init_pool() -> Pool<Sqlite> {
+    install_keys();
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    pool
+}
+
+async fn stored(pool: &Pool<Sqlite>) -> Vec<(Vec<u8>, Option<String>)> {
+    sqlx::query_as("SELECT data, key_id FROM records ORDER BY id")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_records_are_encrypted_at_rest() {
+    let pool = init_pool().await;
+
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: b"secret reading".to_vec(), ..Default::default() },
+        Record {
+            src_id: "src1".to_string(),
+            data: b"compressed secret reading".repeat(10),
+            compression: Some(Compression::Zstd),
+            ..Default::default()
+        },
+    ]).await.unwrap();
+
+    let rows = stored(&pool).await;
+    assert!(rows.iter().all(|(_, key_id)| key_id.as_deref() == Some("k1")));
+    assert!(!rows[0].0.windows(6).any(|w| w == b"secret"));
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records[0].data, b"secret reading");
+    assert_eq!(records[1].data, b"compressed secret reading".repeat(10));
+    assert!(records.iter().all(|r| r.key_id.is_none()));
+
+    // dead letters keep the key id and are read decrypted
+    let claimed = delivery::claim(&pool, "owner", DeliveryOrder::Fifo, &1, 60, 10).await.unwrap();
+    assert_eq!(claimed[0].data, b"secret reading");
+    delivery::reject(&pool, "owner", &[claimed[0].id], "rejected").await.unwrap();
+
+    let letter = dead_letter::get_dead_letter(&pool, claimed[0].id).await.unwrap().unwrap();
+    assert_eq!(letter.data, b"secret reading");
+}
+
+#[tokio::test]
+async fn test_compressed_size_is_counted_before_encryption() {
+    let pool = init_pool().await;
+    let data = b"compressed secret reading".repeat(10);
+
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: data.clone(), compression: Some(Compression::Zstd), ..Default::default() },
+    ]).await.unwrap();
+
+    let compressed = broker::common::compression::compress(&data, Compression::Zstd).unwrap();
+    let stats = stats::get_compression_stats(&pool).await.unwrap();
+    assert_eq!(stats[0].stored_bytes, compressed.len() as i64);
+}
+
+#[tokio::test]
+async fn test_spooled_payloads_are_encrypted_at_rest() {
+    install_keys();
+    let dir = tempfile::tempdir().unwrap();
+    let data = b"secret reading ".repeat(10_000);
+
+    let chunks = data.chunks(1000).map(|chunk| Ok::<_, std::io::Error>(bytes::Bytes::copy_from_slice(chunk)));
+    let payload = spool::receive(futures::stream::iter(chunks), data.len(), 0, dir.path().to_str().unwrap())
+        .await
+        .unwrap();
+    let spool::Payload::Spooled { path, size } = &payload else {
+        panic!("payload is not spooled");
+    };
+    assert_eq!(*size, data.len());
+
+    let raw = std::fs::read(path).unwrap();
+    assert!(!raw.windows(6).any(|w| w == b"secret"));
+    assert_eq!(spool::read_file(path).await.unwrap(), data);
+
+    let record = Record { data_file: Some(path.clone()), ..Default::default() };
+    assert_eq!(spool::load(&record).await.unwrap(), data);
+    assert_eq!(spool::digest(&payload).await.unwrap(), spool::digest(&spool::Payload::Inline(data)).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_sealed_files_are_authenticated() {
+    install_keys();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("payload.bin");
+    spool::write_file(&path, &b"secret reading ".repeat(10_000)).await.unwrap();
+
+    // magic, key id and file id, then two full chunks and the final one
+    let raw = std::fs::read(&path).unwrap();
+    let header = 8 + 1 + 2 + 16;
+    let chunk = 4 + 12 + 64 * 1024 + 16;
+    let first = &raw[header..header + chunk];
+    let second = &raw[header + chunk..header + 2 * chunk];
+
+    let tampered = [
+        // cut off after a chunk
+        raw[..header + 2 * chunk].to_vec(),
+        // a chunk dropped
+        [&raw[..header + chunk], &raw[header + 2 * chunk..]].concat(),
+        // chunks reordered
+        [&raw[..header], second, first, &raw[header + 2 * chunk..]].concat(),
+    ];
+    for data in tampered {
+        std::fs::write(&path, data).unwrap();
+        assert!(matches!(spool::read_file(&path).await, Err(spool::SpoolError::Crypto(CryptoError::Tampered(_)))));
+    }
+
+    // a chunk moved from another file with the same key
+    let other = dir.path().join("other.bin");
+    spool::write_file(&other, &b"secret reading ".repeat(10_000)).await.unwrap();
+    let other_raw = std::fs::read(&other).unwrap();
+    std::fs::write(&path, [&raw[..header], &other_raw[header..header + chunk], &raw[header + chunk..]].concat()).unwrap();
+    assert!(spool::read_file(&path).await.is_err());
+
+    // plaintext files are not accepted while encryption is enabled
+    std::fs::write(&path, b"plain reading").unwrap();
+    assert!(matches!(spool::read_file(&path).await, Err(spool::SpoolError::Crypto(CryptoError::Unsealed(_)))));
+}
+
+#[tokio::test]
+async fn test_files_are_resealed_with_new_key() {
+    let pool = init_pool().await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let sealed = dir.path().join("sealed.bin");
+    spool::write_file(&sealed, b"sealed reading").await.unwrap();
+    // spooled before the encryption was enabled
+    let plain = dir.path().join("plain.bin");
+    std::fs::write(&plain, b"plain reading").unwrap();
+    let init = dir.path().join("video").join("cam1").join("init.mp4");
+    std::fs::create_dir_all(init.parent().unwrap()).unwrap();
+    spool::write_file(&init, b"init segment").await.unwrap();
+
+    for path in [&sealed, &plain] {
+        rep::add_data(&pool, &[Record {
+            src_id: "src1".to_string(),
+            data_file: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        }]).await.unwrap();
+    }
+
+    let file = key_file("second-secret-of-the-broker");
+    let keyring = KeyRing::from_config(&EncryptionCfg {
+        active_key: "k2".to_string(),
+        keys: vec![env_key("k1"), file_key("k2", &file)],
+    }).unwrap();
+
+    let video_dir = dir.path().join("video");
+    let video_dir = video_dir.to_str().unwrap();
+    assert_eq!(encryption::reseal_files(&pool, &keyring, video_dir).await.unwrap(), 3);
+    assert_eq!(encryption::reseal_files(&pool, &keyring, video_dir).await.unwrap(), 0);
+
+    // the files name the new key in their header
+    for path in [&sealed, &plain, &init] {
+        let raw = std::fs::read(path).unwrap();
+        assert_eq!(&raw[8..11], b"\x02k2");
+    }
+}
+
+#[test]
+fn test_log_frames_with_retired_keys_are_counted() {
+    install_keys();
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut log = SegmentLog::open(dir.path().join("src1"), "src1", 1024 * 1024).unwrap();
+    log.append(&[Record { src_id: "src1".to_string(), data: b"reading".to_vec(), ..Default::default() }]).unwrap();
+
+    assert_eq!(segment_log::count_not_sealed_with(dir.path(), "k1").unwrap(), 0);
+    assert_eq!(segment_log::count_not_sealed_with(dir.path(), "k2").unwrap(), 1);
+
+    // records moved to the database are re-encrypted there
+    log.set_drained(1).unwrap();
+    assert_eq!(segment_log::count_not_sealed_with(dir.path(), "k2").unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_rejected_payloads_are_encrypted_at_rest() {
+    let pool = init_pool().await;
+
+    stats::add_rejected_payload(&pool, "src1", "invalid", b"secret reading").await.unwrap();
+
+    let (data, key_id): (Vec<u8>, Option<String>) = sqlx::query_as("SELECT data, key_id FROM rejected_payloads")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(key_id.as_deref(), Some("k1"));
+    assert!(!data.windows(6).any(|w| w == b"secret"));
+
+    let samples = stats::get_rejected_payloads(&pool, "src1").await.unwrap();
+    assert_eq!(samples[0].data, b"secret reading");
+}
+
+#[test]
+fn test_log_frames_are_encrypted_at_rest() {
+    install_keys();
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut log = SegmentLog::open(dir.path(), "src1", 1024 * 1024).unwrap();
+    log.append(&[Record { src_id: "src1".to_string(), data: b"secret reading".to_vec(), ..Default::default() }])
+        .unwrap();
+    log.sync().unwrap();
+
+    for entry in std::fs::read_dir(dir.path()).unwrap() {
+        let raw = std::fs::read(entry.unwrap().path()).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+    }
+
+    let records = log.read(0, 10).unwrap();
+    assert_eq!(records[0].1.data, b"secret reading");
+    assert_eq!(records[0].1.key_id, None);
+}
+
+#[tokio::test]
+async fn test_records_are_reencrypted_with_new_key() {
+    let pool = init_pool().await;
+
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: b"a".to_vec(), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: b"b".to_vec(), ..Default::default() },
+    ]).await.unwrap();
+    // stored before the encryption was enabled
+    sqlx::query("INSERT INTO records (src_id, data, sent) VALUES ('src1', X'63', 0)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let file = key_file("second-secret-of-the-broker");
+    let keyring = KeyRing::from_config(&EncryptionCfg {
+        active_key: "k2".to_string(),
+        keys: vec![env_key("k1"), file_key("k2", &file)],
+    }).unwrap();
+
+    assert_eq!(encryption::reencrypt(&pool, &keyring).await.unwrap(), 3);
+    assert_eq!(encryption::reencrypt(&pool, &keyring).await.unwrap(), 0);
+
+    let data: Vec<Vec<u8>> = stored(&pool).await
+        .into_iter()
+        .map(|(data, key_id)| keyring.decrypt(key_id.as_deref().unwrap(), &data).unwrap())
+        .collect();
+    assert_eq!(data, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+    // the records cannot be read without the new key
+    assert!(rep::get_last_data(&pool, &10).await.is_err());
+}
+
+#[test]
+fn test_key_ring_errors() {
+    let short = key_file("short");
+    let result = KeyRing::from_config(&EncryptionCfg { active_key: "k1".to_string(), keys: vec![file_key("k1", &short)] });
+    assert!(matches!(result, Err(CryptoError::Key { .. })));
+
+    let result = KeyRing::from_config(&EncryptionCfg { active_key: "k2".to_string(), keys: vec![env_key("k1")] });
+    assert!(matches!(result, Err(CryptoError::UnknownKey(_))));
+
+    let keyring = KeyRing::from_config(&EncryptionCfg { active_key: "k1".to_string(), keys: vec![env_key("k1")] }).unwrap();
+    let mut encrypted = keyring.encrypt(b"data").unwrap();
+    assert_eq!(keyring.decrypt("k1", &encrypted).unwrap(), b"data");
+
+    let last = encrypted.len() - 1;
+    encrypted[last] ^= 1;
+    assert!(matches!(keyring.decrypt("k1", &encrypted), Err(CryptoError::Decryption(_))));
+    assert!(matches!(keyring.decrypt("k3", &encrypted), Err(CryptoError::UnknownKey(_))));
+}
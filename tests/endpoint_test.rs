@@ -1,4 +1,5 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 use actix_http::body::MessageBody;
 use actix_http::Request;
 use actix_web::dev::{Service, ServiceResponse};
@@ -7,9 +8,11 @@ use actix_web::middleware::from_fn;
 use bytes::Bytes;
 use broker::api::endpoints;
 use broker::api::filters::only_private_ip;
+use broker::config::Config;
 use broker::data::db::init_db_in_memory;
 use broker::data::rep;
-use broker::models::Source;
+use broker::data::store::{self, MemoryStore, RecordStore, SourceStore, SqliteStore};
+use broker::models::{RecordKind, Source};
 
 async fn init_app() -> impl Service<
     Request,
@@ -26,7 +29,9 @@ async fn init_app() -> impl Service<
 
     test::init_service(
         App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Config::default()))
             .wrap(from_fn(only_private_ip))
             .service(endpoints::receive_data),
     ).await
@@ -67,4 +72,37 @@ async fn test_save_data_error() {
     let resp = test::call_service(&app, http_req).await;
 
     assert_eq!(resp.status(), 500);
-}
\ No newline at end of file
+}
+#[actix_web::test]
+async fn test_records_are_stored_in_the_configured_store() {
+    let store = Arc::new(MemoryStore::new());
+    store.add_source(&Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    // no database is registered, everything goes through the store
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(store.clone()))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    let request = |seq: &str, payload: &'static str| test::TestRequest::post()
+        .uri("/add")
+        .peer_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345))
+        .insert_header(("X-Source-Id", "src1"))
+        .insert_header(("X-Sequence", seq))
+        .set_payload(payload)
+        .to_request();
+
+    for (seq, payload) in [("1", "a"), ("2", "a"), ("5", "b")] {
+        let resp = test::call_service(&app, request(seq, payload)).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    // the repeated payload is a duplicate, the gap is a diagnostic record
+    let records = store.get_last_data(&10).await.unwrap();
+    let kinds: Vec<RecordKind> = records.iter().map(|r| r.kind).collect();
+    assert_eq!(kinds, vec![RecordKind::Data, RecordKind::Data, RecordKind::Diagnostic]);
+    assert_eq!(records[0].data, b"a");
+    assert_eq!(records[1].data, b"b");
+}
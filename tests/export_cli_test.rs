@@ -0,0 +1,50 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use broker::app;
+use broker::common::crypto::KeyRing;
+use broker::config::{Config, EncryptionCfg, KeyCfg};
+use broker::data::db::init_db_in_memory;
+use broker::data::export::ExportFormat;
+use broker::data::rep;
+use broker::models::{RecordFilter, Source};
+
+const KEY_ENV: &str = "BROKER_TEST_EXPORT_KEY";
+
+fn encryption_cfg() -> EncryptionCfg {
+    std::env::set_var(KEY_ENV, "secret-of-the-exported-broker");
+    EncryptionCfg {
+        active_key: "k1".to_string(),
+        keys: vec![KeyCfg { id: "k1".to_string(), file: None, env: Some(KEY_ENV.to_string()) }],
+    }
+}
+
+#[tokio::test]
+async fn test_export_decrypts_encrypted_records() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    // stored encrypted by the broker, the export runs in a process of its own without installed keys
+    let keyring = KeyRing::from_config(&encryption_cfg()).unwrap();
+    for data in [b"first reading", b"other reading"] {
+        sqlx::query("INSERT INTO records (src_id, data, sent, key_id) VALUES ('src1', ?, 0, 'k1')")
+            .bind(keyring.encrypt(data).unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("records.jsonl");
+    let cfg = Config { encryption: Some(encryption_cfg()), ..Default::default() };
+    app::export_records(&cfg, pool, &path, ExportFormat::Jsonl, RecordFilter::default(), false).await.unwrap();
+
+    let exported: Vec<Vec<u8>> = std::fs::read_to_string(&path).unwrap()
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap();
+            STANDARD.decode(record["data"].as_str().unwrap()).unwrap()
+        })
+        .collect();
+    assert_eq!(exported, vec![b"first reading".to_vec(), b"other reading".to_vec()]);
+}
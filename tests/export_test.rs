@@ -0,0 +1,174 @@
+use actix_web::{test, web, App};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::RowAccessor;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use broker::config::{ApiKeyCfg, Config, Role};
+use broker::api::admin;
+use broker::common::compression::Compression;
+use broker::data::db::init_db_in_memory;
+use broker::data::export::{ExportFormat, Exporter};
+use broker::data::rep;
+use broker::models::{Record, RecordFilter, Source};
+
+const ADMIN_KEY: &str = "admin-key-0123456789abcdef";
+
+/// Gets the config with an API key granting the admin role
+fn admin_config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "admin".to_string(), key: ADMIN_KEY.to_string(), roles: vec![Role::Admin] }],
+        ..Default::default()
+    }
+}
+
+fn admin_auth() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", ADMIN_KEY))
+}
+
+async fn init_pool() -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    for src_id in ["src1", "src2"] {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: None, active: true }).await.unwrap();
+    }
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: b"one".to_vec(), seq: Some(1), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: b"two".to_vec(), compression: Some(Compression::Gzip), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: b"sent".to_vec(), sent: true, ..Default::default() },
+        Record { src_id: "src2".to_string(), data: b"other".to_vec(), priority: 5, ..Default::default() },
+    ]).await.unwrap();
+    pool
+}
+
+async fn export(pool: &Pool<Sqlite>, filter: RecordFilter, format: ExportFormat, mark_sent: bool) -> Vec<u8> {
+    let mut exporter = Exporter::new(pool.clone(), filter, format, mark_sent).unwrap();
+    let mut data = Vec::new();
+    while let Some(chunk) = exporter.next_chunk().await.unwrap() {
+        data.extend(chunk);
+    }
+    data
+}
+
+fn jsonl_data(export: &[u8]) -> Vec<String> {
+    String::from_utf8(export.to_vec()).unwrap()
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap();
+            String::from_utf8(STANDARD.decode(record["data"].as_str().unwrap()).unwrap()).unwrap()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_export_json_lines() {
+    let pool = init_pool().await;
+
+    let all = export(&pool, RecordFilter::default(), ExportFormat::Jsonl, false).await;
+    assert_eq!(jsonl_data(&all), vec!["one", "two", "sent", "other"]);
+
+    let filter = RecordFilter { src_id: Some("src1".to_string()), sent: Some(false), ..Default::default() };
+    let unsent = export(&pool, filter, ExportFormat::Jsonl, false).await;
+    assert_eq!(jsonl_data(&unsent), vec!["one", "two"]);
+
+    let first: Value = serde_json::from_slice(unsent.split(|b| *b == b'\n').next().unwrap()).unwrap();
+    assert_eq!(first["id"], 1);
+    assert_eq!(first["src_id"], "src1");
+    assert_eq!(first["seq"], 1);
+    assert_eq!(first["sent"], false);
+    assert!(first["created_at"].is_i64());
+}
+
+#[tokio::test]
+async fn test_export_time_range() {
+    let pool = init_pool().await;
+    for (id, created_at) in [(1, 100), (2, 200), (3, 300), (4, 400)] {
+        sqlx::query("UPDATE records SET created_at = ? WHERE id = ?").bind(created_at).bind(id).execute(&pool).await.unwrap();
+    }
+
+    let filter = RecordFilter { from: Some(200), to: Some(400), ..Default::default() };
+    let data = export(&pool, filter, ExportFormat::Jsonl, false).await;
+    assert_eq!(jsonl_data(&data), vec!["two", "sent"]);
+}
+
+#[tokio::test]
+async fn test_export_csv() {
+    let pool = init_pool().await;
+
+    let filter = RecordFilter { src_id: Some("src2".to_string()), ..Default::default() };
+    let data = String::from_utf8(export(&pool, filter, ExportFormat::Csv, false).await).unwrap();
+    let lines: Vec<&str> = data.lines().collect();
+    assert_eq!(lines[0], "id,src_id,sent,seq,priority,created_at,content_encoding,data");
+    assert!(lines[1].starts_with("4,src2,false,,5,"));
+    assert!(lines[1].ends_with(&format!(",,{}", STANDARD.encode("other"))));
+
+    // an empty export has the header only
+    let filter = RecordFilter { src_id: Some("src3".to_string()), ..Default::default() };
+    let data = String::from_utf8(export(&pool, filter, ExportFormat::Csv, false).await).unwrap();
+    assert_eq!(data.lines().count(), 1);
+}
+
+#[tokio::test]
+async fn test_export_parquet_in_row_groups() {
+    let pool = init_pool().await;
+    let records: Vec<Record> = (0..2500)
+        .map(|n| Record { src_id: "src2".to_string(), data: format!("{}", n).into_bytes(), ..Default::default() })
+        .collect();
+    rep::bulk_add_data(&pool, &records).await.unwrap();
+
+    let data = export(&pool, RecordFilter::default(), ExportFormat::Parquet, false).await;
+    let reader = SerializedFileReader::new(bytes::Bytes::from(data)).unwrap();
+
+    // a row group per page
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 2504);
+    assert_eq!(reader.num_row_groups(), 3);
+
+    let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(rows[0].get_long(0).unwrap(), 1);
+    assert_eq!(rows[0].get_string(1).unwrap(), "src1");
+    assert_eq!(rows[0].get_long(3).unwrap(), 1);
+    assert_eq!(rows[1].get_bytes(7).unwrap().data(), b"two");
+    assert!(rows[2503].get_long(3).is_err());
+    assert_eq!(rows[2503].get_bytes(7).unwrap().data(), b"2499");
+}
+
+#[tokio::test]
+async fn test_exported_records_are_marked_sent() {
+    let pool = init_pool().await;
+
+    let filter = RecordFilter { src_id: Some("src1".to_string()), ..Default::default() };
+    export(&pool, filter, ExportFormat::Jsonl, true).await;
+
+    let unsent: Vec<String> = rep::get_last_data(&pool, &10).await.unwrap()
+        .into_iter()
+        .map(|r| r.src_id)
+        .collect();
+    assert_eq!(unsent, vec!["src2"]);
+}
+
+#[actix_web::test]
+async fn test_export_endpoint() {
+    let pool = init_pool().await;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(admin_config()))
+            .service(admin::export_records),
+    ).await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/export?format=csv&sent=false&mark_sent=true")
+        .insert_header(admin_auth())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("Content-Type").unwrap(), "text/csv");
+
+    let body = test::read_body(resp).await;
+    assert_eq!(String::from_utf8(body.to_vec()).unwrap().lines().count(), 4);
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+
+    let req = test::TestRequest::get().uri("/admin/export?format=xml").insert_header(admin_auth()).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
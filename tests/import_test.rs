@@ -0,0 +1,207 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use sqlx::{Pool, Sqlite};
+use tempfile::TempDir;
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, stats};
+use broker::data::segment_log::Logs;
+use broker::ingest::import::{self, ImportReport, PROCESSED_DIR, QUARANTINE_DIR};
+use broker::models::Source;
+
+const METER_CFG: &str = r#"{
+    "import": { "pattern": "meter-*.jsonl", "delimiter": "\n" },
+    "schema": {
+        "format": "json",
+        "schema": { "type": "object", "required": ["kwh"] }
+    }
+}"#;
+
+async fn init_pool() -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    let sources = [
+        ("meter", Some(METER_CFG), true),
+        ("camera", Some(r#"{"import": {"pattern": "cam??.jpg"}}"#), true),
+        ("pump", Some(r#"{"import": {"pattern": "pump-*"}}"#), false),
+        ("sensor", Some(r#"{"import": {"pattern": "sensor-*", "delimiter": "\n", "max_file_size": 16}}"#), true),
+        ("gauge", Some(r#"{"import": {"pattern": "gauge-*", "delimiter": "\n"}, "storage": "log"}"#), true),
+        ("relay", Some(r#"{"import": {"pattern": "relay-*"}, "storage": "log", "dedupe_window": 60}"#), true),
+    ];
+    for (src_id, cfg, active) in sources {
+        let source = Source { src_id: src_id.to_string(), cfg: cfg.map(str::to_string), active };
+        rep::add_source(&pool, &source).await.unwrap();
+    }
+    pool
+}
+
+fn config(dir: &TempDir) -> Config {
+    Config {
+        import_dir: Some(dir.path().join("import").to_string_lossy().into_owned()),
+        spool_dir: dir.path().join("spool").to_string_lossy().into_owned(),
+        log_dir: dir.path().join("log").to_string_lossy().into_owned(),
+        ..Default::default()
+    }
+}
+
+fn logs(cfg: &Config) -> Arc<Logs> {
+    Arc::new(Logs::new(&cfg.log_dir, cfg.log_segment_size))
+}
+
+/// Drops the file to the import directory as if it was copied a minute ago
+fn drop_file(cfg: &Config, name: &str, data: &[u8]) {
+    let dir = Path::new(cfg.import_dir.as_ref().unwrap());
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join(name), data).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(dir.join(name))
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(60))
+        .unwrap();
+}
+
+fn moved(cfg: &Config, folder: &str) -> Vec<String> {
+    let dir = Path::new(cfg.import_dir.as_ref().unwrap()).join(folder);
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map(|entries| entries.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+async fn stored_data(pool: &Pool<Sqlite>) -> Vec<(String, Vec<u8>)> {
+    rep::get_last_data(pool, &100).await.unwrap().into_iter().map(|r| (r.src_id, r.data)).collect()
+}
+
+#[tokio::test]
+async fn test_files_are_split_to_records() {
+    let pool = init_pool().await;
+    let dir = TempDir::new().unwrap();
+    let cfg = config(&dir);
+
+    drop_file(&cfg, "meter-01.jsonl", b"{\"kwh\": 1}\n{\"kwh\": 2}\n\n{\"kwh\": 3}\n");
+    drop_file(&cfg, "cam01.jpg", &[0xff, 0xd8, 0xff]);
+
+    let report = import::scan(&pool, &cfg, &logs(&cfg)).await.unwrap();
+    assert_eq!(report, ImportReport { processed: 2, quarantined: 0, records: 4 });
+    assert_eq!(moved(&cfg, PROCESSED_DIR), vec!["cam01.jpg", "meter-01.jsonl"]);
+
+    let mut data = stored_data(&pool).await;
+    data.sort();
+    assert_eq!(data, vec![
+        ("camera".to_string(), vec![0xff, 0xd8, 0xff]),
+        ("meter".to_string(), br#"{"kwh": 1}"#.to_vec()),
+        ("meter".to_string(), br#"{"kwh": 2}"#.to_vec()),
+        ("meter".to_string(), br#"{"kwh": 3}"#.to_vec()),
+    ]);
+
+    // the same file carried in again is not stored twice
+    drop_file(&cfg, "cam01.jpg", &[0xff, 0xd8, 0xff]);
+    let report = import::scan(&pool, &cfg, &logs(&cfg)).await.unwrap();
+    assert_eq!(report, ImportReport { processed: 1, quarantined: 0, records: 0 });
+    assert_eq!(moved(&cfg, PROCESSED_DIR).len(), 3);
+    assert_eq!(stored_data(&pool).await.len(), 4);
+}
+
+#[tokio::test]
+async fn test_rejected_files_are_quarantined() {
+    let pool = init_pool().await;
+    let dir = TempDir::new().unwrap();
+    let cfg = config(&dir);
+
+    drop_file(&cfg, "meter-02.jsonl", b"{\"kwh\": 1}\n{\"volts\": 230}\n");
+    drop_file(&cfg, "pump-01.bin", b"data");
+    drop_file(&cfg, "unknown.txt", b"data");
+
+    let report = import::scan(&pool, &cfg, &logs(&cfg)).await.unwrap();
+    assert_eq!(report, ImportReport { processed: 0, quarantined: 3, records: 0 });
+
+    // nothing of a file with a rejected record is stored
+    assert!(stored_data(&pool).await.is_empty());
+    assert_eq!(stats::get_rejected_payloads(&pool, "meter").await.unwrap().len(), 1);
+
+    assert_eq!(moved(&cfg, QUARANTINE_DIR), vec![
+        "meter-02.jsonl", "meter-02.jsonl.error",
+        "pump-01.bin", "pump-01.bin.error",
+        "unknown.txt", "unknown.txt.error",
+    ]);
+
+    let quarantine = Path::new(cfg.import_dir.as_ref().unwrap()).join(QUARANTINE_DIR);
+    let error = fs::read_to_string(quarantine.join("pump-01.bin.error")).unwrap();
+    assert_eq!(error, "Source with ID pump is disabled");
+    let error = fs::read_to_string(quarantine.join("unknown.txt.error")).unwrap();
+    assert_eq!(error, "No source pattern matches the file");
+}
+
+#[tokio::test]
+async fn test_large_delimited_files_are_quarantined() {
+    let pool = init_pool().await;
+    let dir = TempDir::new().unwrap();
+    let cfg = config(&dir);
+
+    drop_file(&cfg, "sensor-01", b"1\n2\n3\n");
+    drop_file(&cfg, "sensor-02", b"1\n2\n3\n4\n5\n6\n7\n8\n9\n");
+
+    let report = import::scan(&pool, &cfg, &logs(&cfg)).await.unwrap();
+    assert_eq!(report, ImportReport { processed: 1, quarantined: 1, records: 3 });
+    assert_eq!(moved(&cfg, QUARANTINE_DIR), vec!["sensor-02", "sensor-02.error"]);
+}
+
+#[tokio::test]
+async fn test_files_being_copied_are_skipped() {
+    let pool = init_pool().await;
+    let dir = TempDir::new().unwrap();
+    let cfg = config(&dir);
+
+    let import_dir = Path::new(cfg.import_dir.as_ref().unwrap());
+    drop_file(&cfg, ".cam02.jpg.part", b"data");
+    fs::write(import_dir.join("cam02.jpg"), b"data").unwrap();
+
+    let report = import::scan(&pool, &cfg, &logs(&cfg)).await.unwrap();
+    assert_eq!(report, ImportReport::default());
+    assert!(import_dir.join("cam02.jpg").exists());
+    assert!(import_dir.join(".cam02.jpg.part").exists());
+}
+
+#[tokio::test]
+async fn test_large_files_are_spooled() {
+    let pool = init_pool().await;
+    let dir = TempDir::new().unwrap();
+    let cfg = Config { inline_payload_limit: 4, max_body_size: 8, ..config(&dir) };
+
+    drop_file(&cfg, "cam03.jpg", b"0123456");
+    drop_file(&cfg, "cam04.jpg", b"012345678");
+
+    let report = import::scan(&pool, &cfg, &logs(&cfg)).await.unwrap();
+    assert_eq!(report, ImportReport { processed: 1, quarantined: 1, records: 1 });
+
+    let record = &rep::get_last_data(&pool, &1).await.unwrap()[0];
+    assert!(record.data_file.as_ref().unwrap().starts_with(&cfg.spool_dir));
+    assert_eq!(fs::read(record.data_file.as_ref().unwrap()).unwrap(), b"0123456");
+    assert_eq!(moved(&cfg, QUARANTINE_DIR), vec!["cam04.jpg", "cam04.jpg.error"]);
+}
+
+#[tokio::test]
+async fn test_records_of_log_sources_are_appended_to_logs() {
+    let pool = init_pool().await;
+    let dir = TempDir::new().unwrap();
+    let cfg = config(&dir);
+    let logs = logs(&cfg);
+
+    drop_file(&cfg, "gauge-01", b"1\n2\n");
+    drop_file(&cfg, "relay-01", b"data");
+
+    let report = import::scan(&pool, &cfg, &logs).await.unwrap();
+    assert_eq!(report, ImportReport { processed: 1, quarantined: 1, records: 2 });
+    assert!(stored_data(&pool).await.is_empty());
+
+    let log = logs.get("gauge").unwrap();
+    let data: Vec<Vec<u8>> = log.lock().unwrap().read(0, 10).unwrap().into_iter().map(|(_, r)| r.data).collect();
+    assert_eq!(data, vec![b"1".to_vec(), b"2".to_vec()]);
+
+    let quarantine = Path::new(cfg.import_dir.as_ref().unwrap()).join(QUARANTINE_DIR);
+    let error = fs::read_to_string(quarantine.join("relay-01.error")).unwrap();
+    assert_eq!(error, "Invalid configuration of the source relay: a dedupe_window cannot be set for the log storage");
+}
@@ -0,0 +1,138 @@
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use rumqttd::{Broker, Notification};
+use sqlx::{Pool, Sqlite};
+use broker::common::compression::Compression;
+use broker::config::{Config, TargetCfg, TargetMode};
+use broker::data::db::init_db_in_memory;
+use broker::data::rep;
+use broker::models::{Record, Source};
+use broker::uplink::{HubClient, Uplink};
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Message published to the broker: topic, payload and user properties
+type Message = (String, Vec<u8>, Vec<(String, String)>);
+
+/// Starts an in-process MQTT broker, returns messages published to it
+fn start_mqtt_broker(port: u16) -> std::sync::mpsc::Receiver<Message> {
+    let config: rumqttd::Config = serde_json::from_value(serde_json::json!({
+        "id": 0,
+        "router": {
+            "max_connections": 10,
+            "max_outgoing_packet_count": 200,
+            "max_segment_size": 1048576,
+            "max_segment_count": 10
+        },
+        "v5": {
+            "1": {
+                "name": "v5-1",
+                "listen": format!("127.0.0.1:{}", port),
+                "next_connection_delay_ms": 1,
+                "connections": {
+                    "connection_timeout_ms": 5000,
+                    "max_payload_size": 20480,
+                    "max_inflight_count": 100
+                }
+            }
+        }
+    })).unwrap();
+
+    let mut broker = Broker::new(config);
+    let (mut link_tx, mut link_rx) = broker.link("observer").unwrap();
+    std::thread::spawn(move || broker.start().unwrap());
+    link_tx.subscribe("#").unwrap();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        while let Ok(notification) = link_rx.recv() {
+            if let Some(Notification::Forward(forward)) = notification {
+                let topic = String::from_utf8(forward.publish.topic.to_vec()).unwrap();
+                let properties = forward.properties.map(|p| p.user_properties).unwrap_or_default();
+                if tx.send((topic, forward.publish.payload.to_vec(), properties)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "MQTT broker is not listening");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    rx
+}
+
+async fn init_pool() -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    for src_id in ["src1", "src2"] {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: None, active: true }).await.unwrap();
+    }
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: b"a".to_vec(), ..Default::default() },
+        Record {
+            src_id: "src2".to_string(),
+            data: b"b".to_vec(),
+            seq: Some(7),
+            content_encoding: Some(Compression::Gzip),
+            ..Default::default()
+        },
+        Record { src_id: "src1".to_string(), data: b"c".to_vec(), ..Default::default() },
+    ]).await.unwrap();
+    pool
+}
+
+fn mqtt_uplink(pool: &Pool<Sqlite>, port: u16) -> Uplink {
+    let config = Config { system_name: "site1".to_string(), ..Default::default() };
+    let target = TargetCfg {
+        name: "cloud".to_string(),
+        endpoint: format!("mqtt://127.0.0.1:{}", port),
+        topic: Some("site/{system_name}/{src_id}".to_string()),
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode: TargetMode::Failover,
+        compression: None,
+    };
+    Uplink::failover(pool.clone(), &config, vec![HubClient::new(&target).unwrap()])
+}
+
+#[tokio::test]
+async fn test_publish_records_to_mqtt_broker() {
+    let port = free_port();
+    let messages = start_mqtt_broker(port);
+    let pool = init_pool().await;
+
+    assert_eq!(mqtt_uplink(&pool, port).deliver_batch().await.unwrap(), 3);
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+
+    let received: Vec<Message> = (0..3)
+        .map(|_| messages.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    let payloads: Vec<(String, Vec<u8>)> = received.iter().map(|(topic, data, _)| (topic.clone(), data.clone())).collect();
+    assert_eq!(payloads, vec![
+        ("site/site1/src1".to_string(), b"a".to_vec()),
+        ("site/site1/src2".to_string(), b"b".to_vec()),
+        ("site/site1/src1".to_string(), b"c".to_vec()),
+    ]);
+
+    // the metadata of the records goes along as user properties
+    let property = |name: &str, value: &str| (name.to_string(), value.to_string());
+    assert_eq!(received[0].2, vec![property("kind", "data")]);
+    assert_eq!(received[1].2, vec![property("kind", "data"), property("seq", "7"), property("content_encoding", "gzip")]);
+}
+
+#[tokio::test]
+async fn test_unreachable_mqtt_broker_keeps_records_unsent() {
+    let pool = init_pool().await;
+
+    assert_eq!(mqtt_uplink(&pool, free_port()).deliver_batch().await.unwrap(), 0);
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|r| r.attempts == 1 && r.last_error.as_deref().unwrap().starts_with("MQTT error")));
+}
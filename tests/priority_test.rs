@@ -0,0 +1,109 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_web::{test, web, App};
+use broker::api::endpoints;
+use broker::common::defaults::MAX_COUNT_DATA_ROWS_KEY;
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::rep;
+use broker::data::store::{self, SqliteStore};
+use broker::models::{Record, Source};
+use broker::tasks;
+
+fn record(src_id: &str, data: u8, priority: i64, sent: bool) -> Record {
+    Record { src_id: src_id.to_string(), data: vec![data], priority, sent, ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_high_priority_records_are_selected_first() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    rep::add_data(&pool, &[
+        record("src1", 1, 0, false),
+        record("src1", 2, 9, false),
+        record("src1", 3, 0, false),
+        record("src1", 4, 5, false),
+    ]).await.unwrap();
+
+    let data: Vec<u8> = rep::get_last_data(&pool, &3).await.unwrap()
+        .iter()
+        .map(|r| r.data[0])
+        .collect();
+    assert_eq!(data, vec![2, 4, 1]);
+
+    let data: Vec<u8> = rep::get_data_by_src_id(&pool, "src1", &1).await.unwrap()
+        .iter()
+        .map(|r| r.data[0])
+        .collect();
+    assert_eq!(data, vec![2]);
+}
+
+#[tokio::test]
+async fn test_low_priority_records_are_evicted_first() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    rep::add_data(&pool, &[
+        record("src1", 1, 9, true),
+        record("src1", 2, 0, false),
+        record("src1", 3, 9, false),
+        record("src1", 4, 0, false),
+        record("src1", 5, 5, false),
+    ]).await.unwrap();
+
+    sqlx::query("UPDATE settings SET value = '3' WHERE key = ?")
+        .bind(MAX_COUNT_DATA_ROWS_KEY)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(tasks::retention::enforce_max_rows(&pool).await.unwrap(), 2);
+    assert_eq!(tasks::retention::enforce_max_rows(&pool).await.unwrap(), 0);
+
+    let mut data: Vec<u8> = rep::get_last_data(&pool, &10).await.unwrap()
+        .iter()
+        .map(|r| r.data[0])
+        .collect();
+    data.sort();
+    // the sent record and the oldest routine record are evicted
+    assert_eq!(data, vec![3, 4, 5]);
+}
+
+#[actix_web::test]
+async fn test_priority_from_source_and_header() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source {
+        src_id: "safety".to_string(),
+        cfg: Some(r#"{"priority": 7}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    for (priority, payload, status) in [(None, "a", 200), (Some("9"), "b", 200), (Some("10"), "c", 400)] {
+        let mut req = test::TestRequest::post()
+            .uri("/add")
+            .peer_addr(socket_addr)
+            .insert_header(("X-Source-Id", "safety"))
+            .set_payload(payload);
+        if let Some(priority) = priority {
+            req = req.insert_header(("X-Priority", priority));
+        }
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), status);
+    }
+
+    let priorities: Vec<i64> = rep::get_last_data(&pool, &10).await.unwrap()
+        .iter()
+        .map(|r| r.priority)
+        .collect();
+    assert_eq!(priorities, vec![9, 7]);
+}
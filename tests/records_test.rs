@@ -0,0 +1,155 @@
+use actix_web::{test, web, App};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+use broker::api::records;
+use broker::common::compression::Compression;
+use broker::config::{ApiKeyCfg, Config, Role};
+use broker::data::db::init_db_in_memory;
+use broker::data::rep;
+use broker::models::{Record, RecordFilter, Source};
+
+const READER_KEY: &str = "hmi-panel-key-0001";
+const NO_ROLE_KEY: &str = "no-role-key-000001";
+
+async fn init_pool() -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    for src_id in ["src1", "src2"] {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: None, active: true }).await.unwrap();
+    }
+    rep::add_data(&pool, &[
+        Record { src_id: "src1".to_string(), data: br#"{"t": 21.5}"#.to_vec(), seq: Some(1), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: b"text".to_vec(), compression: Some(Compression::Gzip), ..Default::default() },
+        Record { src_id: "src1".to_string(), data: vec![0xff, 0x00], sent: true, ..Default::default() },
+        Record { src_id: "src2".to_string(), data: b"other".to_vec(), priority: 5, ..Default::default() },
+    ]).await.unwrap();
+    pool
+}
+
+fn config() -> Config {
+    Config {
+        api_keys: vec![
+            ApiKeyCfg { name: "hmi".to_string(), key: READER_KEY.to_string(), roles: vec![Role::Read] },
+            ApiKeyCfg { name: "none".to_string(), key: NO_ROLE_KEY.to_string(), roles: Vec::new() },
+        ],
+        ..Default::default()
+    }
+}
+
+async fn get_records(pool: &Pool<Sqlite>, uri: &str) -> Value {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(config()))
+            .service(records::query_records),
+    ).await;
+
+    let req = test::TestRequest::get()
+        .uri(uri)
+        .insert_header(("Authorization", format!("Bearer {}", READER_KEY)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    serde_json::from_slice(&test::read_body(resp).await).unwrap()
+}
+
+fn ids(page: &Value) -> Vec<u64> {
+    page["records"].as_array().unwrap().iter().map(|record| record["id"].as_u64().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn test_get_data_by_filter() {
+    let pool = init_pool().await;
+
+    let all = rep::get_data_by_filter(&pool, &RecordFilter::default(), None, &10).await.unwrap();
+    assert_eq!(all.iter().map(|stored| stored.record.id).collect::<Vec<_>>(), vec![4, 3, 2, 1]);
+    assert!(all.iter().all(|stored| stored.created_at.is_some()));
+    // stored data is decoded
+    assert_eq!(all[2].record.data, b"text");
+    assert_eq!(all[2].record.compression, None);
+
+    let filter = RecordFilter { src_id: Some("src1".to_string()), sent: Some(false), ..Default::default() };
+    let unsent = rep::get_data_by_filter(&pool, &filter, None, &10).await.unwrap();
+    assert_eq!(unsent.iter().map(|stored| stored.record.id).collect::<Vec<_>>(), vec![2, 1]);
+
+    let before = rep::get_data_by_filter(&pool, &RecordFilter::default(), Some(3), &1).await.unwrap();
+    assert_eq!(before.iter().map(|stored| stored.record.id).collect::<Vec<_>>(), vec![2]);
+
+    let created_at = all[0].created_at.unwrap();
+    let filter = RecordFilter { to: Some(created_at), ..Default::default() };
+    assert!(rep::get_data_by_filter(&pool, &filter, None, &10).await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_query_records_pages() {
+    let pool = init_pool().await;
+
+    let first = get_records(&pool, "/records?count=3").await;
+    assert_eq!(ids(&first), vec![4, 3, 2]);
+    assert_eq!(first["next_cursor"], 2);
+
+    let second = get_records(&pool, "/records?count=3&cursor=2").await;
+    assert_eq!(ids(&second), vec![1]);
+    assert_eq!(second["next_cursor"], Value::Null);
+
+    let filtered = get_records(&pool, "/records?src_id=src1&sent=true").await;
+    assert_eq!(ids(&filtered), vec![3]);
+
+    let record = &first["records"][0];
+    assert_eq!(record["src_id"], "src2");
+    assert_eq!(record["priority"], 5);
+    assert_eq!(record["sent"], false);
+    assert_eq!(record["kind"], "data");
+    assert!(record["created_at"].is_i64());
+    assert_eq!(record["encoding"], "base64");
+    assert_eq!(STANDARD.decode(record["data"].as_str().unwrap()).unwrap(), b"other");
+}
+
+#[actix_web::test]
+async fn test_query_records_raw_payloads() {
+    let pool = init_pool().await;
+
+    let page = get_records(&pool, "/records?src_id=src1&encoding=raw").await;
+    let payloads: Vec<(Value, Value)> = page["records"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|record| (record["encoding"].clone(), record["data"].clone()))
+        .collect();
+
+    assert_eq!(payloads, vec![
+        (json!("base64"), json!(STANDARD.encode([0xff, 0x00]))),
+        (json!("text"), json!("text")),
+        (json!("json"), json!({"t": 21.5})),
+    ]);
+}
+
+#[actix_web::test]
+async fn test_query_records_requires_read_role() {
+    let pool = init_pool().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(config()))
+            .service(records::query_records),
+    ).await;
+
+    let req = test::TestRequest::get().uri("/records").insert_header(("X-Source-Id", "src1")).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers().get("WWW-Authenticate").unwrap(), "Bearer");
+
+    let req = test::TestRequest::get()
+        .uri("/records")
+        .insert_header(("Authorization", "Bearer unknown-key-000001"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    let req = test::TestRequest::get()
+        .uri("/records")
+        .insert_header(("Authorization", format!("Bearer {}", NO_ROLE_KEY)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+use actix_web::body::to_bytes;
+use actix_web::ResponseError;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::Connection;
+use tempfile::TempDir;
+use broker::config::DatabaseCfg;
+use broker::data::db::init_db;
+use broker::data::error::RepError;
+use broker::data::rep;
+use broker::models::{Record, Source};
+
+#[tokio::test]
+async fn test_locked_database_is_busy() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("broker.db");
+    let pool = init_db(&path.to_string_lossy(), &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    // another writer holds the write lock
+    let mut writer = sqlx::SqliteConnection::connect_with(&SqliteConnectOptions::new().filename(&path)).await.unwrap();
+    sqlx::query("BEGIN IMMEDIATE").execute(&mut writer).await.unwrap();
+
+    let options = SqliteConnectOptions::new().filename(&path).busy_timeout(Duration::ZERO);
+    let impatient = SqlitePoolOptions::new().connect_with(options).await.unwrap();
+
+    let record = Record { src_id: "src1".to_string(), data: b"data".to_vec(), ..Default::default() };
+    let e = rep::add_data(&impatient, std::slice::from_ref(&record)).await.unwrap_err();
+    assert!(matches!(e, RepError::Busy(_)), "{:?}", e);
+    assert!(e.is_retryable());
+
+    let response = e.error_response();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+
+    // the retry succeeds when the lock is released
+    sqlx::query("ROLLBACK").execute(&mut writer).await.unwrap();
+    assert_eq!(rep::add_data(&impatient, &[record]).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_error_response_hides_details() {
+    let cases = [
+        (RepError::NotFound("source src1".to_string()), 404, "Not found"),
+        (RepError::Conflict("UNIQUE constraint failed: sources.src_id".to_string()), 409, "Already exists"),
+        (RepError::StorageFull("database or disk is full".to_string()), 507, "Storage is full"),
+        (RepError::Database("no such table: sources".to_string()), 500, "Internal storage error"),
+    ];
+
+    for (e, status, body) in cases {
+        assert!(!e.is_retryable());
+        let response = e.error_response();
+        assert_eq!(response.status(), status);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), body);
+    }
+}
+
+#[test]
+fn test_boxed_errors_are_classified() {
+    let e = RepError::from_boxed(Box::new(RepError::Busy("database is locked".to_string())));
+    assert!(e.is_retryable());
+
+    let e = RepError::from_boxed(Box::new(sqlx::Error::RowNotFound));
+    assert!(matches!(e, RepError::NotFound(_)));
+
+    let e = RepError::from_boxed("invalid state".into());
+    assert!(matches!(e, RepError::Database(_)));
+}
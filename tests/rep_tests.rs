@@ -1,266 +1,290 @@
-use broker::*;
-use sqlx::{Pool, Sqlite};
 use std::collections::HashMap;
 use std::error::Error;
 use anyhow::Result;
 use broker::common::defaults;
 use broker::data::db::init_db_in_memory;
+use broker::data::dedupe::Stored;
+use broker::data::error::RepError;
+use broker::data::store::{MemoryStore, RecordStore, SettingsStore, SourceStore, SqliteStore};
 use broker::models::{Record, Source};
 
-async fn setup_pool() -> Result<Pool<Sqlite>, Box<dyn Error>> {
+trait Store: RecordStore + SourceStore + SettingsStore {}
+
+impl<S: RecordStore + SourceStore + SettingsStore> Store for S {}
+
+/// Gets the SQLite and the in-memory stores, every test runs against both
+async fn setup_stores() -> Result<Vec<Box<dyn Store>>, Box<dyn Error>> {
     let pool = init_db_in_memory().await?;
-    Ok(pool)
+    Ok(vec![Box::new(SqliteStore::new(pool)), Box::new(MemoryStore::new())])
+}
+
+async fn add_sources(store: &dyn Store, sources: &[(&str, Option<&str>, bool)]) -> Result<(), Box<dyn Error>> {
+    for (src_id, cfg, active) in sources {
+        let source = Source { src_id: src_id.to_string(), cfg: cfg.map(str::to_string), active: *active };
+        store.add_source(&source).await?;
+    }
+    Ok(())
+}
+
+fn record(src_id: &str, data: &[u8], sent: bool) -> Record {
+    Record { id: 0, src_id: src_id.to_string(), data: data.to_vec(), sent, ..Default::default() }
 }
 
 #[tokio::test]
 async fn test_get_setting_by_key() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    
-    sqlx::query("INSERT INTO settings (key, value) VALUES ('test_key', 'test_value')")
-        .execute(&pool)
-        .await?;
-    
-    let result = get_setting_by_key(&pool, "test_key").await?;
-    assert_eq!(result, Some("test_value".to_string()));
-    
-    let result = get_setting_by_key(&pool, "non_existent").await?;
-    assert_eq!(result, None);
-    
+    for store in setup_stores().await? {
+        store.set_setting("test_key", "test_value").await?;
+
+        let result = store.get_setting_by_key("test_key").await?;
+        assert_eq!(result, Some("test_value".to_string()));
+
+        let result = store.get_setting_by_key("non_existent").await?;
+        assert_eq!(result, None);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_get_all_setting() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    sqlx::query("INSERT INTO settings (key, value) VALUES ('key1', 'val1'), ('key2', 'val2')")
-        .execute(&pool)
-        .await?;
-    let result = get_all_setting(&pool).await?;
-    let expected: HashMap<String, String> = HashMap::from([
-        ("key1".to_string(), "val1".to_string()),
-        ("key2".to_string(), "val2".to_string()),
-    ]);
-    assert_eq!(result.len(), expected.len() + 9);
+    for store in setup_stores().await? {
+        store.set_setting("key1", "val1").await?;
+        store.set_setting("key2", "val2").await?;
+        let result = store.get_all_setting().await?;
+        let expected: HashMap<String, String> = HashMap::from([
+            ("key1".to_string(), "val1".to_string()),
+            ("key2".to_string(), "val2".to_string()),
+        ]);
+        assert_eq!(result.len(), expected.len() + 9);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_get_all_setting2() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    let result = get_all_setting(&pool).await?;
-    assert_eq!(result[defaults::DESCRIPTION_KEY], "Embedded broker");
+    for store in setup_stores().await? {
+        let result = store.get_all_setting().await?;
+        assert_eq!(result[defaults::DESCRIPTION_KEY], "Embedded broker");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_set_setting_replaces_value() -> Result<(), Box<dyn Error>> {
+    for store in setup_stores().await? {
+        store.set_setting(defaults::DESCRIPTION_KEY, "Gateway").await?;
+        let result = store.get_setting_by_key(defaults::DESCRIPTION_KEY).await?;
+        assert_eq!(result, Some("Gateway".to_string()));
+        assert_eq!(store.get_all_setting().await?.len(), 9);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_get_all_sources() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    sqlx::query(
-        r#"
-                INSERT INTO sources (src_id, cfg, active)
-                VALUES ('src1', 'cfg1', 1), ('src2', NULL, 0)
-             "#
-    )
-        .execute(&pool)
-        .await?;
-    let result = get_all_sources(&pool).await?;
-    let expected = vec![
-        Source { src_id: "src1".to_string(), cfg: Some("cfg1".to_string()), active: true },
-        Source { src_id: "src2".to_string(), cfg: None, active: false },
-    ];
-    assert_eq!(result, expected);
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true), ("src2", None, false)]).await?;
+        let result = store.get_all_sources().await?;
+        let expected = vec![
+            Source { src_id: "src1".to_string(), cfg: Some("cfg1".to_string()), active: true },
+            Source { src_id: "src2".to_string(), cfg: None, active: false },
+        ];
+        assert_eq!(result, expected);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_get_source_by_name() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    sqlx::query(r#"INSERT INTO sources (src_id, cfg, active) VALUES ('src1', 'cfg1', 1)"#)
-        .execute(&pool)
-        .await?;
-    let result = get_source_by_id(&pool, "src1").await?;
-    let expected = Source { src_id: "src1".to_string(), cfg: Some("cfg1".to_string()), active: true };
-    assert_eq!(result, Some(expected));
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true)]).await?;
+        let result = store.get_source_by_id("src1").await?;
+        let expected = Source { src_id: "src1".to_string(), cfg: Some("cfg1".to_string()), active: true };
+        assert_eq!(result, Some(expected));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_duplicate_source() -> Result<(), Box<dyn Error>> {
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true)]).await?;
+        let source = Source { src_id: "src1".to_string(), cfg: None, active: false };
+        assert!(matches!(store.add_source(&source).await, Err(RepError::Conflict(_))));
+        assert!(matches!(store.update_source(Source { src_id: "src2".to_string(), ..source }).await,
+                         Err(RepError::NotFound(_))));
+        assert!(matches!(store.delete_source("src2").await, Err(RepError::NotFound(_))));
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_update_source() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    sqlx::query(r#"INSERT INTO sources (src_id, cfg, active) VALUES ('src1', 'old_cfg', 0)"#)
-        .execute(&pool)
-        .await?;
-    let updated_source = Source {
-        src_id: "src1".to_string(),
-        cfg: Some("new_cfg".to_string()),
-        active: true,
-    };
-    update_source(&pool, updated_source.clone()).await?;
-    let result = get_source_by_id(&pool, "src1").await?;
-    assert_eq!(result, Some(updated_source));
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("old_cfg"), false)]).await?;
+        let updated_source = Source {
+            src_id: "src1".to_string(),
+            cfg: Some("new_cfg".to_string()),
+            active: true,
+        };
+        store.update_source(updated_source.clone()).await?;
+        let result = store.get_source_by_id("src1").await?;
+        assert_eq!(result, Some(updated_source));
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_delete_source() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    sqlx::query(r#"INSERT INTO sources (src_id, cfg, active) VALUES ('src1', 'cfg1', 1)"#)
-        .execute(&pool)
-        .await?;
-    delete_source(&pool, "src1").await?;
-    let result= get_source_by_id(&pool, "src1").await;
-    assert!(result?.is_none());
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true), ("src2", None, true)]).await?;
+        store.add_data(&[record("src1", &[1, 2], false), record("src2", &[3, 4], false)]).await?;
+
+        store.delete_source("src1").await?;
+        let result = store.get_source_by_id("src1").await;
+        assert!(result?.is_none());
+
+        // records of the deleted source are deleted too
+        let result = store.get_last_data(&10).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].src_id, "src2");
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_get_data_by_src_id() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-
-    sqlx::query(r#"INSERT INTO sources (src_id, cfg, active) VALUES ('src1', 'cfg1', 1)"#)
-        .execute(&pool)
-        .await?;
-    
-    sqlx::query(
-        r#"
-                INSERT INTO records (src_id, data, sent)
-                VALUES ('src1', X'0102', 0), ('src1', X'0304', 0)
-             "#
-    )
-        .execute(&pool)
-        .await?;
-    let result = get_data_by_src_id(&pool, "src1", &2).await?;
-    let expected = vec![
-        Record { id: 2, src_id: "src1".to_string(), data: vec![3, 4], sent: false },
-        Record { id: 1, src_id: "src1".to_string(), data: vec![1, 2], sent: false },
-    ];
-    assert_eq!(result, expected);
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true)]).await?;
+        store.add_data(&[record("src1", &[1, 2], false), record("src1", &[3, 4], false)]).await?;
+
+        let result = store.get_data_by_src_id("src1", &2).await?;
+        let expected = vec![
+            Record { id: 1, src_id: "src1".to_string(), data: vec![1, 2], sent: false, ..Default::default() },
+            Record { id: 2, src_id: "src1".to_string(), data: vec![3, 4], sent: false, ..Default::default() },
+        ];
+        assert_eq!(result, expected);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_add_data() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-
-    sqlx::query(r#"INSERT INTO sources (src_id, cfg, active) VALUES ('src1', 'cfg1', 1)"#)
-        .execute(&pool)
-        .await?;
-    
-    let records = vec![
-        Record { id: 0, src_id: "src1".to_string(), data: vec![1, 2], sent: false },
-    ];
-    let ids = add_data(&pool, &records).await?;
-    assert_eq!(ids, vec![1]);
-    let result = get_last_data(&pool, &1).await?;
-    assert_eq!(result[0].src_id, "src1");
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true)]).await?;
+
+        let ids = store.add_data(&[record("src1", &[1, 2], false)]).await?;
+        assert_eq!(ids, vec![1]);
+        let result = store.get_last_data(&1).await?;
+        assert_eq!(result[0].src_id, "src1");
+
+        // records of unknown sources are rejected
+        let result = store.add_data(&[record("src2", &[3, 4], false)]).await;
+        assert!(matches!(result, Err(RepError::Constraint(_))));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_data_once() -> Result<(), Box<dyn Error>> {
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", None, true), ("src2", None, true)]).await?;
+
+        let Stored::Added(id) = store.add_data_once(&record("src1", &[1], false), "k1", 60).await? else {
+            panic!("the first record of the key is added");
+        };
+        assert_eq!(store.add_data_once(&record("src1", &[2], false), "k1", 60).await?, Stored::Duplicate(id));
+        // keys are used per source
+        assert!(matches!(store.add_data_once(&record("src2", &[3], false), "k1", 60).await?, Stored::Added(_)));
+
+        let result = store.get_last_data(&10).await?;
+        assert_eq!(result.iter().map(|r| r.data[0]).collect::<Vec<u8>>(), vec![1, 3]);
+
+        let result = store.add_data_once(&record("src3", &[4], false), "k1", 60).await;
+        assert!(matches!(result, Err(RepError::Constraint(_))));
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_bulk_add_data() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-
-    sqlx::query(
-        r#"
-            INSERT INTO sources (src_id, cfg, active) 
-            VALUES ('src1', 'cfg1', 1),
-                   ('src2', 'cfg2', 0)
-        "#
-    )
-        .execute(&pool)
-        .await?;
-    
-    let records = vec![
-        Record { id: 0, src_id: "src1".to_string(), data: vec![1, 2], sent: false },
-        Record { id: 0, src_id: "src2".to_string(), data: vec![3, 4], sent: false },
-    ];
-    let ids = bulk_add_data(&pool, &records).await?;
-    assert_eq!(ids.len(), 2);
-    let result = get_last_data(&pool, &2).await?;
-    assert_eq!(result.len(), 2);
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true), ("src2", Some("cfg2"), false)]).await?;
+
+        let records = vec![record("src1", &[1, 2], false), record("src2", &[3, 4], false)];
+        let ids = store.bulk_add_data(&records).await?;
+        assert_eq!(ids.len(), 2);
+        let result = store.get_last_data(&2).await?;
+        assert_eq!(result.len(), 2);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_update_data() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-
-    sqlx::query(
-        r#"
-            INSERT INTO sources (src_id, cfg, active) 
-            VALUES ('src1', 'cfg1', 1),
-                   ('src2', 'cfg2', 0)
-        "#
-    )
-        .execute(&pool)
-        .await?;
-    
-    sqlx::query(r#"INSERT INTO records (src_id, data, sent) VALUES ('src1', X'0102', 0)"#)
-        .execute(&pool)
-        .await?;
-    
-    let records = vec![
-        Record { id: 1, src_id: "src2".to_string(), data: vec![3, 4], sent: true },
-    ];
-    
-    update_data(&pool, &records).await?;
-    
-    let updated = sqlx::query_as::<_, Record>("SELECT * FROM records WHERE id = 1")
-        .fetch_one(&pool)
-        .await?;
-    
-    assert_eq!(updated.src_id, "src2");
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true), ("src2", Some("cfg2"), false)]).await?;
+        store.add_data(&[record("src1", &[1, 2], false)]).await?;
+
+        let records = vec![
+            Record { id: 1, src_id: "src2".to_string(), data: vec![3, 4], sent: false, ..Default::default() },
+        ];
+        store.update_data(&records).await?;
+
+        let updated = store.get_data_by_src_id("src2", &1).await?;
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].data, vec![3, 4]);
+
+        // sent records are not read as unsent ones
+        store.update_data(&[Record { sent: true, ..records[0].clone() }]).await?;
+        assert!(store.get_last_data(&1).await?.is_empty());
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_delete_data() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-
-    sqlx::query(
-        r#"
-            INSERT INTO sources (src_id, cfg, active) 
-            VALUES ('src1', 'cfg1', 1)
-        "#
-    )
-        .execute(&pool)
-        .await?;
-    
-    sqlx::query(r#"INSERT INTO records (src_id, data, sent) VALUES ('src1', X'0102', 0)"#)
-        .execute(&pool)
-        .await?;
-    delete_data(&pool, vec![1]).await?;
-    let result = get_last_data(&pool, &1).await?;
-    assert_eq!(result.len(), 0);
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true)]).await?;
+        store.add_data(&[record("src1", &[1, 2], false)]).await?;
+
+        store.delete_data(vec![1]).await?;
+        let result = store.get_last_data(&1).await?;
+        assert_eq!(result.len(), 0);
+    }
     Ok(())
 }
 
 #[tokio::test]
 async fn test_delete_sent_data() -> Result<(), Box<dyn Error>> {
-    let pool = setup_pool().await?;
-    
-    sqlx::query(
-        r#"
-            INSERT INTO sources (src_id, cfg, active) 
-            VALUES ('src1', 'cfg1', 1),
-                   ('src2', 'cfg2', 0)
-        "#
-    )
-        .execute(&pool)
-        .await?;
-    
-    sqlx::query(
-        r#"
-                INSERT INTO records (src_id, data, sent)
-                VALUES ('src1', X'0102', 1), ('src2', X'0304', 0)
-             "#
-   )
-        .execute(&pool)
-        .await?;
-    
-    delete_sent_data(&pool).await?;
-    let result = get_last_data(&pool, &2).await?;
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0].src_id, "src2");
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", Some("cfg1"), true), ("src2", Some("cfg2"), false)]).await?;
+        store.add_data(&[record("src1", &[1, 2], true), record("src2", &[3, 4], false)]).await?;
+
+        store.delete_sent_data().await?;
+        let result = store.get_last_data(&2).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].src_id, "src2");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_evict_data() -> Result<(), Box<dyn Error>> {
+    for store in setup_stores().await? {
+        add_sources(store.as_ref(), &[("src1", None, true)]).await?;
+        store.add_data(&[
+            Record { priority: 1, ..record("src1", &[1], false) },
+            record("src1", &[2], false),
+            record("src1", &[3], true),
+            record("src1", &[4], false),
+        ]).await?;
+
+        // the sent record goes first, then the oldest of the lowest priority
+        assert_eq!(store.evict_data(2).await?, 2);
+        let data: Vec<Vec<u8>> = store.get_last_data(&10).await?.into_iter().map(|r| r.data).collect();
+        assert_eq!(data, vec![vec![1], vec![4]]);
+
+        assert_eq!(store.evict_data(2).await?, 0);
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,144 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_web::{web, App};
+use actix_web::test::{call_service, init_service, TestRequest};
+use broker::api::endpoints;
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, stats};
+use broker::data::store::{self, SqliteStore};
+use broker::ingest::schema::{validate, SchemaError};
+use broker::models::{PayloadSchema, Source};
+
+const METER_CFG: &str = r#"{"schema": {"format": "json", "schema": {"type": "object", "required": ["kwh"]}}}"#;
+
+fn schema_of(cfg: &str) -> PayloadSchema {
+    let source = Source { src_id: "src1".to_string(), cfg: Some(cfg.to_string()), active: true };
+    source.config().unwrap().schema.unwrap()
+}
+
+#[test]
+fn test_json_schema() {
+    let schema = schema_of(r#"{
+        "schema": {
+            "format": "json",
+            "schema": {
+                "type": "object",
+                "properties": { "temp": { "type": "number" } },
+                "required": ["temp"]
+            }
+        }
+    }"#);
+
+    assert!(validate(&schema, br#"{"temp": 21.5}"#).is_ok());
+    assert!(matches!(validate(&schema, b"{temp"), Err(SchemaError::Json(_))));
+    assert!(matches!(
+        validate(&schema, br#"{"temp": "hot"}"#),
+        Err(SchemaError::JsonSchema { ref path, .. }) if path == "/temp"
+    ));
+}
+
+#[test]
+fn test_csv_schema() {
+    let schema = schema_of(r#"{
+        "schema": {
+            "format": "csv",
+            "header": true,
+            "columns": [
+                { "name": "ts", "type": "integer" },
+                { "name": "temp", "type": "number" },
+                { "name": "label" }
+            ]
+        }
+    }"#);
+
+    assert!(validate(&schema, b"ts,temp,label\n1,20.5,a\n2,21,b\n").is_ok());
+    assert_eq!(
+        validate(&schema, b"ts,temp,label\n1,20.5,a\n2,hot,b\n"),
+        Err(SchemaError::Csv { line: 3, message: "column 'temp' expected Number, got 'hot'".to_string() })
+    );
+    assert_eq!(
+        validate(&schema, b"ts,temp,label\n1,20.5\n"),
+        Err(SchemaError::Csv { line: 2, message: "expected 3 columns, got 2".to_string() })
+    );
+}
+
+#[test]
+fn test_binary_schema() {
+    let schema = schema_of(r#"{
+        "schema": {
+            "format": "binary",
+            "fields": [
+                { "name": "ts", "type": "u32" },
+                { "name": "temp", "type": "f32" },
+                { "name": "flags", "type": "u8" }
+            ]
+        }
+    }"#);
+
+    assert!(validate(&schema, &[0; 9]).is_ok());
+    assert!(matches!(validate(&schema, &[0; 8]), Err(SchemaError::Binary(_))));
+
+    let repeated = schema_of(r#"{
+        "schema": { "format": "binary", "repeated": true, "fields": [{ "name": "v", "type": "i16" }] }
+    }"#);
+
+    assert!(validate(&repeated, &[0; 6]).is_ok());
+    assert!(matches!(validate(&repeated, &[0; 5]), Err(SchemaError::Binary(_))));
+}
+
+#[actix_web::test]
+async fn test_rejected_payload_returns_422_and_is_sampled() {
+    let pool = init_db_in_memory().await.unwrap();
+
+    rep::add_source(&pool, &Source {
+        src_id: "src1".to_string(),
+        cfg: Some(r#"{"schema": {"format": "json", "schema": {"type": "array"}}}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+
+    let app = init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    for payload in ["[1, 2]", "{}", "not json"] {
+        let req = TestRequest::post()
+            .uri("/add")
+            .peer_addr(socket_addr)
+            .insert_header(("X-Source-Id", "src1"))
+            .set_payload(payload)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        let expected = if payload == "[1, 2]" { 200 } else { 422 };
+        assert_eq!(resp.status(), expected);
+    }
+
+    assert_eq!(rep::get_last_data(&pool, &10).await.unwrap().len(), 1);
+    assert_eq!(stats::get_rejected_count(&pool, "src1").await.unwrap(), 2);
+
+    let samples = stats::get_rejected_payloads(&pool, "src1").await.unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[0].data, b"not json");
+    assert!(samples[0].error.starts_with("Payload is not valid JSON"));
+}
+
+#[test]
+fn test_invalid_schema_is_rejected_with_source_config() {
+    for cfg in [
+        r#"{"schema": {"format": "json", "schema": {"type": "temperature"}}}"#,
+        r#"{"schema": {"format": "csv", "columns": [], "delimiter": "§"}}"#,
+        r#"{"schema": {"format": "binary", "fields": []}}"#,
+    ] {
+        let source = Source { src_id: "src1".to_string(), cfg: Some(cfg.to_string()), active: true };
+        let error = source.validated_config().unwrap_err();
+        assert!(error.to_string().starts_with("Invalid payload schema"), "{}", error);
+    }
+
+    let source = Source { src_id: "src1".to_string(), cfg: Some(METER_CFG.to_string()), active: true };
+    assert!(source.validated_config().is_ok());
+}
@@ -0,0 +1,255 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use actix_web::{web, App};
+use actix_web::test::{call_service, init_service, TestRequest};
+use tempfile::TempDir;
+use broker::api::endpoints;
+use broker::common::compression::Compression;
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::rep;
+use broker::data::segment_log::{Logs, SegmentLog};
+use broker::data::store::{self, SqliteStore};
+use broker::models::{Record, Source};
+use broker::tasks;
+
+fn record(n: u8) -> Record {
+    Record { src_id: "src1".to_string(), data: vec![n; 20], seq: Some(n as i64), priority: 2, ..Default::default() }
+}
+
+fn segment_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    files.sort();
+    files
+}
+
+fn read_data(log: &SegmentLog, from: u64) -> Vec<u8> {
+    log.read(from, 100).unwrap().into_iter().map(|(_, r)| r.data[0]).collect()
+}
+
+#[test]
+fn test_records_are_appended_to_segments() {
+    let dir = TempDir::new().unwrap();
+    let mut log = SegmentLog::open(dir.path(), "src1", 200).unwrap();
+
+    let records: Vec<Record> = (0..10).map(record).collect();
+    assert_eq!(log.append(&records[..4]).unwrap(), vec![0, 1, 2, 3]);
+    assert_eq!(log.append(&records[4..]).unwrap(), vec![4, 5, 6, 7, 8, 9]);
+
+    // a frame is 56 bytes, so a 200 bytes segment keeps 3 records
+    assert_eq!(log.segment_count(), 4);
+    assert_eq!(read_data(&log, 0), (0..10).collect::<Vec<u8>>());
+    assert_eq!(read_data(&log, 7), vec![7, 8, 9]);
+
+    let read = log.read(5, 1).unwrap();
+    assert_eq!(read[0].0, 5);
+    assert_eq!(read[0].1, Record { id: 0, ..record(5) });
+
+    // the log continues after reopening
+    drop(log);
+    let mut log = SegmentLog::open(dir.path(), "src1", 200).unwrap();
+    assert_eq!(log.next_offset(), 10);
+    assert_eq!(log.append(&[record(10)]).unwrap(), vec![10]);
+    assert_eq!(read_data(&log, 9), vec![9, 10]);
+}
+
+#[test]
+fn test_records_are_read_from_indexed_position() {
+    let dir = TempDir::new().unwrap();
+    let mut log = SegmentLog::open(dir.path(), "src1", 1024 * 1024).unwrap();
+    let records: Vec<Record> = (0..1000).map(|n| Record { seq: Some(n), ..record(n as u8) }).collect();
+    log.append(&records).unwrap();
+
+    let read = log.read(777, 3).unwrap();
+    assert_eq!(read.iter().map(|(offset, r)| (*offset, r.seq)).collect::<Vec<_>>(),
+               vec![(777, Some(777)), (778, Some(778)), (779, Some(779))]);
+
+    // the index of the last segment is rebuilt when the log is opened
+    drop(log);
+    let log = SegmentLog::open(dir.path(), "src1", 1024 * 1024).unwrap();
+    assert_eq!(log.read(999, 10).unwrap()[0].1.seq, Some(999));
+}
+
+#[test]
+fn test_torn_write_is_truncated() {
+    let dir = TempDir::new().unwrap();
+    let mut log = SegmentLog::open(dir.path(), "src1", 1024).unwrap();
+    log.append(&(0..3).map(record).collect::<Vec<_>>()).unwrap();
+    drop(log);
+
+    let segment = segment_files(dir.path()).pop().unwrap();
+    let size = fs::metadata(&segment).unwrap().len();
+
+    // the length and a part of the next frame reached the disk before the power loss
+    let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+    file.write_all(&[47, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap();
+    drop(file);
+
+    let mut log = SegmentLog::open(dir.path(), "src1", 1024).unwrap();
+    assert_eq!(fs::metadata(&segment).unwrap().len(), size);
+    assert_eq!(log.next_offset(), 3);
+
+    log.append(&[record(3)]).unwrap();
+    assert_eq!(read_data(&log, 0), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn test_corrupted_frame_is_truncated() {
+    let dir = TempDir::new().unwrap();
+    let mut log = SegmentLog::open(dir.path(), "src1", 1024).unwrap();
+    log.append(&(0..3).map(record).collect::<Vec<_>>()).unwrap();
+    drop(log);
+
+    // the last frame is partly written, its CRC does not match
+    let segment = segment_files(dir.path()).pop().unwrap();
+    let mut data = fs::read(&segment).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&segment, data).unwrap();
+
+    let log = SegmentLog::open(dir.path(), "src1", 1024).unwrap();
+    assert_eq!(log.next_offset(), 2);
+    assert_eq!(read_data(&log, 0), vec![0, 1]);
+}
+
+#[test]
+fn test_whole_segments_are_deleted() {
+    let dir = TempDir::new().unwrap();
+    let mut log = SegmentLog::open(dir.path(), "src1", 200).unwrap();
+    log.append(&(0..10).map(record).collect::<Vec<_>>()).unwrap();
+
+    // segments are deleted when all their records are drained
+    log.set_drained(4).unwrap();
+    assert_eq!(log.segment_count(), 3);
+    assert_eq!(read_data(&log, 0), vec![3, 4, 5, 6, 7, 8, 9]);
+
+    // the drained offset is kept after reopening
+    drop(log);
+    let mut log = SegmentLog::open(dir.path(), "src1", 200).unwrap();
+    assert_eq!(log.drained(), 4);
+
+    // the oldest segment is dropped with its undrained records 4 and 5
+    assert_eq!(log.retain(log.size() - 1).unwrap(), 2);
+    assert_eq!(log.segment_count(), 2);
+    assert_eq!(log.drained(), 6);
+    assert_eq!(segment_files(dir.path()).len(), 2);
+}
+
+#[tokio::test]
+async fn test_corrupted_sealed_segment_is_skipped() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let logs = Logs::new(dir.path(), 200);
+    let source_log = logs.get("src1").unwrap();
+    source_log.lock().unwrap().append(&(0..7).map(record).collect::<Vec<_>>()).unwrap();
+
+    // the second frame of the first sealed segment is corrupted
+    let segment = segment_files(&dir.path().join("src1")).remove(0);
+    let mut data = fs::read(&segment).unwrap();
+    data[56 + 40] ^= 0xff;
+    fs::write(&segment, data).unwrap();
+
+    // the records after the corrupted frame up to the next segment are skipped, the log is still drained
+    assert_eq!(tasks::segment_log::flush(&pool, &logs, &source_log, 1024).await.unwrap(), 5);
+    assert_eq!(tasks::segment_log::flush(&pool, &logs, &source_log, 1024).await.unwrap(), 0);
+    assert_eq!(source_log.lock().unwrap().drained(), 7);
+
+    let stored = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(stored.iter().map(|r| r.data[0]).collect::<Vec<_>>(), vec![0, 3, 4, 5, 6]);
+}
+
+#[tokio::test]
+async fn test_records_are_moved_to_delivery_queue() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let logs = Logs::new(dir.path(), 200);
+    let source_log = logs.get("src1").unwrap();
+
+    let mut records: Vec<Record> = (0..5).map(record).collect();
+    records[0].compression = Some(Compression::Gzip);
+    source_log.lock().unwrap().append(&records).unwrap();
+
+    assert_eq!(tasks::segment_log::flush(&pool, &logs, &source_log, 1024).await.unwrap(), 5);
+    assert_eq!(tasks::segment_log::flush(&pool, &logs, &source_log, 1024).await.unwrap(), 0);
+    assert_eq!(source_log.lock().unwrap().segment_count(), 1);
+
+    let stored = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(stored.iter().map(|r| r.data[0]).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    assert_eq!(stored[3].seq, Some(3));
+    assert_eq!(stored[3].priority, 2);
+    assert_eq!(stored[0].data, vec![0; 20]);
+
+    // the log of a deleted source is deleted
+    rep::delete_source(&pool, "src1").await.unwrap();
+    tasks::segment_log::flush(&pool, &logs, &source_log, 1024).await.unwrap();
+    assert!(logs.all().is_empty());
+    assert!(!dir.path().join("src1").exists());
+}
+
+#[actix_web::test]
+async fn test_log_source_records_are_appended() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source {
+        src_id: "meter".to_string(),
+        cfg: Some(r#"{"storage": "log"}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let logs = Arc::new(Logs::new(dir.path(), 1024));
+
+    let app = init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::from(logs.clone()))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    for (n, payload) in ["a", "a", "b"].iter().enumerate() {
+        let req = TestRequest::post()
+            .uri("/add")
+            .peer_addr(socket_addr)
+            .insert_header(("X-Source-Id", "meter"))
+            .set_payload(*payload)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("X-Log-Offset").unwrap(), n.to_string().as_str());
+    }
+
+    // records are not in the delivery queue until the log is drained, duplicates are kept
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+
+    let source_log: Arc<Mutex<SegmentLog>> = logs.get("meter").unwrap();
+    assert_eq!(tasks::segment_log::flush(&pool, &logs, &source_log, 1024).await.unwrap(), 3);
+
+    let data: Vec<Vec<u8>> = rep::get_last_data(&pool, &10).await.unwrap().into_iter().map(|r| r.data).collect();
+    assert_eq!(data, vec![b"a".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+
+    // records appended to a log are not deduplicated, a dedupe window of the source is rejected
+    rep::add_source(&pool, &Source {
+        src_id: "meter2".to_string(),
+        cfg: Some(r#"{"storage": "log", "dedupe_window": 60}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+    let req = TestRequest::post()
+        .uri("/add")
+        .peer_addr(socket_addr)
+        .insert_header(("X-Source-Id", "meter2"))
+        .set_payload("a")
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 500);
+}
@@ -0,0 +1,130 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_web::{test, web, App};
+use serde_json::Value;
+use broker::api::{admin, endpoints};
+use broker::config::{ApiKeyCfg, Config, Role};
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, sequence};
+use broker::data::store::{self, RecordStore, SqliteStore};
+use broker::data::writer::Writer;
+use broker::models::{Record, RecordKind, SequenceEventKind, Source};
+
+const ADMIN_KEY: &str = "admin-key-0123456789abcdef";
+
+/// Gets the config with an API key granting the admin role
+fn admin_config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "admin".to_string(), key: ADMIN_KEY.to_string(), roles: vec![Role::Admin] }],
+        ..Default::default()
+    }
+}
+
+fn admin_auth() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", ADMIN_KEY))
+}
+
+#[tokio::test]
+async fn test_track_sequence() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let mut events = Vec::new();
+    for seq in [1, 2, 5, 5, 3, 6, 0, 1] {
+        events.push(sequence::track(&pool, "src1", seq).await.unwrap());
+    }
+
+    assert_eq!(events, vec![
+        None,
+        None,
+        Some(SequenceEventKind::Gap),
+        Some(SequenceEventKind::Duplicate),
+        Some(SequenceEventKind::OutOfOrder),
+        None,
+        Some(SequenceEventKind::Reset),
+        None,
+    ]);
+
+    let states = sequence::get_sequence_states(&pool).await.unwrap();
+    assert_eq!(states.len(), 1);
+    assert_eq!(states[0].last_seq, 1);
+    assert_eq!(states[0].gaps, 1);
+    assert_eq!(states[0].missing, 2);
+    assert_eq!(states[0].duplicates, 1);
+    assert_eq!(states[0].out_of_order, 1);
+    assert_eq!(states[0].resets, 1);
+
+    let events = sequence::get_sequence_events(&pool, Some("src1"), &10).await.unwrap();
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[3].kind, SequenceEventKind::Gap);
+    assert_eq!((events[3].expected, events[3].received), (3, 5));
+
+    // the gap is forwarded to the hub as a diagnostic record
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].kind, RecordKind::Diagnostic);
+    let diagnostic: Value = serde_json::from_slice(&records[0].data).unwrap();
+    assert_eq!(diagnostic["event"], "sequence_gap");
+    assert_eq!(diagnostic["missing"], 2);
+}
+
+#[tokio::test]
+async fn test_sequence_is_tracked_with_added_records() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    let store = SqliteStore::with_writer(pool.clone(), Writer::start(pool.clone(), 16));
+
+    let record = |seq| Record { src_id: "src1".to_string(), data: vec![1], seq: Some(seq), ..Default::default() };
+    store.add_data(&[record(1), record(2)]).await.unwrap();
+    store.add_data_once(&record(4), "key", 60).await.unwrap();
+    // a duplicate key is not stored, so its number is not tracked
+    store.add_data_once(&record(7), "key", 60).await.unwrap();
+
+    let states = sequence::get_sequence_states(&pool).await.unwrap();
+    assert_eq!(states[0].last_seq, 4);
+    assert_eq!((states[0].gaps, states[0].missing), (1, 1));
+
+    let records = rep::get_data_by_src_id(&pool, "src1", &10).await.unwrap();
+    let kinds: Vec<RecordKind> = records.iter().map(|r| r.kind).collect();
+    assert_eq!(kinds, vec![RecordKind::Data, RecordKind::Data, RecordKind::Data, RecordKind::Diagnostic]);
+}
+
+#[actix_web::test]
+async fn test_sequence_header_and_report() {
+    let pool = init_db_in_memory().await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(admin_config()))
+            .service(endpoints::receive_data)
+            .service(admin::sequence_report),
+    ).await;
+
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    for (seq, payload) in [("10", "a"), ("12", "b"), ("x", "c")] {
+        let req = test::TestRequest::post()
+            .uri("/add")
+            .peer_addr(socket_addr)
+            .insert_header(("X-Source-Id", "src1"))
+            .insert_header(("X-Sequence", seq))
+            .set_payload(payload)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let expected = if seq == "x" { 400 } else { 200 };
+        assert_eq!(resp.status(), expected);
+    }
+
+    let data = rep::get_data_by_src_id(&pool, "src1", &10).await.unwrap();
+    let seqs: Vec<Option<i64>> = data.iter().filter(|r| r.kind == RecordKind::Data).map(|r| r.seq).collect();
+    assert_eq!(seqs, vec![Some(10), Some(12)]);
+
+    let req = test::TestRequest::get().uri("/admin/sequences?src_id=src1").insert_header(admin_auth()).to_request();
+    let report: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(report["sources"][0]["last_seq"], 12);
+    assert_eq!(report["sources"][0]["missing"], 1);
+    assert_eq!(report["events"][0]["kind"], "gap");
+}
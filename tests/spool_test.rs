@@ -0,0 +1,148 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_http::body::MessageBody;
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, web, App, Error};
+use bytes::Bytes;
+use futures::stream;
+use sqlx::{Pool, Sqlite};
+use tempfile::TempDir;
+use broker::api::endpoints;
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, spool};
+use broker::data::spool::{Payload, SpoolError};
+use broker::data::store::{self, SqliteStore};
+use broker::models::Source;
+
+async fn init_app(pool: Pool<Sqlite>, cfg: Config) -> impl Service<
+    Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = Error,
+> {
+    rep::add_source(&pool, &Source {
+        src_id: "src1".to_string(),
+        cfg: None,
+        active: true,
+    }).await.unwrap();
+
+    rep::add_source(&pool, &Source {
+        src_id: "small".to_string(),
+        cfg: Some(r#"{"max_body_size": 4}"#.to_string()),
+        active: true,
+    }).await.unwrap();
+
+    test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(cfg))
+            .service(endpoints::receive_data),
+    ).await
+}
+
+fn test_config(dir: &TempDir) -> Config {
+    Config {
+        max_body_size: 1024,
+        inline_payload_limit: 16,
+        spool_dir: dir.path().to_string_lossy().into_owned(),
+        ..Default::default()
+    }
+}
+
+fn add_request(src_id: &str, payload: Vec<u8>) -> Request {
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    test::TestRequest::post()
+        .uri("/add")
+        .peer_addr(socket_addr)
+        .insert_header(("X-Source-Id", src_id))
+        .set_payload(payload)
+        .to_request()
+}
+
+#[actix_web::test]
+async fn test_small_payload_is_stored_inline() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    let resp = test::call_service(&app, add_request("src1", vec![1; 8])).await;
+    assert_eq!(resp.status(), 200);
+
+    let records = rep::get_last_data(&pool, &1).await.unwrap();
+    assert_eq!(records[0].data, vec![1; 8]);
+    assert_eq!(records[0].data_file, None);
+}
+
+#[actix_web::test]
+async fn test_large_payload_is_stored_as_file() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    let resp = test::call_service(&app, add_request("src1", vec![7; 100])).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(test::read_body(resp).await, "100");
+
+    let records = rep::get_last_data(&pool, &1).await.unwrap();
+    assert!(records[0].data.is_empty());
+    assert!(records[0].data_file.is_some());
+    assert_eq!(spool::load(&records[0]).await.unwrap(), vec![7; 100]);
+
+    // deleting the record removes its payload file
+    let path = records[0].data_file.clone().unwrap();
+    rep::delete_data(&pool, vec![records[0].id]).await.unwrap();
+    assert!(!std::path::Path::new(&path).exists());
+}
+
+#[actix_web::test]
+async fn test_payload_too_large() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), test_config(&dir)).await;
+
+    let resp = test::call_service(&app, add_request("src1", vec![0; 2048])).await;
+    assert_eq!(resp.status(), 413);
+
+    // the source cfg overrides the global limit
+    let resp = test::call_service(&app, add_request("small", vec![0; 5])).await;
+    assert_eq!(resp.status(), 413);
+
+    assert!(rep::get_last_data(&pool, &10).await.unwrap().is_empty());
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_receive_chunked_stream() {
+    let dir = TempDir::new().unwrap();
+    let chunks = (0..10).map(|i| Ok::<_, SpoolError>(Bytes::from(vec![i as u8; 10])));
+
+    let payload = spool::receive(stream::iter(chunks), 1024, 16, dir.path().to_str().unwrap())
+        .await
+        .unwrap();
+
+    match payload {
+        Payload::Spooled { path, size } => {
+            assert_eq!(size, 100);
+            let data = std::fs::read(path).unwrap();
+            assert_eq!(&data[0..10], &[0; 10]);
+            assert_eq!(&data[90..100], &[9; 10]);
+        }
+        Payload::Inline(_) => panic!("Payload must be spooled"),
+    }
+}
+
+#[tokio::test]
+async fn test_interrupted_upload_is_cleaned_up() {
+    let dir = TempDir::new().unwrap();
+    let chunks = vec![
+        Ok(Bytes::from(vec![1; 32])),
+        Err(SpoolError::Payload("connection reset".to_string())),
+    ];
+
+    let result = spool::receive(stream::iter(chunks), 1024, 16, dir.path().to_str().unwrap()).await;
+
+    assert!(matches!(result, Err(SpoolError::Payload(_))));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
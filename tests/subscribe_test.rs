@@ -0,0 +1,207 @@
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::body::MessageBody;
+use actix_web::{test, web, App};
+use bytes::Bytes;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use broker::api::{endpoints, subscribe};
+use broker::config::{ApiKeyCfg, Config, LagPolicy, Role, SubscriptionCfg};
+use broker::data::db::init_db_in_memory;
+use broker::data::feed::{Feed, FeedError};
+use broker::data::rep;
+use broker::data::store::{self, SqliteStore};
+use broker::models::{Record, Source};
+use broker::tasks;
+
+const READER_KEY: &str = "dashboard-key-0001";
+
+async fn init_pool() -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    for src_id in ["src1", "src2"] {
+        rep::add_source(&pool, &Source { src_id: src_id.to_string(), cfg: None, active: true }).await.unwrap();
+    }
+    pool
+}
+
+fn record(src_id: &str, data: &str) -> Record {
+    Record { src_id: src_id.to_string(), data: data.as_bytes().to_vec(), ..Default::default() }
+}
+
+/// Stores the records and publishes them as the feed task does
+async fn store_and_publish(pool: &Pool<Sqlite>, feed: &Feed, records: &[Record]) {
+    rep::add_data(pool, records).await.unwrap();
+    feed.publish_stored(pool).await.unwrap();
+}
+
+fn config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "dashboard".to_string(), key: READER_KEY.to_string(), roles: vec![Role::Read] }],
+        ..Default::default()
+    }
+}
+
+async fn next_chunk<B>(body: &mut B) -> Bytes
+where
+    B: MessageBody + Unpin,
+    B::Error: Debug,
+{
+    futures::future::poll_fn(|cx| Pin::new(&mut *body).poll_next(cx)).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn test_subscription_resumes_from_last_id() {
+    let pool = init_pool().await;
+    let feed = Feed::new(&SubscriptionCfg::default());
+
+    store_and_publish(&pool, &feed, &[record("src1", "one"), record("src2", "other"), record("src1", "two")]).await;
+
+    let mut live = feed.subscribe(pool.clone(), None, None).await.unwrap();
+    let mut resumed = feed.subscribe(pool.clone(), Some("src1".to_string()), Some(1)).await.unwrap();
+
+    store_and_publish(&pool, &feed, &[record("src2", "three"), record("src1", "four")]).await;
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        ids.push(live.next().await.unwrap().unwrap().record.id);
+    }
+    assert_eq!(ids, vec![4, 5]);
+
+    // the stored record is followed by the live one of the source
+    let first = resumed.next().await.unwrap().unwrap();
+    assert_eq!((first.record.id, first.record.data.as_slice()), (3, b"two".as_slice()));
+    assert!(first.created_at.is_some());
+    assert_eq!(resumed.next().await.unwrap().unwrap().record.id, 5);
+}
+
+#[tokio::test]
+async fn test_lagging_subscriber() {
+    let pool = init_pool().await;
+    let records: Vec<Record> = (0..5).map(|i| record("src1", &i.to_string())).collect();
+
+    let feed = Feed::new(&SubscriptionCfg { buffer_size: 2, lag_policy: LagPolicy::Resume });
+    let mut subscription = feed.subscribe(pool.clone(), None, None).await.unwrap();
+    store_and_publish(&pool, &feed, &records).await;
+
+    // the missed records are read from the database
+    let mut ids = Vec::new();
+    for _ in 0..5 {
+        ids.push(subscription.next().await.unwrap().unwrap().record.id);
+    }
+    assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+
+    let feed = Feed::new(&SubscriptionCfg { buffer_size: 2, lag_policy: LagPolicy::Disconnect });
+    let mut subscription = feed.subscribe(pool.clone(), None, None).await.unwrap();
+    store_and_publish(&pool, &feed, &records).await;
+
+    assert!(matches!(subscription.next().await, Err(FeedError::Lagged(3))));
+}
+
+#[tokio::test]
+async fn test_records_are_published_in_order_of_ids() {
+    let pool = init_pool().await;
+    let feed = Feed::new(&SubscriptionCfg::default());
+    let mut subscription = feed.subscribe(pool.clone(), None, None).await.unwrap();
+
+    // the records stored by concurrent requests are published once, in the order of their ids
+    rep::add_data(&pool, &[record("src1", "one")]).await.unwrap();
+    rep::add_data(&pool, &[record("src1", "two")]).await.unwrap();
+    assert_eq!(feed.publish_stored(&pool).await.unwrap(), 2);
+    assert_eq!(feed.publish_stored(&pool).await.unwrap(), 0);
+
+    for id in [1, 2] {
+        assert_eq!(subscription.next().await.unwrap().unwrap().record.id, id);
+    }
+}
+
+#[tokio::test]
+async fn test_records_moved_from_log_are_published() {
+    let pool = init_pool().await;
+    let feed = Arc::new(Feed::new(&SubscriptionCfg::default()));
+    tasks::feed::start(pool.clone(), feed.clone());
+    let mut subscription = feed.subscribe(pool.clone(), Some("src1".to_string()), None).await.unwrap();
+
+    // stored without a signal as the segment log drain and the import do
+    rep::bulk_add_data(&pool, &[record("src1", "logged")]).await.unwrap();
+
+    let next = tokio::time::timeout(Duration::from_secs(5), subscription.next()).await.unwrap();
+    assert_eq!(next.unwrap().unwrap().record.data, b"logged");
+}
+
+#[actix_web::test]
+async fn test_server_sent_events() {
+    let pool = init_pool().await;
+    rep::add_data(&pool, &[record("src1", "one"), record("src1", "two")]).await.unwrap();
+
+    let feed = web::Data::new(Feed::new(&SubscriptionCfg::default()));
+    tasks::feed::start(pool.clone(), feed.clone().into_inner());
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(config()))
+            .app_data(feed.clone())
+            .service(endpoints::receive_data)
+            .service(subscribe::subscribe),
+    ).await;
+
+    let req = test::TestRequest::get()
+        .uri("/subscribe?src_id=src1&encoding=raw")
+        .insert_header(("Authorization", format!("Bearer {}", READER_KEY)))
+        .insert_header(("Last-Event-ID", "1"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("Content-Type").unwrap(), "text/event-stream");
+    let mut body = resp.into_body();
+
+    let event = String::from_utf8(next_chunk(&mut body).await.to_vec()).unwrap();
+    assert!(event.starts_with("id: 2\nevent: record\ndata: "));
+    let data: Value = serde_json::from_str(event.lines().nth(2).unwrap().strip_prefix("data: ").unwrap()).unwrap();
+    assert_eq!((data["src_id"].as_str(), data["data"].as_str()), (Some("src1"), Some("two")));
+
+    // records are pushed once /add stores them
+    for (src_id, payload) in [("src2", "other"), ("src1", "{\"t\": 1}")] {
+        let req = test::TestRequest::post()
+            .uri("/add")
+            .insert_header(("X-Source-Id", src_id))
+            .set_payload(payload)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    let event = String::from_utf8(next_chunk(&mut body).await.to_vec()).unwrap();
+    assert!(event.starts_with("id: 4\nevent: record\ndata: "));
+    let data: Value = serde_json::from_str(event.lines().nth(2).unwrap().strip_prefix("data: ").unwrap()).unwrap();
+    assert_eq!(data["data"]["t"], 1);
+    assert_eq!(data["encoding"], "json");
+}
+
+#[actix_web::test]
+async fn test_websocket_subscription() {
+    let pool = init_pool().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(config()))
+            .app_data(web::Data::new(Feed::new(&SubscriptionCfg::default())))
+            .service(subscribe::subscribe_ws),
+    ).await;
+
+    let handshake = |req: test::TestRequest| req
+        .uri("/subscribe/ws?after=0")
+        .insert_header(("Upgrade", "websocket"))
+        .insert_header(("Connection", "Upgrade"))
+        .insert_header(("Sec-WebSocket-Version", "13"))
+        .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+        .to_request();
+
+    let resp = test::call_service(&app, handshake(test::TestRequest::get())).await;
+    assert_eq!(resp.status(), 401);
+
+    let req = test::TestRequest::get().insert_header(("Authorization", format!("Bearer {}", READER_KEY)));
+    let resp = test::call_service(&app, handshake(req)).await;
+    assert_eq!(resp.status(), 101);
+}
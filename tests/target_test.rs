@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use actix_web::{test, web, App, HttpResponse, HttpServer};
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use broker::api::admin;
+use broker::config::{ApiKeyCfg, Config, Role, TargetCfg, TargetMode};
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, target};
+use broker::models::{Record, Source};
+use broker::uplink::Uplink;
+
+const ADMIN_KEY: &str = "admin-key-0123456789abcdef";
+
+/// Gets the config with an API key granting the admin role
+fn admin_config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "admin".to_string(), key: ADMIN_KEY.to_string(), roles: vec![Role::Admin] }],
+        ..Default::default()
+    }
+}
+
+fn admin_auth() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", ADMIN_KEY))
+}
+
+/// Test hub answering with `status` and counting received records
+struct Hub {
+    status: Arc<AtomicU16>,
+    received: Arc<AtomicUsize>,
+    endpoint: String,
+}
+
+fn start_hub(status: u16) -> Hub {
+    let status = Arc::new(AtomicU16::new(status));
+    let received = Arc::new(AtomicUsize::new(0));
+
+    let (hub_status, hub_received) = (status.clone(), received.clone());
+    let server = HttpServer::new(move || {
+        let (status, received) = (hub_status.clone(), hub_received.clone());
+        App::new().route("/records", web::post().to(move |batch: web::Json<Value>| {
+            let status = status.load(Ordering::SeqCst);
+            if status == 200 {
+                received.fetch_add(batch["records"].as_array().unwrap().len(), Ordering::SeqCst);
+            }
+            async move {
+                HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish()
+            }
+        }))
+    })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+    let endpoint = format!("http://{}/records", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    Hub { status, received, endpoint }
+}
+
+fn target_cfg(name: &str, hub: &Hub, mode: TargetMode) -> TargetCfg {
+    TargetCfg {
+        name: name.to_string(),
+        endpoint: hub.endpoint.clone(),
+        topic: None,
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode,
+        compression: None,
+    }
+}
+
+async fn init(targets: Vec<TargetCfg>, count: u8) -> (Pool<Sqlite>, Config) {
+    let pool = init_db_in_memory().await.unwrap();
+    let config = Config { system_name: "test".to_string(), targets, ..Default::default() };
+    target::sync_targets(&pool, &config.targets).await.unwrap();
+
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    let records: Vec<Record> = (0..count)
+        .map(|n| Record { src_id: "src1".to_string(), data: vec![n], ..Default::default() })
+        .collect();
+    rep::add_data(&pool, &records).await.unwrap();
+
+    (pool, config)
+}
+
+async fn unsent(pool: &Pool<Sqlite>) -> usize {
+    rep::get_last_data(pool, &100).await.unwrap().len()
+}
+
+#[actix_web::test]
+async fn test_failover_to_secondary() {
+    let primary = start_hub(503);
+    let secondary = start_hub(200);
+    let (pool, config) = init(vec![
+        target_cfg("primary", &primary, TargetMode::Failover),
+        target_cfg("secondary", &secondary, TargetMode::Failover),
+    ], 3).await;
+
+    let uplinks = Uplink::from_config(pool.clone(), &config).unwrap();
+    assert_eq!(uplinks.len(), 1);
+
+    assert_eq!(uplinks[0].deliver_batch().await.unwrap(), 3);
+    assert_eq!(secondary.received.load(Ordering::SeqCst), 3);
+    assert_eq!(unsent(&pool).await, 0);
+
+    let stats = target::get_target_stats(&pool).await.unwrap();
+    assert_eq!(stats[0].name, "primary");
+    assert!(stats[0].last_success_at.is_none());
+    assert!(stats[0].last_error.as_ref().unwrap().contains("503"));
+    assert!(stats[1].last_success_at.is_some());
+}
+
+#[actix_web::test]
+async fn test_fan_out_tracks_delivery_per_target() {
+    let primary = start_hub(200);
+    let mirror = start_hub(503);
+    let (pool, config) = init(vec![
+        target_cfg("primary", &primary, TargetMode::Failover),
+        target_cfg("mirror", &mirror, TargetMode::FanOut),
+    ], 3).await;
+
+    let uplinks = Uplink::from_config(pool.clone(), &config).unwrap();
+    assert_eq!(uplinks.len(), 2);
+
+    assert_eq!(uplinks[0].deliver_batch().await.unwrap(), 3);
+    assert_eq!(uplinks[1].deliver_batch().await.unwrap(), 0);
+
+    // records delivered to the primary wait for the mirror before being marked as sent
+    assert_eq!(unsent(&pool).await, 3);
+
+    let stats = target::get_target_stats(&pool).await.unwrap();
+    assert_eq!((stats[0].name.as_str(), stats[0].backlog), ("primary", 0));
+    assert_eq!((stats[1].name.as_str(), stats[1].backlog), ("mirror", 3));
+
+    mirror.status.store(200, Ordering::SeqCst);
+    assert_eq!(uplinks[1].deliver_batch().await.unwrap(), 3);
+    assert_eq!(primary.received.load(Ordering::SeqCst), 3);
+    assert_eq!(mirror.received.load(Ordering::SeqCst), 3);
+    assert_eq!(unsent(&pool).await, 0);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(admin_config()))
+            .service(admin::target_report),
+    ).await;
+    let req = test::TestRequest::get().uri("/admin/targets").insert_header(admin_auth()).to_request();
+    let report: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report[1]["mode"], "fan_out");
+    assert_eq!(report[1]["backlog"], 0);
+    assert!(report[1]["last_success_at"].is_i64());
+}
+
+#[actix_web::test]
+async fn test_new_fan_out_target_receives_unsent_records() {
+    let primary = start_hub(200);
+    let mirror = start_hub(200);
+    let (pool, mut config) = init(vec![target_cfg("primary", &primary, TargetMode::Failover)], 2).await;
+
+    config.targets.push(target_cfg("mirror", &mirror, TargetMode::FanOut));
+    target::sync_targets(&pool, &config.targets).await.unwrap();
+
+    let stats = target::get_target_stats(&pool).await.unwrap();
+    assert_eq!(stats[1].backlog, 2);
+
+    // removing the target releases the records
+    config.targets.pop();
+    target::sync_targets(&pool, &config.targets).await.unwrap();
+    let uplinks = Uplink::from_config(pool.clone(), &config).unwrap();
+    assert_eq!(uplinks[0].deliver_batch().await.unwrap(), 2);
+    assert_eq!(unsent(&pool).await, 0);
+}
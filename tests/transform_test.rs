@@ -0,0 +1,143 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_web::{web, App};
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use serde_json::{json, Value};
+use broker::api::endpoints;
+use broker::config::Config;
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, stats};
+use broker::data::store::{self, SqliteStore};
+use broker::ingest::transform::{self, Pipeline, Transform, TransformCfg, TransformError};
+
+fn pipeline(cfg: Value) -> Pipeline {
+    let cfg: Vec<TransformCfg> = serde_json::from_value(cfg).unwrap();
+    Pipeline::build(&cfg).unwrap()
+}
+
+fn apply(pipeline: &Pipeline, data: &[u8]) -> Option<Value> {
+    pipeline.apply(data.to_vec())
+        .unwrap()
+        .map(|data| serde_json::from_slice(&data).unwrap())
+}
+
+#[test]
+fn test_csv_to_json_with_scaling_and_renaming() {
+    let pipeline = pipeline(json!([
+        { "type": "csv_to_json" },
+        { "type": "scale", "path": "/*/temp", "factor": 0.1, "offset": -10 },
+        { "type": "rename", "path": "/*/t", "to": "ts" },
+        { "type": "drop", "paths": ["/*/noise"] }
+    ]));
+
+    let result = apply(&pipeline, b"t,temp,noise\n1,250,x\n2,300,y\n");
+
+    assert_eq!(result, Some(json!([
+        { "ts": 1, "temp": 15.0 },
+        { "ts": 2, "temp": 20.0 }
+    ])));
+}
+
+#[test]
+fn test_extract() {
+    let pipeline = pipeline(json!([{ "type": "extract", "path": "/payload/values" }]));
+    let result = apply(&pipeline, br#"{"header": {}, "payload": {"values": [1, 2]}}"#);
+    assert_eq!(result, Some(json!([1, 2])));
+}
+
+#[test]
+fn test_filter() {
+    let items = pipeline(json!([{ "type": "filter", "path": "/level", "op": "ge", "value": 2 }]));
+    let result = apply(&items, br#"[{"level": 1}, {"level": 2}, {"level": 3}]"#);
+    assert_eq!(result, Some(json!([{ "level": 2 }, { "level": 3 }])));
+
+    let document = pipeline(json!([{ "type": "filter", "path": "/kind", "op": "ne", "value": "heartbeat" }]));
+    assert_eq!(apply(&document, br#"{"kind": "heartbeat"}"#), None);
+    assert_eq!(apply(&document, br#"{"kind": "alarm"}"#), Some(json!({ "kind": "alarm" })));
+}
+
+#[test]
+fn test_error_reports_stage_name() {
+    let pipeline = pipeline(json!([
+        { "type": "extract", "path": "/data" },
+        { "type": "scale", "path": "/value", "factor": 2 }
+    ]));
+
+    let result = pipeline.apply(br#"{"data": {"value": "high"}}"#.to_vec());
+
+    assert_eq!(result, Err(TransformError {
+        stage: "scale".to_string(),
+        message: "value at /value is not a number".to_string(),
+    }));
+}
+
+struct Uppercase;
+
+impl Transform for Uppercase {
+    fn name(&self) -> &str {
+        "uppercase"
+    }
+
+    fn apply(&self, data: Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+        Ok(Some(data.to_ascii_uppercase()))
+    }
+}
+
+#[test]
+fn test_custom_stage() {
+    transform::register("uppercase", |_| Ok(Box::new(Uppercase)));
+
+    let pipeline = pipeline(json!([{ "type": "custom", "name": "uppercase" }]));
+    assert_eq!(pipeline.apply(b"abc".to_vec()), Ok(Some(b"ABC".to_vec())));
+
+    let unknown: Vec<TransformCfg> = serde_json::from_value(json!([{ "type": "custom", "name": "unknown" }])).unwrap();
+    assert!(Pipeline::build(&unknown).is_err());
+}
+
+#[actix_web::test]
+async fn test_payload_is_transformed_before_storing() {
+    let pool = init_db_in_memory().await.unwrap();
+
+    rep::add_source(&pool, &broker::models::Source {
+        src_id: "src1".to_string(),
+        cfg: Some(json!({
+            "transforms": [
+                { "type": "scale", "path": "/temp", "factor": 0.01 },
+                { "type": "filter", "path": "/temp", "op": "exists", "value": null }
+            ]
+        }).to_string()),
+        active: true,
+    }).await.unwrap();
+
+    let app = init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    let request = |payload: &'static str| TestRequest::post()
+        .uri("/add")
+        .peer_addr(socket_addr)
+        .insert_header(("X-Source-Id", "src1"))
+        .set_payload(payload)
+        .to_request();
+
+    let resp = call_service(&app, request(r#"{"temp": 2150}"#)).await;
+    assert_eq!(resp.status(), 200);
+
+    // filtered out, nothing is stored
+    let resp = call_service(&app, request(r#"{"humidity": 40}"#)).await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = call_service(&app, request(r#"{"temp": "n/a"}"#)).await;
+    assert_eq!(resp.status(), 422);
+    assert_eq!(read_body(resp).await, "Transform stage 'scale' failed: value at /temp is not a number");
+
+    let records = rep::get_last_data(&pool, &10).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(serde_json::from_slice::<Value>(&records[0].data).unwrap(), json!({ "temp": 21.5 }));
+    assert_eq!(stats::get_rejected_count(&pool, "src1").await.unwrap(), 1);
+}
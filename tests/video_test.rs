@@ -0,0 +1,280 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use actix_http::body::MessageBody;
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, web, App, Error, HttpResponse, HttpServer};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use tempfile::TempDir;
+use broker::api::video;
+use broker::config::{Config, TargetCfg, TargetMode};
+use broker::data::db::init_db_in_memory;
+use broker::data::{rep, video as video_rep};
+use broker::data::store::{self, SqliteStore};
+use broker::models::Source;
+use broker::tasks;
+use broker::uplink::Uplink;
+
+async fn init_app(pool: Pool<Sqlite>, dir: &TempDir) -> impl Service<
+    Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = Error,
+> {
+    rep::add_source(&pool, &Source {
+        src_id: "cam1".to_string(),
+        cfg: None,
+        active: true,
+    }).await.unwrap();
+
+    let cfg = Config {
+        video_dir: dir.path().to_string_lossy().into_owned(),
+        ..Default::default()
+    };
+
+    test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(cfg))
+            .service(video::upload_init_segment)
+            .service(video::get_init_segment)
+            .service(video::get_playlist)
+            .service(video::upload_segment)
+            .service(video::get_segment),
+    ).await
+}
+
+fn upload_request(uri: &str, duration: &str, payload: Vec<u8>) -> Request {
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345);
+    test::TestRequest::post()
+        .uri(uri)
+        .peer_addr(socket_addr)
+        .insert_header(("X-Source-Id", "cam1"))
+        .insert_header(("X-Segment-Duration", duration))
+        .insert_header(("Content-Type", "video/mp2t"))
+        .set_payload(payload)
+        .to_request()
+}
+
+#[actix_web::test]
+async fn test_upload_and_get_segment() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    let resp = test::call_service(&app, upload_request("/video/front/1", "2.0", vec![0x47; 188])).await;
+    assert_eq!(resp.status(), 200);
+
+    let req = test::TestRequest::get().uri("/video/front/1").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "video/mp2t");
+    assert_eq!(test::read_body(resp).await.to_vec(), vec![0x47; 188]);
+
+    // the same sequence number cannot be uploaded twice
+    let resp = test::call_service(&app, upload_request("/video/front/1", "2.0", vec![1; 10])).await;
+    assert_eq!(resp.status(), 409);
+
+    // the rejected upload does not replace the stored file
+    let req = test::TestRequest::get().uri("/video/front/1").to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await.to_vec(), vec![0x47; 188]);
+}
+
+#[actix_web::test]
+async fn test_playlist() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    for (seq, duration) in [(5, "2.0"), (6, "2.5"), (8, "1.0")] {
+        let uri = format!("/video/front/{}", seq);
+        let resp = test::call_service(&app, upload_request(&uri, duration, vec![1; 10])).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    let req = test::TestRequest::get().uri("/video/front/index.m3u8").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let body = test::read_body(resp).await;
+    let expected = "#EXTM3U\n\
+        #EXT-X-VERSION:3\n\
+        #EXT-X-TARGETDURATION:3\n\
+        #EXT-X-MEDIA-SEQUENCE:5\n\
+        #EXTINF:2.000,\n5\n\
+        #EXTINF:2.500,\n6\n\
+        #EXT-X-DISCONTINUITY\n\
+        #EXTINF:1.000,\n8\n";
+    assert_eq!(body, expected);
+
+    let req = test::TestRequest::get().uri("/video/unknown/index.m3u8").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_web::test]
+async fn test_init_segment_is_referenced_by_playlist() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    let resp = test::call_service(&app, upload_request("/video/front/init", "1", vec![2; 20])).await;
+    assert_eq!(resp.status(), 200);
+    let resp = test::call_service(&app, upload_request("/video/front/0", "1", vec![1; 10])).await;
+    assert_eq!(resp.status(), 200);
+
+    let req = test::TestRequest::get().uri("/video/front/index.m3u8").to_request();
+    let body = test::read_body(test::call_service(&app, req).await).await;
+    assert!(std::str::from_utf8(&body).unwrap().contains("#EXT-X-MAP:URI=\"init\""));
+
+    let req = test::TestRequest::get().uri("/video/front/init").to_request();
+    let body = test::read_body(test::call_service(&app, req).await).await;
+    assert_eq!(body.to_vec(), vec![2; 20]);
+}
+
+#[actix_web::test]
+async fn test_stream_of_another_source_is_forbidden() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    rep::add_source(&pool, &Source {
+        src_id: "cam2".to_string(),
+        cfg: None,
+        active: true,
+    }).await.unwrap();
+
+    let resp = test::call_service(&app, upload_request("/video/front/0", "1", vec![1; 10])).await;
+    assert_eq!(resp.status(), 200);
+
+    for uri in ["/video/front/1", "/video/front/init"] {
+        let mut req = upload_request(uri, "1", vec![2; 10]);
+        req.headers_mut().insert("X-Source-Id".parse().unwrap(), "cam2".parse().unwrap());
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    assert!(!dir.path().join("front").join("init.mp4").exists());
+    assert_eq!(video_rep::get_stream_segments(&pool, "front").await.unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn test_invalid_stream_id() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    let resp = test::call_service(&app, upload_request("/video/..%2F..%2Fetc/1", "1", vec![1; 10])).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_web::test]
+async fn test_purge_expired_segments() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    for seq in 1..=3 {
+        let uri = format!("/video/front/{}", seq);
+        test::call_service(&app, upload_request(&uri, "2", vec![1; 10])).await;
+    }
+
+    // the first two segments are older than the default expiration (72 hours)
+    let expired = chrono::Utc::now().timestamp() - 73 * 3600;
+    sqlx::query("UPDATE video_segments SET created_at = ? WHERE seq < 3")
+        .bind(expired)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let purged = tasks::video::purge_expired_segments(&pool).await.unwrap();
+    assert_eq!(purged, 2);
+
+    let segments = video_rep::get_stream_segments(&pool, "front").await.unwrap();
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].seq, 3);
+    assert!(!dir.path().join("front").join("1.ts").exists());
+    assert!(dir.path().join("front").join("3.ts").exists());
+}
+
+#[actix_web::test]
+async fn test_unsent_segments() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    for seq in 1..=2 {
+        let uri = format!("/video/front/{}", seq);
+        test::call_service(&app, upload_request(&uri, "2", vec![1; 10])).await;
+    }
+
+    let unsent = video_rep::get_unsent_segments(&pool, &10).await.unwrap();
+    assert_eq!(unsent.len(), 2);
+
+    video_rep::mark_segments_sent(&pool, &[unsent[0].id]).await.unwrap();
+
+    let unsent = video_rep::get_unsent_segments(&pool, &10).await.unwrap();
+    assert_eq!(unsent.len(), 1);
+    assert_eq!(unsent[0].seq, 2);
+}
+
+#[actix_web::test]
+async fn test_segments_are_uploaded_by_uplink() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db_in_memory().await.unwrap();
+    let app = init_app(pool.clone(), &dir).await;
+
+    for seq in 1..=2 {
+        let uri = format!("/video/front/{}", seq);
+        test::call_service(&app, upload_request(&uri, "2", vec![seq as u8; 10])).await;
+    }
+
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let hub_batches = batches.clone();
+    let server = HttpServer::new(move || {
+        let batches = hub_batches.clone();
+        App::new().route("/records", web::post().to(move |batch: web::Json<Value>| {
+            batches.lock().unwrap().push(batch.into_inner());
+            async { HttpResponse::Ok().finish() }
+        }))
+    })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let endpoint = format!("http://{}/records", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    let config = Config {
+        system_name: "test".to_string(),
+        targets: vec![TargetCfg {
+            name: "hub".to_string(),
+            endpoint,
+            topic: None,
+            auth_endpoint: None,
+            client_id: String::new(),
+            secret: String::new(),
+            mode: TargetMode::Failover,
+            compression: None,
+        }],
+        ..Default::default()
+    };
+    let uplinks = Uplink::from_config(pool.clone(), &config).unwrap();
+
+    assert_eq!(uplinks[0].deliver_segment().await.unwrap(), 1);
+    assert_eq!(uplinks[0].deliver_segment().await.unwrap(), 1);
+    assert_eq!(uplinks[0].deliver_segment().await.unwrap(), 0);
+    assert!(video_rep::get_unsent_segments(&pool, &10).await.unwrap().is_empty());
+
+    let batches = batches.lock().unwrap();
+    let record = &batches[0]["records"][0];
+    assert_eq!(record["kind"], "video");
+    assert_eq!(record["src_id"], "cam1");
+    assert_eq!(record["data"], STANDARD.encode([1; 10]));
+    assert_eq!(record["segment"]["stream_id"], "front");
+    assert_eq!(record["segment"]["seq"], 1);
+    assert_eq!(record["segment"]["content_type"], "video/mp2t");
+    assert_eq!(batches[1]["records"][0]["segment"]["seq"], 2);
+}
@@ -0,0 +1,344 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite};
+use broker::api::{admin, endpoints};
+use broker::config::{ApiKeyCfg, Config, Role, TargetCfg, TargetMode, WebhookCfg};
+use broker::data::db::init_db_in_memory;
+use broker::data::store::{self, SqliteStore};
+use broker::data::{rep, target, webhook};
+use broker::models::{EventKind, Record, Source, WebhookDeliveryState};
+use broker::uplink::Uplink;
+use broker::webhook::{retry_delay, Dispatcher, Event, Monitor};
+
+const ADMIN_KEY: &str = "admin-key-0123456789abcdef";
+
+/// Gets the config with an API key granting the admin role
+fn admin_config() -> Config {
+    Config {
+        api_keys: vec![ApiKeyCfg { name: "admin".to_string(), key: ADMIN_KEY.to_string(), roles: vec![Role::Admin] }],
+        ..Default::default()
+    }
+}
+
+fn admin_auth() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", ADMIN_KEY))
+}
+
+const SECRET: &str = "webhook-secret-0001";
+
+/// Headers and body of a request received by the stub
+type StubRequest = (Vec<(String, String)>, Bytes);
+
+/// Local endpoint answering with `status` and keeping the received requests
+struct Stub {
+    status: Arc<AtomicU16>,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+    url: String,
+}
+
+fn start_stub(status: u16) -> Stub {
+    let status = Arc::new(AtomicU16::new(status));
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let (stub_status, stub_requests) = (status.clone(), requests.clone());
+    let server = HttpServer::new(move || {
+        let (status, requests) = (stub_status.clone(), stub_requests.clone());
+        App::new().route("/hook", web::post().to(move |req: HttpRequest, body: Bytes| {
+            let headers = req
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_string()))
+                .collect();
+            requests.lock().unwrap().push((headers, body));
+            let status = status.load(Ordering::SeqCst);
+            async move {
+                HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish()
+            }
+        }))
+    })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+    let url = format!("http://{}/hook", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    Stub { status, requests, url }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str()).unwrap()
+}
+
+async fn init_pool(sources: &[(&str, Option<&str>)]) -> Pool<Sqlite> {
+    let pool = init_db_in_memory().await.unwrap();
+    for (src_id, cfg) in sources {
+        let source = Source { src_id: src_id.to_string(), cfg: cfg.map(str::to_string), active: true };
+        rep::add_source(&pool, &source).await.unwrap();
+    }
+    pool
+}
+
+/// Makes the pending deliveries due at once instead of waiting for the retry delay
+async fn make_due(pool: &Pool<Sqlite>) {
+    sqlx::query("UPDATE webhook_deliveries SET next_attempt_at = 0").execute(pool).await.unwrap();
+}
+
+#[actix_web::test]
+async fn test_first_seen_source_is_delivered_signed() {
+    let stub = start_stub(200);
+    let pool = init_pool(&[("src1", None)]).await;
+    let config = Config { system_name: "test".to_string(), ..Default::default() };
+    let id = webhook::add_webhook(&pool, &stub.url, SECRET, &[EventKind::SourceFirstSeen]).await.unwrap();
+    webhook::add_webhook(&pool, &stub.url, SECRET, &[EventKind::HubDisconnected]).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(Arc::new(SqliteStore::new(pool.clone()))))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(config.clone()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    // only the first record of the source raises the event
+    for payload in ["one", "two"] {
+        let req = test::TestRequest::post()
+            .uri("/add")
+            .insert_header(("X-Source-Id", "src1"))
+            .set_payload(payload)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    let dispatcher = Dispatcher::new(pool.clone(), &config);
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+
+    let requests = stub.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 1);
+    let (headers, body) = &requests[0];
+    assert_eq!(header(headers, "x-webhook-event"), "source.first_seen");
+
+    let timestamp = header(headers, "x-webhook-timestamp");
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    let expected = format!("sha256={:x}", mac.finalize().into_bytes());
+    assert_eq!(header(headers, "x-webhook-signature"), expected);
+
+    let body: Value = serde_json::from_slice(body).unwrap();
+    assert_eq!(body["event"], "source.first_seen");
+    assert_eq!(body["system_name"], "test");
+    assert_eq!(body["data"]["src_id"], "src1");
+    assert_eq!(body["id"].to_string(), header(headers, "x-webhook-id"));
+
+    let deliveries = webhook::get_deliveries(&pool, id, &10).await.unwrap();
+    assert_eq!((deliveries[0].state, deliveries[0].attempts), (WebhookDeliveryState::Delivered, 1));
+}
+
+#[actix_web::test]
+async fn test_failed_delivery_is_retried() {
+    let stub = start_stub(500);
+    let pool = init_pool(&[]).await;
+    let config = Config { webhooks: WebhookCfg { max_attempts: 3, ..Default::default() }, ..Default::default() };
+    let id = webhook::add_webhook(&pool, &stub.url, SECRET, &[EventKind::HubReconnected]).await.unwrap();
+    let dispatcher = Dispatcher::new(pool.clone(), &config);
+
+    broker::webhook::emit(&pool, Event::HubReconnected { target: "hub".to_string() }).await;
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+
+    // the delivery waits for the retry delay
+    let delivery = webhook::get_deliveries(&pool, id, &10).await.unwrap().remove(0);
+    assert_eq!((delivery.state, delivery.attempts), (WebhookDeliveryState::Pending, 1));
+    assert!(delivery.last_error.unwrap().contains("500"));
+    assert!(delivery.next_attempt_at > delivery.created_at);
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+    assert_eq!(stub.requests.lock().unwrap().len(), 1);
+
+    stub.status.store(204, Ordering::SeqCst);
+    make_due(&pool).await;
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+    let delivery = webhook::get_deliveries(&pool, id, &10).await.unwrap().remove(0);
+    assert_eq!((delivery.state, delivery.attempts), (WebhookDeliveryState::Delivered, 2));
+
+    // the delivery fails once it reaches the max attempts
+    stub.status.store(503, Ordering::SeqCst);
+    broker::webhook::emit(&pool, Event::HubReconnected { target: "hub".to_string() }).await;
+    for _ in 0..3 {
+        make_due(&pool).await;
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+    }
+    let delivery = webhook::get_deliveries(&pool, id, &10).await.unwrap().remove(0);
+    assert_eq!((delivery.state, delivery.attempts), (WebhookDeliveryState::Failed, 3));
+    make_due(&pool).await;
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+    assert_eq!(stub.requests.lock().unwrap().len(), 5);
+
+    assert_eq!((retry_delay(0), retry_delay(1), retry_delay(3)), (5, 10, 40));
+    assert_eq!(retry_delay(100), 3600);
+}
+
+#[actix_web::test]
+async fn test_quota_and_backlog_events() {
+    let pool = init_pool(&[("src1", Some(r#"{"quota": 2}"#)), ("src2", None)]).await;
+    let config = Config { webhooks: WebhookCfg { backlog_threshold: Some(4), ..Default::default() }, ..Default::default() };
+    let id = webhook::add_webhook(&pool, "http://127.0.0.1:1/hook", SECRET, &[
+        EventKind::SourceQuotaExceeded,
+        EventKind::BacklogAboveThreshold,
+    ]).await.unwrap();
+
+    let add = |src_id: &str, count: usize| {
+        let records: Vec<Record> = (0..count)
+            .map(|_| Record { src_id: src_id.to_string(), data: b"data".to_vec(), ..Default::default() })
+            .collect();
+        let pool = pool.clone();
+        async move { rep::add_data(&pool, &records).await.unwrap() }
+    };
+
+    let mut monitor = Monitor::new(pool.clone(), &config);
+    add("src1", 2).await;
+    add("src2", 2).await;
+    monitor.check().await.unwrap();
+    assert!(webhook::get_deliveries(&pool, id, &10).await.unwrap().is_empty());
+
+    let ids = add("src1", 1).await;
+    monitor.check().await.unwrap();
+    monitor.check().await.unwrap();
+    let deliveries = webhook::get_deliveries(&pool, id, &10).await.unwrap();
+    assert_eq!(deliveries.len(), 2);
+    let events: Vec<(EventKind, Value)> = deliveries.into_iter().map(|d| (d.event, d.data)).collect();
+    assert!(events.contains(&(EventKind::SourceQuotaExceeded, serde_json::json!({ "src_id": "src1", "unsent": 3, "quota": 2 }))));
+    assert!(events.contains(&(EventKind::BacklogAboveThreshold, serde_json::json!({ "unsent": 5, "threshold": 4 }))));
+
+    // the events are raised again once the backlog goes back under the limits and exceeds them again
+    rep::delete_data(&pool, ids).await.unwrap();
+    monitor.check().await.unwrap();
+    add("src1", 1).await;
+    monitor.check().await.unwrap();
+    assert_eq!(webhook::get_deliveries(&pool, id, &10).await.unwrap().len(), 4);
+}
+
+#[actix_web::test]
+async fn test_hub_connection_events() {
+    let hub = start_stub(503);
+    let pool = init_pool(&[("src1", None)]).await;
+    let target = TargetCfg {
+        name: "hub".to_string(),
+        endpoint: hub.url.clone(),
+        topic: None,
+        auth_endpoint: None,
+        client_id: String::new(),
+        secret: String::new(),
+        mode: TargetMode::Failover,
+        compression: None,
+    };
+    let config = Config { targets: vec![target], ..Default::default() };
+    target::sync_targets(&pool, &config.targets).await.unwrap();
+    let id = webhook::add_webhook(&pool, "http://127.0.0.1:1/hook", SECRET, &[
+        EventKind::HubDisconnected,
+        EventKind::HubReconnected,
+    ]).await.unwrap();
+
+    rep::add_data(&pool, &[Record { src_id: "src1".to_string(), data: vec![1], ..Default::default() }]).await.unwrap();
+    let uplinks = Uplink::from_config(pool.clone(), &config).unwrap();
+
+    // the event is raised once per change of the connection
+    for _ in 0..2 {
+        assert_eq!(uplinks[0].deliver_batch().await.unwrap(), 0);
+    }
+    hub.status.store(200, Ordering::SeqCst);
+    assert_eq!(uplinks[0].deliver_batch().await.unwrap(), 1);
+
+    let deliveries = webhook::get_deliveries(&pool, id, &10).await.unwrap();
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!((deliveries[1].event, &deliveries[1].data["target"]), (EventKind::HubDisconnected, &Value::from("hub")));
+    assert!(deliveries[1].data["error"].as_str().unwrap().contains("503"));
+    assert_eq!(deliveries[0].event, EventKind::HubReconnected);
+}
+
+#[actix_web::test]
+async fn test_webhook_admin() {
+    let stub = start_stub(200);
+    let pool = init_pool(&[]).await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(admin_config()))
+            .service(admin::add_webhook)
+            .service(admin::list_webhooks)
+            .service(admin::delete_webhook)
+            .service(admin::list_webhook_deliveries)
+            .service(admin::test_webhook),
+    ).await;
+
+    let add = |body: Value| test::TestRequest::post()
+        .uri("/admin/webhooks")
+        .insert_header(admin_auth())
+        .set_json(body)
+        .to_request();
+
+    let resp = test::call_service(&app, add(serde_json::json!({
+        "url": stub.url, "secret": "short", "events": ["source.first_seen"],
+    }))).await;
+    assert_eq!(resp.status(), 400);
+    let resp = test::call_service(&app, add(serde_json::json!({
+        "url": "ftp://localhost/hook", "secret": SECRET, "events": ["source.first_seen"],
+    }))).await;
+    assert_eq!(resp.status(), 400);
+    let resp = test::call_service(&app, add(serde_json::json!({
+        "url": stub.url, "secret": SECRET, "events": ["source.unknown"],
+    }))).await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = test::call_service(&app, add(serde_json::json!({
+        "url": stub.url, "secret": SECRET, "events": ["source.first_seen", "hub.disconnected"],
+    }))).await;
+    assert_eq!(resp.status(), 201);
+    let created: Value = test::read_body_json(resp).await;
+    assert!(created.get("secret").is_none());
+    assert_eq!(created["events"], serde_json::json!(["source.first_seen", "hub.disconnected"]));
+    let id = created["id"].as_u64().unwrap();
+
+    let req = test::TestRequest::get().uri("/admin/webhooks").insert_header(admin_auth()).to_request();
+    let webhooks: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(webhooks.as_array().unwrap().len(), 1);
+
+    // the test event is sent whatever events the webhook is subscribed to
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/webhooks/{}/test", id))
+        .insert_header(admin_auth())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 202);
+    let dispatcher = Dispatcher::new(pool.clone(), &Config::default());
+    assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+    assert_eq!(header(&stub.requests.lock().unwrap()[0].0, "x-webhook-event"), "webhook.test");
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/webhooks/{}/deliveries", id))
+        .insert_header(admin_auth())
+        .to_request();
+    let deliveries: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(deliveries[0]["event"], "webhook.test");
+    assert_eq!(deliveries[0]["state"], "delivered");
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/admin/webhooks/{}", id))
+        .insert_header(admin_auth())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    for req in [
+        test::TestRequest::delete().uri(&format!("/admin/webhooks/{}", id)).insert_header(admin_auth()).to_request(),
+        test::TestRequest::post().uri(&format!("/admin/webhooks/{}/test", id)).insert_header(admin_auth()).to_request(),
+        test::TestRequest::get()
+            .uri(&format!("/admin/webhooks/{}/deliveries", id))
+            .insert_header(admin_auth())
+            .to_request(),
+    ] {
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use actix_web::{test, web, App};
+use tempfile::TempDir;
+use broker::api::endpoints;
+use broker::config::{Config, DatabaseCfg, Synchronous};
+use broker::data::db::init_db;
+use broker::data::dedupe::Stored;
+use broker::data::error::RepError;
+use broker::data::rep;
+use broker::data::store::{self, RecordStore, SqliteStore};
+use broker::data::writer::Writer;
+use broker::models::{Record, Source};
+
+fn record(src_id: &str, n: u8) -> Record {
+    Record { src_id: src_id.to_string(), data: vec![n], ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_pool_is_configured() {
+    let dir = TempDir::new().unwrap();
+    let cfg = DatabaseCfg { busy_timeout: 1234, synchronous: Synchronous::Full, cache_size: 4096, ..Default::default() };
+    let pool = init_db(&dir.path().join("broker.db").to_string_lossy(), &cfg).await.unwrap();
+
+    let pragma = |name: &'static str| {
+        let pool = pool.clone();
+        async move { sqlx::query_scalar::<_, i64>(&format!("PRAGMA {}", name)).fetch_one(&pool).await.unwrap() }
+    };
+    assert_eq!(pragma("busy_timeout").await, 1234);
+    assert_eq!(pragma("synchronous").await, 2);
+    assert_eq!(pragma("cache_size").await, -4096);
+    assert_eq!(pragma("foreign_keys").await, 1);
+
+    // records of a deleted source are deleted by the foreign key
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+    rep::add_data(&pool, &[record("src1", 1)]).await.unwrap();
+    rep::delete_source(&pool, "src1").await.unwrap();
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM records").fetch_one(&pool).await.unwrap();
+    assert_eq!(count, 0);
+}
+
+#[tokio::test]
+async fn test_concurrent_inserts_are_committed() {
+    let dir = TempDir::new().unwrap();
+    let cfg = DatabaseCfg { busy_timeout: 0, ..Default::default() };
+    let pool = init_db(&dir.path().join("broker.db").to_string_lossy(), &cfg).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let store = SqliteStore::with_writer(pool.clone(), Writer::start(pool.clone(), 16));
+
+    // without waiting for the lock the inserts only succeed if they are serialized
+    let handles: Vec<_> = (0..100u8)
+        .map(|n| {
+            let store = store.clone();
+            tokio::spawn(async move { store.add_data(&[record("src1", n), record("src1", n)]).await })
+        })
+        .collect();
+
+    let mut ids = HashSet::new();
+    for handle in handles {
+        ids.extend(handle.await.unwrap().unwrap());
+    }
+    assert_eq!(ids.len(), 200);
+    assert_eq!(rep::get_last_data(&pool, &1000).await.unwrap().len(), 200);
+}
+
+#[tokio::test]
+async fn test_rejected_insert_does_not_roll_back_others() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db(&dir.path().join("broker.db").to_string_lossy(), &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let writer = Writer::start(pool.clone(), 16);
+    let (first, unknown, last) = tokio::join!(
+        writer.add_data(vec![record("src1", 1)]),
+        writer.add_data(vec![record("src1", 2), record("src2", 3)]),
+        writer.add_data(vec![record("src1", 4)]),
+    );
+
+    assert!(first.is_ok());
+    assert!(matches!(unknown, Err(RepError::Constraint(_))));
+    assert!(last.is_ok());
+
+    let data: Vec<u8> = rep::get_last_data(&pool, &10).await.unwrap().into_iter().map(|r| r.data[0]).collect();
+    assert_eq!(data, vec![1, 4]);
+}
+
+#[tokio::test]
+async fn test_keyed_inserts_are_committed_together() {
+    let dir = TempDir::new().unwrap();
+    let pool = init_db(&dir.path().join("broker.db").to_string_lossy(), &DatabaseCfg::default()).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    let writer = Writer::start(pool.clone(), 16);
+    let (first, retry, other) = tokio::join!(
+        writer.add_data_once(record("src1", 1), "k1".to_string(), 60),
+        writer.add_data_once(record("src1", 2), "k1".to_string(), 60),
+        writer.add_data_once(record("src1", 3), "k2".to_string(), 60),
+    );
+
+    let Stored::Added(id) = first.unwrap() else { panic!("the first insert of the key is stored") };
+    assert_eq!(retry.unwrap(), Stored::Duplicate(id));
+    assert!(matches!(other.unwrap(), Stored::Added(_)));
+
+    let data: Vec<u8> = rep::get_last_data(&pool, &10).await.unwrap().into_iter().map(|r| r.data[0]).collect();
+    assert_eq!(data, vec![1, 3]);
+}
+
+#[actix_web::test]
+async fn test_default_config_inserts_through_writer() {
+    let dir = TempDir::new().unwrap();
+    let cfg = DatabaseCfg { busy_timeout: 0, ..Default::default() };
+    let pool = init_db(&dir.path().join("broker.db").to_string_lossy(), &cfg).await.unwrap();
+    rep::add_source(&pool, &Source { src_id: "src1".to_string(), cfg: None, active: true }).await.unwrap();
+
+    // records are deduplicated by default
+    assert!(Config::default().dedupe_window > 0);
+
+    let store = Arc::new(SqliteStore::with_writer(pool.clone(), Writer::start(pool.clone(), 16)));
+    let app = test::init_service(
+        App::new()
+            .configure(store::configure(store))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Config::default()))
+            .service(endpoints::receive_data),
+    ).await;
+
+    let request = |n: u8| test::TestRequest::post()
+        .uri("/add")
+        .peer_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 12345))
+        .insert_header(("X-Source-Id", "src1"))
+        .set_payload(format!("payload {}", n % 50))
+        .to_request();
+
+    // without waiting for the lock the inserts only succeed if they are serialized,
+    // the repeated payloads are duplicates of the first ones
+    let responses = futures::future::join_all((0..100u8).map(|n| test::call_service(&app, request(n)))).await;
+    assert!(responses.iter().all(|resp| resp.status() == 200));
+    let duplicates = responses.iter().filter(|resp| resp.headers().contains_key("X-Duplicate")).count();
+    assert_eq!(duplicates, 50);
+
+    assert_eq!(rep::get_last_data(&pool, &1000).await.unwrap().len(), 50);
+}